@@ -0,0 +1,54 @@
+//! Posts alerts to a Slack or Discord incoming webhook, for when hookhub is left running and
+//! someone needs to know it's degraded.
+
+use std::time::Duration;
+
+use log::{info, warn};
+use serde_json::json;
+use url::Url;
+
+#[derive(Clone)]
+pub struct Alerter {
+    url: Url,
+    http: reqwest::Client,
+}
+
+impl Alerter {
+    pub fn new(url: Url) -> Self {
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .unwrap();
+
+        Self { url, http }
+    }
+
+    /// Posts the message in the background, logging if it couldn't be delivered
+    pub fn send(&self, message: impl Into<String>) {
+        let message = message.into();
+        // Discord's webhooks take the message as content, Slack's (and most others') as text
+        let body = if self
+            .url
+            .host_str()
+            .is_some_and(|h| h.ends_with("discord.com"))
+        {
+            json!({ "content": message })
+        } else {
+            json!({ "text": message })
+        };
+
+        let (url, http) = (self.url.clone(), self.http.clone());
+        tokio::spawn(async move {
+            let request = http
+                .post(url)
+                .header("content-type", "application/json")
+                .body(body.to_string());
+
+            match request.send().await {
+                Ok(resp) if resp.status().is_success() => info!("Sent alert: {}", message),
+                Ok(resp) => warn!("Alert webhook responded {} to: {}", resp.status(), message),
+                Err(e) => warn!("Could not send alert ({}): {}", e, message),
+            }
+        });
+    }
+}
@@ -0,0 +1,45 @@
+use anyhow::{anyhow, Context, Result};
+use serde::de::DeserializeOwned;
+use url::Url;
+
+use hookhub::client::{http_client, prepare_remote_url};
+
+/// Gets `path` below the remote's `/__hookhub__/` with the secret, decoding the JSON it
+/// responds with
+pub async fn get<T: DeserializeOwned>(
+    mut remote: Url,
+    secret: String,
+    path: &str,
+    query: &[(&str, String)],
+) -> Result<T> {
+    prepare_remote_url(&mut remote)?;
+
+    let mut url = remote.clone();
+    let scheme = if remote.scheme() == "wss" {
+        "https"
+    } else {
+        "http"
+    };
+    url.set_scheme(scheme)
+        .map_err(|_| anyhow!("can't derive admin url from {}", remote))?;
+    url.set_path(&format!("{}{}", remote.path(), path));
+    if !query.is_empty() {
+        url.query_pairs_mut().extend_pairs(query);
+    }
+
+    let response = http_client()?
+        .get(url.clone())
+        .basic_auth("hookhub", Some(secret))
+        .send()
+        .await
+        .with_context(|| format!("couldn't reach {}", url))?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        return Err(anyhow!("{} responded {} {}", url, status, body));
+    }
+
+    serde_json::from_slice(&response.bytes().await?)
+        .context("unexpected response, is the server too old for the admin API?")
+}
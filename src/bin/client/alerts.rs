@@ -0,0 +1,99 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use hookhub::alert::Alerter;
+
+/// Sends alerts when forwarding keeps failing or the connection stays down, and again when
+/// things recover. Only degraded states are alerted, not every failure.
+struct Alerts {
+    alerter: Alerter,
+    remote: String,
+    after_failures: u32,
+    after_disconnect: Duration,
+    failures: AtomicU32,
+    /// Bumped on every disconnect, so a pending alert knows if it's been reconnected since
+    outage: AtomicU64,
+    disconnect_alerted: AtomicBool,
+}
+
+static ALERTS: OnceLock<Alerts> = OnceLock::new();
+
+/// Turns on alerting, starting off disconnected so not connecting at all is alerted too
+pub fn enable(alerter: Alerter, remote: &str, after_failures: u32, after_disconnect: Duration) {
+    let _ = ALERTS.set(Alerts {
+        alerter,
+        remote: remote.to_string(),
+        after_failures,
+        after_disconnect,
+        failures: AtomicU32::new(0),
+        outage: AtomicU64::new(0),
+        disconnect_alerted: AtomicBool::new(false),
+    });
+
+    disconnected();
+}
+
+pub fn forwarded() {
+    let Some(alerts) = ALERTS.get() else {
+        return;
+    };
+
+    if alerts.failures.swap(0, Ordering::Relaxed) >= alerts.after_failures {
+        alerts
+            .alerter
+            .send("hookhub: forwarding to the local origin recovered");
+    }
+}
+
+pub fn forward_failed(error: &str) {
+    let Some(alerts) = ALERTS.get() else {
+        return;
+    };
+
+    if alerts.failures.fetch_add(1, Ordering::Relaxed) + 1 == alerts.after_failures {
+        alerts.alerter.send(format!(
+            "hookhub: the last {} forwards to the local origin failed, most recently with: {}",
+            alerts.after_failures, error
+        ));
+    }
+}
+
+pub fn disconnected() {
+    let Some(alerts) = ALERTS.get() else {
+        return;
+    };
+
+    let outage = alerts.outage.fetch_add(1, Ordering::Relaxed) + 1;
+
+    tokio::spawn(async move {
+        tokio::time::sleep(alerts.after_disconnect).await;
+
+        // still the same outage, nothing has connected since
+        if alerts.outage.load(Ordering::Relaxed) == outage {
+            alerts.disconnect_alerted.store(true, Ordering::Relaxed);
+            alerts.alerter.send(format!(
+                "hookhub: not connected to {} for over {} minutes",
+                alerts.remote,
+                alerts.after_disconnect.as_secs() / 60
+            ));
+        }
+    });
+}
+
+pub fn connected() {
+    let Some(alerts) = ALERTS.get() else {
+        return;
+    };
+
+    alerts.outage.fetch_add(1, Ordering::Relaxed);
+    if alerts.disconnect_alerted.swap(false, Ordering::Relaxed) {
+        alerts
+            .alerter
+            .send(format!("hookhub: reconnected to {}", alerts.remote));
+    }
+}
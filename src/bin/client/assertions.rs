@@ -0,0 +1,216 @@
+//! Expectations of how the local origin responds, from `--assertions`, checked on every forward
+//! and replay with a summary of how each fared at the end.
+
+use std::{
+    fs,
+    path::Path,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use glob::Pattern;
+use hookhub::RequestMessage;
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::history_db::RecordedResponse;
+
+/// One expectation, optionally limited to a method and path glob like response rules
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Assertion {
+    /// What to call it in the report, described from the rest by default
+    name: Option<String>,
+    method: Option<String>,
+    path: Option<String>,
+    /// A status like 200, or a class like 2xx
+    status: Option<String>,
+    /// The most milliseconds the response may take
+    within_ms: Option<u64>,
+    /// Text the response body must contain
+    body_contains: Option<String>,
+}
+
+struct Check {
+    name: String,
+    pattern: Option<Pattern>,
+    assertion: Assertion,
+    passed: usize,
+    failed: usize,
+}
+
+static CHECKS: OnceLock<Mutex<Vec<Check>>> = OnceLock::new();
+
+/// Reads the assertions to check forwards against from the JSON file at `path`
+pub fn load(path: &Path) -> Result<()> {
+    let assertions: Vec<Assertion> = serde_json::from_slice(&fs::read(path)?)
+        .map_err(|e| anyhow!("invalid assertions in {}: {}", path.display(), e))?;
+
+    let checks = assertions
+        .into_iter()
+        .map(|assertion| {
+            if let Some(status) = &assertion.status {
+                if status_matches(status, 200).is_none() {
+                    bail!(
+                        "invalid status {} in {}, expected like 200 or 2xx",
+                        status,
+                        path.display()
+                    );
+                }
+            }
+
+            Ok(Check {
+                name: assertion
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| describe(&assertion)),
+                pattern: assertion.path.as_deref().map(Pattern::new).transpose()?,
+                assertion,
+                passed: 0,
+                failed: 0,
+            })
+        })
+        .collect::<Result<_>>()?;
+
+    let _ = CHECKS.set(Mutex::new(checks));
+
+    Ok(())
+}
+
+fn describe(assertion: &Assertion) -> String {
+    let mut name = format!(
+        "{} {}",
+        assertion.method.as_deref().unwrap_or("*"),
+        assertion.path.as_deref().unwrap_or("*")
+    );
+    if let Some(status) = &assertion.status {
+        name.push_str(&format!(" returns {}", status));
+    }
+    if let Some(ms) = assertion.within_ms {
+        name.push_str(&format!(" within {}ms", ms));
+    }
+    if let Some(text) = &assertion.body_contains {
+        name.push_str(&format!(" with {:?} in the body", text));
+    }
+
+    name
+}
+
+/// Whether `status` matches the expected status or class, `None` if the expectation is invalid
+fn status_matches(expected: &str, status: u16) -> Option<bool> {
+    match expected.to_ascii_lowercase().strip_suffix("xx") {
+        Some(class) => class
+            .parse::<u16>()
+            .ok()
+            .filter(|class| (1..6).contains(class))
+            .map(|class| status / 100 == class),
+        None => expected
+            .parse::<u16>()
+            .ok()
+            .map(|expected| expected == status),
+    }
+}
+
+/// Checks how the local origin responded to `req`, `None` if it couldn't be reached, against
+/// every assertion matching it, logging those that fail with its path as `logged`, redacted
+pub fn check(
+    id: &str,
+    req: &RequestMessage,
+    logged: &str,
+    response: Option<&RecordedResponse>,
+    elapsed: Duration,
+) {
+    let Some(checks) = CHECKS.get() else {
+        return;
+    };
+    let path = req.fullpath.split('?').next().unwrap_or_default();
+
+    for check in checks.lock().unwrap().iter_mut() {
+        let assertion = &check.assertion;
+        let applies = assertion
+            .method
+            .as_ref()
+            .is_none_or(|m| m.eq_ignore_ascii_case(&req.method))
+            && check.pattern.as_ref().is_none_or(|p| p.matches(path));
+        if !applies {
+            continue;
+        }
+
+        match failure(assertion, response, elapsed) {
+            None => check.passed += 1,
+            Some(reason) => {
+                check.failed += 1;
+                warn!(
+                    event = "assertion_failed",
+                    request_id = id,
+                    assertion = check.name.as_str();
+                    "Assertion failed for {} {} ({}): {}: {}", req.method, logged, id, check.name, reason
+                );
+            }
+        }
+    }
+}
+
+fn failure(
+    assertion: &Assertion,
+    response: Option<&RecordedResponse>,
+    elapsed: Duration,
+) -> Option<String> {
+    let Some(response) = response else {
+        return Some("the local origin couldn't be reached".to_string());
+    };
+
+    if let Some(expected) = &assertion.status {
+        if status_matches(expected, response.status) != Some(true) {
+            return Some(format!(
+                "responded {}, expected {}",
+                response.status, expected
+            ));
+        }
+    }
+    if let Some(ms) = assertion.within_ms {
+        if elapsed > Duration::from_millis(ms) {
+            return Some(format!(
+                "took {}ms, expected within {}ms",
+                elapsed.as_millis(),
+                ms
+            ));
+        }
+    }
+    if let Some(text) = &assertion.body_contains {
+        if !String::from_utf8_lossy(&response.body).contains(text.as_str()) {
+            return Some(format!("the body doesn't contain {:?}", text));
+        }
+    }
+
+    None
+}
+
+/// Logs how each assertion fared, failing if any did and `ci` is set
+pub fn report(ci: bool) -> Result<()> {
+    let Some(checks) = CHECKS.get() else {
+        return Ok(());
+    };
+    let checks = checks.lock().unwrap();
+
+    let failed: usize = checks.iter().map(|check| check.failed).sum();
+    let passed: usize = checks.iter().map(|check| check.passed).sum();
+    info!(
+        event = "assertions",
+        passed = passed,
+        failed = failed;
+        "Assertions: {} passed, {} failed", passed, failed
+    );
+    for check in checks.iter() {
+        info!(
+            "  {}: {} passed, {} failed",
+            check.name, check.passed, check.failed
+        );
+    }
+
+    match ci && failed > 0 {
+        true => Err(anyhow!("{} assertions failed", failed)),
+        false => Ok(()),
+    }
+}
@@ -0,0 +1,329 @@
+//! `bench`: driving synthetic requests through the remote's public ingest endpoint at a steady
+//! rate while connected as a client, to measure what the relay sustains end to end.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use hookhub::{
+    client::{http_client, Client},
+    output::OutputFormat,
+    DeliveryOutcome, DeliveryReport,
+};
+use log::{info, warn};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Serialize;
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{self, Instant},
+};
+use url::Url;
+
+use crate::selftest::ingest_url;
+
+/// How long to wait for requests still being relayed once sending stops
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize)]
+struct BenchReport {
+    rate_per_sec: f64,
+    duration_secs: f64,
+    body_size: usize,
+    sent: u64,
+    /// Responded to with a success status by the ingest endpoint
+    accepted: u64,
+    /// Responded to with another status, counted by status
+    rejected: BTreeMap<u16, u64>,
+    /// Couldn't be sent at all
+    send_errors: u64,
+    /// Accepted and relayed back over the websocket
+    relayed: u64,
+    /// Accepted but never relayed back
+    dropped: u64,
+    /// Requests relayed per second of sending
+    throughput_per_sec: f64,
+    /// From sending a request to it being relayed back
+    latency: Percentiles,
+    /// From sending a request to the ingest endpoint responding
+    ingest_latency: Percentiles,
+}
+
+#[derive(Serialize)]
+struct Percentiles {
+    p50_ms: f64,
+    p90_ms: f64,
+    p99_ms: f64,
+    max_ms: f64,
+}
+
+impl Percentiles {
+    fn of(mut durations: Vec<Duration>) -> Self {
+        durations.sort();
+        let at = |p: f64| match durations.len() {
+            0 => 0.0,
+            n => durations[((p * n as f64).ceil() as usize).clamp(1, n) - 1].as_secs_f64() * 1000.0,
+        };
+
+        Percentiles {
+            p50_ms: at(0.5),
+            p90_ms: at(0.9),
+            p99_ms: at(0.99),
+            max_ms: at(1.0),
+        }
+    }
+}
+
+impl std::fmt::Display for Percentiles {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "p50 {:.1}ms, p90 {:.1}ms, p99 {:.1}ms, max {:.1}ms",
+            self.p50_ms, self.p90_ms, self.p99_ms, self.max_ms
+        )
+    }
+}
+
+struct Sent {
+    seq: u64,
+    at: Instant,
+    /// The status the ingest endpoint responded with and when
+    response: Option<(u16, Instant)>,
+}
+
+/// Parses a duration like 30s, 5m or 1h
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (number, unit) = value.split_at(value.len().saturating_sub(1));
+    let number: u64 = number
+        .parse()
+        .map_err(|_| "expected a duration like 30s, 5m or 1h".to_string())?;
+
+    match unit {
+        "s" => Ok(Duration::from_secs(number)),
+        "m" => Ok(Duration::from_secs(number * 60)),
+        "h" => Ok(Duration::from_secs(number * 60 * 60)),
+        _ => Err("expected a duration like 30s, 5m or 1h".to_string()),
+    }
+}
+
+/// Parses a size like 512, 4k or 1m in bytes
+pub fn parse_size(value: &str) -> Result<usize, String> {
+    let lower = value.to_ascii_lowercase();
+    let lower = lower.strip_suffix('b').unwrap_or(&lower);
+    let (number, multiplier) = match lower.strip_suffix('k') {
+        Some(number) => (number, 1024),
+        None => match lower.strip_suffix('m') {
+            Some(number) => (number, 1024 * 1024),
+            None => (lower, 1),
+        },
+    };
+
+    number
+        .parse::<usize>()
+        .map(|number| number * multiplier)
+        .map_err(|_| "expected a size like 512, 4k or 1m".to_string())
+}
+
+pub async fn handle(
+    remote: Url,
+    secret: String,
+    interval: Duration,
+    duration: Duration,
+    body_size: usize,
+    format: OutputFormat,
+) -> Result<()> {
+    let mut connection = Client::new(remote.clone(), secret).connect().await?;
+    if let Some(max) = connection.ack().and_then(|ack| ack.max_body_size) {
+        if body_size > max {
+            bail!("the server accepts bodies up to {} bytes", max);
+        }
+    }
+
+    let run = names::Generator::default().next().unwrap();
+    let prefix = format!("/__hookhub_bench__/{}/", run);
+
+    // receives the requests as they're relayed back, noting when each arrived
+    let (arrived_tx, mut arrived) = mpsc::unbounded_channel::<(u64, Instant)>();
+    let (stop, mut stopped) = oneshot::channel::<()>();
+    let receiving = {
+        let prefix = prefix.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let req = tokio::select! {
+                    req = connection.next() => req,
+                    _ = &mut stopped => break,
+                };
+                let req = match req {
+                    Some(Ok(req)) => req,
+                    Some(Err(e)) => {
+                        warn!("Connection to the remote failed: {}", e);
+                        break;
+                    }
+                    None => break,
+                };
+
+                let Some(seq) = req
+                    .fullpath
+                    .strip_prefix(&prefix)
+                    .and_then(|seq| seq.parse().ok())
+                else {
+                    continue;
+                };
+                let _ = arrived_tx.send((seq, Instant::now()));
+
+                if let Some(request_id) = req.delivery_id() {
+                    let _ = connection
+                        .report(DeliveryReport {
+                            request_id,
+                            outcome: DeliveryOutcome::Skipped,
+                            status: None,
+                            duration_ms: 0,
+                        })
+                        .await;
+                }
+            }
+
+            connection.close().await;
+        })
+    };
+
+    let mut body = vec![0; body_size];
+    SystemRandom::new().fill(&mut body).unwrap();
+
+    let http = http_client()?;
+    let start = Instant::now();
+    let mut next = start;
+    let mut sending = vec![];
+
+    info!(
+        "Sending {:.0} requests per second of {} bytes to {} for {:?}, press Ctrl-C to stop early",
+        1.0 / interval.as_secs_f64(),
+        body_size,
+        ingest_url(&remote, &prefix),
+        duration
+    );
+
+    for seq in 0.. {
+        if next >= start + duration {
+            break;
+        }
+        tokio::select! {
+            _ = time::sleep_until(next) => {}
+            _ = tokio::signal::ctrl_c() => break,
+        }
+        next += interval;
+
+        let ingest = ingest_url(&remote, &format!("{}{}", prefix, seq));
+        let (http, body) = (http.clone(), body.clone());
+        sending.push(tokio::spawn(async move {
+            let at = Instant::now();
+            let response = http
+                .post(ingest)
+                .header("content-type", "application/octet-stream")
+                .body(body)
+                .send()
+                .await
+                .ok()
+                .map(|resp| (resp.status().as_u16(), Instant::now()));
+
+            Sent { seq, at, response }
+        }));
+    }
+
+    // up to when the next would have been sent, or when stopped early
+    let sending_time = (next - start).min(start.elapsed());
+
+    let sent: Vec<Sent> = futures::future::join_all(sending)
+        .await
+        .into_iter()
+        .collect::<Result<_, _>>()?;
+
+    let accepted: HashMap<u64, &Sent> = sent
+        .iter()
+        .filter(|sent| {
+            sent.response
+                .is_some_and(|(status, _)| (200..300).contains(&status))
+        })
+        .map(|sent| (sent.seq, sent))
+        .collect();
+
+    let mut arrivals = HashMap::new();
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    while arrivals.len() < accepted.len() {
+        match time::timeout_at(deadline, arrived.recv()).await {
+            Ok(Some((seq, at))) if accepted.contains_key(&seq) => {
+                arrivals.insert(seq, at);
+            }
+            Ok(Some(_)) => {}
+            _ => break,
+        }
+    }
+    let _ = stop.send(());
+    let _ = receiving.await;
+
+    let mut rejected = BTreeMap::new();
+    for (status, _) in sent.iter().filter_map(|sent| sent.response) {
+        if !(200..300).contains(&status) {
+            *rejected.entry(status).or_default() += 1;
+        }
+    }
+
+    let report = BenchReport {
+        rate_per_sec: 1.0 / interval.as_secs_f64(),
+        duration_secs: sending_time.as_secs_f64(),
+        body_size,
+        sent: sent.len() as u64,
+        accepted: accepted.len() as u64,
+        rejected,
+        send_errors: sent.iter().filter(|sent| sent.response.is_none()).count() as u64,
+        relayed: arrivals.len() as u64,
+        dropped: (accepted.len() - arrivals.len()) as u64,
+        throughput_per_sec: arrivals.len() as f64 / sending_time.as_secs_f64(),
+        latency: Percentiles::of(
+            arrivals
+                .iter()
+                .map(|(seq, at)| *at - accepted[seq].at)
+                .collect(),
+        ),
+        ingest_latency: Percentiles::of(
+            accepted
+                .values()
+                .filter_map(|sent| Some(sent.response?.1 - sent.at))
+                .collect(),
+        ),
+    };
+
+    if let OutputFormat::Json = format {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    let rejected: Vec<String> = report
+        .rejected
+        .iter()
+        .map(|(status, count)| format!("{} {}", count, status))
+        .collect();
+
+    info!(
+        "Sent: {} in {:.1}s ({:.0} per second of {} bytes)",
+        report.sent, report.duration_secs, report.rate_per_sec, report.body_size
+    );
+    info!(
+        "Accepted: {}, rejected {}{}, {} couldn't be sent",
+        report.accepted,
+        report.rejected.values().sum::<u64>(),
+        match rejected.is_empty() {
+            true => String::new(),
+            false => format!(" ({})", rejected.join(", ")),
+        },
+        report.send_errors
+    );
+    info!("Relayed: {}, dropped {}", report.relayed, report.dropped);
+    info!("Throughput: {:.1} per second", report.throughput_per_sec);
+    info!("Latency: {}", report.latency);
+    info!("Ingest latency: {}", report.ingest_latency);
+
+    Ok(())
+}
@@ -0,0 +1,80 @@
+//! `--canary`: sending a percentage of requests to a second local target instead of the local
+//! origin, to trial a new handler on a slice of real traffic.
+
+use std::str::FromStr;
+
+use hookhub::RequestMessage;
+use ring::{
+    digest,
+    rand::{SecureRandom, SystemRandom},
+};
+use url::Url;
+
+/// What to hash to decide where a request goes, so related requests always go to the same one
+#[derive(Clone, Debug)]
+pub enum CanaryKey {
+    /// The value of a header, e.g. `X-Shop-Domain`
+    Header(String),
+}
+
+impl FromStr for CanaryKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("header", name)) if !name.is_empty() => {
+                Ok(CanaryKey::Header(name.to_lowercase()))
+            }
+            _ => Err("expected header:<name>".to_string()),
+        }
+    }
+}
+
+pub fn parse_percent(value: &str) -> Result<f64, String> {
+    match value.strip_suffix('%').unwrap_or(value).parse::<f64>() {
+        Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(percent),
+        _ => Err("expected a percentage between 0 and 100".to_string()),
+    }
+}
+
+pub struct Canary {
+    pub target: Url,
+    percent: f64,
+    key: Option<CanaryKey>,
+    random: SystemRandom,
+}
+
+impl Canary {
+    pub fn new(target: Url, percent: f64, key: Option<CanaryKey>) -> Self {
+        Self {
+            target,
+            percent,
+            key,
+            random: SystemRandom::new(),
+        }
+    }
+
+    /// Whether to send `req` to the canary. With a key the same value always gets the same
+    /// answer, requests without it are picked at random like when there's no key.
+    pub fn picks(&self, req: &RequestMessage) -> bool {
+        let value = match &self.key {
+            Some(CanaryKey::Header(name)) => req.header(name),
+            None => None,
+        };
+
+        let bucket = match value {
+            Some(value) => {
+                let hash = digest::digest(&digest::SHA256, value.as_bytes());
+                u64::from_be_bytes(hash.as_ref()[..8].try_into().unwrap())
+            }
+            None => {
+                let mut bytes = [0; 8];
+                self.random.fill(&mut bytes).unwrap();
+                u64::from_be_bytes(bytes)
+            }
+        };
+
+        // in hundredths of a percent, so fractions like 0.5% work
+        ((bucket % 10_000) as f64) < self.percent * 100.0
+    }
+}
@@ -0,0 +1,95 @@
+//! `--chaos`: randomly dropping, delaying and duplicating forwards, to see how the local
+//! handler copes with the ways webhooks really arrive.
+
+use std::{str::FromStr, time::Duration};
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// How often each fault happens, parsed from e.g. `drop=5%,delay=200-800ms,dup=1%`
+#[derive(Clone, Debug, Default)]
+pub struct Chaos {
+    drop: f64,
+    /// Every forward is delayed by somewhere in this range of milliseconds
+    delay: Option<(u64, u64)>,
+    duplicate: f64,
+}
+
+/// What to do to one request
+#[derive(Default)]
+pub struct Faults {
+    pub drop: bool,
+    pub delay: Option<Duration>,
+    pub duplicate: bool,
+}
+
+impl FromStr for Chaos {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chaos = Chaos::default();
+
+        for part in s.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            let Some((name, value)) = part.split_once('=') else {
+                return Err(format!("expected name=value, got {}", part));
+            };
+
+            match name {
+                "drop" => chaos.drop = percent(value)?,
+                "dup" => chaos.duplicate = percent(value)?,
+                "delay" => chaos.delay = Some(millis(value)?),
+                _ => {
+                    return Err(format!(
+                        "unknown fault {}, expected drop, delay or dup",
+                        name
+                    ))
+                }
+            }
+        }
+
+        Ok(chaos)
+    }
+}
+
+fn percent(value: &str) -> Result<f64, String> {
+    match value.strip_suffix('%').unwrap_or(value).parse::<f64>() {
+        Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(percent),
+        _ => Err(format!("expected a percentage like 5%, got {}", value)),
+    }
+}
+
+fn millis(value: &str) -> Result<(u64, u64), String> {
+    let error = || format!("expected a delay like 500ms or 200-800ms, got {}", value);
+
+    let range = value.strip_suffix("ms").ok_or_else(error)?;
+    let (min, max) = range.split_once('-').unwrap_or((range, range));
+    let (min, max) = (
+        min.parse::<u64>().map_err(|_| error())?,
+        max.parse::<u64>().map_err(|_| error())?,
+    );
+
+    match min <= max {
+        true => Ok((min, max)),
+        false => Err(error()),
+    }
+}
+
+impl Chaos {
+    /// Rolls the dice for a request
+    pub fn faults(&self) -> Faults {
+        let random = SystemRandom::new();
+        let roll = || {
+            let mut bytes = [0; 8];
+            random.fill(&mut bytes).unwrap();
+            u64::from_be_bytes(bytes)
+        };
+        let chance = |percent: f64| ((roll() % 10_000) as f64) < percent * 100.0;
+
+        Faults {
+            drop: chance(self.drop),
+            delay: self
+                .delay
+                .map(|(min, max)| Duration::from_millis(min + roll() % (max - min + 1))),
+            duplicate: chance(self.duplicate),
+        }
+    }
+}
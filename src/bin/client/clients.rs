@@ -0,0 +1,54 @@
+use anyhow::Result;
+use chrono::Utc;
+use url::Url;
+
+use hookhub::{
+    output::{self, OutputFormat},
+    server::sessions::SessionInfo,
+};
+
+use crate::admin;
+
+/// Lists the sessions connected to the remote through its admin API
+pub async fn handle(remote: Url, secret: String, format: OutputFormat) -> Result<()> {
+    let sessions: Vec<SessionInfo> = admin::get(remote, secret, "sessions", &[]).await?;
+
+    output::print(
+        format,
+        &[
+            "ID",
+            "REMOTE ADDR",
+            "NAME",
+            "CREDENTIAL",
+            "VERSION",
+            "LABELS",
+            "PATH",
+            "HOST",
+            "CONNECTED",
+            "DELIVERED",
+        ],
+        &sessions,
+        |s| {
+            vec![
+                s.id.to_string(),
+                s.remote_addr.clone(),
+                s.name.clone().unwrap_or("-".to_string()),
+                s.credential.clone(),
+                s.version.clone().unwrap_or("-".to_string()),
+                match s.labels.is_empty() {
+                    true => "-".to_string(),
+                    false => s
+                        .labels
+                        .iter()
+                        .map(|(k, v)| format!("{}={}", k, v))
+                        .collect::<Vec<_>>()
+                        .join(","),
+                },
+                s.path.clone().unwrap_or("-".to_string()),
+                s.host.clone().unwrap_or("-".to_string()),
+                format!("{}m ago", (Utc::now() - s.connected_at).num_minutes()),
+                s.messages.to_string(),
+            ]
+        },
+    )
+}
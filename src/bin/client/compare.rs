@@ -0,0 +1,108 @@
+//! Compares the local origin's response to a replayed request with the one recorded when it was
+//! first forwarded.
+
+use serde::Serialize;
+
+use crate::history_db::RecordedResponse;
+
+/// Bodies with more lines than this between them aren't diffed line by line
+const MAX_DIFF_LINES: usize = 2000;
+
+#[derive(Serialize)]
+pub struct Comparison {
+    pub recorded_status: u16,
+    pub status: u16,
+    /// Lines of the bodies, pretty-printed if they're JSON, when they differ
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body_diff: Option<Vec<DiffLine>>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "change", content = "line", rename_all = "snake_case")]
+pub enum DiffLine {
+    Same(String),
+    Removed(String),
+    Added(String),
+}
+
+impl Comparison {
+    pub fn matches(&self) -> bool {
+        self.recorded_status == self.status && self.body_diff.is_none()
+    }
+}
+
+/// Compares the status and body of `response` with `recorded`. JSON bodies are compared as
+/// values so formatting and the order of keys don't count, and if only part of the recorded
+/// body was kept just that much of the new one is compared.
+pub fn compare(recorded: &RecordedResponse, response: &RecordedResponse) -> Comparison {
+    let mut body = response.body.as_slice();
+    if recorded.truncated_from.is_some() {
+        body = &body[..body.len().min(recorded.body.len())];
+    }
+
+    let same = match (
+        serde_json::from_slice::<serde_json::Value>(&recorded.body),
+        serde_json::from_slice::<serde_json::Value>(body),
+    ) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => recorded.body == body,
+    };
+
+    Comparison {
+        recorded_status: recorded.status,
+        status: response.status,
+        body_diff: (!same).then(|| diff(&lines(&recorded.body), &lines(body))),
+    }
+}
+
+fn lines(body: &[u8]) -> Vec<String> {
+    let text = match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(json) => serde_json::to_string_pretty(&json).unwrap(),
+        Err(_) => String::from_utf8_lossy(body).to_string(),
+    };
+
+    text.lines().map(str::to_string).collect()
+}
+
+/// A line diff of `a` to `b` by longest common subsequence, or every line of `a` removed and
+/// every line of `b` added when there are too many lines for that
+fn diff(a: &[String], b: &[String]) -> Vec<DiffLine> {
+    if a.len() + b.len() > MAX_DIFF_LINES {
+        return a
+            .iter()
+            .cloned()
+            .map(DiffLine::Removed)
+            .chain(b.iter().cloned().map(DiffLine::Added))
+            .collect();
+    }
+
+    // common[i][j] is the length of the longest common subsequence of a[i..] and b[j..]
+    let mut common = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            common[i][j] = if a[i] == b[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut lines = vec![];
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            lines.push(DiffLine::Same(a[i].clone()));
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || common[i + 1][j] >= common[i][j + 1]) {
+            lines.push(DiffLine::Removed(a[i].clone()));
+            i += 1;
+        } else {
+            lines.push(DiffLine::Added(b[j].clone()));
+            j += 1;
+        }
+    }
+
+    lines
+}
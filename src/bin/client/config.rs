@@ -0,0 +1,203 @@
+//! `config.toml` in the config directory, setting defaults for any flag so they don't need
+//! typing on every invocation, with `[profiles.<name>]` sections on top chosen by `--profile`.
+
+use std::{env, fs, io, path::Path};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{builder::Resettable, Command};
+use hookhub::output::{self, OutputFormat};
+use log::info;
+use serde::Serialize;
+use toml::{Table, Value};
+
+#[derive(Serialize)]
+struct ProfileSummary {
+    name: String,
+    /// With the defaults at the top of the file applied, as `--profile` uses them
+    remote: Option<String>,
+    local: Option<String>,
+    /// What the profile sets itself, without their values as some are secrets
+    settings: Vec<String>,
+}
+
+/// The value of the flag `long`, or else the environment variable `env`, found before parsing
+/// for settings that decide the defaults parsing uses like `--profile`
+pub fn early_arg(long: &str, env: &str) -> Option<String> {
+    let mut args = env::args().skip(1).take_while(|arg| arg != "--");
+    while let Some(arg) = args.next() {
+        if arg == long {
+            return args.next();
+        }
+        if let Some(value) = arg
+            .strip_prefix(long)
+            .and_then(|rest| rest.strip_prefix('='))
+        {
+            return Some(value.to_string());
+        }
+    }
+
+    env::var(env).ok()
+}
+
+/// Sets the defaults of `command`'s flags from the config file at `path`, with those of
+/// `profile` overriding them. Flags and environment variables still take precedence.
+pub fn apply(mut command: Command, path: &Path, profile: Option<&str>) -> Result<Command> {
+    for (key, value) in read(path, profile)? {
+        let values = match value {
+            Value::Array(values) => values.into_iter().map(scalar).collect(),
+            value => scalar(value).map(|value| vec![value]),
+        }
+        .ok_or_else(|| {
+            anyhow!(
+                "{} in {} should be a string, number, boolean or a list of them",
+                key,
+                path.display()
+            )
+        })?;
+
+        let found;
+        (command, found) = set_default(command, &key, &values);
+        if !found {
+            bail!(
+                "unknown setting {} in {}, settings are named like the flags",
+                key,
+                path.display()
+            );
+        }
+    }
+
+    Ok(command)
+}
+
+/// The value of the setting `key` in the config file at `path` with `profile` applied, e.g. a
+/// profile's `local`
+pub fn setting(path: &Path, profile: &str, key: &str) -> Result<Option<String>> {
+    match read(path, Some(profile))?.remove(key) {
+        Some(value) => scalar(value)
+            .map(Some)
+            .ok_or_else(|| anyhow!("{} in {} should be a string", key, path.display())),
+        None => Ok(None),
+    }
+}
+
+/// Lists the profiles in the config file at `path`
+pub fn list_profiles(path: &Path, format: OutputFormat) -> Result<()> {
+    let (_, profiles) = load(path)?;
+
+    let mut summaries = vec![];
+    for (name, settings) in &profiles {
+        let Value::Table(settings) = settings else {
+            bail!("profile {} in {} should be a table", name, path.display());
+        };
+        let resolved = |key| setting(path, name, key);
+
+        summaries.push(ProfileSummary {
+            name: name.clone(),
+            remote: resolved("remote")?,
+            local: resolved("local")?,
+            settings: settings.keys().cloned().collect(),
+        });
+    }
+
+    if summaries.is_empty() && !matches!(format, OutputFormat::Json) {
+        info!("There are no profiles in {}", path.display());
+        return Ok(());
+    }
+
+    output::print(
+        format,
+        &["NAME", "REMOTE", "LOCAL", "SETTINGS"],
+        &summaries,
+        |p| {
+            vec![
+                p.name.clone(),
+                p.remote.clone().unwrap_or_default(),
+                p.local.clone().unwrap_or_default(),
+                p.settings.join(", "),
+            ]
+        },
+    )
+}
+
+/// The settings in the config file at `path`, with those of `profile` overriding the defaults
+fn read(path: &Path, profile: Option<&str>) -> Result<Table> {
+    let (mut settings, profiles) = load(path)?;
+
+    if let Some(name) = profile {
+        match profiles.get(name) {
+            Some(Value::Table(overrides)) => settings.extend(overrides.clone()),
+            Some(_) => bail!("profile {} in {} should be a table", name, path.display()),
+            None => bail!("no profile named {} in {}", name, path.display()),
+        }
+    }
+
+    Ok(settings)
+}
+
+/// The defaults in the config file at `path` and its profiles
+fn load(path: &Path) -> Result<(Table, Table)> {
+    let mut settings = match fs::read_to_string(path) {
+        Ok(text) => text
+            .parse::<Table>()
+            .with_context(|| format!("invalid config {}", path.display()))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Table::new(),
+        Err(e) => {
+            return Err(e).with_context(|| format!("couldn't read config {}", path.display()))
+        }
+    };
+
+    let profiles = match settings.remove("profiles") {
+        Some(Value::Table(profiles)) => profiles,
+        Some(_) => bail!("profiles in {} should be a table", path.display()),
+        None => Table::new(),
+    };
+
+    Ok((settings, profiles))
+}
+
+fn scalar(value: Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value),
+        Value::Integer(value) => Some(value.to_string()),
+        Value::Float(value) => Some(value.to_string()),
+        Value::Boolean(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// Sets the default of the flag named `long` in `command` and its subcommands, returning
+/// whether any of them have it
+fn set_default(mut command: Command, long: &str, values: &[String]) -> (Command, bool) {
+    let ids: Vec<_> = command
+        .get_arguments()
+        .filter(|arg| arg.get_long() == Some(long))
+        .map(|arg| arg.get_id().clone())
+        .collect();
+    let mut found = !ids.is_empty();
+
+    for id in ids {
+        command = command.mut_arg(id, |arg| {
+            arg.default_values(values.to_vec())
+                // a default doesn't count towards being required
+                .required(false)
+                .required_unless_present(Resettable::Reset)
+                // so --help doesn't print it
+                .hide_default_value(matches!(long, "secret" | "github-token" | "stripe-key"))
+        });
+    }
+
+    let names: Vec<String> = command
+        .get_subcommands()
+        .map(|subcommand| subcommand.get_name().to_string())
+        .collect();
+
+    for name in names {
+        command = command.mut_subcommand(name, |subcommand| {
+            let (subcommand, set) = set_default(subcommand, long, values);
+            found |= set;
+            subcommand
+        });
+    }
+
+    (command, found)
+}
@@ -0,0 +1,221 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    get,
+    http::header,
+    middleware::{from_fn, Next},
+    post,
+    web::{self, Data, Json, Query},
+    App, HttpResponse, HttpServer, Responder,
+};
+use anyhow::Result;
+use log::{info, warn};
+use reqwest::Method;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use hookhub::client::http_client;
+
+use crate::{
+    filters::{Filters, Reloaded},
+    forward_request,
+    history_db::ItemId,
+    redact::Redactor,
+    state::Status,
+    HISTORY_DB, STATE,
+};
+
+pub fn serve(addr: SocketAddr, filters: Arc<Filters>) -> Result<()> {
+    let filters = Data::from(filters);
+
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(filters.clone())
+            .wrap(from_fn(same_origin))
+            .service(get_status)
+            .service(list_requests)
+            .service(replay_request)
+            .service(reload_filters)
+            .service(pause)
+            .service(resume)
+    })
+    .workers(1)
+    .disable_signals()
+    .bind(addr)?
+    .run();
+
+    info!("Control API listening on http://{}", addr);
+
+    tokio::spawn(server);
+
+    Ok(())
+}
+
+/// Rejects requests a browser sent from another origin, so a page the user visits can't replay
+/// requests or pause forwarding, and those for another host, as arrive through a DNS name
+/// rebound to this address
+async fn same_origin(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> actix_web::Result<ServiceResponse<impl MessageBody>> {
+    let local = req.app_config().local_addr();
+    let host = req.connection_info().host().to_string();
+
+    let known_host = local.ip().is_unspecified()
+        || host == local.to_string()
+        || host == format!("localhost:{}", local.port());
+    let same_origin = req
+        .headers()
+        .get(header::ORIGIN)
+        .is_none_or(|origin| origin.as_bytes() == format!("http://{}", host).as_bytes());
+
+    if !known_host || !same_origin {
+        warn!(
+            "Refused a control API request to {} from {}",
+            host,
+            req.headers()
+                .get(header::ORIGIN)
+                .and_then(|origin| origin.to_str().ok())
+                .unwrap_or("no origin")
+        );
+        return Err(actix_web::error::ErrorForbidden(
+            "the control API only takes requests from the same origin",
+        ));
+    }
+
+    next.call(req).await
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReplayResult {
+    pub id: ItemId,
+    pub status: Option<u16>,
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    limit: Option<usize>,
+    offset: Option<usize>,
+}
+
+#[get("/status")]
+async fn get_status() -> impl Responder {
+    HttpResponse::Ok().json(STATE.status())
+}
+
+#[get("/requests")]
+async fn list_requests(query: Query<ListQuery>) -> actix_web::Result<impl Responder> {
+    let summaries = HISTORY_DB
+        .summaries(query.offset.unwrap_or(0), Some(query.limit.unwrap_or(20)))
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(Json(summaries))
+}
+
+#[post("/requests/{id}/replay")]
+async fn replay_request(
+    id: web::Path<ItemId>,
+    filters: Data<Filters>,
+) -> actix_web::Result<impl Responder> {
+    let id = id.into_inner();
+
+    let Some(item) = HISTORY_DB
+        .get(&id)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?
+    else {
+        return Err(actix_web::error::ErrorNotFound(format!("{} not found", id)));
+    };
+
+    let Some(local) = STATE.local() else {
+        return Err(actix_web::error::ErrorServiceUnavailable(
+            "no local origin configured",
+        ));
+    };
+
+    let Some(req) = filters.apply(&id, item.request) else {
+        return Ok(Json(ReplayResult { id, status: None }));
+    };
+
+    let http = http_client().map_err(actix_web::error::ErrorInternalServerError)?;
+
+    let status = forward_request(id.clone(), req, local, http, &Redactor::default())
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?
+        .map(|response| response.status);
+
+    info!(event = "replayed", request_id = id.as_str(), status = status; "Replayed {}", id);
+
+    Ok(Json(ReplayResult { id, status }))
+}
+
+#[post("/filters/reload")]
+async fn reload_filters(filters: Data<Filters>) -> actix_web::Result<impl Responder> {
+    let reloaded = filters
+        .reload()
+        .map_err(|e| actix_web::error::ErrorUnprocessableEntity(format!("{:#}", e)))?;
+
+    info!(
+        "Reloaded {} script(s) and {} plugin(s)",
+        reloaded.scripts, reloaded.plugins
+    );
+
+    Ok(Json(reloaded))
+}
+
+#[post("/pause")]
+async fn pause() -> impl Responder {
+    STATE.set_paused(true);
+    info!("Forwarding paused, requests will only be recorded to history");
+
+    HttpResponse::Ok().json(STATE.status())
+}
+
+#[post("/resume")]
+async fn resume() -> impl Responder {
+    STATE.set_paused(false);
+    info!("Forwarding resumed");
+
+    HttpResponse::Ok().json(STATE.status())
+}
+
+pub async fn status(addr: SocketAddr) -> Result<Option<Status>> {
+    request(addr, Method::GET, "/status").await
+}
+
+pub async fn set_paused(addr: SocketAddr, paused: bool) -> Result<Option<Status>> {
+    let path = if paused { "/pause" } else { "/resume" };
+
+    request(addr, Method::POST, path).await
+}
+
+pub async fn reload(addr: SocketAddr) -> Result<Option<Reloaded>> {
+    request(addr, Method::POST, "/filters/reload").await
+}
+
+async fn request<T: DeserializeOwned>(
+    addr: SocketAddr,
+    method: Method,
+    path: &str,
+) -> Result<Option<T>> {
+    let http = http_client()?;
+
+    match http
+        .request(method, format!("http://{}{}", addr, path))
+        .send()
+        .await
+    {
+        Ok(resp) if resp.status().is_client_error() => {
+            let status = resp.status();
+            Err(anyhow::anyhow!("{}: {}", status, resp.text().await?))
+        }
+        Ok(resp) => {
+            let body = resp.error_for_status()?.bytes().await?;
+            Ok(Some(serde_json::from_slice(&body)?))
+        }
+        Err(e) if e.is_connect() => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
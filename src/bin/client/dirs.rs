@@ -0,0 +1,62 @@
+//! Where the client keeps its config and history: both in `--config-dir` / `HOOKHUB_HOME` when
+//! given, or `~/.hookhub` when it's there from an older version, otherwise split between the
+//! XDG config and data directories. Each profile keeps its own history.
+
+use std::{env, path::PathBuf};
+
+use crate::config;
+
+pub struct Dirs {
+    /// Where config.toml is read from
+    pub config: PathBuf,
+    /// Where history and sessions are kept
+    pub data: PathBuf,
+    /// Where history is kept, of the `--profile` given when there is one so projects don't mix
+    pub history: PathBuf,
+}
+
+impl Dirs {
+    pub fn resolve() -> Self {
+        let (config, data) = Self::config_and_data();
+
+        let history = match config::early_arg("--profile", "HOOKHUB_PROFILE") {
+            Some(profile) => data.join("history").join("profiles").join(profile),
+            None => data.join("history"),
+        };
+
+        Self {
+            config,
+            data,
+            history,
+        }
+    }
+
+    fn config_and_data() -> (PathBuf, PathBuf) {
+        if let Some(dir) = config::early_arg("--config-dir", "HOOKHUB_HOME") {
+            return (PathBuf::from(&dir), PathBuf::from(dir));
+        }
+
+        let home = homedir::my_home().unwrap().unwrap();
+
+        let legacy = home.join(".hookhub");
+        if legacy.is_dir() {
+            return (legacy.clone(), legacy);
+        }
+
+        (
+            xdg_dir("XDG_CONFIG_HOME")
+                .unwrap_or(home.join(".config"))
+                .join("hookhub"),
+            xdg_dir("XDG_DATA_HOME")
+                .unwrap_or(home.join(".local/share"))
+                .join("hookhub"),
+        )
+    }
+}
+
+/// The directory in `var`, which the spec says to ignore unless it's absolute
+fn xdg_dir(var: &str) -> Option<PathBuf> {
+    env::var_os(var)
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+}
@@ -0,0 +1,236 @@
+use std::{fmt::Display, os::unix::fs::PermissionsExt, path::Path, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use async_tungstenite::{tokio::connect_async, tungstenite::handshake::client::Response};
+use chrono::{DateTime, Utc};
+use reqwest::StatusCode;
+use tokio::{fs, net::lookup_host, time::timeout};
+use url::Url;
+
+use hookhub::client::{
+    connect_request, handshake_error, http_client, prepare_local_url, prepare_remote_url,
+};
+
+use crate::{config, HISTORY_DB};
+
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+struct Report {
+    failures: usize,
+}
+
+impl Report {
+    fn check<T: Display>(&mut self, name: &str, result: Result<T>) -> Option<T> {
+        match result {
+            Ok(detail) => {
+                self.pass(name, &detail);
+                Some(detail)
+            }
+            Err(e) => {
+                self.fail(name, e);
+                None
+            }
+        }
+    }
+
+    fn pass(&self, name: &str, detail: impl Display) {
+        println!("[PASS] {}: {}", name, detail);
+    }
+
+    fn fail(&mut self, name: &str, error: anyhow::Error) {
+        self.failures += 1;
+        println!("[FAIL] {}: {:#}", name, error);
+    }
+
+    fn skip(&self, name: &str, reason: &str) {
+        println!("[SKIP] {}: {}", name, reason);
+    }
+}
+
+pub async fn handle(remote: Option<Url>, secret: Option<String>, local: Option<Url>) -> Result<()> {
+    let mut report = Report::default();
+
+    report.check("History directory", check_dir(HISTORY_DB.path()).await);
+
+    match remote {
+        Some(mut remote) => {
+            if report
+                .check(
+                    "Remote origin",
+                    prepare_remote_url(&mut remote).map(|_| &remote),
+                )
+                .is_some()
+            {
+                report.check("Remote DNS", resolve(&remote).await);
+
+                match secret {
+                    Some(secret) => match handshake(&remote, &secret).await {
+                        Ok(response) => {
+                            report.pass("Websocket handshake", "authenticated successfully");
+                            report.check("Clock skew", clock_skew(&response));
+                        }
+                        Err(e) => report.fail("Websocket handshake", e),
+                    },
+                    None => report.skip("Websocket handshake", "no --secret given"),
+                }
+            }
+        }
+        None => report.skip("Remote origin", "no --remote given"),
+    }
+
+    match local {
+        Some(mut local) => {
+            if report
+                .check(
+                    "Local origin",
+                    prepare_local_url(&mut local).map(|_| &local),
+                )
+                .is_some()
+            {
+                report.check(
+                    "Local reachability",
+                    probe_local(&local)
+                        .await
+                        .map(|s| format!("responded {}", s)),
+                );
+            }
+        }
+        None => report.skip("Local origin", "no --local given"),
+    }
+
+    if report.failures > 0 {
+        return Err(anyhow!("{} check(s) failed", report.failures));
+    }
+
+    Ok(())
+}
+
+/// Tests the remote and local legs of the profile called `name` in the config file at `config`
+/// independently, with the settings `connect --profile <name>` would use
+pub async fn handle_test(config: &Path, name: &str) -> Result<()> {
+    let setting = |key: &str| config::setting(config, name, key);
+    let required =
+        |key: &str| setting(key)?.ok_or_else(|| anyhow!("profile {} doesn't set {}", name, key));
+    let url = |key: &str, value: String| -> Result<Url> {
+        value
+            .parse()
+            .with_context(|| format!("{} of profile {} isn't a URL: {}", key, name, value))
+    };
+
+    let mut remote = url("remote", required("remote")?)?;
+    let secret = required("secret")?;
+    let local = setting("local")?
+        .map(|value| url("local", value))
+        .transpose()?;
+
+    prepare_remote_url(&mut remote)?;
+
+    let mut report = Report::default();
+
+    report.check(
+        "Remote leg",
+        handshake(&remote, &secret)
+            .await
+            .map(|_| format!("authenticated with {}", remote)),
+    );
+    match local {
+        Some(mut local) => {
+            prepare_local_url(&mut local)?;
+            report.check(
+                "Local leg",
+                probe_local(&local)
+                    .await
+                    .map(|s| format!("{} responded {}", local, s)),
+            );
+        }
+        None => report.skip("Local leg", "the profile doesn't set local"),
+    }
+
+    if report.failures > 0 {
+        return Err(anyhow!("{} leg(s) failed", report.failures));
+    }
+
+    Ok(())
+}
+
+async fn check_dir(path: &Path) -> Result<String> {
+    let metadata = fs::metadata(path)
+        .await
+        .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+
+    if !metadata.is_dir() {
+        return Err(anyhow!("{} is not a directory", path.display()));
+    }
+
+    let probe = path.join(".doctor");
+    fs::write(&probe, b"")
+        .await
+        .map_err(|e| anyhow!("{} is not writable: {}", path.display(), e))?;
+    fs::remove_file(&probe).await?;
+
+    let mode = metadata.permissions().mode() & 0o777;
+    if mode & 0o002 != 0 {
+        return Err(anyhow!("{} is world writable ({:o})", path.display(), mode));
+    }
+
+    Ok(format!("{} is writable ({:o})", path.display(), mode))
+}
+
+async fn resolve(remote: &Url) -> Result<String> {
+    let host = remote
+        .host_str()
+        .ok_or_else(|| anyhow!("{} has no host", remote))?;
+    let port = remote.port_or_known_default().unwrap_or(443);
+
+    let addrs: Vec<String> = lookup_host((host, port))
+        .await?
+        .map(|a| a.ip().to_string())
+        .collect();
+
+    Ok(format!("{} resolves to {}", host, addrs.join(", ")))
+}
+
+/// Opens an authenticated websocket to the remote and immediately closes it again
+pub async fn handshake(remote: &Url, secret: &str) -> Result<Response> {
+    let request = connect_request(remote, secret)?;
+
+    let result = timeout(Duration::from_secs(10), connect_async(request))
+        .await
+        .map_err(|_| anyhow!("timed out connecting to {}", remote))?;
+
+    match result {
+        Ok((mut stream, response)) => {
+            let _ = stream.close(None).await;
+            Ok(response)
+        }
+        Err(e) => Err(handshake_error(e)),
+    }
+}
+
+/// Sends a request to the local origin, any response at all counts as reachable
+pub async fn probe_local(local: &Url) -> Result<StatusCode> {
+    let response = http_client()?.get(local.clone()).send().await?;
+
+    Ok(response.status())
+}
+
+fn clock_skew(response: &Response) -> Result<String> {
+    let date = response
+        .headers()
+        .get("date")
+        .ok_or_else(|| anyhow!("server did not send a Date header"))?
+        .to_str()?;
+
+    let server_time = DateTime::parse_from_rfc2822(date)?.with_timezone(&Utc);
+    let skew = (Utc::now() - server_time).abs().to_std()?;
+
+    if skew > MAX_CLOCK_SKEW {
+        return Err(anyhow!(
+            "local clock differs from the server by {}s",
+            skew.as_secs()
+        ));
+    }
+
+    Ok(format!("{}s", skew.as_secs()))
+}
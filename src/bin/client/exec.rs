@@ -0,0 +1,45 @@
+use std::process::Stdio;
+
+use hookhub::{JsonRequest, RequestMessage};
+use log::{info, warn};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+use crate::history_db::ItemId;
+
+/// Runs `command` with the shell in the background, writing the request to its stdin as JSON.
+/// Its output goes to the client's, and the request's id is in HOOKHUB_REQUEST_ID.
+pub fn spawn(command: &str, id: &ItemId, req: &RequestMessage) {
+    let command = command.to_string();
+    let id = id.clone();
+    let input = serde_json::to_vec(&JsonRequest::from(req)).unwrap();
+
+    tokio::spawn(async move {
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .env("HOOKHUB_REQUEST_ID", &id)
+            .stdin(Stdio::piped())
+            .spawn();
+
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Could not run --exec for {}: {}", id, e);
+                return;
+            }
+        };
+
+        if let Some(mut stdin) = child.stdin.take() {
+            // commands that don't read their input close stdin early, which isn't an error
+            let _ = stdin.write_all(&input).await;
+        }
+
+        match child.wait().await {
+            Ok(status) if status.success() => {
+                info!(event = "executed", request_id = id.as_str(); "Ran --exec for {}", id)
+            }
+            Ok(status) => warn!("--exec for {} failed with {}", id, status),
+            Err(e) => warn!("Could not wait for --exec for {}: {}", id, e),
+        }
+    });
+}
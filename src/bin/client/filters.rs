@@ -0,0 +1,96 @@
+//! What a request goes through before it's forwarded: the scripts, then the plugins, then the
+//! headers removed and added. Shared by the relay and the control API, which replays requests
+//! through it and reloads the scripts and plugins from their files.
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Result;
+use hookhub::{plugin::Plugin, script::Script, RequestMessage};
+use serde::{Deserialize, Serialize};
+
+use crate::{add_proxy_headers, filter_request, history_db::ItemId};
+
+pub struct Filters {
+    script_paths: Vec<PathBuf>,
+    plugin_paths: Vec<PathBuf>,
+    loaded: RwLock<Arc<Loaded>>,
+    /// Headers removed from requests
+    stripped: Vec<String>,
+    proxy_headers: bool,
+}
+
+struct Loaded {
+    scripts: Vec<Script>,
+    plugins: Vec<Plugin>,
+}
+
+impl Loaded {
+    fn load(scripts: &[PathBuf], plugins: &[PathBuf]) -> Result<Self> {
+        Ok(Self {
+            scripts: scripts
+                .iter()
+                .map(|path| Script::load(path))
+                .collect::<Result<_>>()?,
+            plugins: plugins
+                .iter()
+                .map(|path| Plugin::load(path))
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+/// How many scripts and plugins `reload` loaded
+#[derive(Serialize, Deserialize)]
+pub struct Reloaded {
+    pub scripts: usize,
+    pub plugins: usize,
+}
+
+impl Filters {
+    pub fn load(
+        scripts: &[PathBuf],
+        plugins: &[PathBuf],
+        stripped: Vec<String>,
+        proxy_headers: bool,
+    ) -> Result<Self> {
+        Ok(Self {
+            script_paths: scripts.to_vec(),
+            plugin_paths: plugins.to_vec(),
+            loaded: RwLock::new(Arc::new(Loaded::load(scripts, plugins)?)),
+            stripped,
+            proxy_headers,
+        })
+    }
+
+    /// Loads the scripts and plugins from their files again, keeping the ones already loaded
+    /// if any of them fails to
+    pub fn reload(&self) -> Result<Reloaded> {
+        let loaded = Loaded::load(&self.script_paths, &self.plugin_paths)?;
+        let reloaded = Reloaded {
+            scripts: loaded.scripts.len(),
+            plugins: loaded.plugins.len(),
+        };
+
+        *self.loaded.write().unwrap() = Arc::new(loaded);
+
+        Ok(reloaded)
+    }
+
+    /// The request to forward, `None` (logging why) if a script or plugin dropped it
+    pub fn apply(&self, id: &ItemId, req: RequestMessage) -> Option<RequestMessage> {
+        // requests already being filtered finish with what they started with
+        let loaded = self.loaded.read().unwrap().clone();
+
+        let mut req = filter_request(id, req, &loaded.scripts, &loaded.plugins)?;
+        req.headers
+            .retain(|(name, _)| !self.stripped.iter().any(|n| n.eq_ignore_ascii_case(name)));
+        if self.proxy_headers {
+            add_proxy_headers(&mut req);
+        }
+
+        Some(req)
+    }
+}
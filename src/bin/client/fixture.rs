@@ -0,0 +1,104 @@
+//! Renders a request from history as a fixture to commit alongside tests.
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use hookhub::{JsonRequest, RequestMessage};
+
+#[derive(ValueEnum, Clone, Copy)]
+pub enum FixtureFormat {
+    /// The request as JSON, with the body base64 encoded as commands and plugins get it
+    Json,
+    /// A Rust expression constructing the RequestMessage
+    Rust,
+    /// A .http file for REST clients like the VS Code and JetBrains ones, requesting {{host}}
+    Http,
+}
+
+pub fn render(req: &RequestMessage, format: FixtureFormat) -> Result<String> {
+    match format {
+        FixtureFormat::Json => Ok(serde_json::to_string_pretty(&JsonRequest::from(req))?),
+        FixtureFormat::Rust => Ok(rust(req)),
+        FixtureFormat::Http => http(req),
+    }
+}
+
+fn rust(req: &RequestMessage) -> String {
+    let mut out = String::from("hookhub::RequestMessage {\n");
+
+    out.push_str(&format!("    method: {:?}.to_string(),\n", req.method));
+    out.push_str(&format!("    fullpath: {:?}.to_string(),\n", req.fullpath));
+    out.push_str(&format!(
+        "    version: hookhub::Version::{:?},\n",
+        req.version
+    ));
+    out.push_str("    headers: vec![\n");
+    for (name, value) in req.headers.iter() {
+        out.push_str(&format!(
+            "        ({:?}.to_string(), hookhub::HeaderBytes({}.to_vec())),\n",
+            name,
+            byte_string(value.as_bytes())
+        ));
+    }
+    out.push_str("    ],\n");
+    out.push_str(&format!("    body: {}.to_vec(),\n", byte_string(&req.body)));
+    out.push_str("    source: None,\n");
+    out.push('}');
+
+    out
+}
+
+/// An expression for `bytes` as a `&[u8]`, a raw string when they're text (raw strings
+/// can't hold a carriage return) so JSON stays readable
+fn byte_string(bytes: &[u8]) -> String {
+    let text = std::str::from_utf8(bytes).ok().filter(|text| {
+        !text
+            .chars()
+            .any(|c| c.is_control() && !matches!(c, '\n' | '\t'))
+    });
+
+    let Some(text) = text else {
+        let escaped: String = bytes
+            .iter()
+            .flat_map(|b| std::ascii::escape_default(*b))
+            .map(char::from)
+            .collect();
+        return format!("b\"{}\"", escaped);
+    };
+
+    // enough #s that nothing in the text ends the literal early
+    let longest_run = bytes
+        .split(|b| *b != b'#')
+        .map(|run| run.len())
+        .max()
+        .unwrap_or(0);
+    let hashes = "#".repeat(longest_run + 1);
+
+    format!("r{}\"{}\"{}.as_bytes()", hashes, text, hashes)
+}
+
+fn http(req: &RequestMessage) -> Result<String> {
+    let body = std::str::from_utf8(&req.body)
+        .map_err(|_| anyhow!("the body isn't text, export it as json or rust instead"))?;
+
+    let mut out = String::from("@host = http://localhost:3000\n\n");
+    out.push_str(&format!(
+        "{} {{{{host}}}}{} {:?}\n",
+        req.method,
+        req.fullpath,
+        http::Version::from(req.version)
+    ));
+    for (name, value) in req.headers.iter() {
+        // the client works it out from the body
+        if name.eq_ignore_ascii_case("content-length") {
+            continue;
+        }
+        out.push_str(&format!("{}: {}\n", name, value.to_str_lossy()));
+    }
+    if !body.is_empty() {
+        out.push('\n');
+        out.push_str(body);
+        out.push('\n');
+    }
+
+    Ok(out)
+}
@@ -0,0 +1,98 @@
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use tokio::{net::TcpStream, sync::watch, time};
+use url::Url;
+
+const PROBE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long a request is held for the local origin to come back before it isn't forwarded
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Probes the local origin in the background so forwards can wait while it's down, instead of
+/// each failing with a connection error
+#[derive(Clone)]
+pub struct Health {
+    up: watch::Receiver<bool>,
+}
+
+enum Probe {
+    /// The port accepting connections
+    Connect(SocketAddr),
+    /// A request to the path responding with a success or redirect status
+    Get(reqwest::Client, Url),
+}
+
+impl Health {
+    /// Starts probing `local`, by connecting to its port or by requesting `path` on it
+    pub fn start(local: &Url, path: Option<&str>) -> Result<Self> {
+        let probe = match path {
+            Some(path) => {
+                let http = reqwest::Client::builder().timeout(PROBE_INTERVAL).build()?;
+                Probe::Get(http, local.join(path)?)
+            }
+            None => Probe::Connect(
+                local
+                    .socket_addrs(|| None)?
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| anyhow!("local origin {} doesn't resolve", local))?,
+            ),
+        };
+
+        let (tx, up) = watch::channel(false);
+
+        tokio::spawn(async move {
+            let mut interval = time::interval(PROBE_INTERVAL);
+            let mut last = None;
+
+            loop {
+                interval.tick().await;
+
+                let healthy = probe.check().await;
+                if last != Some(healthy) {
+                    if healthy {
+                        info!(event = "local_up"; "Local target up, forwarding");
+                    } else {
+                        warn!(event = "local_down"; "Local target down, holding requests until it's back");
+                    }
+                    last = Some(healthy);
+                }
+
+                if tx.send(healthy).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(Self { up })
+    }
+
+    /// Waits for the local origin to be up, false if it's still down after a while
+    pub fn wait_ready(&self) -> impl std::future::Future<Output = bool> + Send + 'static {
+        let mut up = self.up.clone();
+
+        async move {
+            time::timeout(READY_TIMEOUT, up.wait_for(|up| *up))
+                .await
+                .is_ok_and(|up| up.is_ok())
+        }
+    }
+}
+
+impl Probe {
+    async fn check(&self) -> bool {
+        match self {
+            Probe::Connect(addr) => matches!(
+                time::timeout(PROBE_INTERVAL, TcpStream::connect(addr)).await,
+                Ok(Ok(_))
+            ),
+            Probe::Get(http, url) => http
+                .get(url.clone())
+                .send()
+                .await
+                .is_ok_and(|resp| resp.status().is_success() || resp.status().is_redirection()),
+        }
+    }
+}
@@ -0,0 +1,476 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashSet},
+    path::PathBuf,
+    time::Duration,
+};
+
+use crate::{
+    assertions, compare, filter_request,
+    fixture::{self, FixtureFormat},
+    forward_request,
+    history_db::{Item, ItemId, ItemSummary},
+    import,
+    redact::Redactor,
+    session, tail, HistoryCommands, HISTORY_DB,
+};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use hookhub::{
+    client::{http_client, prepare_local_url},
+    output::{self, OutputFormat},
+    script::Script,
+    JsonRequest,
+};
+use log::{error, info, warn};
+use serde::Serialize;
+use tokio::time;
+use url::Url;
+
+/// Bytes of body `show` prints unless --full is given
+const SHOW_MAX_BODY: usize = 4096;
+
+#[derive(Serialize, Default)]
+struct HistoryStats {
+    requests: usize,
+    average_body_size: usize,
+    methods: BTreeMap<String, usize>,
+    /// By first path segment
+    paths: BTreeMap<String, usize>,
+    hours: BTreeMap<String, usize>,
+}
+
+/// How often `watch` checks for new requests
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How many items `search` reads at a time
+const SEARCH_BATCH: usize = 100;
+
+#[derive(Serialize)]
+struct SearchResult {
+    #[serde(flatten)]
+    summary: ItemSummary,
+    /// Which part of the request matched: path, header or body
+    matched: &'static str,
+}
+
+#[derive(Serialize)]
+struct JsonItem<'a> {
+    id: &'a ItemId,
+    received_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signature_valid: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    truncated_from: Option<usize>,
+    #[serde(flatten)]
+    request: JsonRequest,
+}
+
+pub async fn handle(command: HistoryCommands, format: OutputFormat) -> Result<()> {
+    match command {
+        HistoryCommands::List { limit, offset } => handle_list(limit, offset, format).await,
+        HistoryCommands::Count => handle_count(format).await,
+        HistoryCommands::Import { file } => handle_import(file, format).await,
+        HistoryCommands::ExportFixture { id, format } => handle_export_fixture(id, format).await,
+        HistoryCommands::Watch { full } => handle_watch(full, format).await,
+        HistoryCommands::Stats { since } => handle_stats(since, format).await,
+        HistoryCommands::Search { query, limit } => handle_search(query, limit, format).await,
+        HistoryCommands::Show { id, full } => handle_show(id, full, format).await,
+        HistoryCommands::Delete { id } => handle_delete(id).await,
+        HistoryCommands::Clear => handle_clear().await,
+        HistoryCommands::Purge { before, shred } => handle_purge(before, shred, format).await,
+        HistoryCommands::Replay {
+            id,
+            local,
+            scripts,
+            compare,
+            assertions,
+        } => {
+            assertions.load()?;
+            let replayed = handle_replay(id, local, scripts, compare, format).await;
+            let reported = assertions::report(assertions.ci);
+            replayed.and(reported)
+        }
+    }
+}
+
+async fn handle_list(limit: Option<usize>, offset: usize, format: OutputFormat) -> Result<()> {
+    let summaries = HISTORY_DB.summaries(offset, limit).await?;
+
+    if summaries.is_empty() && !matches!(format, OutputFormat::Json) {
+        info!("History is empty");
+        return Ok(());
+    }
+
+    output::print(
+        format,
+        &["ID", "RECEIVED AT", "METHOD", "PATH", "SIZE"],
+        &summaries,
+        |s| {
+            vec![
+                s.id.clone(),
+                s.received_at.to_rfc3339(),
+                s.method.clone(),
+                s.fullpath.clone(),
+                s.body_size.to_string(),
+            ]
+        },
+    )
+}
+
+async fn handle_search(query: String, limit: usize, format: OutputFormat) -> Result<()> {
+    let needle = query.to_lowercase();
+    let total = HISTORY_DB.count().await?;
+
+    // read a batch at a time, newest first, so a search with recent results stops early
+    let mut results = vec![];
+    let mut offset = 0;
+    while offset < total && results.len() < limit {
+        for item in HISTORY_DB.page(offset, Some(SEARCH_BATCH)).await? {
+            if let Some(matched) = item.find(&needle) {
+                results.push(SearchResult {
+                    summary: item.summary(),
+                    matched,
+                });
+            }
+        }
+        offset += SEARCH_BATCH;
+    }
+    results.truncate(limit);
+
+    if results.is_empty() && !matches!(format, OutputFormat::Json) {
+        info!("Nothing in history matches {:?}", query);
+        return Ok(());
+    }
+
+    output::print(
+        format,
+        &["ID", "RECEIVED AT", "METHOD", "PATH", "MATCHED"],
+        &results,
+        |r| {
+            vec![
+                r.summary.id.clone(),
+                r.summary.received_at.to_rfc3339(),
+                r.summary.method.clone(),
+                r.summary.fullpath.clone(),
+                r.matched.to_string(),
+            ]
+        },
+    )
+}
+
+async fn handle_export_fixture(id: ItemId, format: FixtureFormat) -> Result<()> {
+    let Some(item) = HISTORY_DB.get(&id).await? else {
+        return Err(anyhow!("{} not found in history", id));
+    };
+    if let Some(size) = item.truncated_from {
+        warn!(
+            "Only the first {} of {} bytes of the body were recorded, the fixture has those",
+            item.request.body.len(),
+            size
+        );
+    }
+
+    println!("{}", fixture::render(&item.request, format)?);
+
+    Ok(())
+}
+
+async fn handle_watch(full: bool, format: OutputFormat) -> Result<()> {
+    info!("Watching {} for new requests", HISTORY_DB.path().display());
+
+    let mut seen: HashSet<ItemId> = HISTORY_DB.ids().await?.into_iter().collect();
+    let mut interval = time::interval(WATCH_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let ids = HISTORY_DB.ids().await?;
+        // oldest first, so they print in the order they arrived
+        for id in ids.iter().rev().filter(|id| !seen.contains(*id)) {
+            let Some(item) = HISTORY_DB.get(id).await? else {
+                continue;
+            };
+
+            match format {
+                OutputFormat::Json => tail::print_jsonl(id, item.received_at, &item.request),
+                _ if full => tail::show(&item, Some(SHOW_MAX_BODY)),
+                _ => {
+                    let summary = item.summary();
+                    println!(
+                        "{}  {} {} ({} bytes)  {}",
+                        summary.received_at.to_rfc3339(),
+                        summary.method,
+                        summary.fullpath,
+                        summary.body_size,
+                        summary.id
+                    );
+                }
+            }
+        }
+
+        // forget deleted items, e.g. after a history clear
+        seen = ids.into_iter().collect();
+    }
+}
+
+async fn handle_stats(since: Option<DateTime<Utc>>, format: OutputFormat) -> Result<()> {
+    let mut stats = HistoryStats::default();
+    let mut body_bytes = 0;
+
+    for summary in HISTORY_DB.summaries(0, None).await? {
+        if since.is_some_and(|since| summary.received_at <= since) {
+            continue;
+        }
+
+        stats.requests += 1;
+        body_bytes += summary.body_size;
+        *stats.methods.entry(summary.method).or_default() += 1;
+        *stats
+            .paths
+            .entry(path_prefix(&summary.fullpath))
+            .or_default() += 1;
+        *stats
+            .hours
+            .entry(summary.received_at.format("%Y-%m-%d %H:00").to_string())
+            .or_default() += 1;
+    }
+    stats.average_body_size = body_bytes.checked_div(stats.requests).unwrap_or(0);
+
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&stats)?),
+        OutputFormat::Plain => {
+            println!("requests\t{}", stats.requests);
+            println!("average_body_size\t{}", stats.average_body_size);
+            for (kind, counts) in [
+                ("method", &stats.methods),
+                ("path", &stats.paths),
+                ("hour", &stats.hours),
+            ] {
+                for (key, count) in counts {
+                    println!("{}\t{}\t{}", kind, key, count);
+                }
+            }
+        }
+        OutputFormat::Table => {
+            println!(
+                "{} requests, {} bytes of body on average",
+                stats.requests, stats.average_body_size
+            );
+            for (header, counts, by_count) in [
+                ("METHOD", &stats.methods, true),
+                ("PATH", &stats.paths, true),
+                ("HOUR", &stats.hours, false),
+            ] {
+                let mut rows: Vec<(&String, &usize)> = counts.iter().collect();
+                if by_count {
+                    rows.sort_by_key(|(_, count)| Reverse(**count));
+                }
+
+                println!();
+                output::print(format, &[header, "COUNT"], &rows, |(key, count)| {
+                    vec![key.to_string(), count.to_string()]
+                })?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The first segment of a path, e.g. `/stripe` for `/stripe/webhook?id=1`
+fn path_prefix(fullpath: &str) -> String {
+    let path = fullpath.split('?').next().unwrap_or_default();
+
+    match path.trim_start_matches('/').split('/').next() {
+        Some(segment) if !segment.is_empty() => format!("/{}", segment),
+        _ => "/".to_string(),
+    }
+}
+
+async fn handle_import(file: String, format: OutputFormat) -> Result<()> {
+    let mut summaries = vec![];
+    for imported in import::read(&file)? {
+        let mut item = Item::new(
+            imported.received_at.unwrap_or_else(Utc::now),
+            imported.request,
+        );
+        item.id = HISTORY_DB.add(&item).await?;
+        summaries.push(item.summary());
+    }
+
+    info!(
+        "Imported {} requests, replay them with history replay <id>",
+        summaries.len()
+    );
+
+    output::print(
+        format,
+        &["ID", "RECEIVED AT", "METHOD", "PATH", "SIZE"],
+        &summaries,
+        |s| {
+            vec![
+                s.id.clone(),
+                s.received_at.to_rfc3339(),
+                s.method.clone(),
+                s.fullpath.clone(),
+                s.body_size.to_string(),
+            ]
+        },
+    )
+}
+
+async fn handle_count(format: OutputFormat) -> Result<()> {
+    let count = HISTORY_DB.count().await?;
+
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::json!({ "count": count })),
+        _ => println!("{}", count),
+    }
+
+    Ok(())
+}
+
+async fn handle_show(id: ItemId, full: bool, format: OutputFormat) -> Result<()> {
+    let Some(item) = HISTORY_DB.get(&id).await? else {
+        return Err(anyhow!("{} not found in history", id));
+    };
+
+    match format {
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&JsonItem {
+                id: &item.id,
+                received_at: item.received_at,
+                signature_valid: item.signature_valid,
+                truncated_from: item.truncated_from,
+                request: JsonRequest::from(&item.request),
+            })?
+        ),
+        _ => tail::show(&item, (!full).then_some(SHOW_MAX_BODY)),
+    }
+
+    Ok(())
+}
+
+async fn handle_delete(id: ItemId) -> Result<()> {
+    HISTORY_DB.delete(&id).await?;
+
+    info!("Item deleted");
+
+    Ok(())
+}
+
+async fn handle_replay(
+    id: ItemId,
+    mut local: Url,
+    scripts: Vec<PathBuf>,
+    compare: bool,
+    format: OutputFormat,
+) -> Result<()> {
+    let scripts = scripts
+        .iter()
+        .map(|path| Script::load(path))
+        .collect::<Result<Vec<_>>>()?;
+
+    let Some(item) = HISTORY_DB.get(&id).await? else {
+        error!("{} not found", id);
+        return Ok(());
+    };
+
+    let recorded = match compare {
+        true => Some(item.response.ok_or_else(|| {
+            anyhow!(
+                "no response was recorded for {}, so there's nothing to compare with",
+                id
+            )
+        })?),
+        false => None,
+    };
+
+    prepare_local_url(&mut local)?;
+
+    if let Some(size) = item.truncated_from {
+        warn!(
+            "{} was {} bytes but only {} were recorded, replaying the truncated body",
+            id,
+            size,
+            item.request.body.len()
+        );
+    }
+
+    let Some(req) = filter_request(&id, item.request, &scripts, &[]) else {
+        return Ok(());
+    };
+
+    let http = http_client()?;
+
+    let response = forward_request(
+        id.clone(),
+        req,
+        local.clone(),
+        http.clone(),
+        // recorded redacted already
+        &Redactor::default(),
+    )
+    .await?;
+    let status = response.as_ref().map(|response| response.status);
+
+    info!(event = "replayed", request_id = id.as_str(), status = status; "Replayed {}", id);
+
+    let Some(recorded) = recorded else {
+        return Ok(());
+    };
+    let Some(response) = response else {
+        return Err(anyhow!(
+            "{} couldn't be replayed to compare its response",
+            id
+        ));
+    };
+
+    let comparison = compare::compare(&recorded, &response);
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string(&comparison)?),
+        _ => tail::print_comparison(&comparison),
+    }
+
+    match comparison.matches() {
+        true => Ok(()),
+        false => Err(anyhow!(
+            "the response to {} differs from the recorded one",
+            id
+        )),
+    }
+}
+
+async fn handle_clear() -> Result<()> {
+    HISTORY_DB.clear().await?;
+
+    info!("History has been cleared");
+
+    Ok(())
+}
+
+async fn handle_purge(before: DateTime<Utc>, shred: bool, format: OutputFormat) -> Result<()> {
+    let mut purged = HISTORY_DB.purge(before, shred).await?;
+    // recorded sessions keep copies
+    let sessions = session::purge(before, shred).await?;
+    purged.items += sessions.items;
+    purged.bytes += sessions.bytes;
+    purged.sessions = sessions.sessions;
+
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&purged)?),
+        _ => info!(
+            "{} {} request(s) received before {}, {} of them from {} recorded session(s), reclaiming {} bytes",
+            if shred { "Shredded" } else { "Purged" },
+            purged.items,
+            before.to_rfc3339(),
+            sessions.items,
+            purged.sessions,
+            purged.bytes
+        ),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,768 @@
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use glob::glob;
+use hookhub::{HeaderBytes, RequestMessage};
+use log::warn;
+use reqwest::StatusCode;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    fs,
+    io::{self, AsyncWriteExt as _},
+};
+
+/// Bodies larger than this are stored in a blob file of their own rather than in the item, so
+/// listing doesn't read them and identical bodies, e.g. of retried deliveries, are stored once
+const BLOB_THRESHOLD: usize = 64 * 1024;
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Db {
+    path: PathBuf,
+}
+
+pub type ItemId = String;
+
+impl Db {
+    pub fn new(path: &PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(path)?;
+
+        Ok(Self { path: path.clone() })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn get(&self, id: &ItemId) -> Result<Option<Item>> {
+        let path = self.path.join(format!("{}.json", id));
+
+        self.read(&path, true).await
+    }
+
+    pub async fn add(&self, item: &Item) -> Result<ItemId> {
+        let mut generator = names::Generator::default();
+        let id = generator.next().unwrap();
+
+        self.write(&id, item, None).await?;
+
+        Ok(id)
+    }
+
+    /// Records what the local origin responded to the item with, leaving it where it was in
+    /// the order items were written
+    pub async fn set_response(&self, id: &ItemId, response: RecordedResponse) -> Result<()> {
+        let path = self.path.join(format!("{}.json", id));
+        // the body stays in its blob
+        let Some(mut item) = self.read(&path, false).await? else {
+            // pruned or deleted while it was forwarded
+            return Ok(());
+        };
+        let modified = fs::metadata(&path).await?.modified()?;
+
+        item.response = Some(response);
+        self.write(id, &item, Some(modified)).await
+    }
+
+    /// Writes the item aside and renames it into place, so a crash can't leave half an item
+    /// behind. A large body is written to its blob first.
+    async fn write(&self, id: &ItemId, item: &Item, modified: Option<SystemTime>) -> Result<()> {
+        // until the item refers to its blob
+        let _lock = self.lock(false).await?;
+        let data = match item.request.body.len() > BLOB_THRESHOLD {
+            true => {
+                serde_json::to_vec(&item.without_body(self.write_blob(&item.request.body).await?))?
+            }
+            false => serde_json::to_vec(item)?,
+        };
+
+        let tmp = self.path.join(format!(".{}.tmp", id));
+        let mut file = fs::File::create(&tmp).await?;
+        file.write_all(&data).await?;
+        file.sync_all().await?;
+        if let Some(modified) = modified {
+            file.into_std().await.set_modified(modified)?;
+        }
+        fs::rename(&tmp, self.path.join(format!("{}.json", id))).await?;
+
+        Ok(())
+    }
+
+    /// Stores `body` in the blob file named by its SHA-256, unless an identical body already is
+    async fn write_blob(&self, body: &[u8]) -> Result<Blob> {
+        let sha256: String = digest::digest(&digest::SHA256, body)
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let path = self.blob_path(&sha256);
+
+        if !fs::try_exists(&path).await? {
+            fs::create_dir_all(self.path.join("blobs")).await?;
+            let tmp = self.path.join("blobs").join(format!(".{}.tmp", sha256));
+            let mut file = fs::File::create(&tmp).await?;
+            file.write_all(body).await?;
+            file.sync_all().await?;
+            fs::rename(&tmp, &path).await?;
+        }
+
+        Ok(Blob {
+            sha256,
+            size: body.len(),
+        })
+    }
+
+    fn blob_path(&self, sha256: &str) -> PathBuf {
+        self.path.join("blobs").join(sha256)
+    }
+
+    /// Locks the db, shared by writers and exclusively while removing blobs, so a blob isn't
+    /// removed between a writer, maybe in another process, finding it's already stored and
+    /// writing the item that refers to it. It's unlocked when the file is dropped.
+    async fn lock(&self, exclusive: bool) -> Result<std::fs::File> {
+        let path = self.path.join(".lock");
+
+        let file = tokio::task::spawn_blocking(move || -> io::Result<std::fs::File> {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(path)?;
+            match exclusive {
+                true => file.lock()?,
+                false => file.lock_shared()?,
+            }
+            Ok(file)
+        })
+        .await??;
+
+        Ok(file)
+    }
+
+    /// Removes the blobs no item refers to any more, e.g. after deleting items, returning how
+    /// many bytes they took up
+    async fn remove_unused_blobs(&self, shred: bool) -> Result<u64> {
+        let _lock = self.lock(true).await?;
+        let mut entries = match fs::read_dir(self.path.join("blobs")).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        let used: HashSet<String> = self
+            .read_all(false)
+            .await?
+            .into_iter()
+            .filter_map(|item| Some(item.body_blob?.sha256))
+            .collect();
+
+        let mut removed = 0;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with('.') && !used.contains(&name) {
+                removed += self.remove(&entry.path(), shred).await?;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Every readable item, skipping (and logging) any that can't be read
+    pub async fn list(&self) -> Result<Vec<Item>> {
+        self.read_all(true).await
+    }
+
+    async fn read_all(&self, bodies: bool) -> Result<Vec<Item>> {
+        let results = glob(self.path.join("*.json").to_str().unwrap())?
+            .filter_map(|p| p.ok())
+            .map(|p| async move {
+                self.read(&p, bodies).await.unwrap_or_else(|e| {
+                    warn!("Could not read {}: {:#}", p.display(), e);
+                    None
+                })
+            });
+
+        Ok(futures::future::join_all(results)
+            .await
+            .iter()
+            .filter_map(|r| r.clone())
+            .collect())
+    }
+
+    /// Items newest first, skipping `offset` and taking up to `limit`. Files are ordered by when
+    /// they were written, so only those returned are read.
+    pub async fn page(&self, offset: usize, limit: Option<usize>) -> Result<Vec<Item>> {
+        self.read_page(offset, limit, true).await
+    }
+
+    /// Summaries of items newest first like `page`, without reading bodies stored in blobs
+    pub async fn summaries(&self, offset: usize, limit: Option<usize>) -> Result<Vec<ItemSummary>> {
+        Ok(self
+            .read_page(offset, limit, false)
+            .await?
+            .iter()
+            .map(Item::summary)
+            .collect())
+    }
+
+    async fn read_page(
+        &self,
+        offset: usize,
+        limit: Option<usize>,
+        bodies: bool,
+    ) -> Result<Vec<Item>> {
+        let files = self.files().await?;
+
+        let results = files
+            .into_iter()
+            .skip(offset)
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|path| async move {
+                self.read(&path, bodies).await.unwrap_or_else(|e| {
+                    warn!("Could not read {}: {:#}", path.display(), e);
+                    None
+                })
+            });
+
+        Ok(futures::future::join_all(results)
+            .await
+            .into_iter()
+            .flatten()
+            .collect())
+    }
+
+    /// Ids of every item, most recently written first
+    pub async fn ids(&self) -> Result<Vec<ItemId>> {
+        Ok(self
+            .files()
+            .await?
+            .iter()
+            .filter_map(|path| Some(path.file_stem()?.to_str()?.to_string()))
+            .collect())
+    }
+
+    pub async fn count(&self) -> Result<usize> {
+        Ok(self.files().await?.len())
+    }
+
+    pub async fn delete(&self, id: &ItemId) -> Result<()> {
+        let path = self.path.join(format!("{}.json", id));
+
+        self.rm(&path).await?;
+        self.remove_unused_blobs(false).await?;
+
+        Ok(())
+    }
+
+    /// Removes the items received before `before` and any blobs only they used, overwriting
+    /// the files with zeros first when `shred` is set
+    pub async fn purge(&self, before: DateTime<Utc>, shred: bool) -> Result<Purged> {
+        let mut purged = Purged::default();
+
+        for item in self.read_all(false).await? {
+            if item.received_at < before {
+                let path = self.path.join(format!("{}.json", item.id));
+                purged.bytes += self.remove(&path, shred).await?;
+                purged.items += 1;
+            }
+        }
+
+        if purged.items > 0 {
+            purged.bytes += self.remove_unused_blobs(shred).await?;
+        }
+
+        Ok(purged)
+    }
+
+    /// Removes items received more than `max_age` ago and the oldest beyond `max_items`,
+    /// returning how many were removed
+    pub async fn prune(
+        &self,
+        max_age: Option<Duration>,
+        max_items: Option<usize>,
+    ) -> Result<usize> {
+        let mut items = self.read_all(false).await?;
+        items.sort_by_key(|item| Reverse(item.received_at));
+
+        let cutoff = max_age.map(|age| Utc::now() - age);
+        let mut removed = 0;
+        for (i, item) in items.iter().enumerate() {
+            if cutoff.is_some_and(|cutoff| item.received_at < cutoff)
+                || max_items.is_some_and(|max| i >= max)
+            {
+                self.rm(&self.path.join(format!("{}.json", item.id)))
+                    .await?;
+                removed += 1;
+            }
+        }
+
+        if removed > 0 {
+            self.remove_unused_blobs(false).await?;
+        }
+
+        Ok(removed)
+    }
+
+    pub async fn clear(&self) -> Result<()> {
+        let results = glob(self.path.join("*.json").to_str().unwrap())?
+            .map(|p| async { self.rm(&p.unwrap()).await.unwrap() });
+
+        futures::future::join_all(results).await;
+
+        match fs::remove_dir_all(self.path.join("blobs")).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reads an item, with its body from its blob if it's in one and `body` is set
+    async fn read(&self, path: &PathBuf, body: bool) -> Result<Option<Item>> {
+        match fs::read(path).await {
+            Ok(s) => match serde_json::from_slice::<Item>(&s) {
+                Ok(mut item) => {
+                    item.id = path.file_stem().unwrap().to_str().unwrap().to_string();
+
+                    if let Some(blob) = item.body_blob.as_ref().filter(|_| body) {
+                        item.request.body = fs::read(self.blob_path(&blob.sha256))
+                            .await
+                            .with_context(|| format!("couldn't read the body of {}", item.id))?;
+                        item.body_blob = None;
+                    }
+
+                    Ok(Some(item))
+                }
+                Err(e) => {
+                    self.quarantine(path, e).await;
+
+                    Ok(None)
+                }
+            },
+            Err(e) => {
+                if e.kind() == io::ErrorKind::NotFound {
+                    Ok(None)
+                } else {
+                    Err(e.into())
+                }
+            }
+        }
+    }
+
+    /// Paths of the items, most recently written first
+    async fn files(&self) -> Result<Vec<PathBuf>> {
+        let mut files = vec![];
+
+        let mut entries = fs::read_dir(&self.path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension() != Some("json".as_ref()) {
+                continue;
+            }
+            // gone since it was listed
+            let Ok(modified) = entry.metadata().await.and_then(|m| m.modified()) else {
+                continue;
+            };
+            files.push((modified, path));
+        }
+
+        files.sort_by_key(|(modified, _)| Reverse(*modified));
+
+        Ok(files.into_iter().map(|(_, path)| path).collect())
+    }
+
+    /// Moves an unreadable item out of the way, so it's kept for inspection without breaking
+    /// every listing
+    async fn quarantine(&self, path: &Path, error: serde_json::Error) {
+        let moved = path.with_extension("json.corrupt");
+
+        match fs::rename(path, &moved).await {
+            Ok(_) => warn!(
+                "{} is corrupt ({}), moved it to {}",
+                path.display(),
+                error,
+                moved.display()
+            ),
+            Err(e) => warn!(
+                "{} is corrupt ({}) and couldn't be moved: {}",
+                path.display(),
+                error,
+                e
+            ),
+        }
+    }
+
+    async fn rm(&self, path: &PathBuf) -> Result<()> {
+        fs::remove_file(path).await.map_err(|e| e.into())
+    }
+
+    /// Removes a file, overwriting it with zeros first when `shred` is set, returning its size
+    async fn remove(&self, path: &PathBuf, shred: bool) -> Result<u64> {
+        let size = fs::metadata(path).await?.len();
+
+        if shred {
+            let mut file = fs::OpenOptions::new().write(true).open(path).await?;
+            let zeros = vec![0; 64 * 1024];
+            let mut left = size;
+            while left > 0 {
+                let n = left.min(zeros.len() as u64) as usize;
+                file.write_all(&zeros[..n]).await?;
+                left -= n as u64;
+            }
+            file.sync_all().await?;
+        }
+
+        self.rm(path).await?;
+
+        Ok(size)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Item {
+    #[serde(skip)]
+    pub id: ItemId,
+    pub received_at: DateTime<Utc>,
+    pub request: RequestMessage,
+    /// Whether the signature was valid, when the client was verifying signatures
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature_valid: Option<bool>,
+    /// Size of the body as received, when it was too large to record in full
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub truncated_from: Option<usize>,
+    /// What the local origin responded with, once it was forwarded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<RecordedResponse>,
+    /// The blob the body is stored in instead, while it hasn't been read from it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_blob: Option<Blob>,
+}
+
+/// What `purge` removed
+#[derive(Serialize, Default)]
+pub struct Purged {
+    pub items: usize,
+    /// Size of the files removed
+    pub bytes: u64,
+    /// Recorded sessions items were removed from too, counted in `items` and `bytes`
+    pub sessions: usize,
+}
+
+/// A body stored in a file of its own under `blobs/`, named by its SHA-256
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Blob {
+    pub sha256: String,
+    pub size: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RecordedResponse {
+    pub status: u16,
+    /// How long it took to respond, until the headers arrived
+    #[serde(default)]
+    pub duration_ms: u64,
+    pub headers: Vec<(String, HeaderBytes)>,
+    pub body: Vec<u8>,
+    /// Size of the body as received, when it was too large to record in full
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub truncated_from: Option<usize>,
+}
+
+impl RecordedResponse {
+    pub fn status_code(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    /// Cuts the recorded body down to at most `max` bytes, remembering how large it was
+    pub fn truncate_body(&mut self, max: usize) {
+        let size = self.body.len();
+
+        if size > max {
+            self.body.truncate(max);
+            self.truncated_from = Some(size);
+        }
+    }
+}
+
+/// Short description of an item, without headers or body
+#[derive(Serialize, Deserialize)]
+pub struct ItemSummary {
+    pub id: ItemId,
+    pub received_at: DateTime<Utc>,
+    pub method: String,
+    pub fullpath: String,
+    pub body_size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature_valid: Option<bool>,
+}
+
+impl Item {
+    pub fn summary(&self) -> ItemSummary {
+        ItemSummary {
+            id: self.id.clone(),
+            received_at: self.received_at,
+            method: self.request.method.clone(),
+            fullpath: self.request.fullpath.clone(),
+            body_size: self
+                .truncated_from
+                .or(self.body_blob.as_ref().map(|blob| blob.size))
+                .unwrap_or(self.request.body.len()),
+            signature_valid: self.signature_valid,
+        }
+    }
+
+    pub fn new(received_at: DateTime<Utc>, request: RequestMessage) -> Self {
+        let mut generator = names::Generator::default();
+        let id = generator.next().unwrap();
+
+        Self {
+            id,
+            received_at,
+            request,
+            signature_valid: None,
+            truncated_from: None,
+            response: None,
+            body_blob: None,
+        }
+    }
+
+    /// A copy of the item with its body left out, as it's stored in `blob`
+    fn without_body(&self, blob: Blob) -> Item {
+        Item {
+            id: self.id.clone(),
+            received_at: self.received_at,
+            request: RequestMessage {
+                method: self.request.method.clone(),
+                fullpath: self.request.fullpath.clone(),
+                version: self.request.version,
+                headers: self.request.headers.clone(),
+                body: vec![],
+                source: self.request.source.clone(),
+            },
+            signature_valid: self.signature_valid,
+            truncated_from: self.truncated_from,
+            response: self.response.clone(),
+            body_blob: Some(blob),
+        }
+    }
+
+    /// Where the request contains `needle`, which must be lowercase: its path, a header value or
+    /// its body, with JSON bodies decoded so escaped strings match too
+    pub fn find(&self, needle: &str) -> Option<&'static str> {
+        let contains = |text: &str| text.to_lowercase().contains(needle);
+
+        if contains(&self.request.fullpath) {
+            return Some("path");
+        }
+        if self
+            .request
+            .headers
+            .iter()
+            .any(|(_, value)| contains(&value.to_str_lossy()))
+        {
+            return Some("header");
+        }
+        if contains(&String::from_utf8_lossy(&self.request.body))
+            || serde_json::from_slice::<Value>(&self.request.body)
+                .is_ok_and(|json| json_contains(&json, needle))
+        {
+            return Some("body");
+        }
+
+        None
+    }
+
+    /// Cuts the recorded body down to at most `max` bytes, remembering how large it was
+    pub fn truncate_body(&mut self, max: usize) {
+        let size = self.request.body.len();
+
+        if size > max {
+            self.request.body.truncate(max);
+            self.truncated_from = Some(size);
+        }
+    }
+}
+
+fn json_contains(value: &Value, needle: &str) -> bool {
+    match value {
+        Value::String(text) => text.to_lowercase().contains(needle),
+        Value::Array(values) => values.iter().any(|v| json_contains(v, needle)),
+        Value::Object(fields) => fields
+            .iter()
+            .any(|(k, v)| k.to_lowercase().contains(needle) || json_contains(v, needle)),
+        other => other.to_string().contains(needle),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hookhub::Version;
+
+    use super::*;
+
+    /// A db in a directory of its own, emptied first
+    fn db(name: &str) -> Db {
+        let path = std::env::temp_dir().join(format!(
+            "hookhub-history-db-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+
+        Db::new(&path).unwrap()
+    }
+
+    fn item(received_at: DateTime<Utc>, body: &[u8]) -> Item {
+        Item::new(
+            received_at,
+            RequestMessage {
+                method: "POST".to_string(),
+                fullpath: "/hook".to_string(),
+                version: Version::Http11,
+                headers: vec![],
+                body: body.to_vec(),
+                source: None,
+            },
+        )
+    }
+
+    fn blobs(db: &Db) -> usize {
+        std::fs::read_dir(db.path().join("blobs"))
+            .map(|entries| entries.count())
+            .unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn purges_only_items_received_before() {
+        let db = db("before");
+        let now = Utc::now();
+        let old = db
+            .add(&item(now - Duration::days(2), b"old"))
+            .await
+            .unwrap();
+        let recent = db
+            .add(&item(now - Duration::hours(1), b"recent"))
+            .await
+            .unwrap();
+        let new = db.add(&item(now, b"new")).await.unwrap();
+
+        let purged = db.purge(now - Duration::days(1), false).await.unwrap();
+
+        assert_eq!(purged.items, 1);
+        assert!(purged.bytes > 0);
+        assert!(db.get(&old).await.unwrap().is_none());
+        assert!(db.get(&recent).await.unwrap().is_some());
+        assert!(db.get(&new).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn purging_nothing_removes_nothing() {
+        let db = db("nothing");
+        let now = Utc::now();
+        db.add(&item(now, b"new")).await.unwrap();
+
+        let purged = db.purge(now - Duration::days(1), false).await.unwrap();
+
+        assert_eq!((purged.items, purged.bytes), (0, 0));
+        assert_eq!(db.count().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn purge_keeps_blobs_items_left_still_use() {
+        let db = db("blobs");
+        let now = Utc::now();
+        let shared = vec![b'a'; BLOB_THRESHOLD + 1];
+        let only_old = vec![b'b'; BLOB_THRESHOLD + 1];
+        db.add(&item(now - Duration::days(2), &shared))
+            .await
+            .unwrap();
+        db.add(&item(now - Duration::days(2), &only_old))
+            .await
+            .unwrap();
+        let new = db.add(&item(now, &shared)).await.unwrap();
+        assert_eq!(blobs(&db), 2);
+
+        let purged = db.purge(now - Duration::days(1), false).await.unwrap();
+
+        assert_eq!(purged.items, 2);
+        assert_eq!(blobs(&db), 1);
+        assert_eq!(db.get(&new).await.unwrap().unwrap().request.body, shared);
+    }
+
+    #[tokio::test]
+    async fn shred_overwrites_files_with_zeros() {
+        let db = db("shred");
+        let now = Utc::now();
+        let id = db
+            .add(&item(now - Duration::days(2), b"secret"))
+            .await
+            .unwrap();
+        let path = db.path().join(format!("{}.json", id));
+        // still refers to the file's contents once it's removed
+        let link = db.path().join("link");
+        std::fs::hard_link(&path, &link).unwrap();
+        let size = std::fs::metadata(&path).unwrap().len();
+
+        let purged = db.purge(now - Duration::days(1), true).await.unwrap();
+
+        assert_eq!((purged.items, purged.bytes), (1, size));
+        assert!(!path.exists());
+        let shredded = std::fs::read(&link).unwrap();
+        assert_eq!(shredded.len() as u64, size);
+        assert!(shredded.iter().all(|b| *b == 0));
+    }
+
+    #[tokio::test]
+    async fn shred_overwrites_blobs_with_zeros() {
+        let db = db("shred-blobs");
+        let now = Utc::now();
+        let body = vec![b'a'; BLOB_THRESHOLD + 1];
+        db.add(&item(now - Duration::days(2), &body)).await.unwrap();
+        let blob = std::fs::read_dir(db.path().join("blobs"))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let link = db.path().join("link");
+        std::fs::hard_link(&blob, &link).unwrap();
+
+        db.purge(now - Duration::days(1), true).await.unwrap();
+
+        assert!(!blob.exists());
+        let shredded = std::fs::read(&link).unwrap();
+        assert_eq!(shredded.len(), body.len());
+        assert!(shredded.iter().all(|b| *b == 0));
+    }
+
+    #[tokio::test]
+    async fn blobs_are_not_removed_from_under_a_writer() {
+        let db = db("lock");
+        let body = vec![b'a'; BLOB_THRESHOLD + 1];
+        let lock = db.lock(false).await.unwrap();
+
+        let cleanup = {
+            let db = Db::new(&db.path().to_path_buf()).unwrap();
+            tokio::spawn(async move { db.remove_unused_blobs(false).await })
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!cleanup.is_finished());
+        // as a writer does while holding the lock
+        let blob = db.write_blob(&body).await.unwrap();
+        let item = item(Utc::now(), &body).without_body(blob);
+        std::fs::write(
+            db.path().join("item.json"),
+            serde_json::to_vec(&item).unwrap(),
+        )
+        .unwrap();
+        drop(lock);
+
+        assert_eq!(cleanup.await.unwrap().unwrap(), 0);
+        assert_eq!(blobs(&db), 1);
+    }
+}
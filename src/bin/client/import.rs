@@ -0,0 +1,319 @@
+//! Reads requests captured elsewhere into history: HAR files from browser devtools or ngrok,
+//! requests as JSON like `history export-fixture` and `--stdout-jsonl` print them, and curl
+//! commands.
+
+use std::fs;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Utc};
+use hookhub::{is_valid_method, HeaderBytes, JsonRequest, RequestMessage, Version};
+use serde::Deserialize;
+use serde_json::Value;
+use url::Url;
+
+/// A request read from a file, with when it was received if the file says
+pub struct Imported {
+    pub received_at: Option<DateTime<Utc>>,
+    pub request: RequestMessage,
+}
+
+/// Reads every request in the file at `path`, working out which kind of file it is from what's
+/// in it
+pub fn read(path: &str) -> Result<Vec<Imported>> {
+    let text = fs::read_to_string(path).with_context(|| format!("couldn't read {}", path))?;
+    let trimmed = text.trim_start();
+
+    if trimmed.starts_with("curl") {
+        return Ok(vec![curl(trimmed)?]);
+    }
+
+    let values = match serde_json::from_str::<Value>(&text) {
+        Ok(Value::Array(values)) => values,
+        Ok(value) if value.get("log").is_some() => return har(value),
+        Ok(value) => vec![value],
+        // JSON lines, as --stdout-jsonl and history watch print
+        Err(e) => text
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()
+            .map_err(|_| anyhow!("{} isn't a HAR file, JSON or a curl command: {}", path, e))?,
+    };
+
+    values.into_iter().map(json).collect()
+}
+
+#[derive(Deserialize)]
+struct JsonItem {
+    received_at: Option<DateTime<Utc>>,
+    #[serde(flatten)]
+    request: JsonRequest,
+}
+
+fn json(value: Value) -> Result<Imported> {
+    let item: JsonItem = serde_json::from_value(value)
+        .context("expected a request with method, path, headers and a base64 body")?;
+
+    let mut request = RequestMessage {
+        method: String::new(),
+        fullpath: String::new(),
+        version: Version::Http11,
+        headers: vec![],
+        body: vec![],
+        source: None,
+    };
+    item.request
+        .apply(&mut request)
+        .context("invalid request")?;
+
+    Ok(Imported {
+        received_at: item.received_at,
+        request,
+    })
+}
+
+#[derive(Deserialize)]
+struct Har {
+    log: HarLog,
+}
+
+#[derive(Deserialize)]
+struct HarLog {
+    entries: Vec<HarEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HarEntry {
+    started_date_time: Option<DateTime<Utc>>,
+    request: HarRequest,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HarRequest {
+    method: String,
+    url: Url,
+    headers: Vec<HarHeader>,
+    post_data: Option<HarPostData>,
+}
+
+#[derive(Deserialize)]
+struct HarHeader {
+    name: String,
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct HarPostData {
+    #[serde(default)]
+    text: String,
+}
+
+fn har(value: Value) -> Result<Vec<Imported>> {
+    let har: Har = serde_json::from_value(value).context("invalid HAR file")?;
+
+    har.log
+        .entries
+        .into_iter()
+        .map(|entry| {
+            if !is_valid_method(&entry.request.method) {
+                bail!("invalid method in HAR entry: {:?}", entry.request.method);
+            }
+            Ok(Imported {
+                received_at: entry.started_date_time,
+                request: RequestMessage {
+                    method: entry.request.method,
+                    fullpath: fullpath(&entry.request.url),
+                    // the version it was captured with says nothing of what the local origin speaks
+                    version: Version::Http11,
+                    headers: entry
+                        .request
+                        .headers
+                        .into_iter()
+                        // HTTP/2 pseudo-headers like :authority, the request line has those
+                        .filter(|header| !header.name.starts_with(':'))
+                        .map(|header| (header.name, header.value.into()))
+                        .collect(),
+                    body: entry
+                        .request
+                        .post_data
+                        .map(|data| data.text.into_bytes())
+                        .unwrap_or_default(),
+                    source: None,
+                },
+            })
+        })
+        .collect()
+}
+
+fn fullpath(url: &Url) -> String {
+    match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    }
+}
+
+/// A curl command like browser devtools' "Copy as cURL" gives, understanding the options that
+/// make up the request and ignoring the rest
+fn curl(command: &str) -> Result<Imported> {
+    let mut words = shell_words(command)?.into_iter().skip(1);
+
+    let mut method = None;
+    let mut url = None;
+    let mut headers: Vec<(String, HeaderBytes)> = vec![];
+    let mut data: Vec<String> = vec![];
+
+    while let Some(word) = words.next() {
+        let (option, inline) = match word.split_once('=') {
+            Some((option, value)) if option.starts_with("--") => (option.to_string(), Some(value)),
+            _ => (word.clone(), None),
+        };
+        let mut value = || {
+            inline
+                .map(str::to_string)
+                .or_else(|| words.next())
+                .ok_or_else(|| anyhow!("{} needs a value", option))
+        };
+
+        match option.as_str() {
+            "-X" | "--request" => {
+                let value = value()?;
+                if !is_valid_method(&value) {
+                    bail!("invalid method for {}: {:?}", option, value);
+                }
+                method = Some(value);
+            }
+            "-H" | "--header" => {
+                let header = value()?;
+                let (name, value) = header.split_once(':').ok_or_else(|| {
+                    anyhow!("expected a header like \"Name: value\", got {}", header)
+                })?;
+                headers.push((name.trim().to_string(), value.trim().into()));
+            }
+            "-A" | "--user-agent" => headers.push(("User-Agent".to_string(), value()?.into())),
+            "-e" | "--referer" => headers.push(("Referer".to_string(), value()?.into())),
+            "-b" | "--cookie" => headers.push(("Cookie".to_string(), value()?.into())),
+            "-d" | "--data" | "--data-ascii" | "--data-binary" | "--data-raw" => {
+                let value = value()?;
+                match value.strip_prefix('@') {
+                    Some(file) if option != "--data-raw" => data.push(
+                        fs::read_to_string(file)
+                            .with_context(|| format!("couldn't read {}", file))?,
+                    ),
+                    _ => data.push(value),
+                }
+            }
+            "--json" => {
+                data.push(value()?);
+                headers.push(("Content-Type".to_string(), "application/json".into()));
+                headers.push(("Accept".to_string(), "application/json".into()));
+            }
+            "--url" => url = Some(value()?),
+            "-o" | "--output" | "-m" | "--max-time" | "--connect-timeout" | "-u" | "--user" => {
+                value()?;
+            }
+            option if option.starts_with('-') => {}
+            _ => url = Some(word),
+        }
+    }
+
+    let Some(url) = url else {
+        bail!("no URL in the curl command");
+    };
+    let url = Url::parse(&url).with_context(|| format!("invalid URL {}", url))?;
+
+    let body = data.join("&").into_bytes();
+    if !body.is_empty()
+        && !headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+    {
+        // what curl sends with -d
+        headers.push((
+            "Content-Type".to_string(),
+            "application/x-www-form-urlencoded".into(),
+        ));
+    }
+
+    Ok(Imported {
+        received_at: None,
+        request: RequestMessage {
+            method: method.unwrap_or_else(|| if body.is_empty() { "GET" } else { "POST" }.into()),
+            fullpath: fullpath(&url),
+            version: Version::Http11,
+            headers,
+            body,
+            source: None,
+        },
+    })
+}
+
+/// Splits a command into words as a POSIX shell would, with ', " and $'' quoting and
+/// backslash escapes and line continuations
+fn shell_words(command: &str) -> Result<Vec<String>> {
+    let mut words = vec![];
+    let mut word: Option<String> = None;
+    let mut chars = command.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                words.extend(word.take());
+            }
+            '\\' => match chars.next() {
+                Some('\n') => {}
+                Some(c) => word.get_or_insert_default().push(c),
+                None => bail!("the curl command ends in a backslash"),
+            },
+            '\'' => {
+                let word = word.get_or_insert_default();
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => bail!("unterminated ' in the curl command"),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_default();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') if matches!(chars.peek(), Some('"' | '\\' | '$' | '`')) => {
+                            word.push(chars.next().unwrap())
+                        }
+                        Some('\\') if chars.peek() == Some(&'\n') => {
+                            chars.next();
+                        }
+                        Some(c) => word.push(c),
+                        None => bail!("unterminated \" in the curl command"),
+                    }
+                }
+            }
+            '$' if chars.peek() == Some(&'\'') => {
+                chars.next();
+                let word = word.get_or_insert_default();
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => word.push('\n'),
+                            Some('r') => word.push('\r'),
+                            Some('t') => word.push('\t'),
+                            Some(c) => word.push(c),
+                            None => bail!("unterminated $' in the curl command"),
+                        },
+                        Some(c) => word.push(c),
+                        None => bail!("unterminated $' in the curl command"),
+                    }
+                }
+            }
+            c => word.get_or_insert_default().push(c),
+        }
+    }
+    words.extend(word);
+
+    Ok(words)
+}
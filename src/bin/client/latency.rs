@@ -0,0 +1,200 @@
+//! How long requests take: from the server receiving them to them arriving here, the local
+//! origin responding to them and the two together, warning when they're over `--latency-budget`
+//! or `--slow-forward`.
+
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
+
+use chrono::Utc;
+use hookhub::{RequestMessage, RECEIVED_AT_HEADER};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+/// How many of the most recent requests percentiles are taken over
+const SAMPLES: usize = 1000;
+
+/// Latency percentiles of recent requests, reported in `client status`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LatencyReport {
+    /// From the server receiving a request to it arriving here, which relies on the two clocks
+    /// agreeing
+    pub relay: Percentiles,
+    /// From forwarding a request to the local origin responding
+    pub forward: Percentiles,
+    /// From the server receiving a request to being done with it here
+    pub end_to_end: Percentiles,
+    /// Requests over --latency-budget or --slow-forward
+    pub slow: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Percentiles {
+    pub count: usize,
+    pub p50_ms: u64,
+    pub p95_ms: u64,
+    pub p99_ms: u64,
+}
+
+struct Samples(VecDeque<Duration>);
+
+impl Samples {
+    const fn new() -> Self {
+        Samples(VecDeque::new())
+    }
+
+    fn add(&mut self, duration: Duration) {
+        if self.0.len() == SAMPLES {
+            self.0.pop_front();
+        }
+        self.0.push_back(duration);
+    }
+
+    fn percentiles(&self) -> Percentiles {
+        let mut durations: Vec<Duration> = self.0.iter().copied().collect();
+        durations.sort();
+        let at = |p: f64| match durations.len() {
+            0 => 0,
+            n => durations[((p * n as f64).ceil() as usize).clamp(1, n) - 1].as_millis() as u64,
+        };
+
+        Percentiles {
+            count: durations.len(),
+            p50_ms: at(0.5),
+            p95_ms: at(0.95),
+            p99_ms: at(0.99),
+        }
+    }
+}
+
+struct Latency {
+    budget: Option<Duration>,
+    slow_forward: Option<Duration>,
+    relay: Samples,
+    forward: Samples,
+    end_to_end: Samples,
+    slow: u64,
+}
+
+static LATENCY: Mutex<Latency> = Mutex::new(Latency {
+    budget: None,
+    slow_forward: None,
+    relay: Samples::new(),
+    forward: Samples::new(),
+    end_to_end: Samples::new(),
+    slow: 0,
+});
+
+/// Sets how long a request may take end to end, and the local origin to respond, before it's
+/// warned about
+pub fn set_thresholds(budget: Option<Duration>, slow_forward: Option<Duration>) {
+    let mut latency = LATENCY.lock().unwrap();
+    latency.budget = budget;
+    latency.slow_forward = slow_forward;
+}
+
+/// How long ago the server received `req`, if it's new enough to say
+pub fn since_received(req: &RequestMessage) -> Option<Duration> {
+    let received_at: i64 = req.header(RECEIVED_AT_HEADER)?.to_str()?.parse().ok()?;
+    let elapsed = (Utc::now().timestamp_millis() - received_at).max(0);
+
+    Some(Duration::from_millis(elapsed as u64))
+}
+
+/// Records how long `req` took to be relayed here, if known, for the local origin to respond,
+/// if it was forwarded, and to be done with once it arrived, warning about it when it's slow
+/// with its path as `logged`, redacted
+pub fn record(
+    id: &str,
+    req: &RequestMessage,
+    logged: &str,
+    relay: Option<Duration>,
+    forward: Option<Duration>,
+    handled: Duration,
+) {
+    let mut latency = LATENCY.lock().unwrap();
+    let end_to_end = relay.map(|relay| relay + handled);
+    let mut slow = false;
+
+    if let Some(relay) = relay {
+        latency.relay.add(relay);
+    }
+    if let Some(forward) = forward {
+        latency.forward.add(forward);
+
+        if latency.slow_forward.is_some_and(|max| forward > max) {
+            slow = true;
+            warn!(
+                event = "slow_forward",
+                request_id = id,
+                duration_ms = forward.as_millis() as u64;
+                "Local origin took {:?} to respond to {} {} ({})", forward, req.method, logged, id
+            );
+        }
+    }
+    if let Some(end_to_end) = end_to_end {
+        latency.end_to_end.add(end_to_end);
+
+        if latency.budget.is_some_and(|max| end_to_end > max) {
+            slow = true;
+            warn!(
+                event = "slow_request",
+                request_id = id,
+                duration_ms = end_to_end.as_millis() as u64,
+                relay_ms = relay.unwrap_or_default().as_millis() as u64;
+                "{} {} ({}) took {:?} end to end, {:?} of it being relayed and {} forwarding",
+                req.method,
+                logged,
+                id,
+                end_to_end,
+                relay.unwrap_or_default(),
+                match forward {
+                    Some(forward) => format!("{:?}", forward),
+                    None => "none".to_string(),
+                }
+            );
+        }
+    }
+
+    if slow {
+        latency.slow += 1;
+    }
+}
+
+pub fn report() -> Option<LatencyReport> {
+    let latency = LATENCY.lock().unwrap();
+    if latency.relay.0.is_empty() && latency.forward.0.is_empty() {
+        return None;
+    }
+
+    Some(LatencyReport {
+        relay: latency.relay.percentiles(),
+        forward: latency.forward.percentiles(),
+        end_to_end: latency.end_to_end.percentiles(),
+        slow: latency.slow,
+    })
+}
+
+/// Logs the latency percentiles, e.g. when shutting down
+pub fn log_report() {
+    if let Some(report) = report() {
+        for line in describe(&report) {
+            info!("{}", line);
+        }
+    }
+}
+
+/// A line for each kind of latency with its percentiles, and one for how many were slow
+pub fn describe(report: &LatencyReport) -> Vec<String> {
+    let line = |name: &str, p: &Percentiles| {
+        format!(
+            "{} latency: p50 {}ms, p95 {}ms, p99 {}ms over {} request(s)",
+            name, p.p50_ms, p.p95_ms, p.p99_ms, p.count
+        )
+    };
+
+    vec![
+        line("Relay", &report.relay),
+        line("Forward", &report.forward),
+        line("End to end", &report.end_to_end),
+        format!("Slow requests: {}", report.slow),
+    ]
+}
@@ -0,0 +1,139 @@
+//! Writes a session out as a load test, a k6 script or a vegeta targets file, with each body in
+//! a file of its own beside it.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde_json::{Map, Value};
+use url::Url;
+
+use crate::history_db::Item;
+
+#[derive(ValueEnum, Clone, Copy)]
+pub enum LoadTestFormat {
+    /// script.js for k6, waiting between requests as they were received
+    K6,
+    /// targets.txt for vegeta attack, in its HTTP format
+    Vegeta,
+}
+
+/// Headers that describe the connection the request came in on rather than the request, the
+/// tool works them out again for the target
+const SKIPPED_HEADERS: &[&str] = &["host", "content-length", "connection", "transfer-encoding"];
+
+/// Writes `items`, oldest first, to `dir` as a load test against `target`, returning the path of
+/// the script or targets file
+pub fn write(items: &[Item], format: LoadTestFormat, target: &Url, dir: &Path) -> Result<PathBuf> {
+    let bodies = dir.join("bodies");
+    fs::create_dir_all(&bodies).with_context(|| format!("couldn't create {}", bodies.display()))?;
+    // vegeta reads bodies relative to where it's run, so they're given in full
+    let bodies = bodies.canonicalize()?;
+
+    let mut body_files = vec![];
+    for (i, item) in items.iter().enumerate() {
+        if item.request.body.is_empty() {
+            body_files.push(None);
+            continue;
+        }
+
+        let name = format!("{:04}.bin", i + 1);
+        fs::write(bodies.join(&name), &item.request.body)?;
+        body_files.push(Some(name));
+    }
+
+    let (file, text) = match format {
+        LoadTestFormat::K6 => ("script.js", k6(items, &body_files, target)),
+        LoadTestFormat::Vegeta => ("targets.txt", vegeta(items, &body_files, target, &bodies)),
+    };
+
+    let path = dir.join(file);
+    fs::write(&path, text).with_context(|| format!("couldn't write {}", path.display()))?;
+
+    Ok(path)
+}
+
+fn headers(item: &Item) -> impl Iterator<Item = (&str, String)> {
+    item.request
+        .headers
+        .iter()
+        .filter(|(name, _)| !SKIPPED_HEADERS.contains(&name.to_ascii_lowercase().as_str()))
+        .map(|(name, value)| (name.as_str(), value.to_str_lossy().to_string()))
+}
+
+fn url(target: &Url, fullpath: &str) -> String {
+    format!("{}{}", target.as_str().trim_end_matches('/'), fullpath)
+}
+
+fn k6(items: &[Item], body_files: &[Option<String>], target: &Url) -> String {
+    let mut out = String::from(
+        "import http from 'k6/http';\nimport { sleep } from 'k6';\n\n// override with k6 run -e TARGET=...\n",
+    );
+    out.push_str(&format!(
+        "const target = __ENV.TARGET || {};\n\n",
+        Value::from(target.as_str().trim_end_matches('/'))
+    ));
+
+    out.push_str("const requests = [\n");
+    let mut previous = items.first().map(|item| item.received_at);
+    for (item, body) in items.iter().zip(body_files) {
+        let wait = previous
+            .map(|previous| (item.received_at - previous).num_milliseconds() as f64 / 1000.0)
+            .unwrap_or_default();
+        previous = Some(item.received_at);
+
+        // k6 takes a header once, so repeated ones are joined as HTTP allows
+        let mut header_map = Map::new();
+        for (name, value) in headers(item) {
+            match header_map.get_mut(name) {
+                Some(Value::String(existing)) => *existing = format!("{}, {}", existing, value),
+                _ => {
+                    header_map.insert(name.to_string(), value.into());
+                }
+            }
+        }
+
+        out.push_str(&format!(
+            "  {{ wait: {}, method: {}, path: {}, headers: {}, body: {} }},\n",
+            wait,
+            Value::from(item.request.method.as_str()),
+            Value::from(item.request.fullpath.as_str()),
+            Value::Object(header_map),
+            match body {
+                Some(name) => format!("open({}, 'b')", Value::from(format!("bodies/{}", name))),
+                None => "null".to_string(),
+            }
+        ));
+    }
+    out.push_str("];\n\n");
+
+    out.push_str(
+        "export default function () {\n  for (const req of requests) {\n    sleep(req.wait);\n    http.request(req.method, target + req.path, req.body, { headers: req.headers });\n  }\n}\n",
+    );
+
+    out
+}
+
+fn vegeta(items: &[Item], body_files: &[Option<String>], target: &Url, bodies: &Path) -> String {
+    let mut out = String::new();
+
+    for (item, body) in items.iter().zip(body_files) {
+        out.push_str(&format!(
+            "{} {}\n",
+            item.request.method,
+            url(target, &item.request.fullpath)
+        ));
+        for (name, value) in headers(item) {
+            out.push_str(&format!("{}: {}\n", name, value));
+        }
+        if let Some(name) = body {
+            out.push_str(&format!("@{}\n", bodies.join(name).display()));
+        }
+        out.push('\n');
+    }
+
+    out
+}
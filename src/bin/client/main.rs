@@ -0,0 +1,1568 @@
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, LazyLock,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use canary::{Canary, CanaryKey};
+use chaos::Chaos;
+use chrono::{DateTime, Utc};
+use filters::Filters;
+use fixture::FixtureFormat;
+use health::Health;
+use history_db::{ItemId, RecordedResponse};
+use hookhub::{
+    alert::Alerter,
+    client::{
+        forward, http_client_with_timeouts, prepare_local_url, prepare_remote_url,
+        DEFAULT_CONNECT_TIMEOUT, DEFAULT_READ_TIMEOUT,
+    },
+    dedup::{Dedup, DedupKey},
+    logging::{self, LogFormat},
+    output::OutputFormat,
+    plugin::{self, Plugin},
+    script::{self, Script},
+    signature::{self, Verifier},
+    DeliveryOutcome, DeliveryReport, HeaderBytes, RequestMessage,
+};
+use loadtest::LoadTestFormat;
+use redact::{JsonPath, Redactor};
+use regex::Regex;
+use register::Registration;
+use reqwest::{Client, StatusCode};
+use simulate::WebhookKind;
+use supervise::Supervisor;
+use tokio::{
+    signal::unix::SignalKind,
+    sync::{broadcast, mpsc},
+    task::JoinHandle,
+    time::{self, interval_at, Instant},
+};
+
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use log::{debug, error, info, warn};
+use url::Url;
+
+mod admin;
+mod alerts;
+mod assertions;
+mod bench;
+mod canary;
+mod chaos;
+mod clients;
+mod compare;
+mod config;
+mod control;
+mod dirs;
+mod doctor;
+mod exec;
+mod filters;
+mod fixture;
+mod health;
+mod history;
+mod history_db;
+mod import;
+mod latency;
+mod loadtest;
+mod mirror;
+mod ngrok;
+mod notify;
+mod redact;
+mod register;
+mod selftest;
+mod server_history;
+mod session;
+mod simulate;
+mod smee;
+mod state;
+mod supervise;
+mod tail;
+
+pub static DIRS: LazyLock<dirs::Dirs> = LazyLock::new(dirs::Dirs::resolve);
+
+pub static HISTORY_DB: LazyLock<history_db::Db> =
+    LazyLock::new(|| history_db::Db::new(&DIRS.history).unwrap());
+
+pub static STATE: LazyLock<state::State> = LazyLock::new(state::State::new);
+
+/// Hookhub client
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Commands,
+
+    /// Profile in config.toml whose settings to use on top of its defaults, and whose history to record to and read
+    #[arg(long, global = true, env = "HOOKHUB_PROFILE")]
+    profile: Option<String>,
+
+    /// Directory to read config.toml from and keep history in, instead of the XDG config and data directories
+    #[arg(long, global = true, env = "HOOKHUB_HOME")]
+    config_dir: Option<PathBuf>,
+
+    /// Format of log output
+    #[arg(
+        long,
+        global = true,
+        env = "HOOKHUB_LOG_FORMAT",
+        value_enum,
+        default_value_t
+    )]
+    log_format: LogFormat,
+
+    /// Format of command output
+    #[arg(
+        long,
+        global = true,
+        env = "HOOKHUB_OUTPUT",
+        value_enum,
+        default_value_t
+    )]
+    output: OutputFormat,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Connect to a remote server and relay requests to a local server
+    Connect(Box<ConnectArgs>),
+    /// Show the state of a running connect process
+    Status {
+        /// Local address of the control API of the running process
+        #[arg(long, env = "HOOKHUB_CONTROL_ADDR", default_value = DEFAULT_CONTROL_ADDR)]
+        control_addr: SocketAddr,
+    },
+    /// Stop forwarding requests to the local origin, only recording them to history
+    Pause {
+        /// Local address of the control API of the running process
+        #[arg(long, env = "HOOKHUB_CONTROL_ADDR", default_value = DEFAULT_CONTROL_ADDR)]
+        control_addr: SocketAddr,
+    },
+    /// Resume forwarding requests to the local origin
+    Resume {
+        /// Local address of the control API of the running process
+        #[arg(long, env = "HOOKHUB_CONTROL_ADDR", default_value = DEFAULT_CONTROL_ADDR)]
+        control_addr: SocketAddr,
+    },
+    /// Load the scripts and plugins of a running connect process from their files again
+    Reload {
+        /// Local address of the control API of the running process
+        #[arg(long, env = "HOOKHUB_CONTROL_ADDR", default_value = DEFAULT_CONTROL_ADDR)]
+        control_addr: SocketAddr,
+    },
+    /// Check the environment and connectivity to the remote and local origins
+    Doctor {
+        /// Remote origin that will relay requests (e.g. wss://something.herokuapp.com)
+        #[arg(long, env = "HOOKHUB_REMOTE")]
+        remote: Option<Url>,
+
+        /// Remote server secret used to authenticate
+        #[arg(long, env = "HOOKHUB_SECRET")]
+        secret: Option<String>,
+
+        /// Local origin to relay requests to (e.g. https://localhost:3000/)
+        #[arg(long, env = "HOOKHUB_LOCAL")]
+        local: Option<Url>,
+    },
+    /// List the clients connected to the remote server
+    Clients {
+        /// Remote origin that will relay requests (e.g. wss://something.herokuapp.com)
+        #[arg(long, env = "HOOKHUB_REMOTE")]
+        remote: Url,
+
+        /// Remote server secret used to authenticate, its --admin-secret if it has one
+        #[arg(long, env = "HOOKHUB_SECRET")]
+        secret: String,
+    },
+    /// List the requests the remote server's request log has received, e.g. while nothing was connected
+    ServerHistory {
+        /// Show this request in full instead
+        id: Option<u64>,
+
+        /// Remote origin that will relay requests (e.g. wss://something.herokuapp.com)
+        #[arg(long, env = "HOOKHUB_REMOTE")]
+        remote: Url,
+
+        /// Remote server secret used to authenticate, its --admin-secret if it has one
+        #[arg(long, env = "HOOKHUB_SECRET")]
+        secret: String,
+
+        /// Only requests received after this time (e.g. 2024-10-01T09:00:00Z)
+        #[arg(long)]
+        since: Option<DateTime<Utc>>,
+
+        /// Most requests to list, newest first
+        #[arg(long, default_value_t = 100)]
+        limit: usize,
+    },
+    /// Manage and replay previously received requests
+    History {
+        #[command(subcommand)]
+        command: HistoryCommands,
+    },
+    /// Manage the profiles in config.toml
+    Profiles {
+        #[command(subcommand)]
+        command: ProfileCommands,
+    },
+    /// Record runs of requests as named sessions and replay them with their original timing
+    Session {
+        #[command(subcommand)]
+        command: SessionCommands,
+    },
+    /// Send requests through the remote's public ingest endpoint at a steady rate and report the throughput, latency and drops of relaying them back
+    Bench {
+        /// Remote origin that will relay requests (e.g. wss://something.herokuapp.com)
+        #[arg(long, env = "HOOKHUB_REMOTE")]
+        remote: Url,
+
+        /// Remote server secret used to authenticate
+        #[arg(long, env = "HOOKHUB_SECRET")]
+        secret: String,
+
+        /// How fast to send requests (e.g. 200/s)
+        #[arg(long, default_value = "100/s", value_parser = simulate::parse_rate)]
+        rate: Duration,
+
+        /// How long to send requests for (e.g. 60s or 5m)
+        #[arg(long, default_value = "30s", value_parser = bench::parse_duration)]
+        duration: Duration,
+
+        /// Size of each request's body (e.g. 512, 4k or 1m)
+        #[arg(long, default_value = "1k", value_parser = bench::parse_size)]
+        body_size: usize,
+    },
+    /// Send realistic example webhooks, signed the way the provider signs them, to exercise a handler
+    Simulate {
+        /// What to send, one of github:push, stripe:invoice.paid or generic
+        kind: WebhookKind,
+
+        /// Where to send them, a URL (e.g. http://localhost:3000/webhooks/github) or a profile in config.toml to send them to its local origin
+        #[arg(long)]
+        to: String,
+
+        /// Path to send them to, instead of the path of --to
+        #[arg(long)]
+        path: Option<String>,
+
+        /// Secret to sign them with, like the provider's signing secret for the endpoint
+        #[arg(long, env = "HOOKHUB_SIGNING_SECRET")]
+        signing_secret: Option<String>,
+
+        /// How fast to send them (e.g. 10/s, 30/m or 100/h), until Ctrl-C unless --count is given
+        #[arg(long, value_parser = simulate::parse_rate)]
+        rate: Option<Duration>,
+
+        /// How many to send, 1 by default without --rate
+        #[arg(long)]
+        count: Option<u64>,
+    },
+}
+
+#[derive(clap::Args)]
+struct ConnectArgs {
+    /// Remote origin that will relay requests (e.g. wss://something.herokuapp.com)
+    #[arg(long, env = "HOOKHUB_REMOTE", required_unless_present = "smee")]
+    remote: Option<Url>,
+
+    /// Remote server secret used to authenticate
+    #[arg(long, env = "HOOKHUB_SECRET", required_unless_present = "smee")]
+    secret: Option<String>,
+
+    /// Receive from a smee.io channel instead of a remote (e.g. https://smee.io/abc123)
+    #[arg(long, env = "HOOKHUB_SMEE", conflicts_with_all = ["selftest", "tunnel_websockets", "tcp_forwards", "quic", "registrations"])]
+    smee: Option<Url>,
+
+    /// Path on the local origin requests from --smee are sent to, smee.io doesn't keep the one they were sent to
+    #[arg(
+        long,
+        env = "HOOKHUB_SMEE_PATH",
+        default_value = "/",
+        requires = "smee"
+    )]
+    smee_path: String,
+
+    /// Local origin to relay requests to (e.g. https://localhost:3000/)
+    #[arg(long, env = "HOOKHUB_LOCAL", required_unless_present_any = ["record_only", "exec", "stdout_jsonl"])]
+    local: Option<Url>,
+
+    /// Command to run with the shell for each received request, the request is written to its stdin as JSON with the body base64 encoded
+    #[arg(long, env = "HOOKHUB_EXEC")]
+    exec: Option<String>,
+
+    /// Local address to expose the control API on
+    #[arg(long, env = "HOOKHUB_CONTROL_ADDR", default_value = DEFAULT_CONTROL_ADDR)]
+    control_addr: SocketAddr,
+
+    /// Only record received requests to history, never forward them
+    #[arg(long, env = "HOOKHUB_RECORD_ONLY")]
+    record_only: bool,
+
+    /// Print each received request in full, including headers and body
+    #[arg(long, env = "HOOKHUB_TAIL")]
+    tail: bool,
+
+    /// Hold forwards while the local origin isn't accepting connections, for up to 30 seconds before giving up on them
+    #[arg(long, env = "HOOKHUB_WAIT_LOCAL")]
+    wait_local: bool,
+
+    /// Path on the local origin that responds with a success status while it's healthy, checked for --wait-local instead of the port accepting connections (implies --wait-local)
+    #[arg(long, env = "HOOKHUB_HEALTH_PATH")]
+    health_path: Option<String>,
+
+    /// Local dev server to run after --, it's restarted if it exits and implies --wait-local (e.g. -- npm run dev)
+    #[arg(last = true, requires = "local")]
+    command: Vec<String>,
+
+    /// Raise a desktop notification when a forward fails or the connection to the remote drops and comes back
+    #[arg(long, env = "HOOKHUB_NOTIFY")]
+    notify: bool,
+
+    /// Slack or Discord incoming webhook to alert when forwards keep failing or the connection to the remote stays down
+    #[arg(long, env = "HOOKHUB_ALERT_WEBHOOK")]
+    alert_webhook: Option<Url>,
+
+    /// Consecutive forward failures before alerting
+    #[arg(long, env = "HOOKHUB_ALERT_AFTER_FAILURES", default_value_t = 5)]
+    alert_after_failures: u32,
+
+    /// Minutes without a connection to the remote before alerting
+    #[arg(long, env = "HOOKHUB_ALERT_AFTER_DISCONNECT", default_value_t = 5)]
+    alert_after_disconnect: u64,
+
+    /// Write each received request to stdout as a line of JSON, with the body base64 encoded
+    #[arg(long, env = "HOOKHUB_STDOUT_JSONL", conflicts_with = "tail")]
+    stdout_jsonl: bool,
+
+    /// Maximum number of body bytes printed by --tail
+    #[arg(long, env = "HOOKHUB_TAIL_MAX_BODY", default_value_t = 4096)]
+    tail_max_body: usize,
+
+    /// Verify signatures of received requests, one of github:<secret>, stripe:<secret> or hmac:<header>:<secret>, can be repeated. The result is recorded in history and sent to the local origin as X-Hookhub-Signature-Valid
+    #[arg(long = "verify-signature", env = "HOOKHUB_VERIFY_SIGNATURE")]
+    verify_signatures: Vec<Verifier>,
+
+    /// Drop repeated deliveries of the same webhook, identified by header:<name> (e.g. header:X-GitHub-Delivery) or body
+    #[arg(long, env = "HOOKHUB_DEDUP")]
+    dedup: Option<DedupKey>,
+
+    /// How long in seconds to remember deliveries for --dedup
+    #[arg(long, env = "HOOKHUB_DEDUP_WINDOW", default_value_t = 300)]
+    dedup_window: u64,
+
+    /// Only record up to this many bytes of a request's body in history, along with its original size, the full body is still forwarded
+    #[arg(
+        long,
+        env = "HOOKHUB_MAX_HISTORY_BODY",
+        visible_alias = "history-max-body-size"
+    )]
+    max_history_body: Option<usize>,
+
+    /// Don't record received requests to history at all
+    #[arg(long, env = "HOOKHUB_NO_HISTORY", conflicts_with = "record_only")]
+    no_history: bool,
+
+    /// WASM plugin exporting filter_request and/or transform_request to run requests through before forwarding, can be repeated and they run in order
+    #[arg(long = "plugin", env = "HOOKHUB_PLUGINS", value_delimiter = ',')]
+    plugins: Vec<PathBuf>,
+
+    /// Rhai script defining accept(req) and/or transform(req) to run requests through before forwarding and replaying, can be repeated and they run in order before any plugins
+    #[arg(long = "script", env = "HOOKHUB_SCRIPTS", value_delimiter = ',')]
+    scripts: Vec<PathBuf>,
+
+    /// Don't ask the server to compress large messages
+    #[arg(long, env = "HOOKHUB_NO_COMPRESSION")]
+    no_compression: bool,
+
+    /// Take websockets providers open to the remote, tunnelling them to the same path on the local origin
+    #[arg(long, env = "HOOKHUB_TUNNEL_WEBSOCKETS", requires = "local")]
+    tunnel_websockets: bool,
+
+    /// Forward raw TCP connections made to a port on the remote (given to it with --tcp-port) to a local address, as <remote port>:<host>:<port> (e.g. 5432:localhost:5432). Can be repeated
+    #[arg(long = "tcp", env = "HOOKHUB_TCP", value_delimiter = ',', value_parser = parse_tcp_forward)]
+    tcp_forwards: Vec<(u16, String)>,
+
+    /// Connect over the experimental QUIC transport to this address of the remote (given to it with --quic-bind-addr, e.g. relay.example.com:4433) instead of the websocket. Its certificate is checked for the --remote host
+    #[arg(long, env = "HOOKHUB_QUIC")]
+    quic: Option<String>,
+
+    /// PEM file of certificates to trust for --quic besides the usual roots, e.g. the remote's self-signed one
+    #[arg(long, env = "HOOKHUB_QUIC_CA", requires = "quic")]
+    quic_ca: Option<PathBuf>,
+
+    /// Subdomain to ask for on a remote routing by subdomain (given --base-domain), so requests to it are relayed to this client alone. Otherwise the remote picks one
+    #[arg(long, env = "HOOKHUB_SUBDOMAIN", conflicts_with = "smee")]
+    subdomain: Option<String>,
+
+    /// What to call this client on the remote, shown in its logs, admin API and dashboard to tell sessions apart (e.g. mikes-laptop)
+    #[arg(long, env = "HOOKHUB_NAME")]
+    name: Option<String>,
+
+    /// Label this client key=value (e.g. team=payments) on the remote, whose filter rules can relay requests to clients by label. Can be repeated
+    #[arg(long = "label", env = "HOOKHUB_LABELS", value_delimiter = ',', value_parser = parse_label)]
+    labels: Vec<(String, String)>,
+
+    /// Register a webhook pointing at the remote with a provider on startup and remove it on shutdown: github:<owner>/<repo>, github:<org> or stripe, followed by @<path> if it's to be sent somewhere other than / on the remote (e.g. github:acme/shop@/webhooks/github). Can be repeated
+    #[arg(long = "register", env = "HOOKHUB_REGISTER", value_delimiter = ',')]
+    registrations: Vec<Registration>,
+
+    /// GitHub token --register uses, allowed to manage the repository's or organization's webhooks
+    #[arg(long, env = "HOOKHUB_GITHUB_TOKEN", hide_env_values = true)]
+    github_token: Option<String>,
+
+    /// Stripe secret key --register uses
+    #[arg(long, env = "HOOKHUB_STRIPE_KEY", hide_env_values = true)]
+    stripe_key: Option<String>,
+
+    /// GitHub API --register uses, for GitHub Enterprise Server (e.g. https://github.example.com/api/v3)
+    #[arg(
+        long,
+        env = "HOOKHUB_GITHUB_API_URL",
+        default_value = "https://api.github.com"
+    )]
+    github_api_url: Url,
+
+    /// Once connected, send a request through the remote and check it arrives and is forwarded
+    #[arg(long)]
+    selftest: bool,
+
+    /// Seconds to wait for the local origin to accept a connection
+    #[arg(long, env = "HOOKHUB_CONNECT_TIMEOUT", default_value_t = DEFAULT_CONNECT_TIMEOUT.as_secs())]
+    connect_timeout: u64,
+
+    /// Seconds the local origin may go without sending any of its response before the forward fails
+    #[arg(long, env = "HOOKHUB_FORWARD_TIMEOUT", default_value_t = DEFAULT_READ_TIMEOUT.as_secs())]
+    forward_timeout: u64,
+
+    /// Seconds to wait before reconnecting when the connection to the remote fails
+    #[arg(long, env = "HOOKHUB_RECONNECT_DELAY", default_value_t = 5)]
+    reconnect_delay: u64,
+
+    /// Double the wait after each failed reconnect, up to this many seconds
+    #[arg(long, env = "HOOKHUB_MAX_RECONNECT_DELAY")]
+    max_reconnect_delay: Option<u64>,
+
+    /// Remove requests from history once they're this many days old
+    #[arg(long, env = "HOOKHUB_HISTORY_MAX_DAYS")]
+    history_max_days: Option<u64>,
+
+    /// Keep at most this many requests in history, removing the oldest
+    #[arg(long, env = "HOOKHUB_HISTORY_MAX_ITEMS")]
+    history_max_items: Option<usize>,
+
+    /// Header to record in history and print with its value replaced by [redacted] (e.g. X-Shopify-Access-Token), can be repeated. Authorization, Proxy-Authorization, Cookie, Set-Cookie and X-Api-Key are unless --no-redact is given
+    #[arg(
+        long = "redact-header",
+        env = "HOOKHUB_REDACT_HEADERS",
+        value_delimiter = ','
+    )]
+    redact_headers: Vec<String>,
+
+    /// Dotted path to a value in JSON bodies to record in history and print as [redacted] (e.g. customer.email or items.*.card), can be repeated
+    #[arg(
+        long = "redact-json",
+        env = "HOOKHUB_REDACT_JSON",
+        value_delimiter = ','
+    )]
+    redact_json: Vec<JsonPath>,
+
+    /// Regex whose matches in paths, header values and bodies are recorded in history and printed as [redacted] (e.g. '\b\d{16}\b'), can be repeated
+    #[arg(long = "redact-pattern", env = "HOOKHUB_REDACT_PATTERNS")]
+    redact_patterns: Vec<Regex>,
+
+    /// Record and print requests exactly as received, without redacting anything
+    #[arg(long, env = "HOOKHUB_NO_REDACT")]
+    no_redact: bool,
+
+    /// Remove the Cookie header from requests before forwarding them, so real session cookies don't reach the local origin
+    #[arg(long, env = "HOOKHUB_STRIP_COOKIES")]
+    strip_cookies: bool,
+
+    /// Remove the Authorization and Proxy-Authorization headers from requests before forwarding them
+    #[arg(long, env = "HOOKHUB_STRIP_AUTH")]
+    strip_auth: bool,
+
+    /// Add X-Forwarded-For, X-Forwarded-Proto and X-Forwarded-Host to forwarded requests with where they came from, as a reverse proxy in production would
+    #[arg(long, env = "HOOKHUB_PROXY_HEADERS")]
+    proxy_headers: bool,
+
+    /// Header to remove from requests before forwarding them (e.g. X-Api-Key), can be repeated
+    #[arg(
+        long = "strip-header",
+        env = "HOOKHUB_STRIP_HEADERS",
+        value_delimiter = ','
+    )]
+    strip_headers: Vec<String>,
+
+    /// Second local target to send a percentage of requests to instead of --local (e.g. http://localhost:4000), to trial a new handler on real traffic
+    #[arg(long, env = "HOOKHUB_CANARY", requires = "local")]
+    canary: Option<Url>,
+
+    /// Percentage of requests to send to --canary
+    #[arg(long, env = "HOOKHUB_CANARY_PERCENT", default_value = "10", value_parser = canary::parse_percent)]
+    canary_percent: f64,
+
+    /// Decide where requests go by hashing this, header:<name> (e.g. header:X-Shop-Domain), so requests with the same value always go to the same target
+    #[arg(long, env = "HOOKHUB_CANARY_KEY")]
+    canary_key: Option<CanaryKey>,
+
+    /// Randomly drop, delay and duplicate forwards to test how the local origin copes, e.g. drop=5%,delay=200-800ms,dup=1%
+    #[arg(long, env = "HOOKHUB_CHAOS")]
+    chaos: Option<Chaos>,
+
+    /// Secondary target to also send every forwarded request to (e.g. http://localhost:4000), without waiting for it, comparing its statuses and response times with the local origin's
+    #[arg(long, env = "HOOKHUB_MIRROR")]
+    mirror: Option<Url>,
+
+    /// Warn when a request takes longer than this many milliseconds from reaching the server to being done with here, including forwarding it
+    #[arg(long, env = "HOOKHUB_LATENCY_BUDGET")]
+    latency_budget: Option<u64>,
+
+    /// Warn when the local origin takes longer than this many milliseconds to respond
+    #[arg(long, env = "HOOKHUB_SLOW_FORWARD")]
+    slow_forward: Option<u64>,
+
+    #[command(flatten)]
+    assertions: AssertionArgs,
+}
+
+impl ConnectArgs {
+    /// Where requests are received from, the --smee channel or the remote
+    fn origin(&self) -> &Url {
+        self.smee
+            .as_ref()
+            .or(self.remote.as_ref())
+            .expect("either --remote or --smee is required")
+    }
+
+    /// The headers --strip-cookies, --strip-auth and --strip-header remove before forwarding
+    fn stripped_headers(&self) -> Vec<String> {
+        let mut headers = self.strip_headers.clone();
+        if self.strip_cookies {
+            headers.push("cookie".to_string());
+        }
+        if self.strip_auth {
+            headers.push("authorization".to_string());
+            headers.push("proxy-authorization".to_string());
+        }
+        headers
+    }
+}
+
+#[derive(clap::Args)]
+struct AssertionArgs {
+    /// JSON file of assertions about the local origin's responses (status, time taken, body contents), checked on every forward with a summary when done
+    #[arg(long, env = "HOOKHUB_ASSERTIONS")]
+    assertions: Option<PathBuf>,
+
+    /// Exit with an error when done if any assertion failed
+    #[arg(long, env = "HOOKHUB_CI")]
+    ci: bool,
+}
+
+impl AssertionArgs {
+    fn load(&self) -> Result<()> {
+        match &self.assertions {
+            Some(path) => assertions::load(path),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum ProfileCommands {
+    /// List the profiles in config.toml, with where each relays from and to
+    List,
+    /// Test a profile by authenticating with its remote, disconnecting straight away, and probing its local origin, reporting which leg fails
+    Test {
+        /// Name of the profile
+        name: String,
+    },
+    /// Add a profile for each tunnel in an ngrok agent config, carrying over where requests are forwarded, TCP ports and whether requests are inspected
+    ImportNgrok {
+        /// The ngrok config (e.g. ~/.config/ngrok/ngrok.yml)
+        file: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum HistoryCommands {
+    /// List previously received requests, newest first
+    List {
+        /// Most requests to list
+        #[arg(long)]
+        limit: Option<usize>,
+
+        /// Skip this many of the newest requests first, e.g. to page through with --limit
+        #[arg(long, default_value_t = 0)]
+        offset: usize,
+    },
+    /// Print how many requests are in history
+    Count,
+    /// Add requests captured elsewhere to history to replay them: a HAR file from browser devtools or ngrok, requests as JSON (one, a list or a line each) or a curl command
+    Import {
+        /// File to read the requests from
+        file: String,
+    },
+    /// Print a previously received request as a fixture to commit alongside tests
+    ExportFixture {
+        /// Identifier of the request
+        id: ItemId,
+
+        /// What to print it as
+        #[arg(long, value_enum, default_value = "json")]
+        format: FixtureFormat,
+    },
+    /// Print requests as they're recorded to history, e.g. by a connect running in another terminal
+    Watch {
+        /// Print each request in full instead of a line
+        #[arg(long)]
+        full: bool,
+    },
+    /// Summarise what's in history: requests by method, path and hour, and their average body size
+    Stats {
+        /// Only requests received after this time (e.g. 2024-10-01T09:00:00Z)
+        #[arg(long)]
+        since: Option<DateTime<Utc>>,
+    },
+    /// Find previously received requests whose path, header values or body contain some text, newest first
+    Search {
+        /// Text to look for, ignoring case (e.g. an order id)
+        query: String,
+
+        /// Most requests to list
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Print a previously received request in full: headers, body and what was recorded about it
+    Show {
+        /// Identifier of the request
+        id: ItemId,
+
+        /// Print the whole body, not just the first 4096 bytes
+        #[arg(long)]
+        full: bool,
+    },
+    /// Delete a previously received request
+    Delete {
+        /// Identifier of the request
+        id: ItemId,
+    },
+    /// Clear all previously received requests
+    Clear,
+    /// Delete the requests received before a time, from recorded sessions too, and report the space reclaimed, e.g. when captured data has to be removed
+    Purge {
+        /// Delete requests received before this time (e.g. 2024-10-01T09:00:00Z)
+        #[arg(long)]
+        before: DateTime<Utc>,
+
+        /// Overwrite the files with zeros before deleting them
+        #[arg(long)]
+        shred: bool,
+    },
+    /// Replay a previously received request
+    Replay {
+        /// Identifier of the request
+        id: ItemId,
+        /// Local origin to relay requests to (e.g. https://localhost:3000/)
+        #[arg(long, env = "HOOKHUB_LOCAL")]
+        local: Url,
+        /// Rhai script to run the request through before replaying it, can be repeated
+        #[arg(long = "script", env = "HOOKHUB_SCRIPTS", value_delimiter = ',')]
+        scripts: Vec<PathBuf>,
+        /// Diff the status and body of the response with the one recorded when it was first forwarded, failing if they differ
+        #[arg(long)]
+        compare: bool,
+        #[command(flatten)]
+        assertions: AssertionArgs,
+    },
+}
+
+#[derive(Subcommand)]
+enum SessionCommands {
+    /// Copy every request recorded to history into a new session until Ctrl-C, e.g. while a connect runs in another terminal
+    Record {
+        /// Name of the session
+        name: String,
+    },
+    /// Replay a session's requests in order, waiting between them as long as they were apart when received
+    Replay {
+        /// Name of the session
+        name: String,
+        /// Local origin to relay requests to (e.g. https://localhost:3000/)
+        #[arg(long, env = "HOOKHUB_LOCAL")]
+        local: Url,
+        /// How much faster than received to replay, e.g. 2x for half the waits or 0.5x for double
+        #[arg(long, default_value = "1x", value_parser = session::parse_speed)]
+        speed: f64,
+        /// Rhai script to run the requests through before replaying them, can be repeated
+        #[arg(long = "script", env = "HOOKHUB_SCRIPTS", value_delimiter = ',')]
+        scripts: Vec<PathBuf>,
+        #[command(flatten)]
+        assertions: AssertionArgs,
+    },
+    /// Write a session out as a k6 script or vegeta targets file, to replay it at scale against staging
+    Export {
+        /// Name of the session
+        name: String,
+        /// Which tool to write it for
+        #[arg(long, value_enum)]
+        format: LoadTestFormat,
+        /// Origin to send the requests to (e.g. https://staging.example.com)
+        #[arg(long)]
+        target: Url,
+        /// Directory to write the script or targets file and the bodies into
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// List recorded sessions
+    List,
+    /// Delete a session, leaving its requests in history
+    Delete {
+        /// Name of the session
+        name: String,
+    },
+}
+
+const DEFAULT_CONTROL_ADDR: &str = "127.0.0.1:9874";
+
+/// How often requests past --history-max-days or --history-max-items are removed
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Parses the flags and environment, with defaults from config.toml
+fn parse_args() -> Result<Args> {
+    let command = config::apply(
+        Args::command(),
+        &DIRS.config.join("config.toml"),
+        config::early_arg("--profile", "HOOKHUB_PROFILE").as_deref(),
+    )?;
+
+    Ok(Args::from_arg_matches(&command.get_matches())?)
+}
+
+/// Parses a --tcp forward, `<remote port>:<host>:<port>`
+fn parse_tcp_forward(value: &str) -> Result<(u16, String), String> {
+    let error = || "expected <remote port>:<host>:<port>, e.g. 5432:localhost:5432".to_string();
+    let (port, addr) = value.split_once(':').ok_or_else(error)?;
+    let port = port.parse().map_err(|_| error())?;
+    match addr.rsplit_once(':') {
+        Some((host, local_port)) if !host.is_empty() && local_port.parse::<u16>().is_ok() => {
+            Ok((port, addr.to_string()))
+        }
+        _ => Err(error()),
+    }
+}
+
+/// Parses a --label, `<key>=<value>`
+fn parse_label(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err("expected <key>=<value>, e.g. team=payments".to_string()),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = parse_args()?;
+
+    logging::init(args.log_format);
+
+    match args.command {
+        Commands::Connect(args) => handle_connect(*args).await,
+        Commands::Status { control_addr } => handle_status(control_addr, args.output).await,
+        Commands::Pause { control_addr } => handle_pause(control_addr, true).await,
+        Commands::Resume { control_addr } => handle_pause(control_addr, false).await,
+        Commands::Reload { control_addr } => handle_reload(control_addr).await,
+        Commands::Doctor {
+            remote,
+            secret,
+            local,
+        } => doctor::handle(remote, secret, local).await,
+        Commands::Clients { remote, secret } => clients::handle(remote, secret, args.output).await,
+        Commands::ServerHistory {
+            id,
+            remote,
+            secret,
+            since,
+            limit,
+        } => server_history::handle(remote, secret, id, since, limit, args.output).await,
+        Commands::History { command } => history::handle(command, args.output).await,
+        Commands::Profiles { command } => match command {
+            ProfileCommands::List => {
+                config::list_profiles(&DIRS.config.join("config.toml"), args.output)
+            }
+            ProfileCommands::Test { name } => {
+                doctor::handle_test(&DIRS.config.join("config.toml"), &name).await
+            }
+            ProfileCommands::ImportNgrok { file } => {
+                ngrok::import(&file, &DIRS.config.join("config.toml"), args.output)
+            }
+        },
+        Commands::Session { command } => session::handle(command, args.output).await,
+        Commands::Bench {
+            remote,
+            secret,
+            rate,
+            duration,
+            body_size,
+        } => bench::handle(remote, secret, rate, duration, body_size, args.output).await,
+        Commands::Simulate {
+            kind,
+            to,
+            path,
+            signing_secret,
+            rate,
+            count,
+        } => simulate::handle(kind, to, path, signing_secret, rate, count).await,
+    }
+}
+
+async fn handle_connect(mut args: ConnectArgs) -> Result<()> {
+    if args.smee.is_some() {
+        args.remote = None;
+    } else if let Some(remote) = args.remote.as_mut() {
+        prepare_remote_url(remote)?;
+    }
+    args.assertions.load()?;
+
+    if args.record_only {
+        args.local = None;
+        args.exec = None;
+        info!("Record only, requests will not be forwarded");
+    }
+
+    if let Some(local) = args.local.as_mut() {
+        prepare_local_url(local)?;
+        info!("Local origin: {}", local);
+    }
+    if let Some(canary) = args.canary.as_mut() {
+        prepare_local_url(canary)?;
+        info!(
+            "Canary: {}% of requests to {}{}",
+            args.canary_percent,
+            canary,
+            match &args.canary_key {
+                Some(CanaryKey::Header(name)) => format!(", by {} header", name),
+                None => String::new(),
+            }
+        );
+    }
+
+    if args.chaos.is_some() {
+        warn!("Chaos mode, forwards will be dropped, delayed and duplicated at random");
+    }
+
+    if let Some(mut target) = args.mirror.clone() {
+        prepare_local_url(&mut target)?;
+        info!("Mirroring to: {}", target);
+        mirror::enable(target);
+    }
+    match &args.smee {
+        Some(channel) => info!("smee.io channel: {}", channel),
+        None => info!("Remote origin: {}", args.origin()),
+    }
+
+    latency::set_thresholds(
+        args.latency_budget.map(Duration::from_millis),
+        args.slow_forward.map(Duration::from_millis),
+    );
+
+    if args.notify {
+        notify::enable();
+    }
+
+    if let Some(url) = &args.alert_webhook {
+        alerts::enable(
+            Alerter::new(url.clone()),
+            args.origin().as_str(),
+            args.alert_after_failures,
+            Duration::from_secs(args.alert_after_disconnect * 60),
+        );
+    }
+
+    if args.history_max_days.is_some() || args.history_max_items.is_some() {
+        let max_age = args
+            .history_max_days
+            .map(|days| chrono::Duration::days(days as i64));
+        let max_items = args.history_max_items;
+
+        tokio::spawn(async move {
+            let mut interval = time::interval(PRUNE_INTERVAL);
+            loop {
+                interval.tick().await;
+                match HISTORY_DB.prune(max_age, max_items).await {
+                    Ok(0) => {}
+                    Ok(removed) => info!("Removed {} old request(s) from history", removed),
+                    Err(e) => warn!("Could not remove old requests from history: {:#}", e),
+                }
+            }
+        });
+    }
+
+    let filters = Arc::new(Filters::load(
+        &args.scripts,
+        &args.plugins,
+        args.stripped_headers(),
+        args.proxy_headers,
+    )?);
+
+    STATE.set_origins(args.origin(), args.local.as_ref());
+    control::serve(args.control_addr, filters.clone())?;
+
+    let registered = register::register_all(&mut args).await?;
+
+    let client = match (&args.smee, &args.remote, &args.secret) {
+        (None, Some(remote), Some(secret)) => {
+            let mut client = hookhub::client::Client::new(remote.clone(), secret.clone())
+                .compression(!args.no_compression);
+            if let Some(local) = args.local.as_ref().filter(|_| args.tunnel_websockets) {
+                client = client.tunnel_websockets(local.clone());
+            }
+            for (port, addr) in &args.tcp_forwards {
+                client = client.forward_tcp(*port, addr.clone());
+            }
+            if let Some(addr) = &args.quic {
+                client = client.quic(addr.clone());
+            }
+            if let Some(path) = &args.quic_ca {
+                client = client.quic_ca(path.clone());
+            }
+            if let Some(name) = &args.subdomain {
+                client = client.subdomain(name.clone());
+            }
+            if let Some(name) = &args.name {
+                client = client.name(name.clone());
+            }
+            for (key, value) in &args.labels {
+                client = client.label(key.clone(), value.clone());
+            }
+            Some(client)
+        }
+        _ => None,
+    };
+
+    let relay = Relay {
+        client,
+        dedup: args
+            .dedup
+            .clone()
+            .map(|key| Dedup::new(key, Duration::from_secs(args.dedup_window))),
+        filters,
+        supervisor: match args.command.is_empty() {
+            false => Some(Supervisor::start(args.command.clone())?),
+            true => None,
+        },
+        health: match &args.local {
+            Some(local)
+                if args.wait_local || args.health_path.is_some() || !args.command.is_empty() =>
+            {
+                Some(Health::start(local, args.health_path.as_deref())?)
+            }
+            _ => None,
+        },
+        canary: args
+            .canary
+            .clone()
+            .map(|target| Canary::new(target, args.canary_percent, args.canary_key.clone())),
+        redactor: Arc::new(match args.no_redact {
+            true => Redactor::default(),
+            false => Redactor::new(
+                &args.redact_headers,
+                &args.redact_json,
+                &args.redact_patterns,
+            ),
+        }),
+        selftest: AtomicBool::new(args.selftest),
+        args,
+    };
+
+    let (shutdown, _) = broadcast::channel::<()>(1);
+
+    let shutdown_tx = shutdown.clone();
+    let reconnect_delay = Duration::from_secs(relay.args.reconnect_delay);
+    let max_reconnect_delay = relay
+        .args
+        .max_reconnect_delay
+        .map(Duration::from_secs)
+        .unwrap_or(reconnect_delay);
+    let mut delay = reconnect_delay;
+
+    tokio::spawn(async move {
+        let mut sigint = std::pin::pin!(interrupt_signal());
+        tokio::select! {
+            _ = sigint.as_mut() => {
+                let _ = shutdown_tx.send(());
+                warn!("SIGINT received, shutting down");
+            }
+        }
+    });
+
+    loop {
+        let result = match (&relay.client, &relay.args.smee) {
+            (Some(client), _) => connect_and_run(&relay, client, shutdown.clone()).await,
+            (None, Some(channel)) => smee::run(&relay, channel, shutdown.clone()).await,
+            (None, None) => unreachable!("clap requires --remote and --secret without --smee"),
+        };
+        let was_connected = STATE.status().connected;
+        STATE.disconnected();
+
+        if let Err(e) = result {
+            STATE.error(&e);
+            if was_connected {
+                notify::disconnected(&e.to_string());
+                alerts::disconnected();
+                delay = reconnect_delay;
+            }
+            error!("Failed with error: {:?}", e);
+            error!("Trying again in {:?}...", delay);
+
+            let mut shutdown = shutdown.clone().subscribe();
+
+            tokio::select! {
+                _ = time::sleep(delay) => {
+                    delay = (delay * 2).min(max_reconnect_delay);
+                },
+                _ = shutdown.recv() => {
+                    break;
+                }
+            }
+        } else {
+            break;
+        }
+    }
+
+    register::unregister_all(&registered).await;
+    if let Some(supervisor) = relay.supervisor {
+        supervisor.stop().await;
+    }
+
+    mirror::log_report();
+    latency::log_report();
+    assertions::report(relay.args.assertions.ci)
+}
+
+/// Everything a connect session needs that outlives a single connection
+struct Relay {
+    args: ConnectArgs,
+    /// Connects to the remote, `None` when receiving from a smee.io channel instead
+    client: Option<hookhub::client::Client>,
+    dedup: Option<Dedup>,
+    filters: Arc<Filters>,
+    supervisor: Option<Supervisor>,
+    health: Option<Health>,
+    canary: Option<Canary>,
+    redactor: Arc<Redactor>,
+    /// Whether `--selftest` is still to be run once connected
+    selftest: AtomicBool,
+}
+
+async fn connect_and_run(
+    relay: &Relay,
+    client: &hookhub::client::Client,
+    shutdown: broadcast::Sender<()>,
+) -> Result<()> {
+    let args = &relay.args;
+    let remote = client.remote();
+
+    let http = http_client_with_timeouts(
+        Duration::from_secs(args.connect_timeout),
+        Duration::from_secs(args.forward_timeout),
+    )?;
+
+    let mut connection = client.connect().await?;
+
+    info!(event = "connected", remote = remote.as_str(); "Connected successfully, waiting for events");
+    STATE.connected();
+    notify::connected(remote.as_str());
+    alerts::connected();
+
+    let start = Instant::now() + Duration::from_secs(20);
+    let mut interval = interval_at(start, Duration::from_secs(20));
+
+    let mut shutdown = shutdown.subscribe();
+
+    // forwards finish in their own tasks, their reports are sent from here
+    let (reports, mut pending_reports) = mpsc::unbounded_channel();
+
+    // only on the first connection, not every reconnect
+    let mut selftest = if relay.selftest.swap(false, Ordering::Relaxed) {
+        Some(selftest::start(remote, connection.ack())?)
+    } else {
+        None
+    };
+
+    loop {
+        tokio::select! {
+            message = connection.next() => {
+                let Some(req) = message.transpose()? else {
+                    break;
+                };
+
+                if selftest.as_ref().is_some_and(|t| t.matches(&req)) {
+                    let forward = args.local.as_ref().map(|local| {
+                        forward_request(
+                            "selftest".to_string(),
+                            req,
+                            local.clone(),
+                            http.clone(),
+                            &relay.redactor,
+                        )
+                    });
+                    selftest.take().unwrap().arrived(forward);
+                    continue;
+                }
+                // another client's, relayed to every client
+                if selftest::is_selftest(&req) {
+                    debug!("Not forwarding another client's self-test {}", relay.redactor.redact_path(&req.fullpath));
+                    report_delivery(&reports, &req, Instant::now(), DeliveryOutcome::Skipped, None);
+                    continue;
+                }
+
+                handle_request(relay, req, &http, &reports).await;
+            },
+            Some(report) = pending_reports.recv() => {
+                connection.report(report).await?;
+            },
+            _ = interval.tick() => {
+                connection.ping().await?;
+            },
+            _ = shutdown.recv() => {
+                break;
+            }
+        }
+    }
+
+    info!(event = "disconnected", remote = remote.as_str(); "Disconnected");
+    connection.close().await;
+
+    Ok(())
+}
+
+async fn handle_request(
+    relay: &Relay,
+    mut req: RequestMessage,
+    http: &Client,
+    reports: &mpsc::UnboundedSender<DeliveryReport>,
+) {
+    let args = &relay.args;
+    let started = Instant::now();
+    let relayed = latency::since_received(&req);
+    // as it's recorded, for logging
+    let path = relay.redactor.redact_path(&req.fullpath);
+
+    if relay.dedup.as_ref().is_some_and(|d| d.is_duplicate(&req)) {
+        info!("Skipped duplicate delivery of {} {}", req.method, path);
+        report_delivery(reports, &req, started, DeliveryOutcome::Skipped, None);
+        return;
+    }
+
+    let mut item = history_db::Item::new(Utc::now(), relay.redactor.redacted(&req));
+
+    if !args.verify_signatures.is_empty() {
+        let result = signature::verify_any(&args.verify_signatures, &req.headers, &req.body);
+        if let Err(reason) = &result {
+            warn!("Invalid signature on {} {} ({})", req.method, path, reason);
+        }
+
+        item.signature_valid = Some(result.is_ok());
+        // the server drops any a provider sent, but older ones and smee.io don't
+        req.headers
+            .retain(|(n, _)| !n.eq_ignore_ascii_case("x-hookhub-signature-valid"));
+        req.headers.push((
+            "x-hookhub-signature-valid".to_string(),
+            result.is_ok().to_string().into(),
+        ));
+    }
+
+    if let Some(max) = args.max_history_body {
+        item.truncate_body(max);
+        if let Some(size) = item.truncated_from {
+            warn!(
+                "Body of {} {} is {} bytes, only the first {} recorded to history",
+                req.method, path, size, max
+            );
+        }
+    }
+
+    let id = match args.no_history {
+        true => item.id.clone(),
+        // still forwarded, history is a record of the request, not what delivers it
+        false => HISTORY_DB.add(&item).await.unwrap_or_else(|e| {
+            warn!(
+                "Couldn't record {} {} in history: {:#}",
+                req.method, path, e
+            );
+            item.id.clone()
+        }),
+    };
+    info!(
+        event = "request_received",
+        request_id = id.as_str(),
+        method = req.method.as_str(),
+        path = item.request.fullpath.as_str();
+        "Received request: {} {} as {}", req.method, item.request.fullpath, id
+    );
+
+    if args.stdout_jsonl {
+        tail::print_jsonl(&id, item.received_at, &relay.redactor.redacted(&req));
+    }
+
+    let delivered = if args.local.is_none() && args.exec.is_none() {
+        Err(DeliveryOutcome::Skipped)
+    } else if STATE.paused() {
+        match args.no_history {
+            true => info!("Forwarding paused, not forwarding {}", id),
+            false => info!("Forwarding paused, {} only recorded to history", id),
+        }
+        Err(DeliveryOutcome::Skipped)
+    } else {
+        relay
+            .filters
+            .apply(&id, req.clone())
+            .ok_or(DeliveryOutcome::Dropped)
+    };
+    let not_delivered = delivered.as_ref().err().copied();
+
+    if let (Some(command), Ok(delivered)) = (&args.exec, &delivered) {
+        exec::spawn(command, &id, delivered);
+    }
+
+    let mirrored = match (&args.local, &delivered) {
+        (Some(_), Ok(delivered)) => mirror::send(delivered, &path, http),
+        _ => None,
+    };
+
+    let canary = match (&relay.canary, &delivered) {
+        (Some(canary), Ok(delivered)) if canary.picks(delivered) => {
+            info!(event = "canary", request_id = id.as_str(); "Sending {} to the canary", id);
+            Some(&canary.target)
+        }
+        _ => None,
+    };
+
+    let faults = args.chaos.as_ref().map(Chaos::faults).unwrap_or_default();
+    let dropped = faults.drop && args.local.is_some() && delivered.is_ok();
+    if dropped {
+        info!(event = "chaos", request_id = id.as_str(); "Chaos: not forwarding {}", id);
+    }
+    let not_delivered = not_delivered.or(dropped.then_some(DeliveryOutcome::Dropped));
+
+    let target = canary.or(args.local.as_ref()).cloned();
+    // the health check is of --local, so the canary isn't held up by it
+    let ready = match (canary, &relay.health) {
+        (None, Some(health)) => Some(health.wait_ready()),
+        _ => None,
+    };
+
+    let forward = match (target, delivered.ok().filter(|_| !dropped)) {
+        (Some(target), Some(delivered)) => {
+            let (id, http, redactor) = (id.clone(), http.clone(), relay.redactor.clone());
+            Some(tokio::spawn(async move {
+                if let Some(ready) = ready {
+                    if !ready.await {
+                        warn!("Local target still down, not forwarding {}", id);
+                        return None;
+                    }
+                }
+                if let Some(delay) = faults.delay {
+                    info!(event = "chaos", request_id = id.as_str(); "Chaos: delaying {} by {:?}", id, delay);
+                    time::sleep(delay).await;
+                }
+
+                let duplicate = faults.duplicate.then(|| delivered.clone());
+                let response = forward_request(
+                    id.clone(),
+                    delivered,
+                    target.clone(),
+                    http.clone(),
+                    &redactor,
+                )
+                .await
+                .ok()
+                .flatten();
+
+                if let Some(duplicate) = duplicate {
+                    info!(event = "chaos", request_id = id.as_str(); "Chaos: forwarding {} again", id);
+                    forward_request(id, duplicate, target, http, &redactor);
+                }
+
+                response
+            }))
+        }
+        _ => None,
+    };
+
+    let tailed = args.tail.then(|| relay.redactor.redacted(&req));
+    let max_body = args.tail_max_body;
+    let (no_history, max_history_body) = (args.no_history, args.max_history_body);
+    let redactor = relay.redactor.clone();
+    let reports = reports.clone();
+    tokio::spawn(async move {
+        let forwarding = forward.is_some();
+        let response = match forward {
+            Some(forward) => forward.await.ok().flatten(),
+            None => None,
+        };
+        let status = response.as_ref().map(|r| r.status_code());
+        let local = response
+            .as_ref()
+            .map(|r| (r.status, Duration::from_millis(r.duration_ms)));
+
+        if let Some(mut response) = response.filter(|_| !no_history) {
+            redactor.redact_response(&mut response);
+            if let Some(max) = max_history_body {
+                response.truncate_body(max);
+            }
+            if let Err(e) = HISTORY_DB.set_response(&id, response).await {
+                warn!("Couldn't record the response to {} in history: {:#}", id, e);
+            }
+        }
+
+        let outcome = match not_delivered {
+            Some(outcome) => outcome,
+            None if !forwarding => DeliveryOutcome::Skipped,
+            None if status.is_some() => DeliveryOutcome::Forwarded,
+            None => DeliveryOutcome::Failed,
+        };
+        report_delivery(&reports, &req, started, outcome, status);
+        latency::record(
+            id.as_str(),
+            &req,
+            &path,
+            relayed,
+            local.map(|(_, elapsed)| elapsed),
+            started.elapsed(),
+        );
+
+        if let Some(tailed) = tailed {
+            tail::print(&tailed, status, max_body);
+        }
+
+        if let Some(mirrored) = mirrored {
+            mirror::compare(&id, &req, &path, local, mirrored.await.ok().flatten());
+        }
+    });
+}
+
+/// Queues a report of what became of a request for the server, if it gave the request an id
+fn report_delivery(
+    reports: &mpsc::UnboundedSender<DeliveryReport>,
+    req: &RequestMessage,
+    started: Instant,
+    outcome: DeliveryOutcome,
+    status: Option<StatusCode>,
+) {
+    if let Some(request_id) = req.delivery_id() {
+        let _ = reports.send(DeliveryReport {
+            request_id,
+            outcome,
+            status: status.map(|s| s.as_u16()),
+            duration_ms: started.elapsed().as_millis() as u64,
+        });
+    }
+}
+
+async fn handle_status(control_addr: SocketAddr, format: OutputFormat) -> Result<()> {
+    let Some(status) = control::status(control_addr).await? else {
+        info!("Not running (nothing listening on {})", control_addr);
+        return Ok(());
+    };
+
+    if let OutputFormat::Json = format {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+        return Ok(());
+    }
+
+    let uptime = Duration::from_secs(status.uptime_secs.max(0) as u64);
+
+    info!(
+        "Status: {}",
+        if status.connected {
+            "connected"
+        } else {
+            "disconnected"
+        }
+    );
+    if let Some(remote) = status.remote {
+        info!("Remote: {}", remote);
+    }
+    if let Some(local) = status.local {
+        info!("Local: {}", local);
+    }
+    if status.paused {
+        info!("Forwarding: paused");
+    }
+    info!("Uptime: {:?}", uptime);
+    info!("Requests forwarded: {}", status.forwarded);
+    info!(
+        "Last error: {}",
+        status.last_error.unwrap_or("none".to_string())
+    );
+    if let Some(report) = status.mirror {
+        info!("Mirror: {}", mirror::describe(&report));
+    }
+    if let Some(report) = status.latency {
+        for line in latency::describe(&report) {
+            info!("{}", line);
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_pause(control_addr: SocketAddr, paused: bool) -> Result<()> {
+    match control::set_paused(control_addr, paused).await? {
+        Some(_) if paused => info!("Forwarding paused"),
+        Some(_) => info!("Forwarding resumed"),
+        None => info!("Not running (nothing listening on {})", control_addr),
+    }
+
+    Ok(())
+}
+
+async fn handle_reload(control_addr: SocketAddr) -> Result<()> {
+    match control::reload(control_addr).await? {
+        Some(reloaded) => info!(
+            "Reloaded {} script(s) and {} plugin(s)",
+            reloaded.scripts, reloaded.plugins
+        ),
+        None => info!("Not running (nothing listening on {})", control_addr),
+    }
+
+    Ok(())
+}
+
+async fn interrupt_signal() {
+    tokio::signal::unix::signal(SignalKind::interrupt())
+        .expect("failed to install SIGINT handler")
+        .recv()
+        .await;
+}
+
+/// Runs a request through the scripts and then the plugins, `None` (logging why) if it
+/// shouldn't be forwarded
+fn filter_request(
+    id: &ItemId,
+    req: RequestMessage,
+    scripts: &[Script],
+    plugins: &[Plugin],
+) -> Option<RequestMessage> {
+    let result = script::apply_all(scripts, req).and_then(|req| match req {
+        Some(req) => plugin::apply_all(plugins, req),
+        None => Ok(None),
+    });
+
+    match result {
+        Ok(Some(req)) => Some(req),
+        Ok(None) => {
+            info!("{} was dropped by a filter, not forwarding it", id);
+            None
+        }
+        Err(e) => {
+            error!("Not forwarding {}: {:#}", id, e);
+            None
+        }
+    }
+}
+
+/// Replaces any `X-Forwarded-*` headers with ones saying where the request came from, when the
+/// server said. `X-Hookhub-Received-At` is already there.
+fn add_proxy_headers(req: &mut RequestMessage) {
+    let Some(source) = req.source.clone() else {
+        return;
+    };
+
+    let headers = [
+        ("x-forwarded-for", source.ip),
+        ("x-forwarded-proto", source.scheme),
+        ("x-forwarded-host", source.host),
+    ];
+    for (name, value) in headers {
+        if let Some(value) = value {
+            req.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+            req.headers.push((name.to_string(), value.into()));
+        }
+    }
+}
+
+/// Forwards a request to the local origin, resolving to its response if it was reached. Its
+/// path is logged redacted by `redactor`.
+fn forward_request(
+    id: ItemId,
+    req: RequestMessage,
+    local: Url,
+    http: Client,
+    redactor: &Redactor,
+) -> JoinHandle<Option<RecordedResponse>> {
+    let path = redactor.redact_path(&req.fullpath);
+
+    tokio::spawn(async move {
+        let start = Instant::now();
+
+        match forward(&http, &local, &req).await {
+            Ok(resp) => {
+                let elapsed = start.elapsed();
+                STATE.forwarded();
+                alerts::forwarded();
+                info!(
+                    event = "forwarded",
+                    request_id = id.as_str(),
+                    method = req.method.as_str(),
+                    path = path.as_str(),
+                    status = resp.status().as_u16(),
+                    duration_ms = elapsed.as_millis() as u64;
+                    "Forwarded request: {} {} - {:?} {:?}",
+                    req.method,
+                    path,
+                    resp.status(),
+                    elapsed,
+                );
+
+                let status = resp.status().as_u16();
+                let headers = resp
+                    .headers()
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), HeaderBytes(value.as_bytes().to_vec())))
+                    .collect();
+                let body = match resp.bytes().await {
+                    Ok(body) => body.to_vec(),
+                    Err(e) => {
+                        warn!("Couldn't read the response to {}: {}", id, e);
+                        vec![]
+                    }
+                };
+
+                let response = RecordedResponse {
+                    status,
+                    duration_ms: elapsed.as_millis() as u64,
+                    headers,
+                    body,
+                    truncated_from: None,
+                };
+                assertions::check(&id, &req, &path, Some(&response), elapsed);
+
+                Some(response)
+            }
+            Err(e) => {
+                STATE.error(&e);
+                error!(
+                    event = "forward_failed",
+                    request_id = id.as_str(),
+                    method = req.method.as_str(),
+                    path = path.as_str(),
+                    error = e.to_string().as_str();
+                    "Forwarded request error: {}", e
+                );
+                notify::forward_failed(&id, &req.method, &path, &e.to_string());
+                alerts::forward_failed(&e.to_string());
+                assertions::check(&id, &req, &path, None, start.elapsed());
+
+                None
+            }
+        }
+    })
+}
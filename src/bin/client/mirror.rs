@@ -0,0 +1,148 @@
+//! `--mirror`: forwarding every request to a secondary target as well as the local origin,
+//! without waiting for it or it affecting anything, comparing how the two respond.
+
+use std::{
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use hookhub::{client::forward, RequestMessage};
+use log::{info, warn};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tokio::{task::JoinHandle, time::Instant};
+use url::Url;
+
+/// How the mirror has compared with the local origin, reported in `client status`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MirrorReport {
+    pub target: Url,
+    /// Requests both were sent and both responded to
+    pub compared: u64,
+    pub same_status: u64,
+    pub different_status: u64,
+    /// Requests the mirror couldn't be reached for
+    pub mirror_failed: u64,
+    pub average_local_ms: u64,
+    pub average_mirror_ms: u64,
+    #[serde(skip)]
+    local_ms_total: u64,
+    #[serde(skip)]
+    mirror_ms_total: u64,
+}
+
+struct Mirror {
+    target: Url,
+    report: Mutex<MirrorReport>,
+}
+
+static MIRROR: OnceLock<Mirror> = OnceLock::new();
+
+pub fn enable(target: Url) {
+    let _ = MIRROR.set(Mirror {
+        report: Mutex::new(MirrorReport {
+            target: target.clone(),
+            compared: 0,
+            same_status: 0,
+            different_status: 0,
+            mirror_failed: 0,
+            average_local_ms: 0,
+            average_mirror_ms: 0,
+            local_ms_total: 0,
+            mirror_ms_total: 0,
+        }),
+        target,
+    });
+}
+
+/// Sends `req` to the mirror, if there is one, resolving to its status and how long it took.
+/// Its path is logged as `logged`, redacted.
+pub fn send(
+    req: &RequestMessage,
+    logged: &str,
+    http: &Client,
+) -> Option<JoinHandle<Option<(u16, Duration)>>> {
+    let mirror = MIRROR.get()?;
+    let (req, logged, http) = (req.clone(), logged.to_string(), http.clone());
+
+    Some(tokio::spawn(async move {
+        let start = Instant::now();
+
+        match forward(&http, &mirror.target, &req).await {
+            Ok(resp) => Some((resp.status().as_u16(), start.elapsed())),
+            Err(e) => {
+                warn!(
+                    "Mirroring {} {} to {} failed: {}",
+                    req.method, logged, mirror.target, e
+                );
+                None
+            }
+        }
+    }))
+}
+
+/// Compares the status the mirror responded to a request with and how long it took with the
+/// local origin's, either `None` if it wasn't reached, logging its path as `logged`, redacted
+pub fn compare(
+    id: &str,
+    req: &RequestMessage,
+    logged: &str,
+    local: Option<(u16, Duration)>,
+    mirrored: Option<(u16, Duration)>,
+) {
+    let Some(mirror) = MIRROR.get() else {
+        return;
+    };
+    let mut report = mirror.report.lock().unwrap();
+
+    let Some((status, elapsed)) = mirrored else {
+        report.mirror_failed += 1;
+        return;
+    };
+    let Some((local_status, local_elapsed)) = local else {
+        return;
+    };
+
+    report.compared += 1;
+    if status == local_status {
+        report.same_status += 1;
+    } else {
+        report.different_status += 1;
+        warn!(
+            event = "mirror_mismatch",
+            request_id = id,
+            status = local_status,
+            mirror_status = status;
+            "Mirror responded {} to {} {} ({}), the local origin {}", status, req.method, logged, id, local_status
+        );
+    }
+
+    report.local_ms_total += local_elapsed.as_millis() as u64;
+    report.mirror_ms_total += elapsed.as_millis() as u64;
+    report.average_local_ms = report.local_ms_total / report.compared;
+    report.average_mirror_ms = report.mirror_ms_total / report.compared;
+}
+
+pub fn report() -> Option<MirrorReport> {
+    Some(MIRROR.get()?.report.lock().unwrap().clone())
+}
+
+/// Logs how the mirror compared, e.g. when shutting down
+pub fn log_report() {
+    if let Some(report) = report() {
+        info!("Mirror: {}", describe(&report));
+    }
+}
+
+pub fn describe(report: &MirrorReport) -> String {
+    format!(
+        "{}, {} compared: {} same status, {} different, {} failed to mirror; {}ms average locally, {}ms mirrored",
+        report.target,
+        report.compared,
+        report.same_status,
+        report.different_status,
+        report.mirror_failed,
+        report.average_local_ms,
+        report.average_mirror_ms
+    )
+}
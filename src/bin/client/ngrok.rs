@@ -0,0 +1,405 @@
+//! Turns the tunnels in an ngrok agent config (`ngrok.yml`, versions 2 and 3) into profiles in
+//! config.toml, for teams moving over. Only what means the same here is carried over: where
+//! requests are forwarded to, forwarded TCP ports and whether requests are inspected. Hostnames,
+//! basic auth and traffic policies belong to ngrok's edge, which here is the server.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, Context, Result};
+use hookhub::output::{self, OutputFormat};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+use url::Url;
+
+/// Settings of a tunnel that make no difference here
+const IGNORED: &[&str] = &["bind_tls", "schemes", "metadata", "description"];
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct NgrokConfig {
+    tunnels: BTreeMap<String, Tunnel>,
+    endpoints: Vec<Endpoint>,
+}
+
+/// A version 2 tunnel, also accepted by version 3
+#[derive(Deserialize)]
+struct Tunnel {
+    proto: Option<String>,
+    addr: Option<Value>,
+    remote_addr: Option<String>,
+    inspect: Option<bool>,
+    #[serde(flatten)]
+    rest: BTreeMap<String, Value>,
+}
+
+/// A version 3 endpoint
+#[derive(Deserialize)]
+struct Endpoint {
+    name: Option<String>,
+    url: Option<String>,
+    upstream: Option<Upstream>,
+    #[serde(flatten)]
+    rest: BTreeMap<String, Value>,
+}
+
+#[derive(Deserialize)]
+struct Upstream {
+    url: Option<Value>,
+    #[serde(flatten)]
+    rest: BTreeMap<String, Value>,
+}
+
+/// A profile made from one tunnel, with what of it couldn't be carried over
+#[derive(Serialize)]
+struct Imported {
+    name: String,
+    settings: toml::Table,
+    skipped: Vec<String>,
+}
+
+/// Adds a profile to `config` for each tunnel in the ngrok config at `path`, leaving any
+/// profile that already exists alone
+pub fn import(path: &Path, config: &Path, format: OutputFormat) -> Result<()> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("couldn't read {}", path.display()))?;
+    let ngrok: NgrokConfig = serde_yaml::from_str(&text)
+        .with_context(|| format!("{} isn't an ngrok config", path.display()))?;
+
+    let mut imported = vec![];
+    for (name, tunnel) in ngrok.tunnels {
+        match from_tunnel(&name, tunnel) {
+            Ok(profile) => imported.push(profile),
+            Err(e) => warn!("Skipped tunnel {}: {}", name, e),
+        }
+    }
+    for (i, endpoint) in ngrok.endpoints.into_iter().enumerate() {
+        let name = endpoint
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("endpoint-{}", i + 1));
+        match from_endpoint(&name, endpoint) {
+            Ok(profile) => imported.push(profile),
+            Err(e) => warn!("Skipped endpoint {}: {}", name, e),
+        }
+    }
+    if imported.is_empty() {
+        return Err(anyhow!(
+            "no tunnels in {} could be imported",
+            path.display()
+        ));
+    }
+
+    let existing = existing_profiles(config)?;
+    let (added, clashing): (Vec<_>, Vec<_>) = imported
+        .into_iter()
+        .partition(|profile| !existing.contains_key(&profile.name));
+    for profile in &clashing {
+        warn!(
+            "Skipped {}, there's already a profile with that name",
+            profile.name
+        );
+    }
+
+    if !added.is_empty() {
+        append_profiles(config, &added)?;
+    }
+    info!(
+        "Added {} profile(s) to {}, use them with connect --profile <name>",
+        added.len(),
+        config.display()
+    );
+
+    output::print(
+        format,
+        &["PROFILE", "SETTINGS", "NOT CARRIED OVER"],
+        &added,
+        |p| {
+            vec![
+                p.name.clone(),
+                p.settings
+                    .iter()
+                    .map(|(key, value)| format!("{} = {}", key, value))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                p.skipped.join(", "),
+            ]
+        },
+    )
+}
+
+fn from_tunnel(name: &str, tunnel: Tunnel) -> Result<Imported> {
+    let addr = tunnel
+        .addr
+        .as_ref()
+        .ok_or_else(|| anyhow!("it has no addr"))?;
+    let mut profile = Imported {
+        name: name.to_string(),
+        settings: toml::Table::new(),
+        skipped: unhandled(&tunnel.rest),
+    };
+
+    match tunnel.proto.as_deref().unwrap_or("http") {
+        "http" => {
+            profile
+                .settings
+                .insert("local".into(), local_url(addr)?.to_string().into());
+        }
+        "tcp" => {
+            let remote_port = tunnel
+                .remote_addr
+                .as_deref()
+                .and_then(|addr| addr.rsplit_once(':'))
+                .and_then(|(_, port)| port.parse().ok());
+            tcp_forward(&mut profile, addr, remote_port)?;
+        }
+        proto => return Err(anyhow!("{} tunnels can't be relayed", proto)),
+    }
+
+    if tunnel.inspect == Some(false) {
+        profile.settings.insert("no-history".into(), true.into());
+    }
+
+    Ok(profile)
+}
+
+fn from_endpoint(name: &str, endpoint: Endpoint) -> Result<Imported> {
+    let upstream = endpoint
+        .upstream
+        .ok_or_else(|| anyhow!("it has no upstream"))?;
+    let addr = upstream
+        .url
+        .as_ref()
+        .ok_or_else(|| anyhow!("its upstream has no url"))?;
+    let mut profile = Imported {
+        name: name.to_string(),
+        settings: toml::Table::new(),
+        skipped: unhandled(&endpoint.rest),
+    };
+    profile.skipped.extend(
+        unhandled(&upstream.rest)
+            .into_iter()
+            .map(|key| format!("upstream.{}", key)),
+    );
+
+    let url = endpoint.url.as_deref().map(Url::parse).transpose()?;
+    match url.as_ref().map(Url::scheme) {
+        Some("tcp") => tcp_forward(&mut profile, addr, url.as_ref().and_then(Url::port))?,
+        None | Some("http" | "https") => {
+            profile
+                .settings
+                .insert("local".into(), local_url(addr)?.to_string().into());
+            // the server's address is where providers send to
+            if let Some(host) = url.as_ref().and_then(Url::host_str) {
+                profile.skipped.insert(0, format!("url ({})", host));
+            }
+        }
+        Some(scheme) => return Err(anyhow!("{} endpoints can't be relayed", scheme)),
+    }
+
+    Ok(profile)
+}
+
+/// Forwards the server's `remote_port` to `addr`, the same port as locally if ngrok was left to
+/// pick one
+fn tcp_forward(profile: &mut Imported, addr: &Value, remote_port: Option<u16>) -> Result<()> {
+    let local = local_addr(addr)?;
+    let remote_port = match remote_port {
+        Some(port) => port,
+        None => local
+            .rsplit_once(':')
+            .and_then(|(_, port)| port.parse().ok())
+            .ok_or_else(|| anyhow!("{} has no port", local))?,
+    };
+
+    profile.settings.insert(
+        "tcp".into(),
+        vec![toml::Value::from(format!("{}:{}", remote_port, local))].into(),
+    );
+    Ok(())
+}
+
+/// An ngrok `addr`, a port, `host:port` or a URL, as a local origin
+fn local_url(addr: &Value) -> Result<Url> {
+    let addr = scalar(addr)?;
+    let mut url = if addr.contains("://") {
+        Url::parse(&addr)?
+    } else if addr.contains(':') {
+        Url::parse(&format!("http://{}/", addr))?
+    } else {
+        Url::parse(&format!("http://localhost:{}/", addr))?
+    };
+
+    if url.path() != "/" {
+        warn!(
+            "Dropped path of {}, local origins are forwarded to at the root",
+            url
+        );
+    }
+    url.set_path("/");
+    Ok(url)
+}
+
+/// An ngrok `addr` as a local `host:port`
+fn local_addr(addr: &Value) -> Result<String> {
+    let addr = scalar(addr)?;
+    let addr = addr
+        .split_once("://")
+        .map(|(_, rest)| rest)
+        .unwrap_or(&addr);
+    if addr.contains(':') {
+        Ok(addr.trim_end_matches('/').to_string())
+    } else {
+        Ok(format!("localhost:{}", addr))
+    }
+}
+
+fn scalar(value: &Value) -> Result<String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Number(n) => Ok(n.to_string()),
+        _ => Err(anyhow!("addr should be a port, host:port or URL")),
+    }
+}
+
+/// The settings of a tunnel that have no equivalent here
+fn unhandled(rest: &BTreeMap<String, Value>) -> Vec<String> {
+    rest.keys()
+        .filter(|key| !IGNORED.contains(&key.as_str()))
+        .cloned()
+        .collect()
+}
+
+fn existing_profiles(config: &Path) -> Result<toml::Table> {
+    let mut settings = match fs::read_to_string(config) {
+        Ok(text) => text
+            .parse::<toml::Table>()
+            .with_context(|| format!("invalid config {}", config.display()))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => toml::Table::new(),
+        Err(e) => {
+            return Err(e).with_context(|| format!("couldn't read config {}", config.display()))
+        }
+    };
+
+    match settings.remove("profiles") {
+        Some(toml::Value::Table(profiles)) => Ok(profiles),
+        Some(_) => Err(anyhow!(
+            "profiles in {} should be a table",
+            config.display()
+        )),
+        None => Ok(toml::Table::new()),
+    }
+}
+
+/// Appends a `[profiles.<name>]` section for each profile, leaving the rest of the file as it
+/// is. The result is parsed before it's written aside and renamed over the config, so a crash or
+/// a clashing profile added meanwhile can't leave it half written or invalid.
+fn append_profiles(config: &Path, profiles: &[Imported]) -> Result<()> {
+    let mut text = match fs::read_to_string(config) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            return Err(e).with_context(|| format!("couldn't read config {}", config.display()))
+        }
+    };
+    for profile in profiles {
+        let mut section = toml::Table::new();
+        section.insert(profile.name.clone(), profile.settings.clone().into());
+        let mut wrapper = toml::Table::new();
+        wrapper.insert("profiles".into(), section.into());
+
+        text.push_str("\n# imported from ngrok");
+        if !profile.skipped.is_empty() {
+            text.push_str(&format!(", without {}", profile.skipped.join(", ")));
+        }
+        text.push('\n');
+        text.push_str(&toml::to_string(&wrapper)?);
+    }
+    text.parse::<toml::Table>().with_context(|| {
+        format!(
+            "adding the profiles would make {} invalid",
+            config.display()
+        )
+    })?;
+
+    if let Some(dir) = config.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = config.with_extension("toml.tmp");
+    fs::File::create(&tmp)
+        .and_then(|mut file| {
+            file.write_all(text.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, config))
+        .with_context(|| format!("couldn't write config {}", config.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(name: &str, text: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("hookhub-ngrok-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(&path, text).unwrap();
+
+        path
+    }
+
+    fn imported(name: &str) -> Imported {
+        let mut settings = toml::Table::new();
+        settings.insert("local".into(), "http://localhost:3000".into());
+
+        Imported {
+            name: name.to_string(),
+            settings,
+            skipped: vec!["metadata".to_string()],
+        }
+    }
+
+    #[test]
+    fn appends_profiles_keeping_the_rest_of_the_config() {
+        let original = "# mine\n[profiles.a]\nlocal = \"http://localhost:4000\"\n";
+        let path = config("append", original);
+
+        append_profiles(&path, &[imported("b")]).unwrap();
+
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with(original));
+        assert!(text.contains("# imported from ngrok, without metadata"));
+        let profiles = existing_profiles(&path).unwrap();
+        assert!(profiles.contains_key("a") && profiles.contains_key("b"));
+        assert!(!path.with_extension("toml.tmp").exists());
+    }
+
+    #[test]
+    fn creates_the_config_when_there_is_none() {
+        let path = config("create", "");
+        fs::remove_file(&path).unwrap();
+
+        append_profiles(&path, &[imported("b")]).unwrap();
+
+        assert!(existing_profiles(&path).unwrap().contains_key("b"));
+    }
+
+    #[test]
+    fn leaves_the_config_alone_when_the_result_would_be_invalid() {
+        // added since the existing profiles were checked
+        let original = "[profiles.b]\nlocal = \"http://localhost:4000\"\n";
+        let path = config("clash", original);
+
+        assert!(append_profiles(&path, &[imported("b")]).is_err());
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), original);
+        assert!(!path.with_extension("toml.tmp").exists());
+    }
+}
@@ -0,0 +1,64 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use log::debug;
+use notify_rust::Notification;
+
+/// Forward failures within this long of the last notified one are only logged, so a broken
+/// local origin doesn't bury the desktop in notifications
+const FAILURE_QUIET_PERIOD: Duration = Duration::from_secs(10);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static DISCONNECTED: AtomicBool = AtomicBool::new(false);
+static LAST_FAILURE: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Turns on desktop notifications, they're off unless --notify is passed
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn forward_failed(id: &str, method: &str, path: &str, error: &str) {
+    let mut last = LAST_FAILURE.lock().unwrap();
+    if last.is_some_and(|at| at.elapsed() < FAILURE_QUIET_PERIOD) {
+        return;
+    }
+    *last = Some(Instant::now());
+
+    send(
+        &format!("Forwarding {} {} failed", method, path),
+        &format!("{}: {}", id, error),
+    );
+}
+
+pub fn disconnected(error: &str) {
+    DISCONNECTED.store(true, Ordering::Relaxed);
+    send("Disconnected from the remote", error);
+}
+
+/// Only notifies when it follows a disconnect, the first connection isn't news
+pub fn connected(remote: &str) {
+    if DISCONNECTED.swap(false, Ordering::Relaxed) {
+        send("Reconnected to the remote", remote);
+    }
+}
+
+fn send(summary: &str, body: &str) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let mut notification = Notification::new();
+    notification.appname("hookhub").summary(summary).body(body);
+
+    // showing a notification can block on the desktop's notification service
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = notification.show() {
+            debug!("Could not show notification: {}", e);
+        }
+    });
+}
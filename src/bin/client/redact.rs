@@ -0,0 +1,246 @@
+//! Redaction of sensitive values from what's recorded to history and printed, by header name,
+//! JSON path into the body and regex, so history can be kept of payloads containing PII.
+//! Requests are still forwarded as received.
+
+use std::str::FromStr;
+
+use hookhub::{HeaderBytes, RequestMessage};
+use regex::Regex;
+use serde_json::Value;
+
+use crate::history_db::RecordedResponse;
+
+const REDACTED: &str = "[redacted]";
+
+/// Headers redacted unless --no-redact is given, on top of any --redact-header
+pub const DEFAULT_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+];
+
+/// Query parameters whose values are redacted from paths unless --no-redact is given, as
+/// providers and callers put tokens in them
+pub const DEFAULT_QUERY_PARAMS: &[&str] = &[
+    "token",
+    "access_token",
+    "api_key",
+    "apikey",
+    "key",
+    "secret",
+    "signature",
+    "sig",
+    "password",
+    "auth",
+];
+
+/// A dotted path to a value in a JSON body, `*` matching any field or array element, e.g.
+/// `customer.email` or `items.*.card`
+#[derive(Clone, Debug)]
+pub struct JsonPath(Vec<String>);
+
+impl FromStr for JsonPath {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let segments: Vec<String> = s.split('.').map(str::to_string).collect();
+        if segments.iter().any(|segment| segment.is_empty()) {
+            return Err(format!(
+                "expected a dotted path like customer.email, got {}",
+                s
+            ));
+        }
+
+        Ok(JsonPath(segments))
+    }
+}
+
+#[derive(Default)]
+pub struct Redactor {
+    headers: Vec<String>,
+    query_params: Vec<String>,
+    json_paths: Vec<JsonPath>,
+    patterns: Vec<Regex>,
+}
+
+impl Redactor {
+    pub fn new(headers: &[String], json_paths: &[JsonPath], patterns: &[Regex]) -> Self {
+        Self {
+            headers: DEFAULT_HEADERS
+                .iter()
+                .map(|name| name.to_string())
+                .chain(headers.iter().cloned())
+                .collect(),
+            query_params: DEFAULT_QUERY_PARAMS
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            json_paths: json_paths.to_vec(),
+            patterns: patterns.to_vec(),
+        }
+    }
+
+    /// A copy of `req` with the sensitive parts of its path, headers and body redacted
+    pub fn redacted(&self, req: &RequestMessage) -> RequestMessage {
+        let mut req = req.clone();
+        req.fullpath = self.redact_path(&req.fullpath);
+        self.redact_headers(&mut req.headers);
+        self.redact_body(&mut req.body);
+        req
+    }
+
+    /// `fullpath` with the values of sensitive query parameters and anything matching the
+    /// patterns redacted, for recording and logging it
+    pub fn redact_path(&self, fullpath: &str) -> String {
+        let fullpath = match fullpath.split_once('?') {
+            Some((path, query)) if !self.query_params.is_empty() => {
+                let query: Vec<String> = query
+                    .split('&')
+                    .map(|pair| match pair.split_once('=') {
+                        Some((name, _))
+                            if self
+                                .query_params
+                                .iter()
+                                .any(|n| n.eq_ignore_ascii_case(name)) =>
+                        {
+                            format!("{}={}", name, REDACTED)
+                        }
+                        _ => pair.to_string(),
+                    })
+                    .collect();
+                format!("{}?{}", path, query.join("&"))
+            }
+            _ => fullpath.to_string(),
+        };
+
+        self.redact_text(&fullpath)
+    }
+
+    /// Redacts the sensitive parts of a response's headers and body
+    pub fn redact_response(&self, response: &mut RecordedResponse) {
+        self.redact_headers(&mut response.headers);
+        self.redact_body(&mut response.body);
+    }
+
+    fn redact_headers(&self, headers: &mut [(String, HeaderBytes)]) {
+        for (name, value) in headers.iter_mut() {
+            if self.headers.iter().any(|n| n.eq_ignore_ascii_case(name)) {
+                *value = REDACTED.into();
+            } else if let Some(text) = value.to_str() {
+                *value = self.redact_text(text).into();
+            }
+        }
+    }
+
+    fn redact_body(&self, body: &mut Vec<u8>) {
+        if !self.json_paths.is_empty() {
+            if let Ok(mut json) = serde_json::from_slice::<Value>(body) {
+                let mut redacted = false;
+                for path in &self.json_paths {
+                    redacted |= redact_json(&mut json, &path.0);
+                }
+                // only re-encoded when something changed, so the body is otherwise as received
+                if redacted {
+                    *body = serde_json::to_vec(&json).unwrap();
+                }
+            }
+        }
+
+        if !self.patterns.is_empty() {
+            if let Ok(text) = std::str::from_utf8(body) {
+                *body = self.redact_text(text).into_bytes();
+            }
+        }
+    }
+
+    fn redact_text(&self, text: &str) -> String {
+        self.patterns
+            .iter()
+            .fold(text.to_string(), |text, pattern| {
+                pattern.replace_all(&text, REDACTED).into_owned()
+            })
+    }
+}
+
+/// Replaces the values at `path` in `value`, returning whether there were any
+fn redact_json(value: &mut Value, path: &[String]) -> bool {
+    let Some((segment, rest)) = path.split_first() else {
+        *value = Value::String(REDACTED.to_string());
+        return true;
+    };
+
+    let children: Vec<&mut Value> = match value {
+        Value::Object(fields) if segment == "*" => fields.values_mut().collect(),
+        Value::Object(fields) => fields.get_mut(segment).into_iter().collect(),
+        Value::Array(values) if segment == "*" => values.iter_mut().collect(),
+        Value::Array(values) => segment
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| values.get_mut(i))
+            .into_iter()
+            .collect(),
+        _ => vec![],
+    };
+
+    children
+        .into_iter()
+        .fold(false, |redacted, child| redact_json(child, rest) | redacted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redactor(patterns: &[&str]) -> Redactor {
+        let patterns: Vec<Regex> = patterns.iter().map(|p| Regex::new(p).unwrap()).collect();
+        Redactor::new(&[], &[], &patterns)
+    }
+
+    #[test]
+    fn redacts_sensitive_query_parameters() {
+        let redactor = redactor(&[]);
+
+        assert_eq!(
+            redactor.redact_path("/hooks?token=abc&page=2&API_KEY=def"),
+            "/hooks?token=[redacted]&page=2&API_KEY=[redacted]"
+        );
+        assert_eq!(redactor.redact_path("/hooks?page=2"), "/hooks?page=2");
+        assert_eq!(redactor.redact_path("/hooks/token"), "/hooks/token");
+    }
+
+    #[test]
+    fn redacts_patterns_in_the_path_and_query() {
+        let redactor = redactor(&[r"\d{16}", r"[^/?&=]+@example\.com"]);
+
+        assert_eq!(
+            redactor.redact_path("/cards/4242424242424242?email=jo@example.com"),
+            "/cards/[redacted]?email=[redacted]"
+        );
+    }
+
+    #[test]
+    fn redacts_the_path_of_requests() {
+        let req = RequestMessage {
+            method: "POST".to_string(),
+            fullpath: "/hooks?secret=abc".to_string(),
+            version: Default::default(),
+            headers: vec![],
+            body: vec![],
+            source: None,
+        };
+
+        assert_eq!(
+            redactor(&[]).redacted(&req).fullpath,
+            "/hooks?secret=[redacted]"
+        );
+    }
+
+    #[test]
+    fn no_redact_leaves_the_path_alone() {
+        let path = "/hooks?token=abc";
+
+        assert_eq!(Redactor::default().redact_path(path), path);
+    }
+}
@@ -0,0 +1,346 @@
+//! `connect --register`: creates webhooks pointing at the remote with providers on startup and
+//! removes them on shutdown, so nothing needs setting up by hand in the provider's dashboard.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use hookhub::{client::http_client, signature::Verifier};
+use log::{info, warn};
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use url::Url;
+
+use crate::{selftest::ingest_url, ConnectArgs};
+
+const STRIPE_API: &str = "https://api.stripe.com/v1";
+
+/// A webhook to register, parsed from `github:<owner>/<repo>`, `github:<org>` or `stripe`,
+/// optionally followed by `@<path>` for where on the remote it's sent
+#[derive(Clone, Debug)]
+pub struct Registration {
+    provider: Provider,
+    path: String,
+}
+
+#[derive(Clone, Debug)]
+enum Provider {
+    GitHubRepo { owner: String, repo: String },
+    GitHubOrg { org: String },
+    Stripe,
+}
+
+impl FromStr for Registration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (provider, path) = match s.split_once('@') {
+            Some((_, path)) if !path.starts_with('/') => {
+                return Err("the path after @ should start with /".to_string())
+            }
+            Some((provider, path)) => (provider, path.to_string()),
+            None => (s, "/".to_string()),
+        };
+
+        let provider =
+            match provider.split_once(':') {
+                Some(("github", target)) => match target.split_once('/') {
+                    Some((owner, repo)) if !owner.is_empty() && !repo.is_empty() => {
+                        Provider::GitHubRepo {
+                            owner: owner.to_string(),
+                            repo: repo.to_string(),
+                        }
+                    }
+                    None if !target.is_empty() => Provider::GitHubOrg {
+                        org: target.to_string(),
+                    },
+                    _ => return Err("expected github:<owner>/<repo> or github:<org>".to_string()),
+                },
+                None if provider == "stripe" => Provider::Stripe,
+                _ => return Err(
+                    "expected github:<owner>/<repo>, github:<org> or stripe, then @<path> if any"
+                        .to_string(),
+                ),
+            };
+
+        Ok(Self { provider, path })
+    }
+}
+
+impl fmt::Display for Registration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.provider {
+            Provider::GitHubRepo { owner, repo } => {
+                write!(f, "GitHub repository {}/{}", owner, repo)
+            }
+            Provider::GitHubOrg { org } => write!(f, "GitHub organization {}", org),
+            Provider::Stripe => write!(f, "Stripe"),
+        }
+    }
+}
+
+/// A webhook created on startup, to remove on shutdown
+pub struct Registered {
+    registration: Registration,
+    /// Where the provider's API has it
+    endpoint: Url,
+    token: String,
+}
+
+/// Registers each of `args.registrations`, adding the signing secret Stripe makes for its
+/// endpoint to `args.verify_signatures` unless one's given
+pub async fn register_all(args: &mut ConnectArgs) -> Result<Vec<Registered>> {
+    let Some(remote) = args.remote.clone() else {
+        return Ok(vec![]);
+    };
+    let http = http_client()?;
+
+    let mut registered = vec![];
+    for registration in args.registrations.clone() {
+        let url = ingest_url(&remote, &registration.path);
+        let result = match &registration.provider {
+            Provider::Stripe => register_stripe(&http, args, &registration, &url).await,
+            _ => register_github(&http, args, &registration, &url).await,
+        };
+
+        match result {
+            Ok(webhook) => registered.push(webhook),
+            Err(e) => {
+                // don't leave behind the ones that did work
+                unregister_all(&registered).await;
+                return Err(e.context(format!("couldn't register with {}", registration)));
+            }
+        }
+    }
+
+    Ok(registered)
+}
+
+/// Removes the webhooks `register_all` created, warning about any that couldn't be
+pub async fn unregister_all(registered: &[Registered]) {
+    let Ok(http) = http_client() else {
+        return;
+    };
+
+    for webhook in registered {
+        let request = match webhook.registration.provider {
+            Provider::Stripe => http
+                .delete(webhook.endpoint.clone())
+                .bearer_auth(&webhook.token),
+            _ => github(http.delete(webhook.endpoint.clone()), &webhook.token),
+        };
+        match request.send().await {
+            Ok(resp) if resp.status().is_success() || resp.status() == StatusCode::NOT_FOUND => {
+                info!("Removed the webhook from {}", webhook.registration);
+            }
+            Ok(resp) => warn!(
+                "Couldn't remove the webhook from {}: {}",
+                webhook.registration,
+                api_error(resp).await
+            ),
+            Err(e) => warn!(
+                "Couldn't remove the webhook from {}: {}",
+                webhook.registration, e
+            ),
+        }
+    }
+}
+
+async fn register_github(
+    http: &reqwest::Client,
+    args: &ConnectArgs,
+    registration: &Registration,
+    url: &Url,
+) -> Result<Registered> {
+    let token = args
+        .github_token
+        .clone()
+        .ok_or_else(|| anyhow!("--github-token is needed to register with GitHub"))?;
+
+    let mut hooks = args.github_api_url.clone();
+    match &registration.provider {
+        Provider::GitHubRepo { owner, repo } => hooks
+            .path_segments_mut()
+            .map_err(|_| anyhow!("invalid --github-api-url"))?
+            .pop_if_empty()
+            .extend(["repos", owner, repo, "hooks"]),
+        Provider::GitHubOrg { org } => hooks
+            .path_segments_mut()
+            .map_err(|_| anyhow!("invalid --github-api-url"))?
+            .pop_if_empty()
+            .extend(["orgs", org, "hooks"]),
+        Provider::Stripe => unreachable!(),
+    };
+
+    let mut config = json!({ "url": url, "content_type": "json" });
+    // so the signatures GitHub sends verify
+    if let Some(Verifier::GitHub { secret }) = args
+        .verify_signatures
+        .iter()
+        .find(|v| matches!(v, Verifier::GitHub { .. }))
+    {
+        config["secret"] = secret.clone().into();
+    }
+
+    let resp = github(http.post(hooks.clone()), &token)
+        .header("Content-Type", "application/json")
+        .body(
+            json!({ "name": "web", "active": true, "events": ["*"], "config": config }).to_string(),
+        )
+        .send()
+        .await?;
+
+    let id = match resp.status() {
+        status if status.is_success() => parse::<Value>(resp).await?["id"].clone(),
+        // left behind by a run that didn't get to remove it
+        StatusCode::UNPROCESSABLE_ENTITY => {
+            let listed = github(http.get(hooks.clone()), &token)
+                .query(&[("per_page", "100")])
+                .send()
+                .await?
+                .error_for_status()?;
+            let existing = parse::<Vec<Value>>(listed)
+                .await?
+                .into_iter()
+                .find(|hook| hook["config"]["url"].as_str() == Some(url.as_str()));
+            match existing {
+                Some(hook) => {
+                    info!(
+                        "{} already has a webhook for {}, taking it over",
+                        registration, url
+                    );
+                    hook["id"].clone()
+                }
+                None => return Err(anyhow!(api_error(resp).await)),
+            }
+        }
+        _ => return Err(anyhow!(api_error(resp).await)),
+    };
+
+    let id = id
+        .as_u64()
+        .ok_or_else(|| anyhow!("GitHub didn't say what the webhook's id is"))?;
+    let mut endpoint = hooks;
+    endpoint
+        .path_segments_mut()
+        .map_err(|_| anyhow!("invalid --github-api-url"))?
+        .push(&id.to_string());
+
+    info!(event = "registered", url = url.as_str(); "Registered a webhook for {} with {}", url, registration);
+    Ok(Registered {
+        registration: registration.clone(),
+        endpoint,
+        token,
+    })
+}
+
+async fn register_stripe(
+    http: &reqwest::Client,
+    args: &mut ConnectArgs,
+    registration: &Registration,
+    url: &Url,
+) -> Result<Registered> {
+    let key = args
+        .stripe_key
+        .clone()
+        .ok_or_else(|| anyhow!("--stripe-key is needed to register with Stripe"))?;
+    let endpoints = format!("{}/webhook_endpoints", STRIPE_API);
+
+    // endpoints left behind by runs that didn't get to remove them, Stripe only says what an
+    // endpoint's signing secret is when it's created so they can't be taken over
+    let existing = http
+        .get(&endpoints)
+        .bearer_auth(&key)
+        .query(&[("limit", "100")])
+        .send()
+        .await?;
+    if !existing.status().is_success() {
+        return Err(anyhow!(api_error(existing).await));
+    }
+    let stale = parse::<Value>(existing).await?["data"]
+        .as_array()
+        .map(|endpoints| {
+            endpoints
+                .iter()
+                .filter(|e| e["url"].as_str() == Some(url.as_str()))
+                .filter_map(|e| e["id"].as_str().map(str::to_string))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    for id in stale {
+        info!(
+            "Removing a webhook endpoint for {} left behind in Stripe",
+            url
+        );
+        http.delete(format!("{}/{}", endpoints, id))
+            .bearer_auth(&key)
+            .send()
+            .await?
+            .error_for_status()?;
+    }
+
+    let resp = http
+        .post(&endpoints)
+        .bearer_auth(&key)
+        .form(&[
+            ("url", url.as_str()),
+            ("enabled_events[]", "*"),
+            ("description", "hookhub connect"),
+        ])
+        .send()
+        .await?;
+    if !resp.status().is_success() {
+        return Err(anyhow!(api_error(resp).await));
+    }
+    let created = parse::<Value>(resp).await?;
+    let id = created["id"]
+        .as_str()
+        .ok_or_else(|| anyhow!("Stripe didn't say what the endpoint's id is"))?;
+
+    // so the signatures Stripe sends verify
+    if let Some(secret) = created["secret"].as_str() {
+        if !args
+            .verify_signatures
+            .iter()
+            .any(|v| matches!(v, Verifier::Stripe { .. }))
+        {
+            args.verify_signatures.push(Verifier::Stripe {
+                secret: secret.to_string(),
+            });
+        }
+    }
+
+    info!(event = "registered", url = url.as_str(); "Registered a webhook for {} with {}", url, registration);
+    Ok(Registered {
+        registration: registration.clone(),
+        endpoint: Url::parse(&format!("{}/{}", endpoints, id))?,
+        token: key,
+    })
+}
+
+/// A request to the GitHub API as it expects them
+fn github(request: RequestBuilder, token: &str) -> RequestBuilder {
+    request
+        .bearer_auth(token)
+        .header("Accept", "application/vnd.github+json")
+        .header("X-GitHub-Api-Version", "2022-11-28")
+        .header("User-Agent", concat!("hookhub/", env!("CARGO_PKG_VERSION")))
+}
+
+async fn parse<T: DeserializeOwned>(resp: Response) -> Result<T> {
+    Ok(serde_json::from_slice(&resp.bytes().await?)?)
+}
+
+/// What a provider's API said was wrong, from the error message both put in their bodies
+async fn api_error(resp: Response) -> String {
+    let status = resp.status();
+    let body = parse::<Value>(resp).await.unwrap_or_default();
+    let message = body["message"]
+        .as_str()
+        .or(body["error"]["message"].as_str());
+
+    match message {
+        Some(message) => format!("{} ({})", message, status),
+        None => format!("responded {}", status),
+    }
+}
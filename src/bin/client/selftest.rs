@@ -0,0 +1,132 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use hookhub::{
+    client::{http_client, public_url},
+    HelloAck, RequestMessage,
+};
+use log::{error, info};
+use tokio::{
+    sync::oneshot,
+    task::JoinHandle,
+    time::{timeout, Instant},
+};
+use url::Url;
+
+use crate::history_db::RecordedResponse;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a self-test's path starts with, below wherever the client's requests go
+const PREFIX: &str = "/__hookhub_selftest__/";
+
+pub struct SelfTest {
+    path: String,
+    arrived: oneshot::Sender<Arrival>,
+}
+
+struct Arrival {
+    at: Instant,
+    forward: Option<JoinHandle<Option<RecordedResponse>>>,
+}
+
+/// Sends a synthetic request to where the server said webhooks for this client go, the
+/// connection loop is expected to call `arrived` once it's relayed back
+pub fn start(remote: &Url, ack: Option<&HelloAck>) -> Result<SelfTest> {
+    let mut generator = names::Generator::default();
+    let path = format!("{}{}", PREFIX, generator.next().unwrap());
+
+    let ingest = match ack.and_then(|ack| ack.public_url.clone()) {
+        Some(mut url) => {
+            let base = url.path().trim_end_matches('/').to_string();
+            url.set_path(&format!("{}{}", base, path));
+            url
+        }
+        // servers from before it was in the handshake
+        None => {
+            let own = ack.and_then(|ack| ack.path.as_deref()).unwrap_or_default();
+            public_url(
+                remote,
+                ack.and_then(|ack| ack.host.as_deref()),
+                &format!("{}{}", own, path),
+            )
+        }
+    };
+    let http = http_client()?;
+    let (tx, rx) = oneshot::channel::<Arrival>();
+
+    tokio::spawn(async move {
+        info!("Self-test: sending request to {}", ingest);
+
+        let started = Instant::now();
+
+        if let Err(e) = http
+            .post(ingest.clone())
+            .body("hookhub selftest")
+            .send()
+            .await
+        {
+            error!(
+                "Self-test failed: couldn't send request to {}: {}",
+                ingest, e
+            );
+            return;
+        }
+
+        let arrival = match timeout(TIMEOUT, rx).await {
+            Ok(Ok(arrival)) => arrival,
+            _ => {
+                error!(
+                    "Self-test failed: request wasn't relayed back within {:?}",
+                    TIMEOUT
+                );
+                return;
+            }
+        };
+
+        let relayed = arrival.at - started;
+
+        match arrival.forward {
+            Some(forward) => match forward.await.ok().flatten() {
+                Some(response) => info!(
+                    "Self-test passed: relayed in {:?}, local responded {} after {:?}",
+                    relayed,
+                    response.status_code(),
+                    started.elapsed()
+                ),
+                None => error!(
+                    "Self-test failed: relayed in {:?} but forwarding to local failed",
+                    relayed
+                ),
+            },
+            None => info!("Self-test passed: relayed in {:?}", relayed),
+        }
+    });
+
+    Ok(SelfTest { path, arrived: tx })
+}
+
+/// The remote's public ingest endpoint for `path`, where providers send webhooks, below the
+/// sub-path it's mounted on if any
+pub fn ingest_url(remote: &Url, path: &str) -> Url {
+    public_url(remote, None, path)
+}
+
+/// Whether a request is a self-test, this client's or another's sent to every client
+pub fn is_selftest(req: &RequestMessage) -> bool {
+    req.fullpath.contains(PREFIX)
+}
+
+impl SelfTest {
+    /// Whether a request is this one, whatever prefix the server kept or took off the path
+    pub fn matches(&self, req: &RequestMessage) -> bool {
+        req.fullpath.ends_with(&self.path)
+    }
+
+    pub fn arrived(self, forward: Option<JoinHandle<Option<RecordedResponse>>>) {
+        let _ = self.arrived.send(Arrival {
+            at: Instant::now(),
+            forward,
+        });
+    }
+}
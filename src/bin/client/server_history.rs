@@ -0,0 +1,74 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use url::Url;
+
+use hookhub::{
+    output::{self, OutputFormat},
+    server::{
+        recent::{Outcome, RecentRequest},
+        request_log::LoggedRequest,
+    },
+};
+
+use crate::admin;
+
+/// Lists what the remote's request log has received, or shows one request from it in full
+pub async fn handle(
+    remote: Url,
+    secret: String,
+    id: Option<u64>,
+    since: Option<DateTime<Utc>>,
+    limit: usize,
+    format: OutputFormat,
+) -> Result<()> {
+    if let Some(id) = id {
+        let entry: LoggedRequest =
+            admin::get(remote, secret, &format!("history/{}", id), &[]).await?;
+        println!("{}", serde_json::to_string_pretty(&entry)?);
+        return Ok(());
+    }
+
+    let mut query = vec![("limit", limit.to_string())];
+    if let Some(since) = since {
+        query.push(("since", since.to_rfc3339()));
+    }
+
+    let requests: Vec<RecentRequest> = admin::get(remote, secret, "history", &query).await?;
+
+    output::print(
+        format,
+        &[
+            "ID",
+            "RECEIVED",
+            "METHOD",
+            "PATH",
+            "SIZE",
+            "OUTCOME",
+            "DELIVERIES",
+        ],
+        &requests,
+        |r| {
+            vec![
+                r.id.to_string(),
+                r.received_at.to_rfc3339(),
+                r.method.clone(),
+                r.path.clone(),
+                r.size.to_string(),
+                match r.outcome {
+                    Outcome::Relayed(clients) => format!("relayed to {}", clients),
+                    Outcome::NoClient => "no client".to_string(),
+                    Outcome::Duplicate => "duplicate".to_string(),
+                    Outcome::SampledOut => "sampled out".to_string(),
+                },
+                r.deliveries
+                    .iter()
+                    .map(|d| match d.report.status {
+                        Some(status) => format!("#{} {:?} {}", d.session, d.report.outcome, status),
+                        None => format!("#{} {:?}", d.session, d.report.outcome),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ]
+        },
+    )
+}
@@ -0,0 +1,313 @@
+//! Named sessions: requests recorded to history over a stretch of time, kept aside so they can
+//! be replayed together with the timing they arrived with.
+
+use std::{collections::HashSet, path::PathBuf, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+use hookhub::{
+    client::{http_client, prepare_local_url},
+    output::{self, OutputFormat},
+    script::Script,
+};
+use log::{info, warn};
+use serde::Serialize;
+use tokio::time::{self, Instant};
+use url::Url;
+
+use crate::{
+    assertions, filter_request, forward_request,
+    history_db::{Db, Item, ItemId, Purged},
+    loadtest::{self, LoadTestFormat},
+    redact::Redactor,
+    SessionCommands, DIRS, HISTORY_DB,
+};
+
+/// How often `record` checks history for new requests
+const RECORD_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Serialize)]
+struct SessionSummary {
+    name: String,
+    requests: usize,
+    started_at: Option<DateTime<Utc>>,
+    /// Seconds between the first and last request
+    duration: i64,
+}
+
+pub async fn handle(command: SessionCommands, format: OutputFormat) -> Result<()> {
+    match command {
+        SessionCommands::Record { name } => handle_record(name).await,
+        SessionCommands::Replay {
+            name,
+            local,
+            speed,
+            scripts,
+            assertions,
+        } => {
+            assertions.load()?;
+            handle_replay(name, local, speed, scripts).await?;
+            assertions::report(assertions.ci)
+        }
+        SessionCommands::Export {
+            name,
+            format,
+            target,
+            out,
+        } => handle_export(name, format, target, out).await,
+        SessionCommands::List => handle_list(format).await,
+        SessionCommands::Delete { name } => handle_delete(name).await,
+    }
+}
+
+/// Parses a replay speed like 2x, 0.5x or 2
+pub fn parse_speed(value: &str) -> Result<f64, String> {
+    match value.strip_suffix('x').unwrap_or(value).parse::<f64>() {
+        Ok(speed) if speed > 0.0 && speed.is_finite() => Ok(speed),
+        _ => Err("expected a positive speed like 2x or 0.5x".to_string()),
+    }
+}
+
+fn dir(name: &str) -> Result<PathBuf> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        bail!("session names can only have letters, numbers, -, _ and . in them");
+    }
+
+    Ok(DIRS.data.join("sessions").join(name))
+}
+
+/// The requests of the session called `name`, in the order they were received
+pub async fn load(name: &str) -> Result<Vec<Item>> {
+    let dir = dir(name)?;
+    if !dir.is_dir() {
+        bail!("no session named {}", name);
+    }
+
+    let mut items = Db::new(&dir)?.list().await?;
+    items.sort_by_key(|item| item.received_at);
+
+    Ok(items)
+}
+
+async fn handle_record(name: String) -> Result<()> {
+    let dir = dir(&name)?;
+    if dir.is_dir() {
+        bail!(
+            "there's already a session named {}, delete it first to record it again",
+            name
+        );
+    }
+    let session = Db::new(&dir)?;
+
+    info!(
+        "Recording requests to {} as session {}, press Ctrl-C to stop",
+        HISTORY_DB.path().display(),
+        name
+    );
+
+    let mut seen: HashSet<ItemId> = HISTORY_DB.ids().await?.into_iter().collect();
+    let mut interval = time::interval(RECORD_INTERVAL);
+    let mut recorded = 0;
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = tokio::signal::ctrl_c() => break,
+        }
+
+        let ids = HISTORY_DB.ids().await?;
+        for id in ids.iter().rev().filter(|id| !seen.contains(*id)) {
+            let Some(item) = HISTORY_DB.get(id).await? else {
+                continue;
+            };
+
+            session.add(&item).await?;
+            recorded += 1;
+            info!(
+                "Recorded {} {} ({} in session)",
+                item.request.method, item.request.fullpath, recorded
+            );
+        }
+
+        seen.extend(ids);
+    }
+
+    info!("Recorded {} requests to session {}", recorded, name);
+
+    Ok(())
+}
+
+async fn handle_replay(
+    name: String,
+    mut local: Url,
+    speed: f64,
+    scripts: Vec<PathBuf>,
+) -> Result<()> {
+    let scripts = scripts
+        .iter()
+        .map(|path| Script::load(path))
+        .collect::<Result<Vec<_>>>()?;
+
+    let items = load(&name).await?;
+    let Some(first) = items.first().map(|item| item.received_at) else {
+        return Err(anyhow!("session {} is empty", name));
+    };
+
+    prepare_local_url(&mut local)?;
+    let http = http_client()?;
+
+    info!(
+        "Replaying {} requests from session {} at {}x speed",
+        items.len(),
+        name,
+        speed
+    );
+
+    let start = Instant::now();
+    let mut forwarding = vec![];
+    for item in items {
+        let offset = (item.received_at - first).to_std().unwrap_or_default();
+        time::sleep_until(start + offset.div_f64(speed)).await;
+
+        if let Some(size) = item.truncated_from {
+            warn!(
+                "{} was {} bytes but only {} were recorded, replaying the truncated body",
+                item.id,
+                size,
+                item.request.body.len()
+            );
+        }
+
+        let Some(req) = filter_request(&item.id, item.request, &scripts, &[]) else {
+            continue;
+        };
+
+        // not waited for, so a slow response doesn't hold up the requests after it
+        forwarding.push(forward_request(
+            item.id.clone(),
+            req,
+            local.clone(),
+            http.clone(),
+            &Redactor::default(),
+        ));
+    }
+
+    let replayed = forwarding.len();
+    futures::future::join_all(forwarding).await;
+
+    info!(event = "session_replayed", session = name.as_str(); "Replayed {} requests from session {}", replayed, name);
+
+    Ok(())
+}
+
+async fn handle_export(
+    name: String,
+    format: LoadTestFormat,
+    target: Url,
+    out: PathBuf,
+) -> Result<()> {
+    let items = load(&name).await?;
+    if items.is_empty() {
+        bail!("session {} is empty", name);
+    }
+    if let Some(item) = items.iter().find(|item| item.truncated_from.is_some()) {
+        warn!(
+            "Some bodies were too large to be recorded in full (like {}'s), they're exported truncated",
+            item.id
+        );
+    }
+
+    let path = loadtest::write(&items, format, &target, &out)?;
+
+    info!("Wrote {} requests to {}", items.len(), path.display());
+
+    Ok(())
+}
+
+/// Names of the recorded sessions, in order
+fn names() -> Vec<String> {
+    let mut names = vec![];
+    if let Ok(entries) = std::fs::read_dir(DIRS.data.join("sessions")) {
+        for entry in entries.flatten() {
+            if entry.path().is_dir() {
+                names.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+    }
+    names.sort();
+
+    names
+}
+
+/// Removes the requests received before `before` from every recorded session too, as
+/// `Db::purge` does from history, as they're copies of them
+pub async fn purge(before: DateTime<Utc>, shred: bool) -> Result<Purged> {
+    let mut purged = Purged::default();
+
+    for name in names() {
+        let session = Db::new(&dir(&name)?)?.purge(before, shred).await?;
+        if session.items > 0 {
+            purged.items += session.items;
+            purged.bytes += session.bytes;
+            purged.sessions += 1;
+        }
+    }
+
+    Ok(purged)
+}
+
+async fn handle_list(format: OutputFormat) -> Result<()> {
+    let mut sessions = vec![];
+    for name in names() {
+        let items = load(&name).await?;
+        let started_at = items.first().map(|item| item.received_at);
+        let duration = match (started_at, items.last()) {
+            (Some(first), Some(last)) => (last.received_at - first).num_seconds(),
+            _ => 0,
+        };
+
+        sessions.push(SessionSummary {
+            name,
+            requests: items.len(),
+            started_at,
+            duration,
+        });
+    }
+
+    if sessions.is_empty() && !matches!(format, OutputFormat::Json) {
+        info!("No sessions have been recorded");
+        return Ok(());
+    }
+
+    output::print(
+        format,
+        &["NAME", "REQUESTS", "STARTED AT", "DURATION"],
+        &sessions,
+        |s| {
+            vec![
+                s.name.clone(),
+                s.requests.to_string(),
+                s.started_at.map(|t| t.to_rfc3339()).unwrap_or_default(),
+                format!("{}s", s.duration),
+            ]
+        },
+    )
+}
+
+async fn handle_delete(name: String) -> Result<()> {
+    let dir = dir(&name)?;
+    if !dir.is_dir() {
+        bail!("no session named {}", name);
+    }
+
+    std::fs::remove_dir_all(dir)?;
+
+    info!("Session {} deleted", name);
+
+    Ok(())
+}
@@ -0,0 +1,372 @@
+//! `simulate`: sending realistic example webhooks, signed the way the provider signs them, so
+//! handlers can be exercised without setting up the provider.
+
+use std::{fmt, str::FromStr, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use chrono::Utc;
+use hookhub::{
+    client::{forward, http_client},
+    signature::Verifier,
+    HeaderBytes, RequestMessage, Version,
+};
+use log::{info, warn};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::json;
+use tokio::time::{self, Instant};
+use url::Url;
+
+use crate::{config, DIRS};
+
+/// Header generic webhooks are signed in
+const GENERIC_SIGNATURE_HEADER: &str = "x-signature-256";
+
+/// A kind of webhook to send, named `<provider>:<event>`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WebhookKind {
+    GitHubPush,
+    StripeInvoicePaid,
+    Generic,
+}
+
+const KINDS: &[(&str, WebhookKind)] = &[
+    ("github:push", WebhookKind::GitHubPush),
+    ("stripe:invoice.paid", WebhookKind::StripeInvoicePaid),
+    ("generic", WebhookKind::Generic),
+];
+
+impl FromStr for WebhookKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        KINDS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(s))
+            .map(|(_, kind)| *kind)
+            .ok_or_else(|| {
+                let names: Vec<_> = KINDS.iter().map(|(name, _)| *name).collect();
+                format!("expected one of {}", names.join(", "))
+            })
+    }
+}
+
+impl fmt::Display for WebhookKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, _) = KINDS.iter().find(|(_, kind)| kind == self).unwrap();
+        write!(f, "{}", name)
+    }
+}
+
+/// Parses a rate like 10/s, 30/m or 100/h into the time between requests, a bare number is
+/// per second
+pub fn parse_rate(value: &str) -> Result<Duration, String> {
+    let (count, per) = match value.split_once('/') {
+        Some((count, "s")) => (count, 1),
+        Some((count, "m")) => (count, 60),
+        Some((count, "h")) => (count, 60 * 60),
+        Some(_) => return Err("expected a rate like 10/s, 30/m or 100/h".to_string()),
+        None => (value, 1),
+    };
+
+    match count.parse::<f64>() {
+        Ok(count) if count > 0.0 && count.is_finite() => {
+            Ok(Duration::from_secs(per).div_f64(count))
+        }
+        _ => Err("expected a rate like 10/s, 30/m or 100/h".to_string()),
+    }
+}
+
+pub async fn handle(
+    kind: WebhookKind,
+    to: String,
+    path: Option<String>,
+    signing_secret: Option<String>,
+    rate: Option<Duration>,
+    count: Option<u64>,
+) -> Result<()> {
+    let to = target(&to)?;
+    let fullpath = match path {
+        Some(path) => path,
+        None => match to.query() {
+            Some(query) => format!("{}?{}", to.path(), query),
+            None => to.path().to_string(),
+        },
+    };
+    let signer = signing_secret.map(|secret| match kind {
+        WebhookKind::GitHubPush => Verifier::GitHub { secret },
+        WebhookKind::StripeInvoicePaid => Verifier::Stripe { secret },
+        WebhookKind::Generic => Verifier::Hmac {
+            header: GENERIC_SIGNATURE_HEADER.to_string(),
+            secret,
+        },
+    });
+    let count = count.unwrap_or(if rate.is_some() { u64::MAX } else { 1 });
+
+    let http = http_client()?;
+    let mut next = Instant::now();
+    let mut sending = vec![];
+
+    match (rate, count) {
+        (Some(_), u64::MAX) => info!("Sending {} to {}, press Ctrl-C to stop", kind, to),
+        _ => info!("Sending {} {} to {}", count, kind, to),
+    }
+
+    for _ in 0..count {
+        tokio::select! {
+            _ = time::sleep_until(next) => {}
+            _ = tokio::signal::ctrl_c() => break,
+        }
+        next += rate.unwrap_or_default();
+
+        let mut req = example(kind, &fullpath);
+        if let Some(signer) = &signer {
+            let signature = signer.sign(&req.body);
+            req.headers
+                .push((signer.header().to_string(), signature.into()));
+        }
+
+        let (http, to) = (http.clone(), to.clone());
+        sending.push(tokio::spawn(async move {
+            let start = Instant::now();
+
+            match forward(&http, &to, &req).await {
+                Ok(resp) => {
+                    info!(
+                        "Sent {} {} {} - {:?} {:?}",
+                        kind,
+                        req.method,
+                        req.fullpath,
+                        resp.status(),
+                        start.elapsed()
+                    );
+                    resp.status().is_success()
+                }
+                Err(e) => {
+                    warn!("Sending {} {} failed: {}", kind, req.fullpath, e);
+                    false
+                }
+            }
+        }));
+    }
+
+    let results = futures::future::join_all(sending).await;
+    let failed = results
+        .iter()
+        .filter(|result| !matches!(result, Ok(true)))
+        .count();
+    info!("Sent {}, {} failed", results.len(), failed);
+
+    match failed {
+        0 => Ok(()),
+        _ => bail!("{} of {} weren't accepted", failed, results.len()),
+    }
+}
+
+/// The URL to send to from `--to`, either a URL or the name of a profile whose local origin to
+/// send to
+fn target(to: &str) -> Result<Url> {
+    if to.contains("://") {
+        return Ok(Url::parse(to)?);
+    }
+
+    let path = DIRS.config.join("config.toml");
+    let local = config::setting(&path, to, "local")?
+        .ok_or_else(|| anyhow!("profile {} in {} doesn't set local", to, path.display()))?;
+
+    Ok(Url::parse(&local)?)
+}
+
+/// A new example of `kind`, with fresh ids and timestamps, sent to `fullpath`
+fn example(kind: WebhookKind, fullpath: &str) -> RequestMessage {
+    let now = Utc::now();
+    let (headers, body) = match kind {
+        WebhookKind::GitHubPush => {
+            let (before, after) = (random_hex(20), random_hex(20));
+            let repository = json!({
+                "id": random_number(),
+                "name": "hello-world",
+                "full_name": "octocat/hello-world",
+                "private": false,
+                "owner": { "login": "octocat", "id": 583231, "type": "User" },
+                "html_url": "https://github.com/octocat/hello-world",
+                "default_branch": "main",
+            });
+            let commit = json!({
+                "id": after,
+                "tree_id": random_hex(20),
+                "distinct": true,
+                "message": "Update README.md",
+                "timestamp": now.to_rfc3339(),
+                "url": format!("https://github.com/octocat/hello-world/commit/{}", after),
+                "author": { "name": "The Octocat", "email": "octocat@github.com", "username": "octocat" },
+                "committer": { "name": "GitHub", "email": "noreply@github.com", "username": "web-flow" },
+                "added": [],
+                "removed": [],
+                "modified": ["README.md"],
+            });
+
+            (
+                vec![
+                    (
+                        "User-Agent",
+                        format!("GitHub-Hookshot/{}", &random_hex(4)[..7]),
+                    ),
+                    ("X-GitHub-Event", "push".to_string()),
+                    ("X-GitHub-Delivery", random_uuid()),
+                    ("X-GitHub-Hook-ID", random_number().to_string()),
+                    (
+                        "X-GitHub-Hook-Installation-Target-Type",
+                        "repository".to_string(),
+                    ),
+                    (
+                        "X-GitHub-Hook-Installation-Target-ID",
+                        repository["id"].to_string(),
+                    ),
+                    ("Content-Type", "application/json".to_string()),
+                ],
+                json!({
+                    "ref": "refs/heads/main",
+                    "before": before,
+                    "after": after,
+                    "created": false,
+                    "deleted": false,
+                    "forced": false,
+                    "compare": format!("https://github.com/octocat/hello-world/compare/{}...{}", &before[..12], &after[..12]),
+                    "commits": [commit],
+                    "head_commit": commit,
+                    "repository": repository,
+                    "pusher": { "name": "octocat", "email": "octocat@github.com" },
+                    "sender": { "login": "octocat", "id": 583231, "type": "User" },
+                }),
+            )
+        }
+        WebhookKind::StripeInvoicePaid => {
+            let (customer, subscription) = (random_id("cus", 14), random_id("sub", 24));
+
+            (
+                vec![
+                    (
+                        "User-Agent",
+                        "Stripe/1.0 (+https://stripe.com/docs/webhooks)".to_string(),
+                    ),
+                    (
+                        "Content-Type",
+                        "application/json; charset=utf-8".to_string(),
+                    ),
+                ],
+                json!({
+                    "id": random_id("evt", 24),
+                    "object": "event",
+                    "api_version": "2024-06-20",
+                    "created": now.timestamp(),
+                    "type": "invoice.paid",
+                    "livemode": false,
+                    "pending_webhooks": 1,
+                    "request": { "id": null, "idempotency_key": null },
+                    "data": {
+                        "object": {
+                            "id": random_id("in", 24),
+                            "object": "invoice",
+                            "customer": customer,
+                            "customer_email": "jenny.rosen@example.com",
+                            "subscription": subscription,
+                            "billing_reason": "subscription_cycle",
+                            "collection_method": "charge_automatically",
+                            "currency": "usd",
+                            "amount_due": 2000,
+                            "amount_paid": 2000,
+                            "amount_remaining": 0,
+                            "paid": true,
+                            "status": "paid",
+                            "created": now.timestamp(),
+                            "period_start": now.timestamp() - 30 * 24 * 60 * 60,
+                            "period_end": now.timestamp(),
+                            "lines": {
+                                "object": "list",
+                                "data": [{
+                                    "id": random_id("il", 24),
+                                    "object": "line_item",
+                                    "amount": 2000,
+                                    "currency": "usd",
+                                    "description": "1 × Pro (at $20.00 / month)",
+                                    "quantity": 1,
+                                    "subscription": subscription,
+                                }],
+                                "has_more": false,
+                            },
+                        },
+                    },
+                }),
+            )
+        }
+        WebhookKind::Generic => (
+            vec![
+                ("User-Agent", "hookhub-simulate".to_string()),
+                ("Content-Type", "application/json".to_string()),
+            ],
+            json!({
+                "id": random_uuid(),
+                "event": "example.created",
+                "created_at": now.to_rfc3339(),
+                "data": { "id": random_number(), "name": "Example", "status": "active" },
+            }),
+        ),
+    };
+
+    RequestMessage {
+        method: "POST".to_string(),
+        fullpath: fullpath.to_string(),
+        version: Version::Http11,
+        headers: headers
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), HeaderBytes::from(value)))
+            .collect(),
+        body: serde_json::to_vec_pretty(&body).unwrap(),
+        source: None,
+    }
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len];
+    SystemRandom::new().fill(&mut bytes).unwrap();
+    bytes
+}
+
+fn random_hex(len: usize) -> String {
+    random_bytes(len)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn random_number() -> u64 {
+    u64::from_be_bytes(random_bytes(8).try_into().unwrap()) % 1_000_000_000
+}
+
+/// A Stripe style id like `evt_1PqXyZ...`
+fn random_id(prefix: &str, len: usize) -> String {
+    const ALPHABET: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+    let id: String = random_bytes(len)
+        .iter()
+        .map(|b| ALPHABET[*b as usize % ALPHABET.len()] as char)
+        .collect();
+    format!("{}_{}", prefix, id)
+}
+
+fn random_uuid() -> String {
+    let mut bytes = random_bytes(16);
+    // version 4, variant 1
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
@@ -0,0 +1,182 @@
+//! Receives from a smee.io channel instead of a hookhub server, for trying the history and
+//! replay tooling without running one. The channel is read as server-sent events, each message
+//! an object with the request's headers as keys alongside `body` (parsed as JSON by smee.io),
+//! `query` and `timestamp`.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use hookhub::{client::http_client_with_timeouts, HeaderBytes, RequestMessage, Version};
+use log::{info, warn};
+use serde_json::{Map, Value};
+use tokio::{
+    sync::{broadcast, mpsc},
+    time,
+};
+use url::{form_urlencoded, Url};
+
+use crate::{alerts, handle_request, notify, Relay, STATE};
+
+/// Reconnects once nothing, not even one of smee.io's pings, has arrived for this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Keys of a message that aren't headers of the request
+const NOT_HEADERS: &[&str] = &["body", "query", "timestamp"];
+
+/// Headers smee.io saw that don't apply once the body's been parsed and the request's sent on
+const DROPPED_HEADERS: &[&str] = &["host", "content-length"];
+
+/// Forwards requests from the channel until it ends or we shut down
+pub async fn run(relay: &Relay, channel: &Url, shutdown: broadcast::Sender<()>) -> Result<()> {
+    let args = &relay.args;
+
+    let http = http_client_with_timeouts(
+        Duration::from_secs(args.connect_timeout),
+        Duration::from_secs(args.forward_timeout),
+    )?;
+
+    let mut response = reqwest::Client::new()
+        .get(channel.clone())
+        .header(reqwest::header::ACCEPT, "text/event-stream")
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(anyhow!("smee.io channel responded {}", response.status()));
+    }
+
+    info!(event = "connected", remote = channel.as_str(); "Connected to smee.io channel, waiting for events");
+    STATE.connected();
+    notify::connected(channel.as_str());
+    alerts::connected();
+
+    let mut shutdown = shutdown.subscribe();
+    let mut events = Events::default();
+
+    // smee.io doesn't take delivery reports, and its requests have no id to report on
+    let (reports, _pending_reports) = mpsc::unbounded_channel();
+
+    loop {
+        tokio::select! {
+            chunk = time::timeout(IDLE_TIMEOUT, response.chunk()) => {
+                let chunk = match chunk {
+                    Ok(Ok(Some(chunk))) => chunk,
+                    Ok(Ok(None)) => return Err(anyhow!("smee.io closed the channel")),
+                    Ok(Err(e)) => return Err(e.into()),
+                    Err(_) => return Err(anyhow!("nothing from smee.io for {:?}", IDLE_TIMEOUT)),
+                };
+
+                for (event, data) in events.feed(&chunk) {
+                    if event != "message" {
+                        continue;
+                    }
+                    match request(&data, &args.smee_path) {
+                        Ok(req) => handle_request(relay, req, &http, &reports).await,
+                        Err(e) => warn!("Couldn't read a request from smee.io: {}", e),
+                    }
+                }
+            },
+            _ = shutdown.recv() => {
+                break;
+            }
+        }
+    }
+
+    info!(event = "disconnected", remote = channel.as_str(); "Disconnected");
+
+    Ok(())
+}
+
+/// Splits a stream of server-sent events into their names and data
+#[derive(Default)]
+struct Events {
+    buffer: Vec<u8>,
+    event: String,
+    data: Vec<String>,
+}
+
+impl Events {
+    /// The events `chunk` completes, keeping any partial one for the next
+    fn feed(&mut self, chunk: &[u8]) -> Vec<(String, String)> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut complete = vec![];
+        while let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\r', '\n']);
+
+            if line.is_empty() {
+                let event = std::mem::take(&mut self.event);
+                let data = std::mem::take(&mut self.data);
+                if !data.is_empty() {
+                    let event = if event.is_empty() {
+                        "message".to_string()
+                    } else {
+                        event
+                    };
+                    complete.push((event, data.join("\n")));
+                }
+                continue;
+            }
+
+            let (field, value) = line.split_once(':').unwrap_or((line, ""));
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            match field {
+                "event" => self.event = value.to_string(),
+                "data" => self.data.push(value.to_string()),
+                // comments, ids and retry times
+                _ => {}
+            }
+        }
+
+        complete
+    }
+}
+
+/// The request a smee.io message describes, sent to `path` on the local origin as smee.io
+/// doesn't keep the path it was sent to
+fn request(data: &str, path: &str) -> Result<RequestMessage> {
+    let message: Map<String, Value> = serde_json::from_str(data)?;
+
+    let mut fullpath = path.to_string();
+    if let Some(Value::Object(query)) = message.get("query") {
+        if !query.is_empty() {
+            let mut serializer = form_urlencoded::Serializer::new(String::new());
+            for (name, value) in query {
+                serializer.append_pair(name, &text(value));
+            }
+            fullpath.push(if fullpath.contains('?') { '&' } else { '?' });
+            fullpath.push_str(&serializer.finish());
+        }
+    }
+
+    let headers = message
+        .iter()
+        .filter(|(name, _)| {
+            !NOT_HEADERS.contains(&name.as_str()) && !DROPPED_HEADERS.contains(&name.as_str())
+        })
+        .map(|(name, value)| (name.to_lowercase(), HeaderBytes::from(text(value))))
+        .collect();
+
+    let body = match message.get("body") {
+        None | Some(Value::Null) => vec![],
+        Some(body) => serde_json::to_vec(body)?,
+    };
+
+    Ok(RequestMessage {
+        method: "POST".to_string(),
+        fullpath,
+        version: Version::Http11,
+        headers,
+        body,
+        source: None,
+    })
+}
+
+/// A value as it'd appear in a header or query string
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
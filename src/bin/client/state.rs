@@ -0,0 +1,100 @@
+use std::{fmt::Display, sync::Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{
+    latency::{self, LatencyReport},
+    mirror::{self, MirrorReport},
+};
+
+/// Live state of a running `connect` process, reported over the control API
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Status {
+    pub connected: bool,
+    pub paused: bool,
+    pub remote: Option<Url>,
+    pub local: Option<Url>,
+    pub started_at: DateTime<Utc>,
+    pub connected_at: Option<DateTime<Utc>>,
+    pub uptime_secs: i64,
+    pub forwarded: u64,
+    pub last_error: Option<String>,
+    /// How --mirror has compared with the local origin
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mirror: Option<MirrorReport>,
+    /// Latency percentiles of recent requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency: Option<LatencyReport>,
+}
+
+pub struct State {
+    status: Mutex<Status>,
+}
+
+impl State {
+    pub fn new() -> Self {
+        Self {
+            status: Mutex::new(Status {
+                connected: false,
+                paused: false,
+                remote: None,
+                local: None,
+                started_at: Utc::now(),
+                connected_at: None,
+                uptime_secs: 0,
+                forwarded: 0,
+                last_error: None,
+                mirror: None,
+                latency: None,
+            }),
+        }
+    }
+
+    pub fn set_origins(&self, remote: &Url, local: Option<&Url>) {
+        let mut status = self.status.lock().unwrap();
+        status.remote = Some(remote.clone());
+        status.local = local.cloned();
+    }
+
+    pub fn connected(&self) {
+        let mut status = self.status.lock().unwrap();
+        status.connected = true;
+        status.connected_at = Some(Utc::now());
+    }
+
+    pub fn disconnected(&self) {
+        let mut status = self.status.lock().unwrap();
+        status.connected = false;
+        status.connected_at = None;
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.status.lock().unwrap().paused = paused;
+    }
+
+    pub fn paused(&self) -> bool {
+        self.status.lock().unwrap().paused
+    }
+
+    pub fn forwarded(&self) {
+        self.status.lock().unwrap().forwarded += 1;
+    }
+
+    pub fn error(&self, error: impl Display) {
+        self.status.lock().unwrap().last_error = Some(error.to_string());
+    }
+
+    pub fn local(&self) -> Option<Url> {
+        self.status.lock().unwrap().local.clone()
+    }
+
+    pub fn status(&self) -> Status {
+        let mut status = self.status.lock().unwrap().clone();
+        status.uptime_secs = (Utc::now() - status.started_at).num_seconds();
+        status.mirror = mirror::report();
+        status.latency = latency::report();
+        status
+    }
+}
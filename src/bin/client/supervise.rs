@@ -0,0 +1,107 @@
+use std::{
+    process::{ExitStatus, Stdio},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use tokio::{
+    process::{Child, Command},
+    sync::watch,
+    task::JoinHandle,
+    time,
+};
+
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Runs the local dev server as a child process, restarting it when it exits
+pub struct Supervisor {
+    stop: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl Supervisor {
+    pub fn start(command: Vec<String>) -> Result<Self> {
+        let mut child = spawn(&command)?;
+        info!("Started `{}`", command.join(" "));
+
+        let (stop, mut stopping) = watch::channel(false);
+
+        let task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    status = child.wait() => {
+                        warn!("`{}` exited with {}, restarting it", command.join(" "), describe(status));
+
+                        time::sleep(RESTART_DELAY).await;
+                        match spawn(&command) {
+                            Ok(restarted) => child = restarted,
+                            Err(e) => {
+                                warn!("Could not restart `{}`: {:#}", command.join(" "), e);
+                                return;
+                            }
+                        }
+                    }
+                    _ = stopping.changed() => {
+                        terminate(&mut child).await;
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(Self { stop, task })
+    }
+
+    /// Stops the child and everything it started
+    pub async fn stop(self) {
+        let _ = self.stop.send(true);
+        let _ = self.task.await;
+    }
+}
+
+fn spawn(command: &[String]) -> Result<Child> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| anyhow!("no command to run"))?;
+
+    // in its own process group so npm and the like can be stopped along with their children,
+    // which also keeps it from reading the terminal
+    let child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .process_group(0)
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| anyhow!("could not start `{}`: {}", command.join(" "), e))?;
+
+    Ok(child)
+}
+
+/// Asks the child's process group to terminate, killing it if it takes too long
+async fn terminate(child: &mut Child) {
+    let Some(pid) = child.id() else {
+        return;
+    };
+
+    info!("Stopping the local dev server");
+    unsafe {
+        libc::kill(-(pid as i32), libc::SIGTERM);
+    }
+
+    if time::timeout(STOP_TIMEOUT, child.wait()).await.is_err() {
+        warn!("Local dev server didn't stop in time, killing it");
+        unsafe {
+            libc::kill(-(pid as i32), libc::SIGKILL);
+        }
+        let _ = child.wait().await;
+    }
+}
+
+fn describe(status: std::io::Result<ExitStatus>) -> String {
+    match status {
+        Ok(status) => status.to_string(),
+        Err(e) => e.to_string(),
+    }
+}
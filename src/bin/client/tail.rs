@@ -0,0 +1,244 @@
+use std::io::{self, IsTerminal};
+
+use chrono::{DateTime, Utc};
+use hookhub::{JsonRequest, RequestMessage, RequestSource};
+use reqwest::StatusCode;
+use serde::Serialize;
+
+use crate::{
+    compare::{Comparison, DiffLine},
+    history_db::{Item, ItemId},
+};
+
+/// Unchanged lines shown around each change in a body diff
+const DIFF_CONTEXT: usize = 3;
+
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const CYAN: &str = "\x1b[36m";
+const RESET: &str = "\x1b[0m";
+
+fn paint(style: &str, text: &str) -> String {
+    if io::stdout().is_terminal() {
+        format!("{}{}{}", style, text, RESET)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Prints a request httpie-style: request line, headers and body (pretty-printed if it's JSON)
+pub fn print(req: &RequestMessage, status: Option<StatusCode>, max_body: usize) {
+    let outcome = match status {
+        Some(status) if status.is_success() => paint(GREEN, &status.to_string()),
+        Some(status) if status.is_server_error() => paint(RED, &status.to_string()),
+        Some(status) => paint(YELLOW, &status.to_string()),
+        None => paint(DIM, "not forwarded"),
+    };
+
+    print_message(req, &format!(" -> {}", outcome), Some(max_body));
+}
+
+/// Prints how a replayed request's response compares with the recorded one, the changed lines
+/// of the body with a few around them
+pub fn print_comparison(comparison: &Comparison) {
+    let status = format!(
+        "{} (recorded {})",
+        comparison.status, comparison.recorded_status
+    );
+    match comparison.status == comparison.recorded_status {
+        true => println!("{} {}", paint(DIM, "Status:"), paint(GREEN, &status)),
+        false => println!("{} {}", paint(DIM, "Status:"), paint(RED, &status)),
+    }
+
+    let Some(diff) = &comparison.body_diff else {
+        println!("{} {}", paint(DIM, "Body:  "), paint(GREEN, "same"));
+        return;
+    };
+    println!("{} {}", paint(DIM, "Body:  "), paint(RED, "differs"));
+
+    let near_change = |i: usize| {
+        diff[i.saturating_sub(DIFF_CONTEXT)..(i + DIFF_CONTEXT + 1).min(diff.len())]
+            .iter()
+            .any(|line| !matches!(line, DiffLine::Same(_)))
+    };
+    let mut skipped = false;
+    for (i, line) in diff.iter().enumerate() {
+        if !near_change(i) {
+            skipped = true;
+            continue;
+        }
+        if std::mem::take(&mut skipped) {
+            println!("{}", paint(DIM, "  ..."));
+        }
+
+        match line {
+            DiffLine::Same(line) => println!("  {}", line),
+            DiffLine::Removed(line) => println!("{}", paint(RED, &format!("- {}", line))),
+            DiffLine::Added(line) => println!("{}", paint(GREEN, &format!("+ {}", line))),
+        }
+    }
+    if skipped {
+        println!("{}", paint(DIM, "  ..."));
+    }
+}
+
+/// Prints a request from history in full, with what's known about it, and its body up to
+/// `max_body` bytes
+pub fn show(item: &Item, max_body: Option<usize>) {
+    let field = |name: &str, value: &str| println!("{} {}", paint(DIM, name), value);
+
+    field("ID:         ", &item.id);
+    field("Received at:", &item.received_at.to_rfc3339());
+    if let Some(source) = &item.request.source {
+        field("Source:     ", &describe_source(source));
+    }
+    if let Some(valid) = item.signature_valid {
+        field(
+            "Signature:  ",
+            &match valid {
+                true => paint(GREEN, "valid"),
+                false => paint(RED, "invalid"),
+            },
+        );
+    }
+    if let Some(size) = item.truncated_from {
+        field(
+            "Body:       ",
+            &paint(
+                YELLOW,
+                &format!(
+                    "only recorded the first {} of {} bytes",
+                    item.request.body.len(),
+                    size
+                ),
+            ),
+        );
+    }
+    if let Some(response) = &item.response {
+        field(
+            "Response:   ",
+            &format!(
+                "{} ({} bytes)",
+                response.status_code(),
+                response.truncated_from.unwrap_or(response.body.len())
+            ),
+        );
+    }
+    println!();
+
+    print_message(&item.request, "", max_body);
+}
+
+/// e.g. `203.0.113.7 via 10.0.0.2, https to hooks.example.com`
+fn describe_source(source: &RequestSource) -> String {
+    let mut text = source.ip.clone().unwrap_or("unknown address".to_string());
+    if let Some(peer) = source
+        .peer
+        .as_ref()
+        .filter(|peer| source.ip.as_ref() != Some(*peer))
+    {
+        text.push_str(&format!(" via {}", peer));
+    }
+    if let Some(scheme) = &source.scheme {
+        text.push_str(&format!(", {}", scheme));
+    }
+    if let Some(host) = &source.host {
+        text.push_str(&format!(" to {}", host));
+    }
+    text
+}
+
+fn print_message(req: &RequestMessage, suffix: &str, max_body: Option<usize>) {
+    let version = http::Version::from(req.version);
+
+    println!(
+        "{} {} {}{}",
+        paint(&format!("{}{}", BOLD, CYAN), &req.method),
+        paint(BOLD, &req.fullpath),
+        paint(DIM, &format!("{:?}", version)),
+        suffix
+    );
+
+    for (name, value) in req.headers.iter() {
+        println!("{}: {}", paint(CYAN, name), value.to_str_lossy());
+    }
+
+    if !req.body.is_empty() {
+        println!();
+        println!("{}", body(&req.body, max_body.unwrap_or(usize::MAX)));
+    }
+
+    println!();
+}
+
+/// The body pretty-printed if it's JSON, as is if it's text and hexdumped otherwise, cut
+/// down to `max_body` bytes
+fn body(body: &[u8], max_body: usize) -> String {
+    let mut text = match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(json) => serde_json::to_string_pretty(&json).unwrap(),
+        Err(_) => match std::str::from_utf8(body) {
+            Ok(text) => text.to_string(),
+            Err(_) => {
+                let mut dump = hexdump(&body[..body.len().min(max_body)]);
+                if body.len() > max_body {
+                    dump.push_str(&format!("\n... ({} bytes total)", body.len()));
+                }
+                return dump;
+            }
+        },
+    };
+
+    if text.len() > max_body {
+        let mut end = max_body;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+        text.push_str(&format!("\n... ({} bytes total)", body.len()));
+    }
+
+    text
+}
+
+/// Offset, bytes in hex and printable ASCII, 16 bytes a line like `hexdump -C`
+fn hexdump(bytes: &[u8]) -> String {
+    bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            let ascii: String = chunk
+                .iter()
+                .map(|&b| match b.is_ascii_graphic() || b == b' ' {
+                    true => b as char,
+                    false => '.',
+                })
+                .collect();
+
+            format!("{:08x}  {:<47}  |{}|", i * 16, hex.join(" "), ascii)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    id: &'a ItemId,
+    received_at: DateTime<Utc>,
+    #[serde(flatten)]
+    request: JsonRequest,
+}
+
+/// Prints a request as a single line of JSON, for piping into jq or another process
+pub fn print_jsonl(id: &ItemId, received_at: DateTime<Utc>, req: &RequestMessage) {
+    let line = JsonLine {
+        id,
+        received_at,
+        request: JsonRequest::from(req),
+    };
+
+    println!("{}", serde_json::to_string(&line).unwrap());
+}
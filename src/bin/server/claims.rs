@@ -0,0 +1,51 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use clap::Subcommand;
+use hookhub::{
+    output::{self, OutputFormat},
+    server::claims::ClaimStore,
+};
+use log::info;
+
+#[derive(Subcommand)]
+pub enum ClaimCommands {
+    /// Lists the path and subdomain kept for each client
+    List,
+    /// Releases what's kept for a client, so it's given a new path and subdomain next time it
+    /// connects and others can have them
+    Release {
+        /// The client's credential (e.g. "token alice"), subdomain or path
+        name: String,
+    },
+}
+
+pub fn handle(command: &ClaimCommands, path: &Path) -> Result<()> {
+    let store = ClaimStore::new(path);
+
+    match command {
+        ClaimCommands::List => {
+            output::print(
+                OutputFormat::Table,
+                &["CREDENTIAL", "SUBDOMAIN", "PATH", "CLAIMED AT"],
+                &store.list()?,
+                |claim| {
+                    vec![
+                        claim.credential.clone(),
+                        claim.subdomain.clone().unwrap_or_default(),
+                        claim.path.clone().unwrap_or_default(),
+                        claim.claimed_at.to_rfc3339(),
+                    ]
+                },
+            )?;
+        }
+        ClaimCommands::Release { name } => {
+            if !store.release(name)? {
+                return Err(anyhow!("nothing is kept for {}", name));
+            }
+            info!("Released what was kept for {}", name);
+        }
+    }
+
+    Ok(())
+}
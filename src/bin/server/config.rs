@@ -0,0 +1,285 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use clap::{parser::ValueSource, ArgMatches};
+use serde::Deserialize;
+use url::Url;
+
+use crate::{parse_bus, parse_cidr, parse_ports, parse_rate, Args};
+
+/// The `--config` file, see "Configuration file" in the README for the schema. Every setting can
+/// be left out, flags and environment variables take precedence over it.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    bind_addr: Option<Vec<String>>,
+    no_client_status: Option<u16>,
+    slack_url_verification: Option<bool>,
+    dedup: Option<String>,
+    dedup_window: Option<u64>,
+    max_body_size: Option<usize>,
+    session_rate_limit: Option<f64>,
+    session_bandwidth_limit: Option<f64>,
+    session_throttle: Option<String>,
+    queue_capacity: Option<usize>,
+    queue_overflow: Option<String>,
+    exclusive: Option<String>,
+    ingest_prefix: Option<String>,
+    client_paths: Option<bool>,
+    base_domain: Option<String>,
+    reserved_subdomains: Option<Vec<String>>,
+    claims_file: Option<PathBuf>,
+    public_url: Option<Url>,
+    tcp_ports: Option<Vec<String>>,
+    quic_bind_addr: Option<String>,
+    quic_cert: Option<PathBuf>,
+    quic_key: Option<PathBuf>,
+    compression_threshold: Option<usize>,
+    stats_interval: Option<u64>,
+    alert_webhook: Option<Url>,
+    request_log: Option<PathBuf>,
+    request_log_size: Option<usize>,
+    bus: Option<String>,
+    bus_subject: Option<String>,
+    bus_stream: Option<String>,
+    bus_consumer: Option<String>,
+    shutdown_timeout: Option<u64>,
+    drain_delay: Option<u64>,
+    auth: Auth,
+    rate_limit: RateLimit,
+    filters: Filters,
+    responses: Responses,
+}
+
+/// Reloaded on SIGHUP
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Auth {
+    secrets: Option<Vec<String>>,
+    admin_secrets: Option<Vec<String>>,
+    tokens_file: Option<PathBuf>,
+}
+
+/// Reloaded on SIGHUP
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct RateLimit {
+    rate: Option<f64>,
+    burst: Option<f64>,
+    ip_rate: Option<f64>,
+    ip_burst: Option<f64>,
+}
+
+/// Reloaded on SIGHUP
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Filters {
+    allow_cidrs: Option<Vec<String>>,
+    trusted_proxies: Option<Vec<String>>,
+    verify_signatures: Option<Vec<String>>,
+    deny_headers: Option<Vec<String>>,
+    allow_headers: Option<Vec<String>>,
+    preserve_host: Option<bool>,
+    rules: Option<PathBuf>,
+    sample_rate: Option<f64>,
+}
+
+/// Reloaded on SIGHUP
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Responses {
+    status: Option<u16>,
+    headers: Option<BTreeMap<String, String>>,
+    body: Option<String>,
+    rules: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("couldn't read config {}", path.display()))?;
+
+        toml::from_str(&text).with_context(|| format!("invalid config {}", path.display()))
+    }
+
+    /// Fills in the settings in `args` that weren't given as a flag or environment variable
+    pub fn apply(self, args: &mut Args, matches: &ArgMatches) -> Result<()> {
+        let given = |id: &str| {
+            matches
+                .value_source(id)
+                .is_some_and(|source| source != ValueSource::DefaultValue)
+        };
+
+        macro_rules! fill {
+            ($field:ident, $value:expr) => {
+                if let Some(value) = $value {
+                    if !given(stringify!($field)) {
+                        args.$field = value;
+                    }
+                }
+            };
+        }
+
+        fill!(bind_addrs, self.bind_addr);
+        fill!(
+            no_client_status,
+            self.no_client_status.map(status).transpose()?
+        );
+        fill!(slack_url_verification, self.slack_url_verification);
+        fill!(dedup, check(self.dedup, "dedup", |v| v.parse())?.map(Some));
+        fill!(dedup_window, self.dedup_window);
+        fill!(max_body_size, self.max_body_size);
+        fill!(
+            session_rate_limit,
+            rate(self.session_rate_limit, "session-rate-limit")?.map(Some)
+        );
+        fill!(
+            session_bandwidth_limit,
+            rate(self.session_bandwidth_limit, "session-bandwidth-limit")?.map(Some)
+        );
+        fill!(
+            session_throttle,
+            check(self.session_throttle, "session-throttle", |v| v.parse())?
+        );
+        fill!(queue_capacity, self.queue_capacity);
+        fill!(
+            queue_overflow,
+            check(self.queue_overflow, "queue-overflow", |v| v.parse())?
+        );
+        fill!(
+            exclusive,
+            check(self.exclusive, "exclusive", |v| v.parse())?.map(Some)
+        );
+        fill!(ingest_prefix, self.ingest_prefix.map(Some));
+        fill!(client_paths, self.client_paths);
+        fill!(base_domain, self.base_domain.map(Some));
+        fill!(reserved_subdomains, self.reserved_subdomains);
+        fill!(claims_file, self.claims_file.map(Some));
+        fill!(public_url, self.public_url.map(Some));
+        fill!(
+            tcp_ports,
+            check_all(self.tcp_ports, "tcp-ports", parse_ports)?
+        );
+        fill!(
+            quic_bind_addr,
+            check(self.quic_bind_addr, "quic-bind-addr", |v| v
+                .parse::<SocketAddr>()
+                .map_err(|e| e.to_string()))?
+            .map(Some)
+        );
+        fill!(quic_cert, self.quic_cert.map(Some));
+        fill!(quic_key, self.quic_key.map(Some));
+        fill!(compression_threshold, self.compression_threshold);
+        fill!(stats_interval, self.stats_interval);
+        fill!(alert_webhook, self.alert_webhook.map(Some));
+        fill!(request_log, self.request_log.map(Some));
+        fill!(request_log_size, self.request_log_size);
+        fill!(bus, check(self.bus, "bus", parse_bus)?.map(Some));
+        fill!(bus_subject, self.bus_subject);
+        fill!(bus_stream, self.bus_stream.map(Some));
+        fill!(bus_consumer, self.bus_consumer.map(Some));
+        fill!(shutdown_timeout, self.shutdown_timeout);
+        fill!(drain_delay, self.drain_delay);
+
+        fill!(secrets, self.auth.secrets);
+        fill!(admin_secrets, self.auth.admin_secrets);
+        fill!(tokens_file, self.auth.tokens_file.map(Some));
+
+        fill!(
+            rate_limit,
+            rate(self.rate_limit.rate, "rate-limit.rate")?.map(Some)
+        );
+        fill!(
+            rate_limit_burst,
+            rate(self.rate_limit.burst, "rate-limit.burst")?.map(Some)
+        );
+        fill!(
+            ip_rate_limit,
+            rate(self.rate_limit.ip_rate, "rate-limit.ip-rate")?.map(Some)
+        );
+        fill!(
+            ip_rate_limit_burst,
+            rate(self.rate_limit.ip_burst, "rate-limit.ip-burst")?.map(Some)
+        );
+
+        fill!(
+            allow_cidrs,
+            check_all(self.filters.allow_cidrs, "filters.allow-cidrs", parse_cidr)?
+        );
+        fill!(
+            trusted_proxies,
+            check_all(
+                self.filters.trusted_proxies,
+                "filters.trusted-proxies",
+                parse_cidr
+            )?
+        );
+        fill!(
+            verify_signatures,
+            check_all(
+                self.filters.verify_signatures,
+                "filters.verify-signatures",
+                |v| v.parse()
+            )?
+        );
+        fill!(deny_headers, self.filters.deny_headers);
+        fill!(allow_headers, self.filters.allow_headers);
+        fill!(preserve_host, self.filters.preserve_host);
+        fill!(filter_rules, self.filters.rules.map(Some));
+        fill!(sample_rate, self.filters.sample_rate.map(Some));
+
+        fill!(
+            response_status,
+            self.responses.status.map(status).transpose()?
+        );
+        fill!(
+            response_headers,
+            self.responses.headers.map(|h| h.into_iter().collect())
+        );
+        fill!(response_body, self.responses.body);
+        fill!(response_rules, self.responses.rules.map(Some));
+
+        Ok(())
+    }
+}
+
+fn status(status: u16) -> Result<u16> {
+    match status {
+        100..600 => Ok(status),
+        _ => Err(anyhow!("invalid status {} in config", status)),
+    }
+}
+
+fn rate(rate: Option<f64>, key: &str) -> Result<Option<f64>> {
+    check(rate.map(|rate| rate.to_string()), key, parse_rate)
+}
+
+fn check<T>(
+    value: Option<String>,
+    key: &str,
+    parse: impl Fn(&str) -> Result<T, String>,
+) -> Result<Option<T>> {
+    value
+        .map(|v| parse(&v).map_err(|e| anyhow!("invalid {} in config: {}", key, e)))
+        .transpose()
+}
+
+fn check_all<T>(
+    values: Option<Vec<String>>,
+    key: &str,
+    parse: impl Fn(&str) -> Result<T, String>,
+) -> Result<Option<Vec<T>>> {
+    values
+        .map(|values| {
+            values
+                .iter()
+                .map(|v| parse(v).map_err(|e| anyhow!("invalid {} in config: {}", key, e)))
+                .collect()
+        })
+        .transpose()
+}
@@ -0,0 +1,495 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
+    path::PathBuf,
+    time::Duration,
+};
+
+use actix_web::http::StatusCode;
+use claims::ClaimCommands;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use config::Config;
+use hookhub::{
+    dedup::DedupKey,
+    logging::{self, LogFormat},
+    server::{
+        queue::{self, Overflow},
+        rate_limit::Limit,
+        responses::ResponseRule,
+        sessions::Exclusive,
+        throttle::ThrottlePolicy,
+        Server, DEFAULT_BIND_ADDR,
+    },
+    signature::Verifier,
+};
+use ipnet::IpNet;
+use tokens::TokenCommands;
+use url::Url;
+
+mod claims;
+mod config;
+mod tokens;
+
+/// Hookhub server
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    /// TOML file of settings, see the README for its schema. Flags and environment variables take precedence over it, and its auth, rate-limit, filters and responses sections are reloaded on SIGHUP
+    #[arg(long, env = "HOOKHUB_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Secret clients must present to connect, defaults to abc123 unless --tokens-file is given. Can be repeated to accept old and new secrets while rotating
+    #[arg(long = "secret", env = "HOOKHUB_SECRET", value_delimiter = ',')]
+    secrets: Vec<String>,
+
+    /// Secret for the session admin API, request log and dashboard instead of the clients' secret (tokens never open them). Can be repeated while rotating
+    #[arg(
+        long = "admin-secret",
+        env = "HOOKHUB_ADMIN_SECRET",
+        value_delimiter = ','
+    )]
+    admin_secrets: Vec<String>,
+
+    /// File of per-client tokens, any unexpired one is accepted in place of the secret
+    #[arg(long, env = "HOOKHUB_TOKENS_FILE", global = true)]
+    tokens_file: Option<PathBuf>,
+
+    /// Address to listen on, host:port or unix:/path/to/socket. Can be repeated to listen on several
+    #[arg(long = "bind-addr", env = "HOOKHUB_BIND_ADDR", value_delimiter = ',', default_value = DEFAULT_BIND_ADDR)]
+    bind_addrs: Vec<String>,
+
+    /// Format of log output
+    #[arg(long, env = "HOOKHUB_LOG_FORMAT", value_enum, default_value_t)]
+    log_format: LogFormat,
+
+    /// Status to respond to incoming requests with while no clients are connected
+    #[arg(long, env = "HOOKHUB_NO_CLIENT_STATUS", default_value_t = 503, value_parser = clap::value_parser!(u16).range(100..600))]
+    no_client_status: u16,
+
+    /// Status to respond to incoming requests with
+    #[arg(long, env = "HOOKHUB_RESPONSE_STATUS", default_value_t = 200, value_parser = clap::value_parser!(u16).range(100..600))]
+    response_status: u16,
+
+    /// Header to respond to incoming requests with (e.g. "Content-Type: application/json"), can be repeated
+    #[arg(long = "response-header", value_parser = parse_header)]
+    response_headers: Vec<(String, String)>,
+
+    /// Body to respond to incoming requests with, {{method}}, {{path}}, {{body}}, {{header.<name>}} and {{json.<path>}} are replaced with values from the request
+    #[arg(long, env = "HOOKHUB_RESPONSE_BODY", default_value = "")]
+    response_body: String,
+
+    /// JSON file of response rules, each optionally matching a method and path glob, checked in order before the default response
+    #[arg(long, env = "HOOKHUB_RESPONSE_RULES")]
+    response_rules: Option<PathBuf>,
+
+    /// Answer Slack url_verification events directly instead of relaying them
+    #[arg(long, env = "HOOKHUB_SLACK_URL_VERIFICATION")]
+    slack_url_verification: bool,
+
+    /// Reject incoming requests without a valid signature, one of github:<secret>, stripe:<secret> or hmac:<header>:<secret>, can be repeated
+    #[arg(long = "verify-signature", env = "HOOKHUB_VERIFY_SIGNATURE")]
+    verify_signatures: Vec<Verifier>,
+
+    /// Don't relay repeated deliveries of the same webhook, identified by header:<name> (e.g. header:X-GitHub-Delivery) or body
+    #[arg(long, env = "HOOKHUB_DEDUP")]
+    dedup: Option<DedupKey>,
+
+    /// How long in seconds to remember deliveries for --dedup
+    #[arg(long, env = "HOOKHUB_DEDUP_WINDOW", default_value_t = 300)]
+    dedup_window: u64,
+
+    /// Only accept incoming requests from this address range (e.g. 192.30.252.0/22), can be repeated
+    #[arg(long = "allow-cidr", env = "HOOKHUB_ALLOW_CIDR", value_delimiter = ',', value_parser = parse_cidr)]
+    allow_cidrs: Vec<IpNet>,
+
+    /// Believe X-Forwarded-For when the request comes from this address range, can be repeated
+    #[arg(long = "trusted-proxy", env = "HOOKHUB_TRUSTED_PROXY", value_delimiter = ',', value_parser = parse_cidr)]
+    trusted_proxies: Vec<IpNet>,
+
+    /// Drop this header from incoming requests instead of relaying it, can be repeated. Hop-by-hop headers are always dropped.
+    #[arg(
+        long = "deny-header",
+        env = "HOOKHUB_DENY_HEADER",
+        value_delimiter = ',',
+        default_value = "origin"
+    )]
+    deny_headers: Vec<String>,
+
+    /// Only relay this header of incoming requests and any others allowed, can be repeated
+    #[arg(
+        long = "allow-header",
+        env = "HOOKHUB_ALLOW_HEADER",
+        value_delimiter = ','
+    )]
+    allow_headers: Vec<String>,
+
+    /// Relay the Host incoming requests were sent with, for local apps doing virtual-host routing
+    #[arg(long, env = "HOOKHUB_PRESERVE_HOST")]
+    preserve_host: bool,
+
+    /// JSON file of rules allowing or denying incoming requests by method, path glob, headers and body size, checked in order before they're relayed
+    #[arg(long, env = "HOOKHUB_FILTER_RULES")]
+    filter_rules: Option<PathBuf>,
+
+    /// Only relay this fraction of incoming requests (e.g. 0.1), picked at random, for shadowing a busy endpoint
+    #[arg(long, env = "HOOKHUB_SAMPLE_RATE")]
+    sample_rate: Option<f64>,
+
+    /// Maximum incoming requests per second across all sources, over which they get 429
+    #[arg(long, env = "HOOKHUB_RATE_LIMIT", value_parser = parse_rate)]
+    rate_limit: Option<f64>,
+
+    /// Requests allowed in a burst above --rate-limit, defaults to one second's worth
+    #[arg(long, env = "HOOKHUB_RATE_LIMIT_BURST", value_parser = parse_rate)]
+    rate_limit_burst: Option<f64>,
+
+    /// Maximum incoming requests per second from a single address
+    #[arg(long, env = "HOOKHUB_IP_RATE_LIMIT", value_parser = parse_rate)]
+    ip_rate_limit: Option<f64>,
+
+    /// Requests allowed in a burst above --ip-rate-limit, defaults to one second's worth
+    #[arg(long, env = "HOOKHUB_IP_RATE_LIMIT_BURST", value_parser = parse_rate)]
+    ip_rate_limit_burst: Option<f64>,
+
+    /// Maximum requests per second sent to each connected client
+    #[arg(long, env = "HOOKHUB_SESSION_RATE_LIMIT", value_parser = parse_rate)]
+    session_rate_limit: Option<f64>,
+
+    /// Maximum bytes per second sent to each connected client, so one on a slow connection is held back
+    #[arg(long, env = "HOOKHUB_SESSION_BANDWIDTH_LIMIT", value_parser = parse_rate)]
+    session_bandwidth_limit: Option<f64>,
+
+    /// What to do with requests for a client over its session limits: delay (hold them until it's under) or drop
+    #[arg(long, env = "HOOKHUB_SESSION_THROTTLE", default_value = "delay")]
+    session_throttle: ThrottlePolicy,
+
+    /// How many requests may wait to be sent to each connected client before it's treated as falling behind
+    #[arg(long, env = "HOOKHUB_QUEUE_CAPACITY", default_value_t = queue::DEFAULT_CAPACITY)]
+    queue_capacity: usize,
+
+    /// What to do when a client's queue is full: drop-oldest, drop-newest or disconnect (the client reconnects)
+    #[arg(long, env = "HOOKHUB_QUEUE_OVERFLOW", default_value = "drop-oldest")]
+    queue_overflow: Overflow,
+
+    /// Only let one client at a time connect with each token or secret: reject refuses others until it disconnects, replace disconnects it to make way (the old client doesn't reconnect)
+    #[arg(long, env = "HOOKHUB_EXCLUSIVE")]
+    exclusive: Option<Exclusive>,
+
+    /// Largest body in bytes an incoming request may have, larger ones are rejected with 413
+    #[arg(long, env = "HOOKHUB_MAX_BODY_SIZE", default_value_t = 262_144)]
+    max_body_size: usize,
+
+    /// Only relay incoming requests to paths under this one (e.g. /hooks), answering anything else with 404
+    #[arg(long, env = "HOOKHUB_INGEST_PREFIX")]
+    ingest_prefix: Option<String>,
+
+    /// Give each client a path of its own, /t/<token>, relayed to it alone
+    #[arg(long, env = "HOOKHUB_CLIENT_PATHS")]
+    client_paths: bool,
+
+    /// Domain with wildcard DNS pointing at the server (e.g. hooks.example.com), each client claims a subdomain of it relayed to it alone
+    #[arg(long, env = "HOOKHUB_BASE_DOMAIN")]
+    base_domain: Option<String>,
+
+    /// Subdomain of --base-domain never given to a client (e.g. www), can be repeated
+    #[arg(
+        long = "reserved-subdomain",
+        env = "HOOKHUB_RESERVED_SUBDOMAINS",
+        value_delimiter = ','
+    )]
+    reserved_subdomains: Vec<String>,
+
+    /// JSON file keeping the path and subdomain each client authenticating with a token is given, so it gets the same ones when it reconnects
+    #[arg(long, env = "HOOKHUB_CLAIMS_FILE", global = true)]
+    claims_file: Option<PathBuf>,
+
+    /// URL providers reach the server at (e.g. https://hooks.example.com), told to clients so they log where to send webhooks. Worked out from the Host clients connect with if not given
+    #[arg(long, env = "HOOKHUB_PUBLIC_URL")]
+    public_url: Option<Url>,
+
+    /// Port to listen for raw TCP connections on (e.g. for gRPC or Postgres), tunnelled to a client forwarding it with --tcp, or a range like 6000-6010. Can be repeated
+    #[arg(long = "tcp-port", env = "HOOKHUB_TCP_PORTS", value_delimiter = ',', value_parser = parse_ports)]
+    tcp_ports: Vec<RangeInclusive<u16>>,
+
+    /// Also accept clients over the experimental QUIC transport on this UDP address (e.g. 0.0.0.0:4433), needs --quic-cert and --quic-key
+    #[arg(long, env = "HOOKHUB_QUIC_BIND_ADDR")]
+    quic_bind_addr: Option<SocketAddr>,
+
+    /// PEM certificate chain the QUIC transport presents, for the host clients connect with
+    #[arg(long, env = "HOOKHUB_QUIC_CERT")]
+    quic_cert: Option<PathBuf>,
+
+    /// PEM private key of --quic-cert
+    #[arg(long, env = "HOOKHUB_QUIC_KEY")]
+    quic_key: Option<PathBuf>,
+
+    /// Messages larger than this many bytes are compressed for clients that support it
+    #[arg(long, env = "HOOKHUB_COMPRESSION_THRESHOLD", default_value_t = 1024)]
+    compression_threshold: usize,
+
+    /// How often in seconds to log a summary of the stats, 0 to never
+    #[arg(long, env = "HOOKHUB_STATS_INTERVAL", default_value_t = 300)]
+    stats_interval: u64,
+
+    /// Slack or Discord incoming webhook to alert when a client falls behind and requests are dropped from its queue
+    #[arg(long, env = "HOOKHUB_ALERT_WEBHOOK")]
+    alert_webhook: Option<Url>,
+
+    /// Directory to keep received requests and their delivery reports in, queryable through the history endpoint
+    #[arg(long, env = "HOOKHUB_REQUEST_LOG")]
+    request_log: Option<PathBuf>,
+
+    /// How many requests to keep in --request-log, the oldest are removed beyond this
+    #[arg(long, env = "HOOKHUB_REQUEST_LOG_SIZE", default_value_t = 1000)]
+    request_log_size: usize,
+
+    /// NATS server to share requests with other instances over (e.g. nats://localhost:4222), so clients connected to any instance get requests received by all of them. Requests aren't stored unless --bus-stream is given
+    #[arg(long, env = "HOOKHUB_BUS", value_parser = parse_bus)]
+    bus: Option<Url>,
+
+    /// Subject to share requests on, instances relaying the same webhooks share one
+    #[arg(long, env = "HOOKHUB_BUS_SUBJECT", default_value = "hookhub.requests")]
+    bus_subject: String,
+
+    /// JetStream stream to store requests shared on the bus in, created if it doesn't exist, so they aren't lost while an instance is down
+    #[arg(long, env = "HOOKHUB_BUS_STREAM", requires = "bus")]
+    bus_stream: Option<String>,
+
+    /// Durable consumer of --bus-stream to read requests through, unique to this instance, so it catches up on what it missed after a restart
+    #[arg(long, env = "HOOKHUB_BUS_CONSUMER", requires = "bus_stream")]
+    bus_consumer: Option<String>,
+
+    /// Seconds to wait for in-flight requests to finish when shutting down
+    #[arg(long, env = "HOOKHUB_SHUTDOWN_TIMEOUT", default_value_t = 10)]
+    shutdown_timeout: u64,
+
+    /// Seconds to keep relaying after SIGTERM with readyz failing, before disconnecting clients, so load balancers stop sending requests first
+    #[arg(long, env = "HOOKHUB_DRAIN_DELAY", default_value_t = 0)]
+    drain_delay: u64,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Manages per-client tokens in --tokens-file
+    Tokens {
+        #[command(subcommand)]
+        command: TokenCommands,
+    },
+    /// Manages the paths and subdomains kept for clients in --claims-file
+    Claims {
+        #[command(subcommand)]
+        command: ClaimCommands,
+    },
+}
+
+fn parse_header(value: &str) -> Result<(String, String), String> {
+    match value.split_once(':') {
+        Some((name, value)) => Ok((name.trim().to_string(), value.trim().to_string())),
+        None => Err("expected a header in the form \"Name: value\"".to_string()),
+    }
+}
+
+fn parse_cidr(value: &str) -> Result<IpNet, String> {
+    value
+        .parse::<IpNet>()
+        .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| "expected an address range like 10.0.0.0/8 or an address".to_string())
+}
+
+fn parse_ports(value: &str) -> Result<RangeInclusive<u16>, String> {
+    let (first, last) = value.split_once('-').unwrap_or((value, value));
+    match (first.parse::<u16>(), last.parse::<u16>()) {
+        (Ok(first), Ok(last)) if first > 0 && first <= last => Ok(first..=last),
+        _ => Err("expected a port like 5432 or a range like 6000-6010".to_string()),
+    }
+}
+
+fn parse_rate(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err("expected a positive number".to_string()),
+    }
+}
+
+fn parse_bus(value: &str) -> Result<Url, String> {
+    match Url::parse(value) {
+        Ok(url) if matches!(url.scheme(), "nats" | "tls") => Ok(url),
+        Ok(_) => Err("only NATS is supported as a bus, expected nats:// or tls://".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Parses the flags and environment, filling in the rest from --config
+fn load_args() -> anyhow::Result<Args> {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches)?;
+
+    if let Some(path) = &args.config {
+        Config::load(path)?.apply(&mut args, &matches)?;
+    }
+    if args.quic_bind_addr.is_some() && (args.quic_cert.is_none() || args.quic_key.is_none()) {
+        return Err(anyhow::anyhow!(
+            "--quic-bind-addr needs --quic-cert and --quic-key"
+        ));
+    }
+
+    Ok(args)
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let args = load_args().map_err(std::io::Error::other)?;
+
+    logging::init(args.log_format);
+
+    if let Some(Commands::Tokens { command }) = &args.command {
+        let path = args
+            .tokens_file
+            .as_deref()
+            .ok_or_else(|| std::io::Error::other("--tokens-file is required"))?;
+
+        return tokens::handle(command, path).map_err(std::io::Error::other);
+    }
+    if let Some(Commands::Claims { command }) = &args.command {
+        let path = args
+            .claims_file
+            .as_deref()
+            .ok_or_else(|| std::io::Error::other("--claims-file is required"))?;
+
+        return claims::handle(command, path).map_err(std::io::Error::other);
+    }
+
+    let reloadable = args.config.is_some();
+    let mut server = build_server(args);
+    if reloadable {
+        server = server.reload_with(|| load_args().map(build_server));
+    }
+
+    server.run().await
+}
+
+fn build_server(args: Args) -> Server {
+    let limit = |rate: Option<f64>, burst: Option<f64>| {
+        rate.map(|rate| Limit {
+            rate,
+            burst: burst.unwrap_or(rate.ceil()),
+        })
+    };
+
+    let mut server = Server::new()
+        .no_client_status(StatusCode::from_u16(args.no_client_status).unwrap())
+        .response(ResponseRule {
+            method: None,
+            path: None,
+            status: args.response_status,
+            headers: args.response_headers.into_iter().collect(),
+            body: args.response_body,
+        })
+        .slack_url_verification(args.slack_url_verification)
+        .max_body_size(args.max_body_size)
+        .compression_threshold(args.compression_threshold)
+        .shutdown_timeout(Duration::from_secs(args.shutdown_timeout))
+        .drain_delay(Duration::from_secs(args.drain_delay))
+        .stats_interval(
+            (args.stats_interval > 0).then(|| Duration::from_secs(args.stats_interval)),
+        );
+
+    for addr in args.bind_addrs {
+        server = server.bind_addr(addr);
+    }
+    for secret in args.secrets {
+        server = server.secret(secret);
+    }
+    for secret in args.admin_secrets {
+        server = server.admin_secret(secret);
+    }
+    if let Some(prefix) = args.ingest_prefix {
+        server = server.ingest_prefix(prefix);
+    }
+    server = server.client_paths(args.client_paths);
+    if let Some(domain) = args.base_domain {
+        server = server.base_domain(domain);
+    }
+    for name in args.reserved_subdomains {
+        server = server.reserve_subdomain(name);
+    }
+    if let Some(path) = args.claims_file {
+        server = server.claims_file(path);
+    }
+    if let Some(url) = args.public_url {
+        server = server.public_url(url);
+    }
+    for ports in args.tcp_ports {
+        server = server.tcp_ports(ports);
+    }
+    if let (Some(addr), Some(cert), Some(key)) =
+        (args.quic_bind_addr, args.quic_cert, args.quic_key)
+    {
+        server = server.quic(addr, cert, key);
+    }
+    if let Some(path) = args.tokens_file {
+        server = server.tokens_file(path);
+    }
+    if let Some(path) = args.response_rules {
+        server = server.response_rules(path);
+    }
+    for verifier in args.verify_signatures {
+        server = server.verify_signature(verifier);
+    }
+    if let Some(key) = args.dedup {
+        server = server.dedup(key, Duration::from_secs(args.dedup_window));
+    }
+    for net in args.allow_cidrs {
+        server = server.allow_cidr(net);
+    }
+    for net in args.trusted_proxies {
+        server = server.trusted_proxy(net);
+    }
+    server = server.deny_headers(args.deny_headers);
+    for name in args.allow_headers {
+        server = server.allow_header(name);
+    }
+    server = server.preserve_host(args.preserve_host);
+    if let Some(path) = args.filter_rules {
+        server = server.filter_rules(path);
+    }
+    if let Some(rate) = args.sample_rate {
+        server = server.sample_rate(rate);
+    }
+    if let Some(limit) = limit(args.rate_limit, args.rate_limit_burst) {
+        server = server.rate_limit(limit);
+    }
+    if let Some(limit) = limit(args.ip_rate_limit, args.ip_rate_limit_burst) {
+        server = server.ip_rate_limit(limit);
+    }
+    if let Some(limit) = limit(args.session_rate_limit, None) {
+        server = server.session_rate_limit(limit);
+    }
+    if let Some(limit) = limit(args.session_bandwidth_limit, None) {
+        server = server.session_bandwidth_limit(limit);
+    }
+    server = server
+        .session_throttle(args.session_throttle)
+        .queue_capacity(args.queue_capacity)
+        .queue_overflow(args.queue_overflow);
+    if let Some(exclusive) = args.exclusive {
+        server = server.exclusive(exclusive);
+    }
+    if let Some(url) = args.alert_webhook {
+        server = server.alert_webhook(url);
+    }
+    if let Some(url) = args.bus {
+        server = server.bus(url, args.bus_subject);
+    }
+    if let Some(stream) = args.bus_stream {
+        server = server.bus_stream(stream, args.bus_consumer);
+    }
+    if let Some(path) = args.request_log {
+        server = server.request_log(path, args.request_log_size);
+    }
+
+    server
+}
@@ -0,0 +1,82 @@
+use std::{path::Path, time::Duration};
+
+use anyhow::{anyhow, Result};
+use clap::Subcommand;
+use hookhub::{
+    output::{self, OutputFormat},
+    server::tokens::TokenStore,
+};
+use log::info;
+
+#[derive(Subcommand)]
+pub enum TokenCommands {
+    /// Creates a token for a client, printing it once
+    Add {
+        /// Who or what the token is for
+        name: String,
+
+        /// How long until the token stops working, e.g. 12h or 30d
+        #[arg(long, value_parser = parse_duration)]
+        expires_in: Option<Duration>,
+    },
+    /// Lists tokens, without their values
+    List,
+    /// Revokes a token so it can no longer be used to connect
+    Revoke {
+        /// The name the token was added with
+        name: String,
+    },
+}
+
+pub fn handle(command: &TokenCommands, path: &Path) -> Result<()> {
+    let store = TokenStore::new(path);
+
+    match command {
+        TokenCommands::Add { name, expires_in } => {
+            let value = store.add(name, *expires_in)?;
+            info!("Added token {}, it won't be shown again", name);
+            println!("{}", value);
+        }
+        TokenCommands::List => {
+            output::print(
+                OutputFormat::Table,
+                &["NAME", "CREATED AT", "EXPIRES AT"],
+                &store.list()?,
+                |token| {
+                    vec![
+                        token.name.clone(),
+                        token.created_at.to_rfc3339(),
+                        match token.expires_at {
+                            Some(_) if token.is_expired() => "expired".to_string(),
+                            Some(at) => at.to_rfc3339(),
+                            None => "never".to_string(),
+                        },
+                    ]
+                },
+            )?;
+        }
+        TokenCommands::Revoke { name } => {
+            if !store.revoke(name)? {
+                return Err(anyhow!("no token named {}", name));
+            }
+            info!("Revoked token {}", name);
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (number, unit) = value.split_at(value.len().saturating_sub(1));
+    let number: u64 = number
+        .parse()
+        .map_err(|_| "expected a duration like 30s, 15m, 12h or 30d".to_string())?;
+
+    match unit {
+        "s" => Ok(Duration::from_secs(number)),
+        "m" => Ok(Duration::from_secs(number * 60)),
+        "h" => Ok(Duration::from_secs(number * 60 * 60)),
+        "d" => Ok(Duration::from_secs(number * 60 * 60 * 24)),
+        _ => Err("expected a duration like 30s, 15m, 12h or 30d".to_string()),
+    }
+}
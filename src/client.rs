@@ -1,228 +1,909 @@
-use std::{fs, io, path::PathBuf, sync::LazyLock, time::Duration};
+//! The relay client: connects to a server and receives the requests it relays, optionally
+//! forwarding them to a local origin.
 
-use anyhow::Result;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
 use async_tungstenite::{
-    tokio::connect_async,
-    tungstenite::{client::IntoClientRequest, Message},
+    tokio::{connect_async, ConnectStream},
+    tungstenite::{
+        client::IntoClientRequest,
+        handshake::client::Request,
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Error as WsError, Message,
+    },
+    WebSocketStream,
 };
-use chrono::Utc;
-use env_logger::Env;
-use futures::prelude::*;
-use history_db::ItemId;
-use hookhub::RequestMessage;
-use reqwest::{Client, Method};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use futures::{future::BoxFuture, prelude::*};
+use log::{error, info, warn};
+use reqwest::{header::HeaderValue, Method, Response, StatusCode};
 use tokio::{
-    signal::unix::SignalKind,
-    sync::broadcast,
-    task::JoinHandle,
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
     time::{self, interval_at, Instant},
 };
-
-use base64::{engine::general_purpose::STANDARD, Engine as _};
-use clap::{Parser, Subcommand};
-use log::{error, info, warn};
 use url::Url;
 
-mod history;
-mod history_db;
+use self::transport::{QuicTransport, Transport};
+use crate::{
+    negotiate_protocol,
+    quic::Login,
+    tunnel::{self, Frame, TunnelFrame},
+    wire, DeliveryOutcome, DeliveryReport, Feature, Hello, HelloAck, PeerEvent, RequestMessage,
+    Version, WireFormat, HELLO_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION, PROTOCOL_HEADER,
+    PROTOCOL_VERSION,
+};
 
-pub static ROOT_PATH: LazyLock<PathBuf> = LazyLock::new(|| {
-    let home = homedir::my_home().unwrap().unwrap();
+mod transport;
 
-    match fs::create_dir(&home) {
-        Ok(_) => home.join(".hookhub"),
-        Err(e) => {
-            if e.kind() == io::ErrorKind::AlreadyExists {
-                home.join(".hookhub")
-            } else {
-                panic!("{}", e);
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Awaited with the details of a lifecycle event while `run` is handling requests
+pub type Callback<T> = Arc<dyn Fn(T) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// Connects to a relay server, built up with `local`, `compression`, `tunnel_websockets`,
+/// `forward_tcp`, `quic`, `subdomain`, `name`, `label` and the `on_*` callbacks before calling `connect` or `run`
+#[derive(Clone)]
+pub struct Client {
+    remote: Url,
+    secret: String,
+    local: Option<Url>,
+    compression: bool,
+    tunnel: Option<Url>,
+    /// Local addresses TCP connections to each server port are forwarded to
+    tcp: HashMap<u16, String>,
+    /// Server's QUIC address, connected to instead of the websocket if set
+    quic: Option<String>,
+    quic_ca: Option<PathBuf>,
+    subdomain: Option<String>,
+    name: Option<String>,
+    labels: BTreeMap<String, String>,
+    callbacks: Callbacks,
+}
+
+#[derive(Clone, Default)]
+struct Callbacks {
+    connected: Vec<Callback<Option<HelloAck>>>,
+    disconnected: Vec<Callback<()>>,
+    request_received: Vec<Callback<RequestMessage>>,
+    forwarded: Vec<Callback<(RequestMessage, Result<StatusCode, String>)>>,
+    error: Vec<Callback<String>>,
+}
+
+impl Client {
+    /// A client for the server at `remote` (e.g. wss://something.herokuapp.com)
+    pub fn new(remote: Url, secret: impl Into<String>) -> Self {
+        Self {
+            remote,
+            secret: secret.into(),
+            local: None,
+            compression: true,
+            tunnel: None,
+            tcp: HashMap::new(),
+            quic: None,
+            quic_ca: None,
+            subdomain: None,
+            name: None,
+            labels: BTreeMap::new(),
+            callbacks: Callbacks::default(),
+        }
+    }
+
+    /// Local origin `run` forwards requests to (e.g. http://localhost:3000/)
+    pub fn local(mut self, local: Url) -> Self {
+        self.local = Some(local);
+        self
+    }
+
+    /// Whether to ask the server to compress large messages, on by default
+    pub fn compression(mut self, enabled: bool) -> Self {
+        self.compression = enabled;
+        self
+    }
+
+    /// Offers to take websockets providers open to the server, tunnelling them to the same path
+    /// on `local` (e.g. http://localhost:3000/, dialled as ws://localhost:3000/)
+    pub fn tunnel_websockets(mut self, local: Url) -> Self {
+        self.tunnel = Some(local);
+        self
+    }
+
+    /// Offers to take TCP connections made to `remote_port` on the server, connecting each to
+    /// `local` (e.g. localhost:5432). Can be called more than once for several ports.
+    pub fn forward_tcp(mut self, remote_port: u16, local: impl Into<String>) -> Self {
+        self.tcp.insert(remote_port, local.into());
+        self
+    }
+
+    /// Connects over the experimental QUIC transport to `addr` (e.g. relay.example.com:4433)
+    /// instead of the websocket, checking the server's certificate is for the host of `remote`.
+    /// Websockets and TCP connections aren't tunnelled over it.
+    pub fn quic(mut self, addr: impl Into<String>) -> Self {
+        self.quic = Some(addr.into());
+        self
+    }
+
+    /// Also trusts the certificates in the PEM file at `path` when connecting over QUIC, e.g. a
+    /// server's self-signed one
+    pub fn quic_ca(mut self, path: impl Into<PathBuf>) -> Self {
+        self.quic_ca = Some(path.into());
+        self
+    }
+
+    /// Asks for `name` as the subdomain relayed to this client alone, on a server routing by
+    /// subdomain. Without it the server picks one.
+    pub fn subdomain(mut self, name: impl Into<String>) -> Self {
+        self.subdomain = Some(name.into());
+        self
+    }
+
+    /// Tells the server this client is called `name` (e.g. mikes-laptop), shown in its logs and
+    /// admin API to tell sessions apart
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Labels this client `key=value` (e.g. team=payments), which the server's filter rules can
+    /// relay requests by. Can be called more than once.
+    pub fn label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.labels.insert(key.into(), value.into());
+        self
+    }
+
+    /// Awaits `callback` once connected, with the server's hello acknowledgement if the
+    /// protocol has one
+    pub fn on_connected<F, Fut>(mut self, callback: F) -> Self
+    where
+        F: Fn(Option<HelloAck>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.callbacks
+            .connected
+            .push(Arc::new(move |ack| callback(ack).boxed()));
+        self
+    }
+
+    /// Awaits `callback` when a connection that was established ends, for whatever reason
+    pub fn on_disconnected<F, Fut>(mut self, callback: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.callbacks
+            .disconnected
+            .push(Arc::new(move |()| callback().boxed()));
+        self
+    }
+
+    /// Awaits `callback` with every request received, before it's forwarded
+    pub fn on_request_received<F, Fut>(mut self, callback: F) -> Self
+    where
+        F: Fn(RequestMessage) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.callbacks
+            .request_received
+            .push(Arc::new(move |req| callback(req).boxed()));
+        self
+    }
+
+    /// Awaits `callback` with every request forwarded to the local origin and the status it
+    /// responded with, or why it couldn't be reached
+    pub fn on_forwarded<F, Fut>(mut self, callback: F) -> Self
+    where
+        F: Fn(RequestMessage, Result<StatusCode, String>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.callbacks
+            .forwarded
+            .push(Arc::new(move |(req, result)| callback(req, result).boxed()));
+        self
+    }
+
+    /// Awaits `callback` with the error that stopped `run`, e.g. a failed connection
+    pub fn on_error<F, Fut>(mut self, callback: F) -> Self
+    where
+        F: Fn(String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.callbacks
+            .error
+            .push(Arc::new(move |error| callback(error).boxed()));
+        self
+    }
+
+    pub fn remote(&self) -> &Url {
+        &self.remote
+    }
+
+    /// Opens an authenticated websocket, or QUIC connection, to the server and negotiates the
+    /// protocol
+    pub async fn connect(&self) -> Result<Connection> {
+        if let Some(addr) = &self.quic {
+            return self.connect_quic(addr).await;
+        }
+
+        let mut remote = self.remote.clone();
+        prepare_remote_url(&mut remote)?;
+
+        let request = connect_request(&remote, &self.secret)?;
+        let (stream, response) = connect_async(request).await.map_err(handshake_error)?;
+
+        let protocol = negotiate_protocol(
+            response
+                .headers()
+                .get(PROTOCOL_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok()),
+        );
+
+        if protocol < MIN_PROTOCOL_VERSION {
+            return Err(anyhow!(
+                "server speaks protocol {} but this client needs at least {}, upgrade the server",
+                protocol,
+                MIN_PROTOCOL_VERSION
+            ));
+        }
+
+        let mut connection = Connection {
+            stream: Transport::Websocket(Box::new(stream)),
+            format: WireFormat::default(),
+            compression: false,
+            reports: false,
+            tunnels: None,
+            ack: None,
+        };
+
+        if protocol >= HELLO_PROTOCOL_VERSION {
+            let Transport::Websocket(stream) = &mut connection.stream else {
+                unreachable!()
+            };
+            let ack = greet(stream, &self.hello(protocol)).await?;
+            self.acknowledged(&mut connection, ack)?;
+        }
+
+        Ok(connection)
+    }
+
+    async fn connect_quic(&self, addr: &str) -> Result<Connection> {
+        let server_name = self
+            .remote
+            .host_str()
+            .ok_or_else(|| anyhow!("remote has no host to check the server's certificate for"))?;
+
+        let mut hello = self.hello(PROTOCOL_VERSION);
+        hello
+            .features
+            .retain(|f| !matches!(f, Feature::Tunnel | Feature::TcpForward));
+        hello.tcp_ports.clear();
+        if self.tunnel.is_some() || !self.tcp.is_empty() {
+            warn!("Websockets and TCP connections aren't tunnelled over QUIC");
+        }
+        let login = Login {
+            password: self.secret.clone(),
+            hello,
+        };
+
+        let (transport, ack) =
+            QuicTransport::connect(addr, server_name, self.quic_ca.as_deref(), &login).await?;
+        let mut connection = Connection {
+            stream: Transport::Quic(transport),
+            format: WireFormat::default(),
+            compression: false,
+            reports: false,
+            tunnels: None,
+            ack: None,
+        };
+        self.acknowledged(&mut connection, ack)?;
+
+        Ok(connection)
+    }
+
+    /// Our `Hello`, offering only the features this client's been set up to use
+    fn hello(&self, protocol: u32) -> Hello {
+        let mut hello = Hello::new(protocol);
+        if !self.compression {
+            hello.features.retain(|f| *f != Feature::Compression);
+        }
+        if self.tunnel.is_none() {
+            hello.features.retain(|f| *f != Feature::Tunnel);
+        }
+        if self.tcp.is_empty() {
+            hello.features.retain(|f| *f != Feature::TcpForward);
+        }
+        hello.tcp_ports = self.tcp.keys().copied().collect();
+        hello.subdomain = self.subdomain.clone();
+        hello.name = self.name.clone();
+        hello.labels = self.labels.clone();
+        hello
+    }
+
+    /// Sets the connection up for what the server agreed to
+    fn acknowledged(&self, connection: &mut Connection, ack: HelloAck) -> Result<()> {
+        info!(
+            "Server {} speaks protocol {} in {:?} with features {:?}",
+            ack.version, ack.protocol, ack.format, ack.features
+        );
+        if let Some(max_body_size) = ack.max_body_size {
+            info!("Server accepts bodies up to {} bytes", max_body_size);
+        }
+        if ack.host.is_none() && self.subdomain.is_some() {
+            warn!("Server doesn't route by subdomain, it relays every request to every client");
+        }
+        match &ack.public_url {
+            Some(url) => info!("Send webhooks to: {}", url),
+            // servers from before it was in the handshake
+            None => {
+                if let Some(host) = &ack.host {
+                    let url = public_url(&self.remote, Some(host), "/");
+                    info!("Send webhooks to: {}", url);
+                }
+                if let Some(path) = &ack.path {
+                    let url = public_url(&self.remote, None, path);
+                    info!("Send webhooks to: {}", url);
+                }
             }
         }
+
+        connection.compression = ack.features.contains(&Feature::Compression);
+        connection.reports = ack.features.contains(&Feature::AckDelivery);
+        connection.format = ack.format;
+        let websockets = ack.features.contains(&Feature::Tunnel);
+        let tcp = ack.features.contains(&Feature::TcpForward);
+        if tcp {
+            for port in self.tcp.keys().filter(|p| !ack.tcp_ports.contains(p)) {
+                warn!("Server doesn't forward TCP port {}", port);
+            }
+        }
+        if websockets || tcp {
+            let local = match self.tunnel.clone().filter(|_| websockets) {
+                Some(mut local) => {
+                    prepare_local_url(&mut local)?;
+                    Some(local)
+                }
+                None => None,
+            };
+            let tcp = if tcp {
+                self.tcp.clone()
+            } else {
+                HashMap::new()
+            };
+            connection.tunnels = Some(LocalTunnels::new(local, tcp));
+        }
+        connection.ack = Some(ack);
+
+        Ok(())
     }
-});
 
-pub static HISTORY_DB: LazyLock<history_db::Db> =
-    LazyLock::new(|| history_db::Db::new(&ROOT_PATH.join("history")).unwrap());
+    /// Connects and handles requests until the server closes the connection, awaiting the
+    /// callbacks and forwarding to the local origin if there is one
+    pub async fn run(&self) -> Result<()> {
+        let result = self.run_connection().await;
 
-/// Hookhub client
-#[derive(Parser)]
-#[command(version, about, long_about = None)]
-struct Args {
-    #[command(subcommand)]
-    command: Commands,
-}
+        if let Err(e) = &result {
+            emit(&self.callbacks.error, e.to_string()).await;
+        }
 
-#[derive(Subcommand)]
-enum Commands {
-    /// Connect to a remote server and relay requests to a local server
-    Connect {
-        /// Remote origin that will relay requests (e.g. wss://something.herokuapp.com)
-        #[arg(long, env = "HOOKHUB_REMOTE")]
-        remote: Url,
-
-        /// Remote server secret used to authenticate
-        #[arg(long, env = "HOOKHUB_SECRET")]
-        secret: String,
-
-        /// Local origin to relay requests to (e.g. https://localhost:3000/)
-        #[arg(long, env = "HOOKHUB_LOCAL")]
-        local: Url,
-    },
-    /// Manage and replay previously received requests
-    History {
-        #[command(subcommand)]
-        command: HistoryCommands,
-    },
+        result
+    }
+
+    async fn run_connection(&self) -> Result<()> {
+        let local = match &self.local {
+            Some(local) => {
+                let mut local = local.clone();
+                prepare_local_url(&mut local)?;
+                Some(local)
+            }
+            None => None,
+        };
+
+        let http = http_client()?;
+        let mut connection = self.connect().await?;
+        emit(&self.callbacks.connected, connection.ack.clone()).await;
+
+        let result = self.handle(&mut connection, &http, local.as_ref()).await;
+
+        connection.close().await;
+        emit(&self.callbacks.disconnected, ()).await;
+
+        result
+    }
+
+    async fn handle(
+        &self,
+        connection: &mut Connection,
+        http: &reqwest::Client,
+        local: Option<&Url>,
+    ) -> Result<()> {
+        let mut interval = interval_at(Instant::now() + PING_INTERVAL, PING_INTERVAL);
+
+        loop {
+            tokio::select! {
+                message = connection.next() => {
+                    let Some(req) = message.transpose()? else {
+                        return Ok(());
+                    };
+
+                    emit(&self.callbacks.request_received, req.clone()).await;
+
+                    let Some(local) = local else {
+                        continue;
+                    };
+
+                    let start = Instant::now();
+                    let result = match forward(http, local, &req).await {
+                        Ok(resp) => {
+                            info!("Forwarded request: {} {} - {:?}", req.method, req.fullpath, resp.status());
+                            Ok(resp.status())
+                        }
+                        Err(e) => {
+                            error!("Forwarded request error: {}", e);
+                            Err(e.to_string())
+                        }
+                    };
+
+                    if let Some(request_id) = req.delivery_id() {
+                        connection.report(DeliveryReport {
+                            request_id,
+                            outcome: match result {
+                                Ok(_) => DeliveryOutcome::Forwarded,
+                                Err(_) => DeliveryOutcome::Failed,
+                            },
+                            status: result.as_ref().ok().map(|s| s.as_u16()),
+                            duration_ms: start.elapsed().as_millis() as u64,
+                        }).await?;
+                    }
+
+                    emit(&self.callbacks.forwarded, (req, result)).await;
+                },
+                _ = interval.tick() => {
+                    connection.ping().await?;
+                }
+            }
+        }
+    }
 }
 
-#[derive(Subcommand)]
-enum HistoryCommands {
-    /// List previously received requests
-    List,
-    /// Delete a previously received request
-    Delete {
-        /// Identifier of the request
-        id: ItemId,
-    },
-    /// Clear all previously received requests
-    Clear,
-    /// Replay a previously received request
-    Replay {
-        /// Identifier of the request
-        id: ItemId,
-        /// Local origin to relay requests to (e.g. https://localhost:3000/)
-        #[arg(long, env = "HOOKHUB_LOCAL")]
-        local: Url,
-    },
+async fn emit<T: Clone>(callbacks: &[Callback<T>], value: T) {
+    for callback in callbacks {
+        callback(value.clone()).await;
+    }
 }
 
-const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// An open websocket, or QUIC connection, to the server
+pub struct Connection {
+    stream: Transport,
+    format: WireFormat,
+    compression: bool,
+    /// Whether the server takes delivery reports
+    reports: bool,
+    /// Websockets and TCP connections tunnelled locally, if the server agreed to send them
+    tunnels: Option<LocalTunnels>,
+    ack: Option<HelloAck>,
+}
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+/// The websockets a connection is tunnelling to the local origin and TCP connections to local
+/// ports, each pumped by its own task
+struct LocalTunnels {
+    /// Origin websockets are opened to, if the client takes them
+    local: Option<Url>,
+    tcp: HashMap<u16, String>,
+    /// Frames from the server for each tunnel's task
+    open: HashMap<u64, UnboundedSender<TunnelFrame>>,
+    /// Frames from the tasks for the server
+    outgoing: UnboundedSender<TunnelFrame>,
+    incoming: UnboundedReceiver<TunnelFrame>,
+}
 
-    let args = Args::parse();
+impl LocalTunnels {
+    fn new(local: Option<Url>, tcp: HashMap<u16, String>) -> Self {
+        let (outgoing, incoming) = mpsc::unbounded_channel();
 
-    match args.command {
-        Commands::Connect {
-            remote,
-            secret,
+        Self {
             local,
-        } => handle_connect(remote, secret, local).await,
-        Commands::History { command } => history::handle(command).await,
+            tcp,
+            open: HashMap::new(),
+            outgoing,
+            incoming,
+        }
+    }
+
+    /// Answers a frame opening a tunnel this connection doesn't take
+    fn refuse(&self, id: u64, reason: &str) {
+        let _ = self.outgoing.send(TunnelFrame::Close {
+            id,
+            code: None,
+            reason: reason.to_string(),
+        });
+    }
+
+    /// Starts a task for a frame opening a tunnel, or passes one about an open tunnel to its task
+    fn received(&mut self, frame: TunnelFrame) {
+        let id = frame.id();
+
+        match frame {
+            TunnelFrame::Open { request, .. } => {
+                let Some(local) = self.local.clone() else {
+                    return self.refuse(id, "websockets aren't tunnelled");
+                };
+                let (to_task, from_server) = mpsc::unbounded_channel();
+                self.open.insert(id, to_task);
+                tokio::spawn(tunnel_to_local(
+                    local,
+                    id,
+                    request,
+                    self.outgoing.clone(),
+                    from_server,
+                ));
+            }
+            TunnelFrame::Connect { port, .. } => {
+                let Some(addr) = self.tcp.get(&port).cloned() else {
+                    return self.refuse(id, "port isn't forwarded");
+                };
+                let (to_task, from_server) = mpsc::unbounded_channel();
+                self.open.insert(id, to_task);
+                tokio::spawn(tcp_to_local(
+                    addr,
+                    id,
+                    port,
+                    self.outgoing.clone(),
+                    from_server,
+                ));
+            }
+            TunnelFrame::Close { .. } => {
+                if let Some(task) = self.open.remove(&id) {
+                    let _ = task.send(frame);
+                }
+            }
+            frame => {
+                if let Some(task) = self.open.get(&id) {
+                    let _ = task.send(frame);
+                }
+            }
+        }
     }
 }
 
-async fn handle_connect(mut remote: Url, secret: String, mut local: Url) -> Result<()> {
-    prepare_remote_url(&mut remote)?;
-    prepare_local_url(&mut local)?;
+/// Says another client started or stopped getting the requests this one does
+fn log_peer_event(event: &PeerEvent) {
+    let (connected, name, clients) = match event {
+        PeerEvent::Connected { name, clients, .. } => ("just connected", name, clients),
+        PeerEvent::Disconnected { name, clients, .. } => ("disconnected", name, clients),
+    };
+    let name = name
+        .clone()
+        .unwrap_or_else(|| format!("session #{}", event.session()));
 
-    info!("Local origin: {}", local);
-    info!("Remote origin: {}", remote);
+    info!(
+        "Another client ({}) {}, requests for every client are now delivered to {} client(s)",
+        name, connected, clients
+    );
+}
 
-    let (shutdown, _) = broadcast::channel::<()>(1);
+/// Waits for a frame from a tunnel's task, never if nothing's tunnelled
+async fn next_outgoing(tunnels: &mut Option<LocalTunnels>) -> Option<TunnelFrame> {
+    match tunnels {
+        Some(tunnels) => tunnels.incoming.recv().await,
+        None => future::pending().await,
+    }
+}
 
-    let shutdown_tx = shutdown.clone();
+impl Connection {
+    /// What the server answered the hello with, if the protocol has one
+    pub fn ack(&self) -> Option<&HelloAck> {
+        self.ack.as_ref()
+    }
 
-    tokio::spawn(async move {
-        let mut sigint = std::pin::pin!(interrupt_signal());
-        tokio::select! {
-            _ = sigint.as_mut() => {
-                let _ = shutdown_tx.send(());
-                warn!("SIGINT received, shutting down");
-            }
-        }
-    });
+    /// Waits for the next request, `None` once the server closes the connection. Tunnelled
+    /// websockets are relayed while waiting.
+    pub async fn next(&mut self) -> Option<Result<RequestMessage>> {
+        loop {
+            let message = tokio::select! {
+                message = self.stream.next() => message?,
+                Some(frame) = next_outgoing(&mut self.tunnels) => {
+                    if let Err(e) = self.send_tunnel_frame(frame).await {
+                        return Some(Err(e));
+                    }
+                    continue;
+                }
+            };
 
-    loop {
-        let result = connect_and_run(
-            local.clone(),
-            remote.clone(),
-            secret.clone(),
-            shutdown.clone(),
-        )
-        .await;
-        if let Err(e) = result {
-            error!("Failed with error: {:?}", e);
-            error!("Trying again in 5 seconds...");
-
-            let mut shutdown = shutdown.clone().subscribe();
+            match message {
+                Ok(Message::Binary(frame)) => {
+                    let Some(tunnels) = &mut self.tunnels else {
+                        return Some(wire::decode(&frame, self.format, self.compression));
+                    };
 
-            tokio::select! {
-                _ = time::sleep(Duration::from_secs(5)) => {
+                    match tunnel::decode(&frame) {
+                        Ok(Frame::Message(data)) => {
+                            return Some(wire::decode(data, self.format, self.compression))
+                        }
+                        Ok(Frame::Tunnel(frame)) => tunnels.received(frame),
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
+                Ok(Message::Text(text)) => match serde_json::from_str::<PeerEvent>(&text) {
+                    Ok(event) => log_peer_event(&event),
+                    Err(e) => warn!("Invalid peer event from the server: {}", e),
                 },
-                _ = shutdown.recv() => {
-                    break;
+                Ok(Message::Close(frame)) => {
+                    // going away is a server shutting down, reconnecting should reach another,
+                    // and try again one whose queue for this client overflowed
+                    match frame {
+                        Some(frame) if frame.code == CloseCode::Away => {
+                            return Some(Err(anyhow!("server went away: {}", frame.reason)));
+                        }
+                        Some(frame) if frame.code == CloseCode::Again => {
+                            return Some(Err(anyhow!(
+                                "server asked to reconnect: {}",
+                                frame.reason
+                            )));
+                        }
+                        Some(frame) if !frame.reason.is_empty() => {
+                            info!("Server closed the connection: {}", frame.reason);
+                            return None;
+                        }
+                        _ => {}
+                    }
+                    info!("Server closed the connection");
+                    return None;
                 }
+                Ok(_) => {}
+                Err(e) => return Some(Err(e.into())),
             }
-        } else {
-            break;
         }
     }
 
-    Ok(())
-}
+    /// Tells the server what became of a request, if it takes delivery reports
+    pub async fn report(&mut self, report: DeliveryReport) -> Result<()> {
+        if self.reports {
+            let mut frame = rmp_serde::to_vec_named(&report)?;
+            if self.tunnels.is_some() {
+                frame = tunnel::message_frame(frame);
+            }
+            self.stream.send(Message::Binary(frame)).await?;
+        }
 
-async fn connect_and_run(
-    local: Url,
-    remote: Url,
-    secret: String,
-    shutdown: broadcast::Sender<()>,
-) -> Result<()> {
-    let mut request = remote.as_str().into_client_request()?;
-    let auth = STANDARD.encode(format!("{}:{}", VERSION, secret));
-    request
-        .headers_mut()
-        .insert("Authorization", format!("Basic {}", auth).parse()?);
+        Ok(())
+    }
 
-    let http = http_client()?;
+    async fn send_tunnel_frame(&mut self, frame: TunnelFrame) -> Result<()> {
+        if let (TunnelFrame::Close { id, .. }, Some(tunnels)) = (&frame, &mut self.tunnels) {
+            tunnels.open.remove(id);
+        }
+        self.stream.send(Message::Binary(frame.encode()?)).await?;
+
+        Ok(())
+    }
 
-    let (mut stream, _) = connect_async(request).await?;
+    /// Keeps the connection alive through proxies that drop idle ones
+    pub async fn ping(&mut self) -> Result<()> {
+        self.stream.send(Message::Ping(vec![5, 4, 3, 2, 1])).await?;
 
-    info!("Connected successfully, waiting for events");
+        Ok(())
+    }
+
+    pub async fn close(self) {
+        self.stream.close().await;
+    }
+}
 
-    let start = Instant::now() + Duration::from_secs(20);
-    let mut interval = interval_at(start, Duration::from_secs(20));
+/// Headers of a tunnelled upgrade that belong to the provider's handshake with the server, the
+/// client making its own with the local origin
+const HANDSHAKE_HEADERS: &[&str] = &[
+    "sec-websocket-key",
+    "sec-websocket-version",
+    "sec-websocket-extensions",
+    "content-length",
+];
 
-    let mut shutdown = shutdown.subscribe();
+/// Opens a websocket to the local origin for a tunnel and pumps messages between it and the
+/// server until either closes it
+async fn tunnel_to_local(
+    local: Url,
+    id: u64,
+    req: RequestMessage,
+    outgoing: UnboundedSender<TunnelFrame>,
+    mut from_server: UnboundedReceiver<TunnelFrame>,
+) {
+    let stream = match connect_local_websocket(&local, &req).await {
+        Ok((stream, protocol)) => {
+            info!(
+                "Tunnelling websocket {} {} to local origin",
+                req.method, req.fullpath
+            );
+            let _ = outgoing.send(TunnelFrame::Opened { id, protocol });
+            stream
+        }
+        Err(e) => {
+            error!("Tunnelled websocket error: {}", e);
+            let _ = outgoing.send(TunnelFrame::Close {
+                id,
+                code: None,
+                reason: e.to_string(),
+            });
+            return;
+        }
+    };
 
+    let (mut sink, mut source) = stream.split();
     loop {
         tokio::select! {
-            Some(message) = stream.next()  => {
-                match message? {
-                    Message::Binary(msg) => {
-                        let req : RequestMessage = rmp_serde::from_slice(&msg)?;
-                        HISTORY_DB.add(&history_db::Item::new(Utc::now(), req.clone())).await.unwrap();
-                        forward_request(req, local.clone(), http.clone());
-                    },
-                    Message::Close(_) => {
-                        info!("Server closed the connection");
+            message = source.next() => {
+                let frame = match message {
+                    Some(Ok(Message::Text(text))) => TunnelFrame::Data { id, text: true, data: text.into_bytes() },
+                    Some(Ok(Message::Binary(data))) => TunnelFrame::Data { id, text: false, data },
+                    Some(Ok(Message::Close(frame))) => {
+                        let _ = outgoing.send(TunnelFrame::Close {
+                            id,
+                            code: frame.as_ref().map(|f| f.code.into()),
+                            reason: frame.map(|f| f.reason.into_owned()).unwrap_or_default(),
+                        });
                         break;
-                    },
-                    _ => { }
+                    }
+                    Some(Ok(_)) => continue,
+                    Some(Err(_)) | None => {
+                        let _ = outgoing.send(TunnelFrame::Close { id, code: None, reason: String::new() });
+                        break;
+                    }
+                };
+                if outgoing.send(frame).is_err() {
+                    break;
                 }
             },
-            _ = interval.tick() => {
-                stream.send(Message::Ping(vec![5, 4, 3, 2, 1])).await?;
-            },
-            _ = shutdown.recv() => {
-                break;
+            frame = from_server.recv() => {
+                let message = match frame {
+                    Some(TunnelFrame::Data { text: true, data, .. }) => {
+                        Message::Text(String::from_utf8_lossy(&data).into_owned())
+                    }
+                    Some(TunnelFrame::Data { data, .. }) => Message::Binary(data),
+                    Some(TunnelFrame::Close { code, reason, .. }) => {
+                        let _ = sink.send(Message::Close(Some(CloseFrame {
+                            code: code.map_or(CloseCode::Normal, CloseCode::from),
+                            reason: reason.into(),
+                        }))).await;
+                        break;
+                    }
+                    Some(_) => continue,
+                    None => {
+                        let _ = sink.send(Message::Close(None)).await;
+                        break;
+                    }
+                };
+                if sink.send(message).await.is_err() {
+                    let _ = outgoing.send(TunnelFrame::Close { id, code: None, reason: String::new() });
+                    break;
+                }
             }
         }
     }
 
-    info!("Disconnected");
-    let _ = stream.close(None).await;
+    info!("Closed tunnelled websocket {}", req.fullpath);
+}
 
-    Ok(())
+/// Largest chunk of a TCP stream sent in one frame
+const TCP_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Connects to a local port for a TCP connection made to the server's forwarded `port` and
+/// pumps bytes between them until either closes
+async fn tcp_to_local(
+    addr: String,
+    id: u64,
+    port: u16,
+    outgoing: UnboundedSender<TunnelFrame>,
+    mut from_server: UnboundedReceiver<TunnelFrame>,
+) {
+    let mut stream = match tokio::net::TcpStream::connect(&addr).await {
+        Ok(stream) => {
+            info!("Forwarding TCP connection on port {} to {}", port, addr);
+            let _ = outgoing.send(TunnelFrame::Opened { id, protocol: None });
+            stream
+        }
+        Err(e) => {
+            error!("Forwarded TCP connection error: {}: {}", addr, e);
+            let _ = outgoing.send(TunnelFrame::Close {
+                id,
+                code: None,
+                reason: e.to_string(),
+            });
+            return;
+        }
+    };
+
+    let (mut reader, mut writer) = stream.split();
+    let mut buf = vec![0; TCP_CHUNK_SIZE];
+    loop {
+        tokio::select! {
+            read = reader.read(&mut buf) => {
+                let data = match read {
+                    Ok(0) | Err(_) => {
+                        let _ = outgoing.send(TunnelFrame::Close { id, code: None, reason: String::new() });
+                        break;
+                    }
+                    Ok(n) => buf[..n].to_vec(),
+                };
+                if outgoing.send(TunnelFrame::Data { id, text: false, data }).is_err() {
+                    break;
+                }
+            },
+            frame = from_server.recv() => match frame {
+                Some(TunnelFrame::Data { data, .. }) => {
+                    if writer.write_all(&data).await.is_err() {
+                        let _ = outgoing.send(TunnelFrame::Close { id, code: None, reason: String::new() });
+                        break;
+                    }
+                }
+                Some(TunnelFrame::Close { .. }) | None => break,
+                Some(_) => {}
+            }
+        }
+    }
+
+    info!("Closed forwarded TCP connection on port {}", port);
 }
 
-async fn interrupt_signal() {
-    tokio::signal::unix::signal(SignalKind::interrupt())
-        .expect("failed to install SIGINT handler")
-        .recv()
-        .await;
+/// Dials the local origin's websocket at the path a tunnelled upgrade asked for, returning the
+/// subprotocol it chose if any
+async fn connect_local_websocket(
+    local: &Url,
+    req: &RequestMessage,
+) -> Result<(WebSocketStream<ConnectStream>, Option<String>)> {
+    let mut local = local.clone();
+    let scheme = if local.scheme() == "https" {
+        "wss"
+    } else {
+        "ws"
+    };
+    local
+        .set_scheme(scheme)
+        .map_err(|_| anyhow!("local origin can't take websockets"))?;
+    match req.fullpath.split_once('?') {
+        Some((path, query)) => {
+            local.set_path(path);
+            local.set_query(Some(query));
+        }
+        None => local.set_path(&req.fullpath),
+    }
+
+    let mut request = local.as_str().into_client_request()?;
+    for (name, value) in req.headers.iter() {
+        if HANDSHAKE_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+        match HeaderValue::from_bytes(value.as_bytes()) {
+            // the host the client made the request with is replaced if the server preserved one
+            Ok(value) => {
+                let name = reqwest::header::HeaderName::from_bytes(name.as_bytes())?;
+                if name == reqwest::header::HOST {
+                    request.headers_mut().insert(name, value);
+                } else {
+                    request.headers_mut().append(name, value);
+                }
+            }
+            Err(_) => warn!(
+                "Dropped invalid value of header {} for {} {}",
+                name, req.method, req.fullpath
+            ),
+        }
+    }
+
+    let (stream, response) = connect_async(request).await.map_err(handshake_error)?;
+    let protocol = response
+        .headers()
+        .get("sec-websocket-protocol")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+
+    Ok((stream, protocol))
 }
 
 pub fn prepare_remote_url(remote: &mut Url) -> Result<()> {
@@ -230,14 +911,34 @@ pub fn prepare_remote_url(remote: &mut Url) -> Result<()> {
         return Err(anyhow::anyhow!("remote must use ws or wss scheme"));
     }
 
-    if remote.path() != "/" {
-        warn!("Remote path isn't supported and will always be /__hookhub__/");
-        remote.set_path("/__hookhub__/");
-    }
+    // the path is where the relay is mounted, usually the root
+    let mount = remote.path().trim_end_matches('/');
+    let mount = mount.strip_suffix("/__hookhub__").unwrap_or(mount);
+    remote.set_path(&format!("{}/__hookhub__/", mount));
 
     Ok(())
 }
 
+/// Where providers send requests for `path` on the server connected to at `remote`, at `host`
+/// instead of the remote's if given
+pub fn public_url(remote: &Url, host: Option<&str>, path: &str) -> Url {
+    let mut url = remote.clone();
+    let _ = url.set_scheme(match remote.scheme() {
+        "wss" => "https",
+        _ => "http",
+    });
+    if let Some(host) = host {
+        let _ = url.set_host(Some(host));
+    }
+
+    let mount = remote.path().trim_end_matches('/');
+    let mount = mount.strip_suffix("/__hookhub__").unwrap_or(mount);
+    url.set_path(&format!("{}{}", mount, path));
+    url.set_query(None);
+
+    url
+}
+
 pub fn prepare_local_url(local: &mut Url) -> Result<()> {
     if local.scheme() != "http" && local.scheme() != "https" {
         return Err(anyhow::anyhow!("local must use http or https scheme"));
@@ -251,49 +952,121 @@ pub fn prepare_local_url(local: &mut Url) -> Result<()> {
     Ok(())
 }
 
-fn forward_request(req: RequestMessage, mut local: Url, http: Client) -> JoinHandle<()> {
-    tokio::spawn(async move {
-        local.set_path(&req.fullpath);
+pub fn connect_request(remote: &Url, secret: &str) -> Result<Request> {
+    let mut request = remote.as_str().into_client_request()?;
+    let auth = STANDARD.encode(format!("{}:{}", VERSION, secret));
+    request
+        .headers_mut()
+        .insert("Authorization", format!("Basic {}", auth).parse()?);
+    request
+        .headers_mut()
+        .insert(PROTOCOL_HEADER, PROTOCOL_VERSION.into());
+
+    Ok(request)
+}
+
+/// Turns a failed websocket handshake into an error explaining what the server said
+pub fn handshake_error(error: WsError) -> anyhow::Error {
+    let WsError::Http(response) = error else {
+        return error.into();
+    };
+
+    let body = response
+        .body()
+        .as_ref()
+        .map(|b| String::from_utf8_lossy(b).into_owned())
+        .unwrap_or_default();
+
+    match response.status() {
+        StatusCode::UNAUTHORIZED => anyhow!("authentication failed, check the secret"),
+        status if body.is_empty() => anyhow!("server responded {}", status),
+        _ => anyhow!("{}", body),
+    }
+}
 
-        let start = Instant::now();
+/// Sends our `Hello` and waits for the server's `HelloAck`
+async fn greet<S>(stream: &mut S, hello: &Hello) -> Result<HelloAck>
+where
+    S: Stream<Item = Result<Message, WsError>> + Sink<Message, Error = WsError> + Unpin,
+{
+    stream
+        .send(Message::Binary(rmp_serde::to_vec_named(hello)?))
+        .await?;
 
-        let mut request_builder = http
-            .request(Method::from_bytes(req.method.as_bytes()).unwrap(), local)
-            .version(req.version.into());
+    let message = time::timeout(Duration::from_secs(10), stream.next())
+        .await
+        .map_err(|_| anyhow!("timed out waiting for the server to acknowledge hello"))?;
 
-        for (name, value) in req.headers.iter() {
-            request_builder = request_builder.header(name, value);
+    match message {
+        Some(Ok(Message::Binary(bytes))) => Ok(rmp_serde::from_slice(&bytes)?),
+        Some(Ok(Message::Close(Some(frame)))) if !frame.reason.is_empty() => {
+            Err(anyhow!("server refused the handshake: {}", frame.reason))
         }
+        Some(Ok(other)) => Err(anyhow!("expected hello acknowledgement, got {:?}", other)),
+        Some(Err(e)) => Err(e.into()),
+        None => Err(anyhow!("server closed the connection during the handshake")),
+    }
+}
+
+/// Sends a request to the local origin, which should have been through `prepare_local_url`
+pub async fn forward(
+    http: &reqwest::Client,
+    local: &Url,
+    req: &RequestMessage,
+) -> Result<Response> {
+    let method = Method::from_bytes(req.method.as_bytes())
+        .map_err(|_| anyhow!("invalid request method: {:?}", req.method))?;
 
-        if !req.body.is_empty() {
-            request_builder = request_builder.body(req.body)
+    let mut local = local.clone();
+    match req.fullpath.split_once('?') {
+        Some((path, query)) => {
+            local.set_path(path);
+            local.set_query(Some(query));
         }
+        None => local.set_path(&req.fullpath),
+    }
 
-        let request = request_builder.build().unwrap();
+    let mut request_builder = http.request(method, local);
+    // the version the provider used says nothing of what the local origin speaks, HTTP/2 or 3
+    // would fail against an HTTP/1 one, so only an HTTP/1.0 request is sent as it came
+    if req.version == Version::Http10 {
+        request_builder = request_builder.version(req.version.into());
+    }
 
-        match http.execute(request).await {
-            Ok(resp) => {
-                info!(
-                    "Forwarded request: {} {} - {:?} {:?}",
-                    req.method,
-                    req.fullpath,
-                    resp.status(),
-                    start.elapsed(),
-                );
-            }
-            Err(e) => {
-                error!("Forwarded request error: {}", e);
-            }
+    for (name, value) in req.headers.iter() {
+        match HeaderValue::from_bytes(value.as_bytes()) {
+            Ok(value) => request_builder = request_builder.header(name, value),
+            Err(_) => warn!(
+                "Dropped invalid value of header {} for {} {}",
+                name, req.method, req.fullpath
+            ),
         }
+    }
+
+    if !req.body.is_empty() {
+        request_builder = request_builder.body(req.body.clone())
+    }
 
-        
-    })
+    Ok(http.execute(request_builder.build()?).await?)
 }
 
+/// How long connecting to the local origin may take by default
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the local origin may go without sending anything by default
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub fn http_client() -> Result<reqwest::Client> {
+    http_client_with_timeouts(DEFAULT_CONNECT_TIMEOUT, DEFAULT_READ_TIMEOUT)
+}
+
+pub fn http_client_with_timeouts(
+    connect_timeout: Duration,
+    read_timeout: Duration,
+) -> Result<reqwest::Client> {
     let client = reqwest::Client::builder()
-        .connect_timeout(Duration::from_secs(10))
-        .read_timeout(Duration::from_secs(30))
+        .connect_timeout(connect_timeout)
+        .read_timeout(read_timeout)
         .build()?;
 
     Ok(client)
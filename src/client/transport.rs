@@ -0,0 +1,214 @@
+use std::{fs, io, io::BufReader, net::SocketAddr, path::Path, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use async_tungstenite::{
+    tokio::ConnectStream,
+    tungstenite::{
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Error as WsError, Message,
+    },
+    WebSocketStream,
+};
+use futures::prelude::*;
+use log::warn;
+use quinn::{
+    crypto::rustls::QuicClientConfig,
+    rustls::{self, crypto::ring},
+    Connection, ConnectionError, Endpoint, SendStream, VarInt,
+};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+use super::PING_INTERVAL;
+use crate::quic::{self, Login, MAX_MESSAGE_SIZE, UNAUTHORIZED};
+use crate::HelloAck;
+
+/// Normal closure, as on a websocket
+const CLOSE_NORMAL: u32 = 1000;
+
+/// How long closing waits for the server to acknowledge it
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What a connection to the server runs over, both carrying the same messages
+pub(super) enum Transport {
+    Websocket(Box<WebSocketStream<ConnectStream>>),
+    Quic(QuicTransport),
+}
+
+impl Transport {
+    /// The next message from the server, `None` once the connection's closed
+    pub async fn next(&mut self) -> Option<Result<Message, WsError>> {
+        match self {
+            Transport::Websocket(stream) => stream.next().await,
+            Transport::Quic(quic) => quic.incoming.recv().await,
+        }
+    }
+
+    pub async fn send(&mut self, message: Message) -> Result<()> {
+        match self {
+            Transport::Websocket(stream) => stream.send(message).await?,
+            Transport::Quic(quic) => match message {
+                Message::Binary(frame) => quic::write_frame(&mut quic.control, &frame).await?,
+                Message::Close(_) => quic.connection.close(VarInt::from_u32(CLOSE_NORMAL), b""),
+                // QUIC keeps the connection alive itself
+                _ => {}
+            },
+        }
+
+        Ok(())
+    }
+
+    pub async fn close(self) {
+        match self {
+            Transport::Websocket(mut stream) => {
+                let _ = stream.as_mut().close(None).await;
+            }
+            Transport::Quic(mut quic) => {
+                let _ = quic.control.finish();
+                quic.connection
+                    .close(VarInt::from_u32(CLOSE_NORMAL), b"client closing");
+                let _ = tokio::time::timeout(CLOSE_TIMEOUT, quic.endpoint.wait_idle()).await;
+            }
+        }
+    }
+}
+
+/// A connection over the experimental QUIC transport, with requests read from their streams as
+/// they arrive by a task of their own
+pub(super) struct QuicTransport {
+    endpoint: Endpoint,
+    connection: Connection,
+    /// Carries delivery reports to the server
+    control: SendStream,
+    incoming: UnboundedReceiver<Result<Message, WsError>>,
+}
+
+impl QuicTransport {
+    /// Connects to the server at `addr`, checking its certificate is for `server_name` against
+    /// the usual roots and `ca` if given, and logs in with `login`
+    pub async fn connect(
+        addr: &str,
+        server_name: &str,
+        ca: Option<&Path>,
+        login: &Login,
+    ) -> Result<(Self, HelloAck)> {
+        let addr: SocketAddr = tokio::net::lookup_host(addr)
+            .await
+            .with_context(|| format!("couldn't resolve {}", addr))?
+            .next()
+            .ok_or_else(|| anyhow!("{} didn't resolve to an address", addr))?;
+
+        let bind: SocketAddr = if addr.is_ipv6() {
+            "[::]:0".parse()?
+        } else {
+            "0.0.0.0:0".parse()?
+        };
+        let endpoint = Endpoint::client(bind)?;
+        let connection = endpoint
+            .connect_with(client_config(ca)?, addr, server_name)?
+            .await
+            .map_err(connection_error)?;
+
+        let (mut control, mut recv) = connection.open_bi().await.map_err(connection_error)?;
+        quic::write_frame(&mut control, &rmp_serde::to_vec_named(login)?).await?;
+
+        let ack = tokio::time::timeout(Duration::from_secs(10), quic::read_frame(&mut recv))
+            .await
+            .map_err(|_| anyhow!("timed out waiting for the server to acknowledge hello"))?;
+        let ack = match ack {
+            Ok(Some(frame)) => rmp_serde::from_slice(&frame)?,
+            Ok(None) => return Err(anyhow!("server closed the connection during the handshake")),
+            // the stream fails with the reason the server closed the connection
+            Err(e) => match e.downcast::<quinn::ReadExactError>() {
+                Ok(quinn::ReadExactError::ReadError(quinn::ReadError::ConnectionLost(e))) => {
+                    return Err(connection_error(e))
+                }
+                Ok(e) => return Err(e.into()),
+                Err(e) => return Err(e),
+            },
+        };
+
+        let (sender, incoming) = mpsc::unbounded_channel();
+        tokio::spawn(receive(connection.clone(), sender));
+
+        let transport = Self {
+            endpoint,
+            connection,
+            control,
+            incoming,
+        };
+
+        Ok((transport, ack))
+    }
+}
+
+fn client_config(ca: Option<&Path>) -> Result<quinn::ClientConfig> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Some(ca) = ca {
+        let file = fs::File::open(ca).with_context(|| format!("couldn't open {}", ca.display()))?;
+        for cert in rustls_pemfile::certs(&mut BufReader::new(file)) {
+            roots.add(cert.with_context(|| format!("invalid certificate in {}", ca.display()))?)?;
+        }
+    }
+
+    let mut tls = rustls::ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    tls.alpn_protocols = vec![quic::ALPN.to_vec()];
+
+    let mut transport = quinn::TransportConfig::default();
+    transport.keep_alive_interval(Some(PING_INTERVAL));
+
+    let mut config = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls)?));
+    config.transport_config(Arc::new(transport));
+
+    Ok(config)
+}
+
+/// Reads each request's stream as the server opens it, without waiting on the others, until
+/// the connection's closed
+async fn receive(connection: Connection, sender: mpsc::UnboundedSender<Result<Message, WsError>>) {
+    loop {
+        let mut stream = match connection.accept_uni().await {
+            Ok(stream) => stream,
+            Err(ConnectionError::ApplicationClosed(close)) => {
+                let _ = sender.send(Ok(Message::Close(Some(CloseFrame {
+                    code: CloseCode::from(close.error_code.into_inner() as u16),
+                    reason: String::from_utf8_lossy(&close.reason).into_owned().into(),
+                }))));
+                return;
+            }
+            Err(ConnectionError::LocallyClosed) => return,
+            Err(e) => {
+                let _ = sender.send(Err(WsError::Io(io::Error::other(e))));
+                return;
+            }
+        };
+
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            match stream.read_to_end(MAX_MESSAGE_SIZE).await {
+                Ok(frame) => {
+                    let _ = sender.send(Ok(Message::Binary(frame)));
+                }
+                Err(e) => warn!("Couldn't read a request from the server: {}", e),
+            }
+        });
+    }
+}
+
+/// Explains why the server closed the connection if it said, like a failed websocket handshake
+fn connection_error(error: ConnectionError) -> anyhow::Error {
+    match error {
+        ConnectionError::ApplicationClosed(close)
+            if close.error_code == VarInt::from_u32(UNAUTHORIZED) =>
+        {
+            anyhow!("authentication failed, check the secret")
+        }
+        ConnectionError::ApplicationClosed(close) if !close.reason.is_empty() => {
+            anyhow!("{}", String::from_utf8_lossy(&close.reason))
+        }
+        error => error.into(),
+    }
+}
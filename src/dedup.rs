@@ -0,0 +1,201 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use ring::digest;
+
+use crate::RequestMessage;
+
+/// What identifies two deliveries as the same webhook
+#[derive(Clone, Debug)]
+pub enum DedupKey {
+    /// The value of a header, e.g. `X-GitHub-Delivery`
+    Header(String),
+    /// A SHA-256 hash of the body
+    Body,
+}
+
+impl FromStr for DedupKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("header", name)) if !name.is_empty() => Ok(DedupKey::Header(name.to_lowercase())),
+            None if s == "body" => Ok(DedupKey::Body),
+            _ => Err("expected header:<name> or body".to_string()),
+        }
+    }
+}
+
+/// Remembers recently seen deliveries so repeats within a time window can be dropped
+pub struct Dedup {
+    key: DedupKey,
+    window: Duration,
+    seen: Mutex<Seen>,
+}
+
+struct Seen {
+    /// When each delivery was first seen
+    at: HashMap<String, Instant>,
+    /// When expired deliveries were last forgotten
+    swept: Instant,
+}
+
+impl Dedup {
+    pub fn new(key: DedupKey, window: Duration) -> Self {
+        Self {
+            key,
+            window,
+            seen: Mutex::new(Seen {
+                at: HashMap::new(),
+                swept: Instant::now(),
+            }),
+        }
+    }
+
+    /// Records the request, returning true if it was first seen within the window, so retries
+    /// don't keep it from expiring. Requests without the key (e.g. the header is missing) are
+    /// never duplicates.
+    pub fn is_duplicate(&self, req: &RequestMessage) -> bool {
+        self.is_duplicate_at(req, Instant::now())
+    }
+
+    fn is_duplicate_at(&self, req: &RequestMessage, now: Instant) -> bool {
+        let Some(key) = self.key_for(req) else {
+            return false;
+        };
+
+        let mut seen = self.seen.lock().unwrap();
+        // forgotten at most once a window rather than on every request, so each is kept for
+        // up to two windows
+        if now.duration_since(seen.swept) >= self.window {
+            let window = self.window;
+            seen.at.retain(|_, at| now.duration_since(*at) < window);
+            seen.swept = now;
+        }
+
+        match seen.at.entry(key) {
+            Entry::Occupied(mut first) if now.duration_since(*first.get()) >= self.window => {
+                first.insert(now);
+                false
+            }
+            Entry::Occupied(_) => true,
+            Entry::Vacant(entry) => {
+                entry.insert(now);
+                false
+            }
+        }
+    }
+
+    fn key_for(&self, req: &RequestMessage) -> Option<String> {
+        match &self.key {
+            DedupKey::Header(name) => req.header(name).map(|v| v.to_str_lossy().into_owned()),
+            DedupKey::Body => {
+                let hash = digest::digest(&digest::SHA256, &req.body);
+                Some(hash.as_ref().iter().map(|b| format!("{:02x}", b)).collect())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Version;
+
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    fn request(delivery: Option<&str>, body: &[u8]) -> RequestMessage {
+        RequestMessage {
+            method: "POST".to_string(),
+            fullpath: "/hook".to_string(),
+            version: Version::Http11,
+            headers: delivery
+                .map(|id| ("X-GitHub-Delivery".to_string(), id.into()))
+                .into_iter()
+                .collect(),
+            body: body.to_vec(),
+            source: None,
+        }
+    }
+
+    fn by_header() -> Dedup {
+        Dedup::new("header:X-GitHub-Delivery".parse().unwrap(), WINDOW)
+    }
+
+    #[test]
+    fn repeats_within_the_window_are_duplicates() {
+        let dedup = by_header();
+        let now = Instant::now();
+
+        assert!(!dedup.is_duplicate_at(&request(Some("a"), b""), now));
+        assert!(dedup.is_duplicate_at(&request(Some("a"), b"other"), now + WINDOW / 2));
+        assert!(!dedup.is_duplicate_at(&request(Some("b"), b""), now + WINDOW / 2));
+    }
+
+    #[test]
+    fn the_window_runs_from_when_a_delivery_was_first_seen() {
+        let dedup = by_header();
+        let now = Instant::now();
+        let a = request(Some("a"), b"");
+
+        assert!(!dedup.is_duplicate_at(&a, now));
+        assert!(dedup.is_duplicate_at(&a, now + WINDOW / 2));
+        // retried ever since, but it was first seen a window ago
+        assert!(!dedup.is_duplicate_at(&a, now + WINDOW));
+        assert!(dedup.is_duplicate_at(&a, now + WINDOW + WINDOW / 2));
+    }
+
+    #[test]
+    fn requests_without_the_key_are_never_duplicates() {
+        let dedup = by_header();
+        let now = Instant::now();
+
+        assert!(!dedup.is_duplicate_at(&request(None, b"x"), now));
+        assert!(!dedup.is_duplicate_at(&request(None, b"x"), now));
+    }
+
+    #[test]
+    fn compares_bodies_by_hash() {
+        let dedup = Dedup::new(DedupKey::Body, WINDOW);
+        let now = Instant::now();
+
+        assert!(!dedup.is_duplicate_at(&request(Some("a"), b"x"), now));
+        assert!(dedup.is_duplicate_at(&request(Some("b"), b"x"), now));
+        assert!(!dedup.is_duplicate_at(&request(Some("a"), b"y"), now));
+    }
+
+    #[test]
+    fn forgets_expired_deliveries_once_a_window() {
+        let dedup = by_header();
+        let now = Instant::now();
+        for id in ["a", "b", "c"] {
+            dedup.is_duplicate_at(&request(Some(id), b""), now);
+        }
+        dedup.is_duplicate_at(&request(Some("d"), b""), now + WINDOW / 2);
+        assert_eq!(dedup.seen.lock().unwrap().at.len(), 4);
+
+        dedup.is_duplicate_at(&request(Some("e"), b""), now + WINDOW);
+
+        let seen = dedup.seen.lock().unwrap();
+        let mut ids: Vec<_> = seen.at.keys().cloned().collect();
+        ids.sort();
+        assert_eq!(ids, ["d", "e"]);
+    }
+
+    #[test]
+    fn parses_keys() {
+        assert!(matches!("body".parse(), Ok(DedupKey::Body)));
+        assert!(matches!(
+            "header:X-Id".parse(),
+            Ok(DedupKey::Header(name)) if name == "x-id"
+        ));
+        for invalid in ["header:", "headers:x", "body:x", ""] {
+            assert!(invalid.parse::<DedupKey>().is_err(), "{}", invalid);
+        }
+    }
+}
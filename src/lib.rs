@@ -1,41 +1,587 @@
-use serde::{Deserialize, Serialize};
+pub mod alert;
+pub mod client;
+pub mod dedup;
+pub mod logging;
+pub mod output;
+pub mod plugin;
+pub mod quic;
+pub mod script;
+pub mod server;
+pub mod signature;
+pub mod tunnel;
+pub mod wire;
 
+use std::{borrow::Cow, collections::BTreeMap, fmt};
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use url::Url;
+
+/// Version of the websocket protocol, bumped whenever what's sent over it changes incompatibly
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest protocol version still spoken, a client and server use the newest one they share
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// First protocol version where the client opens with a `Hello` and the server answers with
+/// a `HelloAck`
+pub const HELLO_PROTOCOL_VERSION: u32 = 2;
+
+/// Header carrying the protocol version in the websocket handshake, in both directions
+pub const PROTOCOL_HEADER: &str = "x-hookhub-protocol";
+
+/// The protocol version to speak with a peer speaking `theirs`, peers that predate the
+/// protocol header speak version 1
+pub fn negotiate_protocol(theirs: Option<u32>) -> u32 {
+    theirs.unwrap_or(1).min(PROTOCOL_VERSION)
+}
+
+/// Optional behaviour a peer supports, only those both sides support are used
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    Compression,
+    AckDelivery,
+    ResponseRelay,
+    /// Websockets tunnelled to the local origin, see `tunnel`
+    Tunnel,
+    /// Raw TCP connections to the server's forwarded ports tunnelled to local ports, framed
+    /// like `Tunnel`
+    TcpForward,
+    /// `PeerEvent`s about the other clients connecting and disconnecting, sent as JSON text
+    /// frames
+    PeerEvents,
+    /// Anything added by a newer peer
+    #[serde(other)]
+    Unknown,
+}
+
+/// What this build supports
+pub const FEATURES: &[Feature] = &[
+    Feature::Compression,
+    Feature::AckDelivery,
+    Feature::Tunnel,
+    Feature::TcpForward,
+    Feature::PeerEvents,
+];
+
+/// Header the server adds to each relayed request, the id delivery reports refer to
+pub const DELIVERY_ID_HEADER: &str = "x-hookhub-delivery-id";
+
+/// Header the server adds to each relayed request, when it received it in milliseconds since
+/// the Unix epoch
+pub const RECEIVED_AT_HEADER: &str = "x-hookhub-received-at";
+
+/// How requests are encoded on the websocket, peers that don't negotiate one use msgpack
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WireFormat {
+    /// rmp-serde, which needs both ends to have identical struct layouts
+    #[default]
+    Msgpack,
+    /// CBOR with explicit field tags, see `wire`
+    Cbor,
+    /// Anything added by a newer peer
+    #[serde(other)]
+    Unknown,
+}
+
+/// Wire formats this build speaks, most preferred first
+pub const WIRE_FORMATS: &[WireFormat] = &[WireFormat::Cbor, WireFormat::Msgpack];
+
+/// First frame sent by the client. Hello and HelloAck are encoded with field names so fields can
+/// be added without breaking older peers.
 #[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Hello {
+    pub protocol: u32,
+    pub version: String,
+    #[serde(default)]
+    pub features: Vec<Feature>,
+    /// Wire formats the client speaks, most preferred first
+    #[serde(default)]
+    pub formats: Vec<WireFormat>,
+    /// Server ports the client forwards raw TCP from, with `Feature::TcpForward`
+    #[serde(default)]
+    pub tcp_ports: Vec<u16>,
+    /// Subdomain the client would like relayed to it, if the server routes by subdomain
+    #[serde(default)]
+    pub subdomain: Option<String>,
+    /// What the client is called, to tell sessions apart, e.g. `mikes-laptop`
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Labels the server's routing rules can pick the client by, e.g. `team=payments`
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// The server's answer to a `Hello`, with the features both sides support
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HelloAck {
+    pub protocol: u32,
+    pub version: String,
+    #[serde(default)]
+    pub features: Vec<Feature>,
+    /// Largest body the server accepts from providers
+    #[serde(default)]
+    pub max_body_size: Option<usize>,
+    /// Wire format requests will be sent in
+    #[serde(default)]
+    pub format: WireFormat,
+    /// Ports the server forwards raw TCP from
+    #[serde(default)]
+    pub tcp_ports: Vec<u16>,
+    /// Path on the server relayed to this client and no other, if the server gives each client
+    /// one
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Host relayed to this client and no other, if the server routes by subdomain
+    #[serde(default)]
+    pub host: Option<String>,
+    /// Where providers should send webhooks for this client, as the server is reached from
+    /// outside, e.g. `https://example.com/t/8f3ab2c01d9e`
+    #[serde(default)]
+    pub public_url: Option<Url>,
+}
+
+/// Server to client with `Feature::PeerEvents`: another session started or finished receiving
+/// requests from the same server
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PeerEvent {
+    Connected {
+        session: u64,
+        /// What the client said it's called
+        name: Option<String>,
+        /// How many clients requests for every client are delivered to now
+        clients: usize,
+    },
+    Disconnected {
+        session: u64,
+        name: Option<String>,
+        clients: usize,
+    },
+}
+
+impl PeerEvent {
+    /// The session it's about
+    pub fn session(&self) -> u64 {
+        match self {
+            PeerEvent::Connected { session, .. } | PeerEvent::Disconnected { session, .. } => {
+                *session
+            }
+        }
+    }
+}
+
+impl Hello {
+    pub fn new(protocol: u32) -> Self {
+        Self {
+            protocol,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            features: FEATURES.to_vec(),
+            formats: WIRE_FORMATS.to_vec(),
+            tcp_ports: vec![],
+            subdomain: None,
+            name: None,
+            labels: BTreeMap::new(),
+        }
+    }
+
+    /// The client's most preferred wire format that this build also speaks
+    pub fn wire_format(&self) -> WireFormat {
+        self.formats
+            .iter()
+            .find(|f| WIRE_FORMATS.contains(f))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The features both this build and the peer that sent the hello support
+    pub fn shared_features(&self) -> Vec<Feature> {
+        FEATURES
+            .iter()
+            .filter(|f| self.features.contains(f))
+            .copied()
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RequestMessage {
     pub method: String,
     pub fullpath: String,
     pub version: Version,
-    pub headers: Vec<(String, String)>,
+    /// In the order received, including repeated headers
+    pub headers: Vec<(String, HeaderBytes)>,
     pub body: Vec<u8>,
+    /// Where the request came from, from servers new enough to say
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<RequestSource>,
+}
+
+/// Where a request came from as the server saw it, so the client can pass it on in proxy
+/// headers. Only sent in CBOR, msgpack peers need the struct layout they know.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestSource {
+    /// Address of the provider, taking trusted proxies into account
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    /// Address the connection to the server came from, the proxy's when there's one in front
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<String>,
+    /// Whether the provider's connection used TLS, to the server or the trusted proxy in front
+    /// of it. The server doesn't terminate TLS itself, so it doesn't see SNI or ALPN.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<bool>,
+    /// `http` or `https`, as the request reached the server or the trusted proxy in front of it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scheme: Option<String>,
+    /// The `Host` the request was sent to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
 }
 
-// this is annoying.
+impl RequestMessage {
+    /// The first value of a header, matched case insensitively
+    pub fn header(&self, name: &str) -> Option<&HeaderBytes> {
+        find_header(&self.headers, name)
+    }
 
+    /// The id the server gave the request, if it's new enough to give one
+    pub fn delivery_id(&self) -> Option<u64> {
+        self.header(DELIVERY_ID_HEADER)?.to_str()?.parse().ok()
+    }
+}
+
+/// Sent by the client once it's done with a request, when both sides support `AckDelivery`.
+/// Encoded with field names like `Hello`.
 #[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct Version(u32);
+pub struct DeliveryReport {
+    pub request_id: u64,
+    pub outcome: DeliveryOutcome,
+    /// What the local origin responded with, if it was reached
+    #[serde(default)]
+    pub status: Option<u16>,
+    #[serde(default)]
+    pub duration_ms: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryOutcome {
+    /// Reached the local origin, whatever it responded
+    Forwarded,
+    /// The local origin couldn't be reached
+    Failed,
+    /// Dropped by a script or plugin
+    Dropped,
+    /// Not forwarded, e.g. a duplicate, paused or recording only
+    Skipped,
+    /// Anything added by a newer peer
+    #[serde(other)]
+    Unknown,
+}
+
+/// A request as JSON for plugins and commands, with the body base64 encoded so binary bodies
+/// survive
+#[derive(Serialize, Deserialize)]
+pub struct JsonRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, HeaderBytes)>,
+    pub body: String,
+    /// Where the request came from, when the server said. Not changed by `apply`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<RequestSource>,
+}
+
+impl From<&RequestMessage> for JsonRequest {
+    fn from(req: &RequestMessage) -> Self {
+        Self {
+            method: req.method.clone(),
+            path: req.fullpath.clone(),
+            headers: req.headers.clone(),
+            body: STANDARD.encode(&req.body),
+            source: req.source.clone(),
+        }
+    }
+}
+
+impl JsonRequest {
+    /// Replaces the request's method, path, headers and body with these
+    pub fn apply(self, req: &mut RequestMessage) -> anyhow::Result<()> {
+        if !is_valid_method(&self.method) {
+            anyhow::bail!("invalid method: {:?}", self.method);
+        }
+        req.body = STANDARD
+            .decode(self.body)
+            .map_err(|e| anyhow::anyhow!("body isn't base64: {}", e))?;
+        req.method = self.method;
+        req.fullpath = self.path;
+        req.headers = self.headers;
+
+        Ok(())
+    }
+}
+
+/// Whether a request can be sent with this method, i.e. it's a valid HTTP token
+pub fn is_valid_method(method: &str) -> bool {
+    http::Method::from_bytes(method.as_bytes()).is_ok()
+}
+
+/// The first value of a header in a list of headers, matched case insensitively
+pub fn find_header<'a>(
+    headers: &'a [(String, HeaderBytes)],
+    name: &str,
+) -> Option<&'a HeaderBytes> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v)
+}
+
+/// A header value as raw bytes, as they aren't required to be UTF-8. Values that are UTF-8 are
+/// serialized as strings, which keeps them readable in history and compatible with older peers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeaderBytes(pub Vec<u8>);
+
+impl HeaderBytes {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn to_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.0).ok()
+    }
+
+    pub fn to_str_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+}
+
+impl From<&str> for HeaderBytes {
+    fn from(value: &str) -> Self {
+        HeaderBytes(value.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for HeaderBytes {
+    fn from(value: String) -> Self {
+        HeaderBytes(value.into_bytes())
+    }
+}
+
+impl Serialize for HeaderBytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.to_str() {
+            Some(s) => serializer.serialize_str(s),
+            None => serializer.serialize_bytes(&self.0),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for HeaderBytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = HeaderBytes;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a string or bytes")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                Ok(v.into())
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+                Ok(HeaderBytes(v.to_vec()))
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut bytes = vec![];
+                while let Some(b) = seq.next_element()? {
+                    bytes.push(b);
+                }
+                Ok(HeaderBytes(bytes))
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+/// HTTP version of a relayed request. It's sent as a number, keeping the numbering the client
+/// always decoded, so messages stay compatible with older peers. Unknown numbers fall back to
+/// HTTP/1.1.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "u32", into = "u32")]
+pub enum Version {
+    Http09,
+    Http10,
+    #[default]
+    Http11,
+    Http2,
+    Http3,
+}
+
+impl From<u32> for Version {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => Version::Http09,
+            1 => Version::Http10,
+            3 => Version::Http2,
+            4 => Version::Http3,
+            _ => Version::Http11,
+        }
+    }
+}
+
+impl From<Version> for u32 {
+    fn from(value: Version) -> Self {
+        match value {
+            Version::Http09 => 0,
+            Version::Http10 => 1,
+            Version::Http11 => 2,
+            Version::Http2 => 3,
+            Version::Http3 => 4,
+        }
+    }
+}
 
 impl From<actix_web::http::Version> for Version {
     fn from(value: actix_web::http::Version) -> Self {
-        Version(match value {
-            actix_web::http::Version::HTTP_09 => 0,
-            actix_web::http::Version::HTTP_10 => 1,
-            actix_web::http::Version::HTTP_11 => 2,
-            actix_web::http::Version::HTTP_2 => 2,
-            actix_web::http::Version::HTTP_3 => 3,
-            _ => panic!("unknown version: {:?}", value),
-        })
+        match value {
+            actix_web::http::Version::HTTP_09 => Version::Http09,
+            actix_web::http::Version::HTTP_10 => Version::Http10,
+            actix_web::http::Version::HTTP_2 => Version::Http2,
+            actix_web::http::Version::HTTP_3 => Version::Http3,
+            _ => Version::Http11,
+        }
     }
 }
 
 impl From<Version> for http::Version {
     fn from(value: Version) -> Self {
-        match value.0 {
-            0 => http::Version::HTTP_09,
-            1 => http::Version::HTTP_10,
-            2 => http::Version::HTTP_11,
-            3 => http::Version::HTTP_2,
-            4 => http::Version::HTTP_3,
-            _ => panic!("unknown version: {:?}", value),
+        match value {
+            Version::Http09 => http::Version::HTTP_09,
+            Version::Http10 => http::Version::HTTP_10,
+            Version::Http11 => http::Version::HTTP_11,
+            Version::Http2 => http::Version::HTTP_2,
+            Version::Http3 => http::Version::HTTP_3,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VERSIONS: [(Version, u32); 5] = [
+        (Version::Http09, 0),
+        (Version::Http10, 1),
+        (Version::Http11, 2),
+        (Version::Http2, 3),
+        (Version::Http3, 4),
+    ];
+
+    #[test]
+    fn versions_keep_their_numbers() {
+        for (version, number) in VERSIONS {
+            assert_eq!(u32::from(version), number);
+            assert_eq!(Version::from(number), version);
+            assert_eq!(serde_json::to_string(&version).unwrap(), number.to_string());
         }
     }
+
+    #[test]
+    fn unknown_versions_fall_back_to_http11() {
+        assert_eq!(Version::from(5), Version::Http11);
+        assert_eq!(Version::from(u32::MAX), Version::Http11);
+        assert_eq!(
+            rmp_serde::from_slice::<Version>(&rmp_serde::to_vec(&99u32).unwrap()).unwrap(),
+            Version::Http11
+        );
+    }
+
+    #[test]
+    fn utf8_header_values_serialize_as_strings() {
+        let value = HeaderBytes::from("application/json");
+
+        assert_eq!(
+            serde_json::to_string(&value).unwrap(),
+            "\"application/json\""
+        );
+        assert_eq!(
+            rmp_serde::from_slice::<String>(&rmp_serde::to_vec(&value).unwrap()).unwrap(),
+            "application/json"
+        );
+    }
+
+    #[test]
+    fn non_utf8_header_values_round_trip() {
+        let value = HeaderBytes(vec![0xff, 0xfe, b'x']);
+
+        let msgpack = rmp_serde::to_vec(&value).unwrap();
+        assert_eq!(
+            rmp_serde::from_slice::<HeaderBytes>(&msgpack).unwrap(),
+            value
+        );
+
+        // JSON has no bytes, they're written as an array of numbers
+        let json = serde_json::to_string(&value).unwrap();
+        assert_eq!(json, "[255,254,120]");
+        assert_eq!(serde_json::from_str::<HeaderBytes>(&json).unwrap(), value);
+    }
+
+    #[test]
+    fn json_requests_apply() {
+        let mut req = RequestMessage {
+            method: "GET".to_string(),
+            fullpath: "/".to_string(),
+            version: Version::Http11,
+            headers: vec![],
+            body: vec![],
+            source: None,
+        };
+        let json = JsonRequest {
+            method: "POST".to_string(),
+            path: "/hooks".to_string(),
+            headers: vec![("X-Binary".to_string(), HeaderBytes(vec![0xff]))],
+            body: STANDARD.encode(b"\x00\x01"),
+            source: None,
+        };
+
+        json.apply(&mut req).unwrap();
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.fullpath, "/hooks");
+        assert_eq!(
+            req.headers,
+            vec![("X-Binary".to_string(), HeaderBytes(vec![0xff]))]
+        );
+        assert_eq!(req.body, vec![0, 1]);
+    }
+
+    #[test]
+    fn json_requests_with_invalid_methods_are_rejected() {
+        let mut req = RequestMessage {
+            method: "GET".to_string(),
+            fullpath: "/".to_string(),
+            version: Version::Http11,
+            headers: vec![],
+            body: vec![],
+            source: None,
+        };
+        let json = JsonRequest {
+            method: "NOT VALID".to_string(),
+            path: "/".to_string(),
+            headers: vec![],
+            body: String::new(),
+            source: None,
+        };
+
+        assert!(json.apply(&mut req).is_err());
+        assert_eq!(req.method, "GET");
+    }
 }
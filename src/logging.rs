@@ -0,0 +1,47 @@
+use std::io::Write;
+
+use chrono::Utc;
+use clap::ValueEnum;
+use env_logger::Env;
+use log::kv::{self, Key, Value, VisitSource};
+use serde_json::Map;
+
+#[derive(ValueEnum, Clone, Copy, Default, Debug)]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, including any structured event fields
+    Json,
+}
+
+pub fn init(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_env(Env::default().default_filter_or("info"));
+
+    if let LogFormat::Json = format {
+        builder.format(|buf, record| {
+            let mut line = Map::new();
+            line.insert("timestamp".into(), Utc::now().to_rfc3339().into());
+            line.insert("level".into(), record.level().as_str().into());
+            line.insert("target".into(), record.target().into());
+            line.insert("message".into(), record.args().to_string().into());
+
+            let _ = record.key_values().visit(&mut Fields(&mut line));
+
+            writeln!(buf, "{}", serde_json::Value::Object(line))
+        });
+    }
+
+    builder.init();
+}
+
+struct Fields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = serde_json::to_value(value).map_err(kv::Error::boxed)?;
+        self.0.insert(key.to_string(), value);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,62 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
+
+#[derive(ValueEnum, Clone, Copy, Default, Debug)]
+pub enum OutputFormat {
+    /// Aligned columns with a header row
+    #[default]
+    Table,
+    /// Tab separated columns without a header row
+    Plain,
+    /// A JSON array of objects
+    Json,
+}
+
+/// Prints rows to stdout in the requested format, `row` picks the columns shown in table and
+/// plain output while json output serializes each item in full
+pub fn print<T: Serialize>(
+    format: OutputFormat,
+    headers: &[&str],
+    items: &[T],
+    row: impl Fn(&T) -> Vec<String>,
+) -> Result<()> {
+    match format {
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(items)?);
+        }
+        OutputFormat::Plain => {
+            for item in items {
+                println!("{}", row(item).join("\t"));
+            }
+        }
+        OutputFormat::Table => {
+            let rows: Vec<Vec<String>> = items.iter().map(row).collect();
+
+            let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+            for row in rows.iter() {
+                for (i, cell) in row.iter().enumerate() {
+                    widths[i] = widths[i].max(cell.len());
+                }
+            }
+
+            let line = |cells: Vec<String>| {
+                cells
+                    .iter()
+                    .zip(widths.iter())
+                    .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                    .collect::<Vec<_>>()
+                    .join("  ")
+                    .trim_end()
+                    .to_string()
+            };
+
+            println!("{}", line(headers.iter().map(|h| h.to_string()).collect()));
+            for row in rows {
+                println!("{}", line(row));
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,100 @@
+//! WASM plugins that filter and transform requests before the client forwards them, loaded with
+//! extism so they can be written in any language with an extism PDK.
+//!
+//! A plugin exports `filter_request`, `transform_request` or both. Each is called with a request
+//! as JSON, `{"method", "path", "headers": [[name, value]], "body"}` with the body base64
+//! encoded. `filter_request` returns `true` to forward the request or `false` to drop it,
+//! `transform_request` returns the request to forward in the same shape.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, Context, Result};
+use extism::{Manifest, Wasm};
+
+use crate::{JsonRequest, RequestMessage};
+
+const FILTER: &str = "filter_request";
+const TRANSFORM: &str = "transform_request";
+
+pub struct Plugin {
+    path: PathBuf,
+    plugin: Mutex<extism::Plugin>,
+    filter: bool,
+    transform: bool,
+}
+
+impl Plugin {
+    pub fn load(path: &Path) -> Result<Self> {
+        let manifest = Manifest::new([Wasm::file(path)]);
+        let plugin = extism::Plugin::new(&manifest, [], true)
+            .map_err(|e| anyhow!("could not load plugin {}: {:#}", path.display(), e))?;
+
+        let filter = plugin.function_exists(FILTER);
+        let transform = plugin.function_exists(TRANSFORM);
+        if !filter && !transform {
+            return Err(anyhow!(
+                "plugin {} exports neither {} nor {}",
+                path.display(),
+                FILTER,
+                TRANSFORM
+            ));
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            plugin: Mutex::new(plugin),
+            filter,
+            transform,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Runs the request through the plugin, `None` if it should be dropped
+    pub fn apply(&self, mut req: RequestMessage) -> Result<Option<RequestMessage>> {
+        if self.filter {
+            let forward: bool = serde_json::from_slice(&self.call(FILTER, &req)?)
+                .with_context(|| format!("{} didn't return true or false", FILTER))?;
+            if !forward {
+                return Ok(None);
+            }
+        }
+
+        if self.transform {
+            let output: JsonRequest = serde_json::from_slice(&self.call(TRANSFORM, &req)?)
+                .with_context(|| format!("{} didn't return a request", TRANSFORM))?;
+
+            output
+                .apply(&mut req)
+                .with_context(|| format!("{} returned an invalid request", TRANSFORM))?;
+        }
+
+        Ok(Some(req))
+    }
+
+    fn call(&self, function: &str, req: &RequestMessage) -> Result<Vec<u8>> {
+        let input = serde_json::to_vec(&JsonRequest::from(req))?;
+
+        let mut plugin = self.plugin.lock().unwrap();
+        let output: &[u8] = plugin
+            .call(function, input)
+            .map_err(|e| anyhow!("{} failed: {:#}", function, e))?;
+
+        Ok(output.to_vec())
+    }
+}
+
+/// Runs the request through each plugin in turn, `None` once one of them drops it
+pub fn apply_all(plugins: &[Plugin], req: RequestMessage) -> Result<Option<RequestMessage>> {
+    plugins.iter().try_fold(Some(req), |req, plugin| match req {
+        Some(req) => plugin
+            .apply(req)
+            .with_context(|| format!("plugin {}", plugin.path().display())),
+        None => Ok(None),
+    })
+}
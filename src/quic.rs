@@ -0,0 +1,62 @@
+//! The experimental QUIC transport between clients and servers, an alternative to the websocket
+//! that survives changing networks and doesn't hold every request up behind a lost packet. The
+//! client opens a control stream and logs in with its secret and `Hello`, the server answers
+//! with its `HelloAck` and sends delivery reports back on it. Each relayed request arrives on a
+//! unidirectional stream of its own, encoded as it would be for a websocket.
+//!
+//! Frames on the control stream are msgpack, each preceded by its length as a big endian u32.
+//! Connections are closed with the websocket close code that means the same, or `UNAUTHORIZED`.
+
+use anyhow::{anyhow, Result};
+use quinn::{RecvStream, SendStream};
+use serde::{Deserialize, Serialize};
+
+use crate::Hello;
+
+/// ALPN protocol negotiated for the transport
+pub const ALPN: &[u8] = b"hookhub";
+
+/// Application error code a connection is closed with when the login isn't accepted
+pub const UNAUTHORIZED: u32 = 4401;
+
+/// Largest frame on the control stream
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// Largest request read from a stream, far more than any server accepts
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// The client's first frame on the control stream
+#[derive(Serialize, Deserialize)]
+pub struct Login {
+    pub password: String,
+    pub hello: Hello,
+}
+
+pub async fn write_frame(stream: &mut SendStream, frame: &[u8]) -> Result<()> {
+    stream
+        .write_all(&(frame.len() as u32).to_be_bytes())
+        .await?;
+    stream.write_all(frame).await?;
+
+    Ok(())
+}
+
+/// The next frame on the control stream, `None` once the peer's finished it
+pub async fn read_frame(stream: &mut RecvStream) -> Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match stream.read_exact(&mut len).await {
+        Ok(()) => {}
+        Err(quinn::ReadExactError::FinishedEarly(0)) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(anyhow!("frame of {} bytes is too large", len));
+    }
+
+    let mut frame = vec![0; len];
+    stream.read_exact(&mut frame).await?;
+
+    Ok(Some(frame))
+}
@@ -0,0 +1,227 @@
+//! Rhai scripts that filter and transform requests, a lighter weight alternative to plugins.
+//!
+//! A script defines `accept(req)`, returning false to drop a request, `transform(req)`,
+//! returning the request to use instead, or both. `req` is a map of `method`, `path`, `headers`
+//! (a map of lowercase names to values, repeated headers joined with ", ") and `body` (a string,
+//! or a blob when it isn't UTF-8). Headers a transform leaves alone are forwarded as they came,
+//! only those it changes are replaced by its value.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use rhai::{Blob, Dynamic, Engine, Map, Scope, AST};
+
+use crate::{is_valid_method, HeaderBytes, RequestMessage, RequestSource};
+
+const ACCEPT: &str = "accept";
+const TRANSFORM: &str = "transform";
+
+/// Stops a runaway script, e.g. one stuck in a loop, from holding up every request
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+pub struct Script {
+    path: PathBuf,
+    engine: Engine,
+    ast: AST,
+    accept: bool,
+    transform: bool,
+}
+
+impl Script {
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|e| anyhow!("could not load script {}: {}", path.display(), e))?;
+
+        let defines = |name: &str| {
+            ast.iter_functions()
+                .any(|f| f.name == name && f.params.len() == 1)
+        };
+        let accept = defines(ACCEPT);
+        let transform = defines(TRANSFORM);
+        if !accept && !transform {
+            return Err(anyhow!(
+                "script {} defines neither {}(req) nor {}(req)",
+                path.display(),
+                ACCEPT,
+                TRANSFORM
+            ));
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            engine,
+            ast,
+            accept,
+            transform,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Runs the request through the script, `None` if it should be dropped
+    pub fn apply(&self, mut req: RequestMessage) -> Result<Option<RequestMessage>> {
+        if self.accept && !self.call::<bool>(ACCEPT, &req)? {
+            return Ok(None);
+        }
+
+        if self.transform {
+            let output = self
+                .call::<Dynamic>(TRANSFORM, &req)?
+                .try_cast::<Map>()
+                .ok_or_else(|| anyhow!("{} didn't return a map", TRANSFORM))?;
+
+            from_map(&mut req, output)?;
+        }
+
+        Ok(Some(req))
+    }
+
+    fn call<T: Clone + Send + Sync + 'static>(
+        &self,
+        function: &str,
+        req: &RequestMessage,
+    ) -> Result<T> {
+        self.engine
+            .call_fn::<T>(&mut Scope::new(), &self.ast, function, (to_map(req),))
+            .map_err(|e| anyhow!("{} failed: {}", function, e))
+    }
+}
+
+/// Runs the request through each script in turn, `None` once one of them drops it
+pub fn apply_all(scripts: &[Script], req: RequestMessage) -> Result<Option<RequestMessage>> {
+    scripts.iter().try_fold(Some(req), |req, script| match req {
+        Some(req) => script
+            .apply(req)
+            .with_context(|| format!("script {}", script.path().display())),
+        None => Ok(None),
+    })
+}
+
+/// Lowercase header names to their values, repeated headers joined with ", "
+fn header_map(headers: &[(String, HeaderBytes)]) -> Map {
+    let mut map = Map::new();
+    for (name, value) in headers.iter() {
+        let value = value.to_str_lossy();
+        match map.get_mut(name.to_lowercase().as_str()) {
+            Some(existing) => *existing = format!("{}, {}", existing, value).into(),
+            None => {
+                map.insert(name.to_lowercase().into(), value.into_owned().into());
+            }
+        }
+    }
+    map
+}
+
+fn to_map(req: &RequestMessage) -> Map {
+    let headers = header_map(&req.headers);
+
+    let body = match std::str::from_utf8(&req.body) {
+        Ok(body) => body.into(),
+        Err(_) => Dynamic::from_blob(req.body.clone()),
+    };
+
+    let mut map = Map::new();
+    map.insert("method".into(), req.method.clone().into());
+    map.insert("path".into(), req.fullpath.clone().into());
+    map.insert("headers".into(), headers.into());
+    map.insert("body".into(), body);
+    if let Some(source) = &req.source {
+        map.insert("source".into(), source_map(source).into());
+    }
+    map
+}
+
+/// What's known of where the request came from, for scripts checking source addresses
+fn source_map(source: &RequestSource) -> Map {
+    let mut map = Map::new();
+    let text = [
+        ("ip", &source.ip),
+        ("peer", &source.peer),
+        ("scheme", &source.scheme),
+        ("host", &source.host),
+    ];
+    for (name, value) in text {
+        if let Some(value) = value {
+            map.insert(name.into(), value.clone().into());
+        }
+    }
+    if let Some(tls) = source.tls {
+        map.insert("tls".into(), tls.into());
+    }
+    map
+}
+
+/// The headers a script returned, keeping the ones it left alone as they were, with their case
+/// and each of a repeated header's values, and replacing only those it changed, added or removed
+fn merge_headers(original: &[(String, HeaderBytes)], returned: Map) -> Vec<(String, HeaderBytes)> {
+    let before = header_map(original);
+    let unchanged = |name: &str| {
+        before
+            .get(name)
+            .zip(returned.get(name))
+            .is_some_and(|(before, after)| before.to_string() == after.to_string())
+    };
+
+    let mut headers = vec![];
+    let mut changed = vec![];
+    for (name, value) in original {
+        let lowercase = name.to_lowercase();
+        if unchanged(&lowercase) {
+            headers.push((name.clone(), value.clone()));
+        } else if !changed.contains(&lowercase) {
+            // in place of the first of them, gone if the script removed it
+            if let Some(value) = returned.get(lowercase.as_str()) {
+                headers.push((lowercase.clone(), HeaderBytes::from(value.to_string())));
+            }
+            changed.push(lowercase);
+        }
+    }
+    for (name, value) in returned.iter() {
+        if !before.contains_key(name) {
+            headers.push((name.to_string(), HeaderBytes::from(value.to_string())));
+        }
+    }
+
+    headers
+}
+
+fn from_map(req: &mut RequestMessage, mut map: Map) -> Result<()> {
+    let mut string = |name: &str| -> Result<String> {
+        map.remove(name)
+            .ok_or_else(|| anyhow!("returned request has no {}", name))?
+            .into_string()
+            .map_err(|t| anyhow!("returned {} is a {}, not a string", name, t))
+    };
+    req.method = string("method")?;
+    if !is_valid_method(&req.method) {
+        return Err(anyhow!(
+            "returned method {:?} isn't a valid method",
+            req.method
+        ));
+    }
+    req.fullpath = string("path")?;
+
+    let headers = map
+        .remove("headers")
+        .ok_or_else(|| anyhow!("returned request has no headers"))?
+        .try_cast::<Map>()
+        .ok_or_else(|| anyhow!("returned headers aren't a map"))?;
+    req.headers = merge_headers(&req.headers, headers);
+
+    let body = map
+        .remove("body")
+        .ok_or_else(|| anyhow!("returned request has no body"))?;
+    req.body = if body.is_blob() {
+        body.cast::<Blob>()
+    } else {
+        body.to_string().into_bytes()
+    };
+
+    Ok(())
+}
@@ -1,102 +1,1619 @@
-use std::{ops::Deref, sync::LazyLock, time::Duration};
+//! The relay server: accepts webhooks on any path and broadcasts them to connected clients over
+//! websockets.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock, RwLock,
+    },
+    time::{Duration, Instant},
+};
 
 use actix_web::{
-    dev::{ConnectionInfo, ServiceRequest},
-    get,
+    dev::{ConnectionInfo, ServerHandle, ServiceRequest},
+    get, guard,
+    http::{
+        header::{HeaderName, HeaderValue, SEC_WEBSOCKET_PROTOCOL},
+        StatusCode,
+    },
     middleware::Logger,
     web::{self, Data},
-    App, HttpRequest, HttpResponse, HttpServer, Responder,
+    App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Resource, Responder, Scope,
 };
 use actix_web_httpauth::{
     extractors::{basic::BasicAuth, AuthenticationError},
     headers::www_authenticate::basic::Basic,
     middleware::HttpAuthentication,
 };
-use actix_ws::Message;
-use env_logger::Env;
+use actix_ws::{CloseCode, CloseReason, Message};
+use chrono::{DateTime, Utc};
 use futures_util::StreamExt as _;
-use hookhub::RequestMessage;
-use log::{info, warn};
-use tokio::sync::broadcast;
+use ipnet::IpNet;
+use log::{debug, info, warn};
+use serde::Deserialize;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc::UnboundedReceiver,
+        oneshot,
+    },
+};
+use url::Url;
+
+use crate::{
+    alert::Alerter,
+    dedup::{Dedup, DedupKey},
+    negotiate_protocol,
+    signature::{self, Verifier},
+    tunnel::{self, Frame, TunnelFrame},
+    wire, DeliveryReport, Feature, HeaderBytes, Hello, HelloAck, PeerEvent, RequestMessage,
+    RequestSource, WireFormat, DELIVERY_ID_HEADER, HELLO_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION,
+    PROTOCOL_HEADER, PROTOCOL_VERSION, RECEIVED_AT_HEADER,
+};
+use access::Access;
+use bus::{Bus, Stream};
+use claims::ClaimStore;
+use filters::{Filters, Verdict};
+use headers::HeaderFilter;
+use queue::{Broadcaster, Overflow};
+use rate_limit::{Limit, RateLimiter};
+use recent::{Delivery, Outcome, Recent};
+use request_log::RequestLog;
+use responses::{ResponseRule, Responses};
+use sessions::{Disconnect, Exclusive, SessionInfo, Sessions};
+use stats::Stats;
+use subdomains::{Claim, Subdomains};
+use throttle::{Quota, Throttle, ThrottlePolicy};
+use tokens::TokenStore;
+use tunnels::{Tunnel, Tunnels};
+
+pub mod access;
+pub mod bus;
+pub mod claims;
+mod dashboard;
+mod events;
+pub mod filters;
+pub mod headers;
+mod paths;
+pub mod queue;
+mod quic;
+pub mod rate_limit;
+pub mod recent;
+pub mod request_log;
+pub mod responses;
+pub mod sessions;
+pub mod stats;
+mod subdomains;
+mod systemd;
+mod tcp;
+pub mod throttle;
+pub mod tokens;
+pub mod tunnels;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+const ALERT_QUIET_PERIOD: Duration = Duration::from_secs(60);
+
+/// How long a client may take to open a tunnelled websocket or TCP connection locally
+const TUNNEL_OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Peer events kept for a client that's slow to take them, older ones are skipped
+const PEER_EVENTS_CAPACITY: usize = 16;
+
+/// Why a session's closed when another client connects with its credential
+const REPLACED: &str = "replaced by another client using the same credential";
+
+/// Address listened on when none are given
+pub const DEFAULT_BIND_ADDR: &str = "127.0.0.1:9873";
+
+/// Secret accepted when no secrets, tokens or authenticator are configured
+pub const DEFAULT_SECRET: &str = "abc123";
+
+/// Called with every request that's about to be relayed
+pub type Hook = Arc<dyn Fn(&RequestMessage) + Send + Sync>;
+
+/// Builds the server again with fresh settings, see `Server::reload_with`
+pub type Reload = Arc<dyn Fn() -> anyhow::Result<Server> + Send + Sync>;
+
+/// Checks a client's password, returning a name for the credential it matched
+pub type Authenticator = Arc<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+/// Builds and runs a relay server, the defaults match the `server` binary's
+pub struct Server {
+    bind_addrs: Vec<String>,
+    secrets: Vec<String>,
+    admin_secrets: Vec<String>,
+    tokens_file: Option<PathBuf>,
+    authenticator: Option<Authenticator>,
+    no_client_status: StatusCode,
+    response: ResponseRule,
+    response_rules: Option<PathBuf>,
+    slack_url_verification: bool,
+    verify_signatures: Vec<Verifier>,
+    dedup: Option<(DedupKey, Duration)>,
+    allow_cidrs: Vec<IpNet>,
+    trusted_proxies: Vec<IpNet>,
+    deny_headers: Vec<String>,
+    allow_headers: Vec<String>,
+    preserve_host: bool,
+    filter_rules: Option<PathBuf>,
+    sample_rate: Option<f64>,
+    rate_limit: Option<Limit>,
+    ip_rate_limit: Option<Limit>,
+    session_quota: Quota,
+    queue_capacity: usize,
+    queue_overflow: Overflow,
+    max_body_size: usize,
+    ingest_prefix: Option<String>,
+    client_paths: bool,
+    base_domain: Option<String>,
+    reserved_subdomains: Vec<String>,
+    claims_file: Option<PathBuf>,
+    public_url: Option<Url>,
+    exclusive: Option<Exclusive>,
+    tcp_ports: Vec<u16>,
+    quic: Option<(SocketAddr, PathBuf, PathBuf)>,
+    compression_threshold: usize,
+    hooks: Vec<Hook>,
+    alert_webhook: Option<Url>,
+    stats_interval: Option<Duration>,
+    request_log: Option<(PathBuf, usize)>,
+    bus: Option<(Url, String)>,
+    bus_stream: Option<Stream>,
+    shutdown_timeout: Duration,
+    drain_delay: Duration,
+    reload: Option<Reload>,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self {
+            bind_addrs: vec![],
+            secrets: vec![],
+            admin_secrets: vec![],
+            tokens_file: None,
+            authenticator: None,
+            no_client_status: StatusCode::SERVICE_UNAVAILABLE,
+            response: ResponseRule::default(),
+            response_rules: None,
+            slack_url_verification: false,
+            verify_signatures: vec![],
+            dedup: None,
+            allow_cidrs: vec![],
+            trusted_proxies: vec![],
+            deny_headers: headers::DEFAULT_DENIED
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            allow_headers: vec![],
+            preserve_host: false,
+            filter_rules: None,
+            sample_rate: None,
+            rate_limit: None,
+            ip_rate_limit: None,
+            session_quota: Quota::default(),
+            queue_capacity: queue::DEFAULT_CAPACITY,
+            queue_overflow: Overflow::default(),
+            max_body_size: 262_144,
+            ingest_prefix: None,
+            client_paths: false,
+            base_domain: None,
+            reserved_subdomains: vec![],
+            claims_file: None,
+            public_url: None,
+            exclusive: None,
+            tcp_ports: vec![],
+            quic: None,
+            compression_threshold: 1024,
+            hooks: vec![],
+            alert_webhook: None,
+            stats_interval: Some(Duration::from_secs(300)),
+            request_log: None,
+            bus: None,
+            bus_stream: None,
+            shutdown_timeout: Duration::from_secs(10),
+            drain_delay: Duration::ZERO,
+            reload: None,
+        }
+    }
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Address to listen on, either `host:port` or `unix:/path/to/socket`. Can be called more
+    /// than once to listen on several, 127.0.0.1:9873 if it isn't called.
+    pub fn bind_addr(mut self, addr: impl Into<String>) -> Self {
+        self.bind_addrs.push(addr.into());
+        self
+    }
+
+    /// Adds a secret clients may present to connect, several can be accepted while rotating
+    pub fn secret(mut self, secret: impl Into<String>) -> Self {
+        self.secrets.push(secret.into());
+        self
+    }
+
+    /// Adds a secret for the session admin API, the request log and the dashboard, which clients'
+    /// secrets and tokens then no longer open. Without one they take the shared secrets, but
+    /// never a token or the authenticator's credentials.
+    pub fn admin_secret(mut self, secret: impl Into<String>) -> Self {
+        self.admin_secrets.push(secret.into());
+        self
+    }
+
+    /// File of per-client tokens, any unexpired one is accepted in place of a secret
+    pub fn tokens_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.tokens_file = Some(path.into());
+        self
+    }
+
+    /// Checks passwords that aren't a secret or token, e.g. against your own user store
+    pub fn authenticator(
+        mut self,
+        authenticate: impl Fn(&str) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.authenticator = Some(Arc::new(authenticate));
+        self
+    }
+
+    /// Status to respond to incoming requests with while no clients are connected
+    pub fn no_client_status(mut self, status: StatusCode) -> Self {
+        self.no_client_status = status;
+        self
+    }
+
+    /// Response to incoming requests when no rule matches
+    pub fn response(mut self, rule: ResponseRule) -> Self {
+        self.response = rule;
+        self
+    }
+
+    /// JSON file of response rules, checked in order before the default response
+    pub fn response_rules(mut self, path: impl Into<PathBuf>) -> Self {
+        self.response_rules = Some(path.into());
+        self
+    }
+
+    /// Answer Slack url_verification events directly instead of relaying them
+    pub fn slack_url_verification(mut self, enabled: bool) -> Self {
+        self.slack_url_verification = enabled;
+        self
+    }
+
+    /// Rejects incoming requests unless this or another verifier accepts their signature
+    pub fn verify_signature(mut self, verifier: Verifier) -> Self {
+        self.verify_signatures.push(verifier);
+        self
+    }
+
+    /// Doesn't relay repeated deliveries of the same webhook within `window`
+    pub fn dedup(mut self, key: DedupKey, window: Duration) -> Self {
+        self.dedup = Some((key, window));
+        self
+    }
+
+    /// Only accepts incoming requests from this range, and any others allowed
+    pub fn allow_cidr(mut self, net: IpNet) -> Self {
+        self.allow_cidrs.push(net);
+        self
+    }
+
+    /// Believes X-Forwarded-For on requests from this range
+    pub fn trusted_proxy(mut self, net: IpNet) -> Self {
+        self.trusted_proxies.push(net);
+        self
+    }
+
+    /// Headers dropped from incoming requests instead of being relayed, replacing the default of
+    /// just Origin. Hop-by-hop headers are always dropped.
+    pub fn deny_headers(mut self, names: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.deny_headers = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Only relays this header, and any others allowed, rather than all of them
+    pub fn allow_header(mut self, name: impl Into<String>) -> Self {
+        self.allow_headers.push(name.into());
+        self
+    }
+
+    /// Relays the Host header incoming requests were sent with, for local apps doing
+    /// virtual-host routing, instead of the client sending its local origin's
+    pub fn preserve_host(mut self, enabled: bool) -> Self {
+        self.preserve_host = enabled;
+        self
+    }
+
+    /// JSON file of rules allowing or denying incoming requests by method, path glob, headers and
+    /// body size, checked in order before they're relayed
+    pub fn filter_rules(mut self, path: impl Into<PathBuf>) -> Self {
+        self.filter_rules = Some(path.into());
+        self
+    }
+
+    /// Only relays this fraction of incoming requests (e.g. 0.1), picked at random, for shadowing
+    /// a busy endpoint. An allow filter rule with its own `sample_rate` overrides it.
+    pub fn sample_rate(mut self, rate: f64) -> Self {
+        self.sample_rate = Some(rate);
+        self
+    }
+
+    /// Limits incoming requests across all sources
+    pub fn rate_limit(mut self, limit: Limit) -> Self {
+        self.rate_limit = Some(limit);
+        self
+    }
+
+    /// Limits incoming requests from a single address
+    pub fn ip_rate_limit(mut self, limit: Limit) -> Self {
+        self.ip_rate_limit = Some(limit);
+        self
+    }
+
+    /// Limits how many requests a second each connected client is sent
+    pub fn session_rate_limit(mut self, limit: Limit) -> Self {
+        self.session_quota.messages = Some(limit);
+        self
+    }
+
+    /// Limits how many bytes a second each connected client is sent, so one on a slow connection
+    /// is held back instead of backing up the server
+    pub fn session_bandwidth_limit(mut self, limit: Limit) -> Self {
+        self.session_quota.bytes = Some(limit);
+        self
+    }
+
+    /// Whether a client over its session limits has requests held until they allow them, the
+    /// default, or dropped
+    pub fn session_throttle(mut self, policy: ThrottlePolicy) -> Self {
+        self.session_quota.policy = policy;
+        self
+    }
+
+    /// How many requests may wait to be sent to each connected client, 50 by default
+    pub fn queue_capacity(mut self, capacity: usize) -> Self {
+        self.queue_capacity = capacity;
+        self
+    }
+
+    /// What happens when a client falls behind and its queue is full, dropping the oldest
+    /// request by default
+    pub fn queue_overflow(mut self, overflow: Overflow) -> Self {
+        self.queue_overflow = overflow;
+        self
+    }
+
+    /// Largest body in bytes an incoming request may have
+    pub fn max_body_size(mut self, size: usize) -> Self {
+        self.max_body_size = size;
+        self
+    }
+
+    /// Only relays incoming requests to paths under `prefix` (e.g. `/hooks`), anything else gets
+    /// 404, so scanners hitting a public server don't reach clients. Paths are still relayed in
+    /// full.
+    pub fn ingest_prefix(mut self, prefix: impl Into<String>) -> Self {
+        let prefix = prefix.into();
+        self.ingest_prefix = Some(format!("/{}", prefix.trim_matches('/')));
+        self
+    }
+
+    /// Gives each client a path of its own, `/t/<token>` below any ingest prefix, telling it in
+    /// the handshake. Requests below it are relayed to that client alone with the `/t/<token>`
+    /// taken out, anything else to every client as before.
+    pub fn client_paths(mut self, enabled: bool) -> Self {
+        self.client_paths = enabled;
+        self
+    }
+
+    /// Routes requests by `Host` on a server with wildcard DNS for `domain` (e.g.
+    /// `hooks.example.com`): each client claims a subdomain of its own when it connects, the one
+    /// it asks for or a random one, and requests to `<subdomain>.<domain>` are relayed to that
+    /// client alone. Requests to `domain` itself are relayed to every client as before.
+    pub fn base_domain(mut self, domain: impl Into<String>) -> Self {
+        self.base_domain = Some(domain.into().trim_matches('.').to_ascii_lowercase());
+        self
+    }
+
+    /// Never gives `name` to a client as its subdomain, e.g. `www` or `api`. Can be called more
+    /// than once.
+    pub fn reserve_subdomain(mut self, name: impl Into<String>) -> Self {
+        self.reserved_subdomains.push(name.into());
+        self
+    }
+
+    /// Keeps the path and subdomain each client's given in a JSON file, so it's given the same
+    /// ones when it reconnects or the server restarts and nobody else gets them meanwhile.
+    /// Clients are told apart by the token they authenticate with, and clients using a shared
+    /// secret don't keep theirs.
+    pub fn claims_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.claims_file = Some(path.into());
+        self
+    }
+
+    /// Where the server is reached from outside (e.g. `https://example.com`), told to clients in
+    /// the handshake so they can say where to send webhooks. Without it, it's worked out from
+    /// the `Host` (or a trusted proxy's `X-Forwarded-Host` and `X-Forwarded-Proto`) a client
+    /// connected with, which is wrong when clients connect some other way than providers.
+    pub fn public_url(mut self, url: Url) -> Self {
+        self.public_url = Some(url);
+        self
+    }
+
+    /// Only lets one client at a time connect with each credential, refusing others or
+    /// disconnecting the one already connected as `exclusive` says, so a client left running
+    /// somewhere doesn't get requests too. With a shared secret that's one client in all.
+    pub fn exclusive(mut self, exclusive: Exclusive) -> Self {
+        self.exclusive = Some(exclusive);
+        self
+    }
+
+    /// Listens for raw TCP connections on `ports`, on the host of the first `host:port` bind
+    /// address, tunnelling each to a client that forwards its port. Can be called more than once.
+    pub fn tcp_ports(mut self, ports: impl IntoIterator<Item = u16>) -> Self {
+        self.tcp_ports.extend(ports);
+        self
+    }
+
+    /// Also accepts clients over the experimental QUIC transport on `addr` (UDP), with the
+    /// certificate chain in `cert` and its private key in `key`, both PEM. Clients check the
+    /// certificate against the host they connect to.
+    pub fn quic(
+        mut self,
+        addr: SocketAddr,
+        cert: impl Into<PathBuf>,
+        key: impl Into<PathBuf>,
+    ) -> Self {
+        self.quic = Some((addr, cert.into(), key.into()));
+        self
+    }
+
+    /// Messages larger than this many bytes are compressed for clients that support it
+    pub fn compression_threshold(mut self, size: usize) -> Self {
+        self.compression_threshold = size;
+        self
+    }
+
+    /// Calls `hook` with every request before it's relayed
+    pub fn on_request(mut self, hook: impl Fn(&RequestMessage) + Send + Sync + 'static) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Slack or Discord incoming webhook to alert when a client's queue overflows
+    pub fn alert_webhook(mut self, url: Url) -> Self {
+        self.alert_webhook = Some(url);
+        self
+    }
+
+    /// How often `run` logs a summary of the stats, `None` to never
+    pub fn stats_interval(mut self, interval: Option<Duration>) -> Self {
+        self.stats_interval = interval;
+        self
+    }
+
+    /// Keeps the last `max_entries` requests received and their delivery reports in the
+    /// directory `path`, so they can be looked up after the fact
+    pub fn request_log(mut self, path: impl Into<PathBuf>, max_entries: usize) -> Self {
+        self.request_log = Some((path.into(), max_entries));
+        self
+    }
+
+    /// Shares requests with other instances over the NATS server at `url`, on `subject`. Instances
+    /// sharing a subject relay every request any of them receives to all their clients.
+    pub fn bus(mut self, url: Url, subject: impl Into<String>) -> Self {
+        self.bus = Some((url, subject.into()));
+        self
+    }
+
+    /// Stores what's shared on the bus in the JetStream stream `name`, creating it if needed, so
+    /// requests published while an instance is down aren't lost. With a durable `consumer`, named
+    /// uniquely per instance, an instance picks up where it left off when it's back.
+    pub fn bus_stream(mut self, name: impl Into<String>, consumer: Option<String>) -> Self {
+        self.bus_stream = Some(Stream {
+            name: name.into(),
+            consumer,
+        });
+        self
+    }
+
+    /// How long `run` waits for in-flight requests to finish once it's stopped listening on
+    /// SIGTERM or SIGINT
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// How long `run` keeps relaying after SIGTERM or SIGINT with readyz failing, before
+    /// disconnecting clients and stopping, so load balancers have time to stop sending to it
+    pub fn drain_delay(mut self, delay: Duration) -> Self {
+        self.drain_delay = delay;
+        self
+    }
+
+    /// On SIGHUP, `run` calls `reload` and switches to the secrets, tokens file, signature
+    /// verifiers, allowed ranges, trusted proxies, rate limits and response rules of the server
+    /// it returns. Everything else needs a restart; connected clients stay connected.
+    pub fn reload_with(
+        mut self,
+        reload: impl Fn() -> anyhow::Result<Server> + Send + Sync + 'static,
+    ) -> Self {
+        self.reload = Some(Arc::new(reload));
+        self
+    }
+
+    /// Builds the relay's shared state without listening, to mount it in an existing app
+    pub fn build(self) -> anyhow::Result<Relay> {
+        Ok(Relay(Data::new(Shared::new(self)?)))
+    }
+
+    /// Listens on the bind address until the process is signalled to stop
+    pub async fn run(self) -> io::Result<()> {
+        self.log_config();
+
+        let bind_addrs = self.bind_addrs();
+        let tcp_listeners = tcp::bind(&self.tcp_host(), &self.tcp_ports).await?;
+        let quic_endpoint = match &self.quic {
+            Some((addr, cert, key)) => {
+                Some(quic::endpoint(*addr, cert, key).map_err(io::Error::other)?)
+            }
+            None => None,
+        };
+        let stats_interval = self.stats_interval;
+        let (shutdown_timeout, drain_delay) = (self.shutdown_timeout, self.drain_delay);
+        let reload = self.reload.clone();
+        let relay = self.build().map_err(io::Error::other)?;
+        relay.connect_bus().await.map_err(io::Error::other)?;
+
+        if let Some(period) = stats_interval {
+            let shared = relay.0.clone();
+            actix_web::rt::spawn(async move {
+                let mut interval =
+                    tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+                loop {
+                    interval.tick().await;
+                    shared.stats.log_summary(shared.sessions());
+                }
+            });
+        }
+
+        for listener in tcp_listeners {
+            actix_web::rt::spawn(tcp::serve(relay.0.clone(), listener));
+        }
+        if let Some(endpoint) = quic_endpoint {
+            actix_web::rt::spawn(quic::serve(relay.0.clone(), endpoint));
+        }
+
+        let shared = relay.0.clone();
+        let mut server =
+            HttpServer::new(move || App::new().wrap(Logger::default()).service(relay.scope()))
+                .keep_alive(Duration::from_secs(30))
+                .shutdown_timeout(shutdown_timeout.as_secs())
+                .disable_signals();
+
+        let listeners = systemd::listeners();
+        if listeners.is_empty() {
+            for addr in bind_addrs {
+                server = match addr.strip_prefix("unix:") {
+                    Some(path) => {
+                        remove_stale_socket(Path::new(path))?;
+                        server.bind_uds(path)?
+                    }
+                    None => server.bind(addr)?,
+                };
+            }
+        } else {
+            info!(
+                "Listening on {} socket(s) from systemd instead of {}",
+                listeners.len(),
+                bind_addrs.join(", ")
+            );
+            for listener in listeners {
+                server = match listener {
+                    systemd::Listener::Tcp(listener) => server.listen(listener)?,
+                    systemd::Listener::Unix(listener) => server.listen_uds(listener)?,
+                };
+            }
+        }
+        let server = server.run();
+
+        if let Some(reload) = reload {
+            actix_web::rt::spawn(reload_on_signal(Relay(shared.clone()), reload));
+        }
+        actix_web::rt::spawn(drain_on_signal(shared, server.handle(), drain_delay));
+        systemd::notify("READY=1");
+        systemd::start_watchdog();
+
+        server.await
+    }
+
+    /// Logs the resolved configuration, leaving out secrets
+    fn log_config(&self) {
+        info!(
+            event = "config",
+            version = VERSION,
+            bind_addrs:? = self.bind_addrs(),
+            secrets = self.secrets.len(),
+            admin_secrets = self.admin_secrets.len(),
+            tokens_file:? = self.tokens_file,
+            response_rules:? = self.response_rules,
+            verify_signatures = self.verify_signatures.len(),
+            dedup = self.dedup.is_some(),
+            allow_cidrs = self.allow_cidrs.len(),
+            deny_headers:? = self.deny_headers,
+            allow_headers:? = self.allow_headers,
+            preserve_host = self.preserve_host,
+            filter_rules:? = self.filter_rules,
+            sample_rate:? = self.sample_rate,
+            rate_limit = self.rate_limit.is_some(),
+            ip_rate_limit = self.ip_rate_limit.is_some(),
+            session_rate_limit = self.session_quota.messages.is_some(),
+            session_bandwidth_limit = self.session_quota.bytes.is_some(),
+            queue_capacity = self.queue_capacity,
+            queue_overflow:? = self.queue_overflow,
+            max_body_size = self.max_body_size,
+            ingest_prefix:? = self.ingest_prefix,
+            client_paths = self.client_paths,
+            base_domain:? = self.base_domain,
+            reserved_subdomains:? = self.reserved_subdomains,
+            claims_file:? = self.claims_file,
+            public_url:? = self.public_url.as_ref().map(Url::as_str),
+            exclusive:? = self.exclusive,
+            tcp_ports:? = self.tcp_ports,
+            quic_addr:? = self.quic.as_ref().map(|(addr, _, _)| addr),
+            compression_threshold = self.compression_threshold,
+            alerting = self.alert_webhook.is_some(),
+            stats_interval_secs:? = self.stats_interval.map(|i| i.as_secs()),
+            request_log:? = self.request_log.as_ref().map(|(path, _)| path),
+            bus:? = self.bus.as_ref().map(|(url, subject)| format!("{}://{}/{}", url.scheme(), url.host_str().unwrap_or_default(), subject)),
+            bus_stream:? = self.bus_stream.as_ref().map(|stream| &stream.name),
+            bus_consumer:? = self.bus_stream.as_ref().and_then(|stream| stream.consumer.as_ref()),
+            shutdown_timeout_secs = self.shutdown_timeout.as_secs(),
+            drain_delay_secs = self.drain_delay.as_secs();
+            "Starting hookhub server {} on {}", VERSION, self.bind_addrs().join(", ")
+        );
+    }
+
+    fn bind_addrs(&self) -> Vec<String> {
+        match self.bind_addrs.is_empty() {
+            true => vec![DEFAULT_BIND_ADDR.to_string()],
+            false => self.bind_addrs.clone(),
+        }
+    }
+
+    /// Where the TCP ports are listened on, every interface when only unix sockets are bound
+    fn tcp_host(&self) -> String {
+        self.bind_addrs()
+            .iter()
+            .filter(|addr| !addr.starts_with("unix:"))
+            .find_map(|addr| addr.rsplit_once(':').map(|(host, _)| host.to_string()))
+            .unwrap_or_else(|| "0.0.0.0".to_string())
+    }
+}
+
+/// Removes a socket file left behind by a previous run, so it can be bound again. Anything
+/// other than a socket is left alone for binding to fail on.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+/// On SIGHUP, reloads the relay with the server `reload` builds, carrying on with the current
+/// settings if it fails
+async fn reload_on_signal(relay: Relay, reload: Reload) {
+    let mut hangup = signal(SignalKind::hangup()).expect("failed to install SIGHUP handler");
+
+    while hangup.recv().await.is_some() {
+        match reload().and_then(|server| relay.reload(server)) {
+            Ok(()) => info!(event = "reloaded"; "Reloaded configuration"),
+            Err(e) => {
+                warn!(event = "reload_failed"; "Could not reload configuration, keeping the current one: {:#}", e)
+            }
+        }
+    }
+}
+
+/// On SIGTERM or SIGINT, fails readyz and keeps relaying for `drain_delay`, then closes the
+/// websockets with a going away frame so clients reconnect elsewhere and stops the server,
+/// letting in-flight requests finish
+async fn drain_on_signal(shared: Data<Shared>, server: ServerHandle, drain_delay: Duration) {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    let mut interrupt = signal(SignalKind::interrupt()).expect("failed to install SIGINT handler");
+
+    tokio::select! {
+        _ = terminate.recv() => {},
+        _ = interrupt.recv() => {},
+    }
+
+    info!(event = "draining", drain_delay_secs = drain_delay.as_secs(); "Shutting down, draining for {:?}", drain_delay);
+    shared.draining.store(true, Ordering::Relaxed);
+    systemd::notify("STOPPING=1");
+    tokio::time::sleep(drain_delay).await;
+
+    info!(
+        "Disconnecting {} client(s)",
+        shared.broadcaster.client_count()
+    );
+    shared.sessions.disconnect_all(Disconnect::Shutdown);
+
+    server.stop(true).await;
+    info!(event = "stopped"; "Stopped");
+}
+
+/// A built `Server` that can be mounted inside an existing actix-web app, cloning it shares
+/// the connected clients
+#[derive(Clone)]
+pub struct Relay(Data<Shared>);
+
+impl Relay {
+    /// Connects to the bus, if one was given, and starts relaying what's published on it. `run`
+    /// does this, an app mounting the relay itself needs to.
+    pub async fn connect_bus(&self) -> anyhow::Result<()> {
+        let Some((url, subject, stream)) = &self.0.bus_config else {
+            return Ok(());
+        };
+
+        let bus = Bus::connect(url, subject, stream.as_ref()).await?;
+        let shared = self.0.clone();
+        bus.subscribe(move |req| shared.relay(req)).await?;
+        let _ = self.0.bus.set(bus);
+
+        Ok(())
+    }
+
+    /// Switches to `server`'s secrets, admin secrets, tokens file, signature verifiers, allowed ranges, trusted
+    /// proxies, header filtering, filter rules, rate limits and response rules, ignoring the rest
+    /// of it. Connected clients stay connected.
+    pub fn reload(&self, mut server: Server) -> anyhow::Result<()> {
+        let policy = Policy::take(&mut server, self.0.authenticator.is_some())?;
+        *self.0.policy.write().unwrap() = Arc::new(policy);
+
+        Ok(())
+    }
+
+    /// Every route of the relay: the websocket and health checks under `/__hookhub__` and the
+    /// catch-all relaying everything else. Nest it in a scope to serve it on a sub-path,
+    /// clients then connect to e.g. `wss://example.com/hooks`.
+    pub fn scope(&self) -> Scope {
+        web::scope("")
+            .app_data(self.0.clone())
+            .app_data(web::PayloadConfig::new(self.0.max_body_size))
+            .service(handle_healthz)
+            .service(
+                web::resource("/__hookhub__/readyz")
+                    .wrap(HttpAuthentication::basic(secret_validator))
+                    .route(web::get().to(handle_readyz)),
+            )
+            .service(
+                web::resource("/__hookhub__/stats")
+                    .wrap(HttpAuthentication::basic(secret_validator))
+                    .route(web::get().to(handle_stats)),
+            )
+            .service(
+                web::resource("/__hookhub__/sessions")
+                    .wrap(HttpAuthentication::basic(admin_validator))
+                    .route(web::get().to(handle_list_sessions)),
+            )
+            .service(
+                web::resource("/__hookhub__/sessions/{id}")
+                    .wrap(HttpAuthentication::basic(admin_validator))
+                    .route(web::delete().to(handle_disconnect_session)),
+            )
+            .service(
+                web::resource("/__hookhub__/requests")
+                    .wrap(HttpAuthentication::basic(admin_validator))
+                    .route(web::get().to(handle_recent_requests)),
+            )
+            .service(
+                web::resource("/__hookhub__/history")
+                    .wrap(HttpAuthentication::basic(admin_validator))
+                    .route(web::get().to(handle_history)),
+            )
+            .service(
+                web::resource("/__hookhub__/history/{id}")
+                    .wrap(HttpAuthentication::basic(admin_validator))
+                    .route(web::get().to(handle_history_item)),
+            )
+            .service(
+                web::resource("/__hookhub__/events")
+                    .wrap(HttpAuthentication::basic(secret_validator))
+                    .route(web::get().to(events::handle_events)),
+            )
+            .service(
+                web::resource("/__hookhub__/ui")
+                    .wrap(HttpAuthentication::basic(admin_validator))
+                    .route(web::get().to(dashboard::handle_ui)),
+            )
+            .service(
+                web::resource("/__hookhub__/ui/events")
+                    .wrap(HttpAuthentication::basic(admin_validator))
+                    .route(web::get().to(dashboard::handle_events)),
+            )
+            .service(
+                web::scope("/__hookhub__")
+                    .wrap(HttpAuthentication::basic(basic_auth_validator))
+                    .service(handle_websocket),
+            )
+            .service(
+                web::resource("/{tail:.*}")
+                    .guard(guard::fn_guard(|ctx| ctx.head().upgrade()))
+                    .to(handle_tunnel),
+            )
+            .default_service(web::to(handle_receive))
+    }
+
+    /// Just the handler relaying requests to the clients connected through `scope`, mounted at
+    /// `path` (a pattern like `/stripe/{tail:.*}` catches everything below it)
+    pub fn receiver(&self, path: &str) -> Resource {
+        web::resource(path)
+            .app_data(self.0.clone())
+            .app_data(web::PayloadConfig::new(self.0.max_body_size))
+            .to(handle_receive)
+    }
+}
+
+/// The relay's routes accepting clients that present `secret`, see `Relay::scope`
+pub fn scope(secret: impl Into<String>) -> Scope {
+    Server::new()
+        .secret(secret)
+        .build()
+        .expect("a server without response rules always builds")
+        .scope()
+}
+
+/// The settings a reload replaces: who may connect and which incoming requests are accepted
+/// and how they're answered
+struct Policy {
+    secrets: Vec<String>,
+    admin_secrets: Vec<String>,
+    tokens_file: Option<PathBuf>,
+    verify_signatures: Vec<Verifier>,
+    access: Access,
+    headers: HeaderFilter,
+    filters: Filters,
+    rate_limiter: RateLimiter,
+    responses: Responses,
+}
+
+impl Policy {
+    /// Takes the settings out of `server`, falling back to the default secret unless there's
+    /// another way to authenticate
+    fn take(server: &mut Server, authenticator: bool) -> anyhow::Result<Self> {
+        let mut secrets = std::mem::take(&mut server.secrets);
+        if secrets.is_empty() && server.tokens_file.is_none() && !authenticator {
+            secrets.push(DEFAULT_SECRET.to_string());
+        }
+
+        Ok(Self {
+            secrets,
+            admin_secrets: std::mem::take(&mut server.admin_secrets),
+            tokens_file: server.tokens_file.take(),
+            verify_signatures: std::mem::take(&mut server.verify_signatures),
+            access: Access::new(
+                std::mem::take(&mut server.allow_cidrs),
+                std::mem::take(&mut server.trusted_proxies),
+            ),
+            headers: HeaderFilter::new(
+                std::mem::take(&mut server.deny_headers),
+                std::mem::take(&mut server.allow_headers),
+                server.preserve_host,
+            ),
+            filters: Filters::new(server.filter_rules.as_deref(), server.sample_rate)?,
+            rate_limiter: RateLimiter::new(server.rate_limit, server.ip_rate_limit),
+            responses: Responses::new(
+                std::mem::take(&mut server.response),
+                server.response_rules.as_deref(),
+            )?,
+        })
+    }
+}
+
+/// Everything the request handlers share, built from a `Server` when it starts
+struct Shared {
+    policy: RwLock<Arc<Policy>>,
+    authenticator: Option<Authenticator>,
+    no_client_status: StatusCode,
+    slack_url_verification: bool,
+    session_quota: Quota,
+    max_body_size: usize,
+    ingest_prefix: Option<String>,
+    client_paths: bool,
+    subdomains: Option<Subdomains>,
+    claims_file: Option<PathBuf>,
+    public_url: Option<Url>,
+    exclusive: Option<Exclusive>,
+    tcp_ports: Vec<u16>,
+    compression_threshold: usize,
+    hooks: Vec<Hook>,
+    dedup: Option<Dedup>,
+    broadcaster: Broadcaster,
+    /// Sessions connecting and disconnecting, for the clients taking peer events
+    peers: broadcast::Sender<PeerEvent>,
+    tunnels: Arc<Tunnels>,
+    stats: Stats,
+    sessions: Sessions,
+    recent: Recent,
+    request_log: Option<RequestLog>,
+    bus_config: Option<(Url, String, Option<Stream>)>,
+    bus: OnceLock<Bus>,
+    /// Set once shutting down, so load balancers stop sending requests and clients
+    draining: AtomicBool,
+    alerter: Option<Alerter>,
+    last_alert: Mutex<Option<Instant>>,
+}
+
+impl Shared {
+    fn new(mut server: Server) -> anyhow::Result<Self> {
+        let authenticator = server.authenticator.is_some();
+        let policy = Policy::take(&mut server, authenticator)?;
+
+        let request_log = match server.request_log {
+            Some((path, max_entries)) => Some(RequestLog::open(path, max_entries)?),
+            None => None,
+        };
+
+        Ok(Self {
+            policy: RwLock::new(Arc::new(policy)),
+            authenticator: server.authenticator,
+            no_client_status: server.no_client_status,
+            slack_url_verification: server.slack_url_verification,
+            session_quota: server.session_quota,
+            max_body_size: server.max_body_size,
+            ingest_prefix: server.ingest_prefix,
+            client_paths: server.client_paths,
+            subdomains: server
+                .base_domain
+                .map(|domain| Subdomains::new(domain, &server.reserved_subdomains)),
+            claims_file: server.claims_file,
+            public_url: server.public_url,
+            exclusive: server.exclusive,
+            tcp_ports: server.tcp_ports,
+            compression_threshold: server.compression_threshold,
+            hooks: server.hooks,
+            dedup: server.dedup.map(|(key, window)| Dedup::new(key, window)),
+            broadcaster: Broadcaster::new(server.queue_capacity, server.queue_overflow),
+            peers: broadcast::channel(PEER_EVENTS_CAPACITY).0,
+            tunnels: Arc::default(),
+            stats: Stats::default(),
+            sessions: Sessions::default(),
+            recent: Recent::starting_after(
+                request_log.as_ref().map(|log| log.last_id()).unwrap_or(0),
+            ),
+            request_log,
+            bus_config: server
+                .bus
+                .map(|(url, subject)| (url, subject, server.bus_stream)),
+            bus: OnceLock::new(),
+            draining: AtomicBool::new(false),
+            alerter: server.alert_webhook.map(Alerter::new),
+            last_alert: Mutex::new(None),
+        })
+    }
+
+    /// The current policy, held on to for the rest of a request even if it's reloaded meanwhile
+    fn policy(&self) -> Arc<Policy> {
+        self.policy.read().unwrap().clone()
+    }
+
+    /// Records requests dropped from a session's queue since it last took one, if any were
+    fn lagged(&self, session_id: u64, remote_addr: &str, missed: u64) {
+        if missed == 0 {
+            return;
+        }
+
+        self.sessions.lagged(session_id, missed);
+        warn!(
+            event = "lagged", session = session_id, remote_addr = remote_addr, missed = missed;
+            "[{remote_addr}] Session #{session_id} fell behind, {missed} request(s) dropped from its queue"
+        );
+        self.alert(format!("hookhub: client {remote_addr} (session #{session_id}) fell behind and {missed} request(s) were dropped from its queue"));
+    }
+
+    /// Sends an alert if there's a webhook, at most once a minute so a struggling server
+    /// doesn't flood the channel
+    fn alert(&self, message: String) {
+        let Some(alerter) = &self.alerter else {
+            return;
+        };
+
+        let mut last = self.last_alert.lock().unwrap();
+        if last.is_some_and(|at| at.elapsed() < ALERT_QUIET_PERIOD) {
+            return;
+        }
+        *last = Some(Instant::now());
+
+        alerter.send(message);
+    }
+
+    /// Logs and counts a client's report of what became of a request
+    fn delivered(&self, session_id: u64, remote_addr: &str, report: DeliveryReport) {
+        info!(
+            event = "delivery_report", session = session_id, request_id = report.request_id, outcome:? = report.outcome, status:? = report.status, duration_ms = report.duration_ms;
+            "[{remote_addr}] Request {} was {:?} ({}) in {}ms",
+            report.request_id,
+            report.outcome,
+            report.status.map(|s| s.to_string()).unwrap_or("no response".to_string()),
+            report.duration_ms
+        );
+
+        self.sessions.reported(session_id, report.outcome);
+
+        let delivery = Delivery {
+            session: session_id,
+            report,
+        };
+        if let Some(log) = &self.request_log {
+            log.reported(delivery.clone());
+        }
+        self.recent.reported(delivery);
+    }
+
+    /// The connected sessions, with how many requests are waiting in each one's queue
+    fn sessions(&self) -> Vec<SessionInfo> {
+        let depths = self.broadcaster.depths();
+        let mut sessions = self.sessions.list();
+        for session in sessions.iter_mut() {
+            session.queue_depth = depths.get(&session.id).copied().unwrap_or(0);
+        }
+
+        sessions
+    }
+
+    /// Hands a request to the connected clients it's for, with the id their reports will refer to
+    fn relay(&self, mut message: RequestMessage) {
+        let target = self.target(&message);
+        let clients = match &target {
+            Target::Everyone => self.broadcaster.client_count(),
+            Target::Labelled(ids) => ids.len(),
+            Target::Session { .. } => 1,
+            Target::Nobody => 0,
+        };
+        let id = self.received(&message, Outcome::Relayed(clients));
+        // the first is the one read, so none may be left from another instance or a provider
+        message
+            .headers
+            .retain(|(name, _)| !name.eq_ignore_ascii_case(DELIVERY_ID_HEADER));
+        message
+            .headers
+            .push((DELIVERY_ID_HEADER.to_string(), id.to_string().into()));
+
+        let (method, path) = (message.method.clone(), message.fullpath.clone());
+        let clients = match target {
+            Target::Everyone => self.broadcaster.send(message),
+            Target::Labelled(ids) => ids
+                .into_iter()
+                .filter(|id| self.broadcaster.send_to(*id, message.clone()))
+                .count(),
+            Target::Session { id, fullpath } => {
+                message.fullpath = fullpath;
+                self.broadcaster.send_to(id, message) as usize
+            }
+            // with a bus, the client may be connected to another instance
+            Target::Nobody => 0,
+        };
+
+        info!(
+            event = "request_received", method = method.as_str(), path = path.as_str(), clients = clients;
+            "Forwarded request to {} client(s)", clients
+        );
+    }
+
+    /// Which clients a request is for, by its host, then its path, then the filter rules
+    fn target(&self, message: &RequestMessage) -> Target {
+        let host = message.source.as_ref().and_then(|s| s.host.as_deref());
+        if let Some(host) = host
+            .zip(self.subdomains.as_ref())
+            .and_then(|(host, subdomains)| subdomains.below(host))
+        {
+            return match self.sessions.by_host(&host) {
+                Some(id) => Target::Session {
+                    id,
+                    fullpath: message.fullpath.clone(),
+                },
+                None => Target::Nobody,
+            };
+        }
+
+        if let Some((path, fullpath)) =
+            paths::split(&message.fullpath, self.ingest_prefix.as_deref())
+                .filter(|_| self.client_paths)
+        {
+            return match self.sessions.by_path(path) {
+                Some(id) => Target::Session { id, fullpath },
+                None => Target::Nobody,
+            };
+        }
+
+        match self.policy().filters.labels(message) {
+            Some(labels) => match self.sessions.labelled(labels) {
+                ids if ids.is_empty() => Target::Nobody,
+                ids => Target::Labelled(ids),
+            },
+            None => Target::Everyone,
+        }
+    }
+
+    /// Registers a session for a client connecting with `credential`, refusing it or
+    /// disconnecting the sessions already using it if only one may use it at a time
+    async fn register(
+        &self,
+        remote_addr: &str,
+        credential: &Credential,
+        version: Option<String>,
+    ) -> Result<(u64, oneshot::Receiver<Disconnect>), String> {
+        let (id, disconnect, replaced) = self
+            .sessions
+            .start(remote_addr, &credential.name, version, self.exclusive)
+            .map_err(|id| {
+                format!(
+                    "session #{} is already connected using {}",
+                    id, credential.name
+                )
+            })?;
+
+        for id in &replaced {
+            info!("Replacing session #{} using {}", id, credential.name);
+        }
+
+        // so the path and subdomain they hold can be given to the new session
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while replaced.iter().any(|id| self.sessions.is_connected(*id)) && Instant::now() < deadline
+        {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        Ok((id, disconnect))
+    }
+
+    /// Where providers send webhooks for a client given `host` and `path`, below the public URL
+    /// or `reached`, where the client reached the server
+    fn public_url(
+        &self,
+        reached: Option<Url>,
+        host: Option<&str>,
+        path: Option<&str>,
+    ) -> Option<Url> {
+        let mut url = self.public_url.clone().or(reached)?;
+        if let Some(host) = host {
+            url.set_host(Some(host)).ok()?;
+        }
+
+        let base = url.path().trim_end_matches('/').to_string();
+        let path = path.or(self.ingest_prefix.as_deref()).unwrap_or("/");
+        url.set_path(&format!("{}{}", base, path));
+        url.set_query(None);
+
+        Some(url)
+    }
+
+    /// The path and subdomain a client is given, if clients get them: the subdomain it asked
+    /// for or a random one, and the same ones as last time if claims are kept and its
+    /// credential says who it is
+    fn routes(
+        &self,
+        credential: &Credential,
+        requested: Option<&str>,
+    ) -> Result<(Option<String>, Option<Claim>), String> {
+        if !self.client_paths && self.subdomains.is_none() {
+            return Ok((None, None));
+        }
+
+        let prefix = self.ingest_prefix.as_deref();
+        let store = self.claims_file.as_deref().map(ClaimStore::new);
+        let claims = match store.as_ref().map(ClaimStore::list) {
+            Some(Ok(claims)) => claims,
+            Some(Err(e)) => {
+                warn!("Could not check claims: {:#}", e);
+                vec![]
+            }
+            None => vec![],
+        };
+        let identity = credential.identity();
+        let mine = claims
+            .iter()
+            .find(|claim| Some(claim.credential.as_str()) == identity);
+
+        let kept_path = mine
+            .and_then(|claim| claim.path.clone())
+            .filter(|path| paths::split(path, prefix).is_some());
+        let path = self.client_paths.then(|| {
+            // a client connected twice with the same credential gets another one
+            match kept_path
+                .clone()
+                .filter(|path| self.sessions.by_path(path).is_none())
+            {
+                Some(path) => path,
+                None => loop {
+                    let path = paths::mint(prefix);
+                    if self.sessions.by_path(&path).is_none()
+                        && !claims
+                            .iter()
+                            .any(|claim| claim.path.as_ref() == Some(&path))
+                    {
+                        break path;
+                    }
+                },
+            }
+        });
+
+        let requested = requested.map(str::to_ascii_lowercase);
+        let kept_subdomain = mine.and_then(|claim| claim.subdomain.clone());
+        let subdomain = match &self.subdomains {
+            Some(subdomains) => {
+                if let Some(name) = &requested {
+                    if claims.iter().any(|claim| {
+                        claim.subdomain.as_ref() == Some(name)
+                            && Some(claim.credential.as_str()) != identity
+                    }) {
+                        return Err(format!("subdomain {} is claimed by another client", name));
+                    }
+                }
+                Some(match (&requested, &kept_subdomain) {
+                    (Some(name), _) => subdomains.claim(Some(name))?,
+                    (None, Some(name)) => subdomains
+                        .claim(Some(name))
+                        .or_else(|_| subdomains.claim(None))?,
+                    (None, None) => subdomains.claim(None)?,
+                })
+            }
+            None => None,
+        };
+
+        if let (Some(store), Some(identity)) = (&store, identity) {
+            // what's kept stays kept while another connection with the credential holds it
+            let keep_path = match self.client_paths {
+                true => kept_path.or_else(|| path.clone()),
+                false => mine.and_then(|claim| claim.path.clone()),
+            };
+            let given = subdomain.as_ref().map(|claim| claim.name.clone());
+            let keep_subdomain = match requested {
+                Some(_) => given,
+                None => kept_subdomain.or(given),
+            };
+            let unchanged = mine
+                .is_some_and(|claim| claim.path == keep_path && claim.subdomain == keep_subdomain);
+            if !unchanged {
+                if let Err(e) = store.keep(identity, keep_subdomain, keep_path) {
+                    warn!("Could not keep claims: {:#}", e);
+                }
+            }
+        }
+
+        Ok((path, subdomain))
+    }
+
+    /// Records the name and labels a session's client gave, and the path and host it was told
+    /// are its own
+    fn routed(&self, session_id: u64, remote_addr: &str, hello: &Hello, ack: &HelloAck) {
+        let labels = hello
+            .labels
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join(", ");
+        match (&hello.name, labels.is_empty()) {
+            (None, true) => {}
+            (Some(name), true) => info!("[{remote_addr}] Session #{session_id} is {name}"),
+            (name, false) => info!(
+                "[{remote_addr}] Session #{session_id} is {} labelled {labels}",
+                name.as_deref().unwrap_or("unnamed")
+            ),
+        }
+        for route in ack.path.iter().chain(&ack.host) {
+            info!("[{remote_addr}] Session #{session_id} has {route} to itself");
+        }
+        self.sessions
+            .named(session_id, hello.name.clone(), hello.labels.clone());
+        self.sessions
+            .routed(session_id, ack.path.clone(), ack.host.clone());
+    }
+
+    /// Tells the clients taking peer events that the session `id` called `name` started or
+    /// finished receiving requests, once it's subscribed or unsubscribed
+    fn announce(&self, id: u64, name: Option<String>, connected: bool) {
+        let clients = self.broadcaster.client_count();
+        let event = match connected {
+            true => PeerEvent::Connected {
+                session: id,
+                name,
+                clients,
+            },
+            false => PeerEvent::Disconnected {
+                session: id,
+                name,
+                clients,
+            },
+        };
+
+        // nobody listening isn't an error
+        let _ = self.peers.send(event);
+    }
+
+    /// Remembers a request that's been received, returning the id it's relayed with
+    fn received(&self, message: &RequestMessage, outcome: Outcome) -> u64 {
+        let path = message.fullpath.split('?').next().unwrap_or_default();
+        let summary = self
+            .recent
+            .record(&message.method, path, message.body.len(), outcome);
+        let id = summary.id;
+
+        if let Some(log) = &self.request_log {
+            log.add(summary, message.clone());
+        }
+
+        id
+    }
+
+    /// Checks a password against the shared secrets, the token store and the authenticator
+    fn authenticate(&self, password: &str) -> Option<Credential> {
+        let policy = self.policy();
+
+        if let Some(i) = policy.secrets.iter().position(|secret| secret == password) {
+            return Some(Credential {
+                name: format!("secret #{}", i + 1),
+                shared: true,
+            });
+        }
+
+        if let Some(path) = &policy.tokens_file {
+            match TokenStore::new(path).find(password) {
+                Ok(Some(token)) => {
+                    return Some(Credential {
+                        name: format!("token {}", token.name),
+                        shared: false,
+                    })
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Could not check tokens: {:#}", e),
+            }
+        }
 
-static SECRET: LazyLock<String> =
-    LazyLock::new(|| std::env::var("HOOKHUB_SECRET").unwrap_or("abc123".to_string()));
+        self.authenticator
+            .as_ref()
+            .and_then(|authenticate| authenticate(password))
+            .map(|name| Credential {
+                name,
+                shared: false,
+            })
+    }
 
-static BIND_ADDR: LazyLock<String> =
-    LazyLock::new(|| std::env::var("HOOKHUB_BIND_ADDR").unwrap_or("127.0.0.1:9873".to_string()));
+    /// Checks a password against the admin secrets, or the shared secrets when there are none,
+    /// as a client's own token mustn't be able to read others' requests or disconnect them
+    fn authenticate_admin(&self, password: &str) -> Option<Credential> {
+        let policy = self.policy();
 
-const VERSION: &str = env!("CARGO_PKG_VERSION");
+        let (secrets, kind) = if policy.admin_secrets.is_empty() {
+            (&policy.secrets, "secret")
+        } else {
+            (&policy.admin_secrets, "admin secret")
+        };
 
-async fn basic_auth_validator(
+        secrets
+            .iter()
+            .position(|secret| secret == password)
+            .map(|i| Credential {
+                name: format!("{} #{}", kind, i + 1),
+                shared: true,
+            })
+    }
+}
+
+/// Which clients a request is relayed to
+enum Target {
+    Everyone,
+    /// The sessions with the labels a filter rule relays it to, never empty
+    Labelled(Vec<u64>),
+    /// Just the session whose host it's to or path it's below, as `fullpath`
+    Session {
+        id: u64,
+        fullpath: String,
+    },
+    /// It's to a host or below a path no session here has
+    Nobody,
+}
+
+/// Which credential a client authenticated with, so sessions can be attributed, e.g. while
+/// rotating secrets
+#[derive(Clone)]
+struct Credential {
+    name: String,
+    /// A secret any number of clients may be using, so it doesn't say which one this is
+    shared: bool,
+}
+
+impl Credential {
+    /// What the client's claims are kept under, none when it's using a shared secret
+    fn identity(&self) -> Option<&str> {
+        (!self.shared).then_some(self.name.as_str())
+    }
+}
+
+async fn secret_validator(
     req: ServiceRequest,
     credentials: BasicAuth,
 ) -> Result<ServiceRequest, (actix_web::Error, ServiceRequest)> {
-    if let Some(password) = credentials.password() {
-        if password != *SECRET {
-            return Err((
-                actix_web::error::ErrorUnauthorized(AuthenticationError::new(Basic::new())),
-                req,
-            ));
-        }
-    } else {
+    let credential = req.app_data::<Data<Shared>>().and_then(|shared| {
+        credentials
+            .password()
+            .and_then(|password| shared.authenticate(password))
+    });
+
+    let Some(credential) = credential else {
         return Err((
             actix_web::error::ErrorUnauthorized(AuthenticationError::new(Basic::new())),
             req,
         ));
-    }
+    };
+
+    req.extensions_mut().insert(credential);
+
+    Ok(req)
+}
 
-    if credentials.user_id() != VERSION {
+async fn admin_validator(
+    req: ServiceRequest,
+    credentials: BasicAuth,
+) -> Result<ServiceRequest, (actix_web::Error, ServiceRequest)> {
+    let credential = req.app_data::<Data<Shared>>().and_then(|shared| {
+        credentials
+            .password()
+            .and_then(|password| shared.authenticate_admin(password))
+    });
+
+    let Some(credential) = credential else {
         return Err((
-            actix_web::error::ErrorBadRequest(format!(
-                "Server is running version {} but you are running {}",
-                credentials.user_id(),
-                VERSION
-            )),
+            actix_web::error::ErrorUnauthorized(AuthenticationError::new(Basic::new())),
             req,
         ));
-    }
+    };
+
+    req.extensions_mut().insert(credential);
 
     Ok(req)
 }
 
-#[derive(Clone)]
-struct Broadcaster(broadcast::Sender<RequestMessage>);
+/// The protocol version negotiated with a client during the websocket handshake
+#[derive(Clone, Copy)]
+struct Protocol(u32);
+
+async fn basic_auth_validator(
+    req: ServiceRequest,
+    credentials: BasicAuth,
+) -> Result<ServiceRequest, (actix_web::Error, ServiceRequest)> {
+    let req = secret_validator(req, credentials.clone()).await?;
+
+    let protocol = req
+        .headers()
+        .get(PROTOCOL_HEADER)
+        .map(|v| v.to_str().ok().and_then(|v| v.parse::<u32>().ok()));
 
-impl Broadcaster {
-    fn send(&self, msg: RequestMessage) {
-        if let Ok(count) = self.0.send(msg) {
-            info!("Forwarded request to {} client(s)", count);
+    match protocol {
+        // clients from before the protocol was versioned only work with the exact same release
+        None if credentials.user_id() == VERSION => {}
+        None => {
+            return Err((
+                actix_web::error::ErrorUpgradeRequired(format!(
+                    "Server is running version {} but you are running {}, upgrade the client",
+                    VERSION,
+                    credentials.user_id()
+                )),
+                req,
+            ));
+        }
+        Some(None) => {
+            return Err((
+                actix_web::error::ErrorBadRequest(format!("Invalid {} header", PROTOCOL_HEADER)),
+                req,
+            ));
         }
+        Some(Some(protocol)) if protocol < MIN_PROTOCOL_VERSION => {
+            return Err((
+                actix_web::error::ErrorUpgradeRequired(format!(
+                    "Client {} speaks protocol {} but server {} needs at least {}, upgrade the client",
+                    credentials.user_id(),
+                    protocol,
+                    VERSION,
+                    MIN_PROTOCOL_VERSION
+                )),
+                req,
+            ));
+        }
+        // newer clients speak our version, older ones below the minimum are rejected above
+        Some(Some(_)) => {}
     }
 
-    fn subscribe(&self) -> broadcast::Receiver<RequestMessage> {
-        self.0.subscribe()
+    req.extensions_mut()
+        .insert(Protocol(negotiate_protocol(protocol.flatten())));
+
+    Ok(req)
+}
+
+#[get("/__hookhub__/healthz")]
+async fn handle_healthz() -> impl Responder {
+    HttpResponse::Ok().body("ok")
+}
+
+async fn handle_readyz(shared: Data<Shared>) -> impl Responder {
+    let draining = shared.draining.load(Ordering::Relaxed);
+    let status = match draining {
+        true => StatusCode::SERVICE_UNAVAILABLE,
+        false => StatusCode::OK,
+    };
+
+    HttpResponse::build(status).json(serde_json::json!({
+        "ready": !draining,
+        "draining": draining,
+        "clients": shared.broadcaster.client_count(),
+        "queue_depth": shared.broadcaster.queue_depth(),
+    }))
+}
+
+async fn handle_stats(shared: Data<Shared>) -> impl Responder {
+    HttpResponse::Ok().json(shared.stats.snapshot(shared.sessions()))
+}
+
+async fn handle_list_sessions(shared: Data<Shared>) -> impl Responder {
+    HttpResponse::Ok().json(shared.sessions())
+}
+
+async fn handle_recent_requests(shared: Data<Shared>) -> impl Responder {
+    HttpResponse::Ok().json(shared.recent.list())
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    since: Option<DateTime<Utc>>,
+    limit: Option<usize>,
+}
+
+async fn handle_history(query: web::Query<HistoryQuery>, shared: Data<Shared>) -> HttpResponse {
+    let Some(log) = &shared.request_log else {
+        return HttpResponse::NotFound().body("The request log isn't enabled on this server");
+    };
+
+    match log.list(query.since, query.limit.unwrap_or(100)) {
+        Ok(summaries) => HttpResponse::Ok().json(summaries),
+        Err(e) => {
+            warn!("Could not read the request log: {:#}", e);
+            HttpResponse::InternalServerError().finish()
+        }
     }
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+async fn handle_history_item(id: web::Path<u64>, shared: Data<Shared>) -> HttpResponse {
+    let Some(log) = &shared.request_log else {
+        return HttpResponse::NotFound().body("The request log isn't enabled on this server");
+    };
 
-    let (tx, _) = broadcast::channel::<RequestMessage>(50);
-    let broadcaster = Broadcaster(tx);
+    match log.get(id.into_inner()) {
+        Ok(Some(entry)) => HttpResponse::Ok().json(entry),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            warn!("Could not read the request log: {:#}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
 
-    HttpServer::new(move || {
-        App::new()
-            .wrap(Logger::default())
-            .app_data(Data::new(broadcaster.clone()))
-            .service(
-                web::scope("/__hookhub__")
-                    .wrap(HttpAuthentication::basic(basic_auth_validator))
-                    .service(handle_websocket),
-            )
-            .default_service(web::to(handle_receive))
-    })
-    .keep_alive(Duration::from_secs(30))
-    .shutdown_timeout(10)
-    .bind(BIND_ADDR.deref())?
-    .run()
-    .await
+async fn handle_disconnect_session(id: web::Path<u64>, shared: Data<Shared>) -> impl Responder {
+    let id = id.into_inner();
+
+    if shared.sessions.disconnect(id, Disconnect::Admin) {
+        info!("Disconnecting session {} through the admin API", id);
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().body(format!("No session {}", id))
+    }
 }
 
 #[get("/")]
@@ -104,17 +1621,130 @@ async fn handle_websocket(
     req: HttpRequest,
     body: web::Payload,
     connection_info: ConnectionInfo,
-    broadcaster: Data<Broadcaster>,
+    shared: Data<Shared>,
 ) -> actix_web::Result<impl Responder> {
-    let (response, mut session, mut msg_stream) = actix_ws::handle(&req, body)?;
+    if shared.draining.load(Ordering::Relaxed) {
+        return Err(actix_web::error::ErrorServiceUnavailable(
+            "server shutting down",
+        ));
+    }
 
-    let remote_addr = connection_info.realip_remote_addr().unwrap().to_owned();
+    let (mut response, mut session, mut msg_stream) = actix_ws::handle(&req, body)?;
+    response.headers_mut().insert(
+        HeaderName::from_static(PROTOCOL_HEADER),
+        PROTOCOL_VERSION.into(),
+    );
 
-    info!("[{remote_addr}] Session started");
+    // there is no peer address over a unix socket, unless a proxy forwards one
+    let remote_addr = connection_info
+        .realip_remote_addr()
+        .unwrap_or("unix socket")
+        .to_owned();
 
-    let mut receiver = broadcaster.subscribe();
+    let Some(credential) = req.extensions().get::<Credential>().cloned() else {
+        return Err(actix_web::error::ErrorUnauthorized("not authenticated"));
+    };
+
+    info!(
+        event = "connected", remote_addr = remote_addr.as_str(), credential = credential.name.as_str();
+        "[{remote_addr}] Session started using {}", credential.name
+    );
+
+    let protocol = req
+        .extensions()
+        .get::<Protocol>()
+        .map(|p| p.0)
+        .unwrap_or(PROTOCOL_VERSION);
+    let reached = reached_at(&req, &shared.policy().access);
 
     actix_web::rt::spawn(async move {
+        let mut features = vec![];
+        let mut format = WireFormat::default();
+        let mut tcp_ports = vec![];
+        let mut routes = None;
+
+        let hello = match protocol >= HELLO_PROTOCOL_VERSION {
+            true => read_hello(&mut msg_stream).await.map(Some),
+            false => Ok(None),
+        };
+        // registered before routing, so a client it replaces has given up its path and subdomain
+        let registered = match hello {
+            Ok(hello) => {
+                let version = hello.as_ref().map(|hello| hello.version.clone());
+                shared
+                    .register(&remote_addr, &credential, version)
+                    .await
+                    .map(|registered| (hello, registered))
+            }
+            Err(err) => Err(err),
+        };
+        let (hello, (session_id, mut disconnect)) = match registered {
+            Ok(registered) => registered,
+            Err(err) => {
+                warn!("[{remote_addr}] Handshake failed: {err}");
+                let _ = session
+                    .close(Some(CloseReason::from((CloseCode::Policy, err.as_str()))))
+                    .await;
+                return;
+            }
+        };
+
+        if let Some(hello) = hello {
+            match greet(&mut session, &shared, &credential, &hello, reached).await {
+                Ok((ack, claim)) => {
+                    features = hello.shared_features();
+                    format = hello.wire_format();
+                    info!(
+                        "[{remote_addr}] Client {} speaks protocol {} in {:?} with features {:?}",
+                        hello.version, protocol, format, features
+                    );
+                    tcp_ports = hello.tcp_ports.clone();
+                    routes = Some((hello, ack, claim));
+                }
+                Err(err) => {
+                    warn!("[{remote_addr}] Handshake failed: {err}");
+                    shared.sessions.finish(session_id);
+                    let _ = session
+                        .close(Some(CloseReason::from((CloseCode::Policy, err.as_str()))))
+                        .await;
+                    return;
+                }
+            }
+        }
+
+        let compress_above = features
+            .contains(&Feature::Compression)
+            .then_some(shared.compression_threshold);
+        let reports = features.contains(&Feature::AckDelivery);
+        let websockets = features.contains(&Feature::Tunnel);
+        if !features.contains(&Feature::TcpForward) {
+            tcp_ports.clear();
+        }
+        tcp_ports.retain(|port| shared.tcp_ports.contains(port));
+        let tunnels = websockets || features.contains(&Feature::TcpForward);
+
+        let name = routes.as_ref().and_then(|(hello, _, _)| hello.name.clone());
+        // the subdomain's held until the session finishes
+        let claim = routes.and_then(|(hello, ack, claim)| {
+            shared.routed(session_id, &remote_addr, &hello, &ack);
+            claim
+        });
+        let mut peer_events = features
+            .contains(&Feature::PeerEvents)
+            .then(|| shared.peers.subscribe());
+        let queue = shared.broadcaster.subscribe(session_id, &remote_addr);
+        shared.announce(session_id, name.clone(), true);
+        let mut tunnel_frames = tunnels.then(|| {
+            shared
+                .tunnels
+                .add_session(session_id, websockets, tcp_ports)
+        });
+        let mut close = None;
+        let mut throttle = Throttle::new(shared.session_quota);
+        // a frame over the session's quota, waiting until it may be sent
+        let mut held: Option<(Vec<u8>, tokio::time::Instant)> = None;
+        let mut dropping = false;
+
         loop {
             tokio::select! {
                 message = msg_stream.next() => {
@@ -127,6 +1757,23 @@ async fn handle_websocket(
                         Some(Ok(Message::Close(_))) => {
                             break;
                         },
+                        Some(Ok(Message::Binary(bytes))) => {
+                            let frame = match tunnels {
+                                true => tunnel::decode(&bytes),
+                                false => Ok(Frame::Message(&bytes)),
+                            };
+                            match frame {
+                                Ok(Frame::Message(data)) if reports => {
+                                    match rmp_serde::from_slice::<DeliveryReport>(data) {
+                                        Ok(report) => shared.delivered(session_id, &remote_addr, report),
+                                        Err(err) => warn!("[{remote_addr}] Invalid delivery report: {err}"),
+                                    }
+                                }
+                                Ok(Frame::Message(_)) => {}
+                                Ok(Frame::Tunnel(frame)) => shared.tunnels.received(session_id, frame),
+                                Err(err) => warn!("[{remote_addr}] Invalid frame: {err}"),
+                            }
+                        },
                         Some(Ok(_)) => {},
                         Some(Err(err)) => {
                             warn!("[{remote_addr}] {err}");
@@ -137,45 +1784,617 @@ async fn handle_websocket(
                         }
                     }
                 },
-                Ok(msg) = receiver.recv() => {
-                    if let Err(err) = session.binary(rmp_serde::to_vec(&msg).unwrap()).await {
+                _ = tokio::time::sleep_until(held.as_ref().map_or_else(tokio::time::Instant::now, |(_, at)| *at)), if held.is_some() => {
+                    let (frame, _) = held.take().unwrap();
+                    let size = frame.len();
+                    match throttle.admit(size) {
+                        Ok(()) => {
+                            if let Err(err) = session.binary(frame).await {
+                                warn!("[{remote_addr}] {err}");
+                                break;
+                            }
+                            shared.sessions.sent(session_id, size);
+                        }
+                        Err(at) => held = Some((frame, at)),
+                    }
+                }
+                received = queue.recv(), if held.is_none() => {
+                    shared.lagged(session_id, &remote_addr, queue.take_dropped());
+
+                    match received {
+                        Some(msg) => {
+                            let mut frame = wire::encode(&msg, format, compress_above).unwrap();
+                            if tunnels {
+                                frame = tunnel::message_frame(frame);
+                            }
+                            let size = frame.len();
+                            if let Err(at) = throttle.admit(size) {
+                                shared.sessions.throttled(session_id, throttle.policy());
+                                match throttle.policy() {
+                                    ThrottlePolicy::Delay => held = Some((frame, at)),
+                                    ThrottlePolicy::Drop if !dropping => {
+                                        dropping = true;
+                                        warn!("[{remote_addr}] Client is over its session limits, dropping requests until it's back under them");
+                                    }
+                                    ThrottlePolicy::Drop => {}
+                                }
+                                continue;
+                            }
+                            if dropping {
+                                dropping = false;
+                                info!("[{remote_addr}] Client is back under its session limits");
+                            }
+                            if let Err(err) = session.binary(frame).await {
+                                warn!("[{remote_addr}] {err}");
+                                break;
+                            }
+                            shared.sessions.sent(session_id, size);
+                        }
+                        None => {
+                            close = Some(CloseReason::from((CloseCode::Again, "fell behind")));
+                            break;
+                        }
+                    }
+                }
+                Some(event) = next_peer_event(&mut peer_events, session_id) => {
+                    if let Err(err) = session.text(serde_json::to_string(&event).unwrap()).await {
+                        warn!("[{remote_addr}] {err}");
+                        break;
+                    }
+                }
+                Some(frame) = next_tunnel_frame(&mut tunnel_frames) => {
+                    if let Err(err) = session.binary(frame.encode().unwrap()).await {
                         warn!("[{remote_addr}] {err}");
                         break;
                     }
                 }
+                Ok(why) = &mut disconnect => {
+                    close = Some(match why {
+                        Disconnect::Admin => {
+                            info!("[{remote_addr}] Disconnected through the admin API");
+                            CloseReason::from((CloseCode::Policy, "disconnected by an admin"))
+                        }
+                        Disconnect::Shutdown => {
+                            CloseReason::from((CloseCode::Away, "server shutting down"))
+                        }
+                        Disconnect::Replaced => {
+                            info!("[{remote_addr}] Replaced by another client");
+                            CloseReason::from((CloseCode::Policy, REPLACED))
+                        }
+                    });
+                    break;
+                }
             }
         }
 
-        let _ = session.close(None).await;
+        let _ = session.close(close).await;
+        shared.broadcaster.unsubscribe(session_id);
+        shared.announce(session_id, name, false);
+        if tunnels {
+            shared.tunnels.remove_session(session_id);
+        }
+        drop(claim);
+        shared.sessions.finish(session_id);
 
-        info!("[{remote_addr}] Session finished");
+        info!(event = "disconnected", remote_addr = remote_addr.as_str(); "[{remote_addr}] Session finished");
     });
 
     Ok(response)
 }
 
+/// The next frame to send for the session's tunnels, never if it didn't negotiate them
+async fn next_tunnel_frame(
+    frames: &mut Option<UnboundedReceiver<TunnelFrame>>,
+) -> Option<TunnelFrame> {
+    match frames {
+        Some(frames) => frames.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The next peer event about a session other than `session_id`, never if the session didn't
+/// negotiate them
+async fn next_peer_event(
+    events: &mut Option<broadcast::Receiver<PeerEvent>>,
+    session_id: u64,
+) -> Option<PeerEvent> {
+    let Some(events) = events else {
+        return std::future::pending().await;
+    };
+
+    loop {
+        match events.recv().await {
+            Ok(event) if event.session() != session_id => return Some(event),
+            Ok(_) | Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+/// Waits for the client's `Hello`
+async fn read_hello(msg_stream: &mut actix_ws::MessageStream) -> Result<Hello, String> {
+    let message = tokio::time::timeout(Duration::from_secs(10), msg_stream.next())
+        .await
+        .map_err(|_| "timed out waiting for hello".to_string())?;
+
+    match message {
+        Some(Ok(Message::Binary(bytes))) => {
+            rmp_serde::from_slice(&bytes).map_err(|e| format!("invalid hello: {}", e))
+        }
+        Some(Ok(other)) => Err(format!("expected hello, got {:?}", other)),
+        Some(Err(err)) => Err(err.to_string()),
+        None => Err("connection closed before hello".to_string()),
+    }
+}
+
+/// Answers the client's `Hello` with a `HelloAck`, once its session's registered
+async fn greet(
+    session: &mut actix_ws::Session,
+    shared: &Shared,
+    credential: &Credential,
+    hello: &Hello,
+    reached: Option<Url>,
+) -> Result<(HelloAck, Option<Claim>), String> {
+    let (path, claim) = shared.routes(credential, hello.subdomain.as_deref())?;
+    let host = claim.as_ref().map(|c| c.host.clone());
+    let public_url = shared.public_url(reached, host.as_deref(), path.as_deref());
+    let ack = HelloAck {
+        protocol: hello.protocol.min(PROTOCOL_VERSION),
+        version: VERSION.to_string(),
+        features: hello.shared_features(),
+        max_body_size: Some(shared.max_body_size),
+        format: hello.wire_format(),
+        tcp_ports: shared.tcp_ports.clone(),
+        path,
+        host,
+        public_url,
+    };
+
+    session
+        .binary(rmp_serde::to_vec_named(&ack).unwrap())
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok((ack, claim))
+}
+
+/// Checks an incoming request is to an ingested path, from an allowed address and within the
+/// rate limits, returning the address it came from or the response rejecting it
+fn admit(
+    req: &HttpRequest,
+    fullpath: &str,
+    shared: &Shared,
+    policy: &Policy,
+) -> Result<Option<IpAddr>, HttpResponse> {
+    if let Some(prefix) = &shared.ingest_prefix {
+        if !is_under(fullpath, prefix) {
+            return Err(HttpResponse::NotFound().finish());
+        }
+    }
+
+    let ip = req.peer_addr().map(|peer| {
+        let forwarded_for = req
+            .headers()
+            .get("x-forwarded-for")
+            .and_then(|v| v.to_str().ok());
+        policy.access.client_ip(peer.ip(), forwarded_for)
+    });
+
+    if let Some(ip) = ip.filter(|ip| !policy.access.allows(*ip)) {
+        warn!(
+            "Rejected {} {} from {} outside of the allowed ranges",
+            req.method(),
+            req.uri(),
+            ip
+        );
+        return Err(HttpResponse::Forbidden().finish());
+    }
+
+    if let Err(retry_after) = policy.rate_limiter.check(ip) {
+        warn!(
+            "Rate limited {} {} from {}",
+            req.method(),
+            req.uri(),
+            ip.map(|ip| ip.to_string()).unwrap_or_default()
+        );
+        return Err(HttpResponse::TooManyRequests()
+            .insert_header((
+                "Retry-After",
+                (retry_after.as_secs_f64().ceil() as u64).to_string(),
+            ))
+            .finish());
+    }
+
+    Ok(ip)
+}
+
 async fn handle_receive(
     req: HttpRequest,
     payload: web::Bytes,
-    broadcaster: Data<Broadcaster>,
-) -> impl Responder {
-    let headers: Vec<(String, String)> = req
-        .headers()
-        .iter()
-        .filter(|(k, _)| k.as_str() != "host")
-        .filter(|(k, _)| k.as_str() != "origin")
-        .map(|(k, v)| (k.as_str().to_owned(), v.to_str().unwrap().to_owned()))
-        .collect();
+    shared: Data<Shared>,
+) -> HttpResponse {
+    let fullpath = relayed_path(&req);
+    let policy = shared.policy();
+    let ip = match admit(&req, &fullpath, &shared, &policy) {
+        Ok(ip) => ip,
+        Err(response) => return response,
+    };
+
+    let path = fullpath.split('?').next().unwrap_or_default();
+    let sample_rate =
+        match policy
+            .filters
+            .check(req.method().as_str(), path, req.headers(), payload.len())
+        {
+            Verdict::Deny(rule) => {
+                debug!(
+                    "Denied {} {} by filter rule {}",
+                    req.method(),
+                    req.uri(),
+                    rule
+                );
+                shared.stats.denied(rule);
+                return HttpResponse::NotFound().finish();
+            }
+            Verdict::Allow { sample_rate } => sample_rate,
+        };
+
+    if shared.slack_url_verification {
+        if let Some(challenge) = responses::slack_challenge(&payload) {
+            info!("Answered Slack url_verification for {}", req.uri());
+            return HttpResponse::Ok()
+                .content_type("text/plain")
+                .body(challenge);
+        }
+    }
+
+    if !policy.verify_signatures.is_empty() {
+        // against every header, so a signature header can be verified and still not relayed
+        let headers: Vec<(String, HeaderBytes)> = req
+            .headers()
+            .iter()
+            .map(|(k, v)| (k.as_str().to_owned(), HeaderBytes(v.as_bytes().to_vec())))
+            .collect();
+        if let Err(reason) = signature::verify_any(&policy.verify_signatures, &headers, &payload) {
+            warn!(
+                "Rejected {} {} with invalid signature ({})",
+                req.method(),
+                req.uri(),
+                reason
+            );
+            return HttpResponse::Unauthorized().finish();
+        }
+    }
+
+    shared.stats.received(path, payload.len());
+
+    let mut headers = policy.headers.filter(req.headers());
+    headers.push((
+        RECEIVED_AT_HEADER.to_string(),
+        Utc::now().timestamp_millis().to_string().into(),
+    ));
 
     let message = RequestMessage {
         method: req.head().method.to_string(),
-        fullpath: req.head().uri.to_string(),
+        fullpath,
         version: req.head().version.into(),
         headers,
         body: payload.into(),
+        source: Some(request_source(&req, ip, &policy.access)),
+    };
+
+    // with a bus, clients may be connected to another instance
+    let connected = match shared.target(&message) {
+        Target::Everyone => shared.broadcaster.client_count() > 0,
+        Target::Labelled(_) | Target::Session { .. } => true,
+        Target::Nobody => false,
+    };
+    if shared.bus.get().is_none() && !connected {
+        warn!(
+            "No clients connected, responding {} to {} {}",
+            shared.no_client_status.as_u16(),
+            req.method(),
+            req.uri()
+        );
+        shared.received(&message, Outcome::NoClient);
+        return HttpResponse::build(shared.no_client_status).finish();
+    }
+
+    let response = policy.responses.respond(&message);
+
+    if shared
+        .dedup
+        .as_ref()
+        .is_some_and(|d| d.is_duplicate(&message))
+    {
+        info!(
+            "Not relaying duplicate delivery of {} {}",
+            message.method, message.fullpath
+        );
+        shared.received(&message, Outcome::Duplicate);
+        return response;
+    }
+
+    if !policy.filters.sampled(sample_rate) {
+        debug!(
+            "Not relaying {} {}, it wasn't sampled",
+            message.method, message.fullpath
+        );
+        shared.stats.sampled_out();
+        shared.received(&message, Outcome::SampledOut);
+        return response;
+    }
+
+    for hook in shared.hooks.iter() {
+        hook(&message);
+    }
+
+    match shared.bus.get() {
+        Some(bus) => {
+            if let Err(e) = bus.publish(&message).await {
+                warn!(
+                    "Could not publish {} {} to the bus: {:#}",
+                    message.method, message.fullpath, e
+                );
+                return HttpResponse::ServiceUnavailable().finish();
+            }
+        }
+        None => shared.relay(message),
+    }
+
+    response
+}
+
+/// Upgrades a provider's websocket and tunnels it through a client that takes tunnels to its
+/// local origin, once that accepts it as well
+async fn handle_tunnel(req: HttpRequest, body: web::Payload, shared: Data<Shared>) -> HttpResponse {
+    let fullpath = tunnelled_path(&req);
+    let policy = shared.policy();
+    let ip = match admit(&req, &fullpath, &shared, &policy) {
+        Ok(ip) => ip,
+        Err(response) => return response,
+    };
+
+    let path = fullpath.split('?').next().unwrap_or_default();
+    if let Verdict::Deny(rule) = policy
+        .filters
+        .check(req.method().as_str(), path, req.headers(), 0)
+    {
+        debug!("Denied websocket {} by filter rule {}", req.uri(), rule);
+        shared.stats.denied(rule);
+        return HttpResponse::NotFound().finish();
+    }
+
+    shared.stats.received(path, 0);
+
+    let message = RequestMessage {
+        method: req.head().method.to_string(),
+        fullpath,
+        version: req.head().version.into(),
+        headers: policy.headers.filter(req.headers()),
+        body: vec![],
+        source: Some(request_source(&req, ip, &policy.access)),
+    };
+
+    // to the client whose subdomain or path it's to, as a request would be
+    let (message, among) = match shared.target(&message) {
+        Target::Everyone => (message, None),
+        Target::Labelled(ids) => (message, Some(ids)),
+        Target::Session { id, fullpath } => (
+            RequestMessage {
+                fullpath,
+                ..message
+            },
+            Some(vec![id]),
+        ),
+        Target::Nobody => {
+            warn!(
+                "No client connected for it, responding {} to websocket {}",
+                shared.no_client_status.as_u16(),
+                req.uri()
+            );
+            return HttpResponse::build(shared.no_client_status).finish();
+        }
+    };
+
+    let Some(mut tunnel) = shared.tunnels.open(message, among.as_deref()) else {
+        warn!(
+            "No connected client takes websockets, responding 502 to {}",
+            req.uri()
+        );
+        return HttpResponse::BadGateway().finish();
+    };
+
+    let protocol = match tokio::time::timeout(TUNNEL_OPEN_TIMEOUT, tunnel.recv()).await {
+        Ok(Some(TunnelFrame::Opened { protocol, .. })) => protocol,
+        Ok(Some(TunnelFrame::Close { reason, .. })) => {
+            warn!(
+                "Session #{} couldn't open websocket {}: {}",
+                tunnel.session_id,
+                req.uri(),
+                reason
+            );
+            return HttpResponse::BadGateway().body(reason);
+        }
+        Ok(_) => return HttpResponse::BadGateway().finish(),
+        Err(_) => {
+            warn!(
+                "Session #{} took too long to open websocket {}",
+                tunnel.session_id,
+                req.uri()
+            );
+            tunnel.send(TunnelFrame::Close {
+                id: tunnel.id,
+                code: None,
+                reason: "timed out".to_string(),
+            });
+            return HttpResponse::GatewayTimeout().finish();
+        }
+    };
+
+    let (mut response, session, stream) = match actix_ws::handle(&req, body) {
+        Ok(handled) => handled,
+        Err(err) => {
+            tunnel.send(TunnelFrame::Close {
+                id: tunnel.id,
+                code: None,
+                reason: err.to_string(),
+            });
+            return HttpResponse::from_error(err);
+        }
+    };
+    if let Some(value) = protocol.and_then(|p| HeaderValue::from_str(&p).ok()) {
+        response.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, value);
+    }
+
+    info!(
+        event = "tunnel_opened", tunnel = tunnel.id, session = tunnel.session_id;
+        "Tunnelling websocket {} through session #{}", req.uri(), tunnel.session_id
+    );
+    actix_web::rt::spawn(pump_tunnel(tunnel, session, stream));
+
+    response
+}
+
+/// Relays messages between a provider's websocket and the client it's tunnelled through until
+/// either closes it
+async fn pump_tunnel(
+    mut tunnel: Tunnel,
+    mut session: actix_ws::Session,
+    mut stream: actix_ws::MessageStream,
+) {
+    let id = tunnel.id;
+    let gone = || Some(CloseReason::from((CloseCode::Away, "client disconnected")));
+
+    let close = loop {
+        tokio::select! {
+            message = stream.next() => {
+                let (text, data) = match message {
+                    Some(Ok(Message::Text(text))) => (true, text.as_bytes().to_vec()),
+                    Some(Ok(Message::Binary(bytes))) => (false, bytes.to_vec()),
+                    Some(Ok(Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            break None;
+                        }
+                        continue;
+                    }
+                    Some(Ok(Message::Close(reason))) => {
+                        tunnel.send(TunnelFrame::Close {
+                            id,
+                            code: reason.as_ref().map(|r| r.code.into()),
+                            reason: reason.as_ref().and_then(|r| r.description.clone()).unwrap_or_default(),
+                        });
+                        break reason;
+                    }
+                    Some(Ok(_)) => continue,
+                    Some(Err(_)) | None => {
+                        tunnel.send(TunnelFrame::Close { id, code: None, reason: String::new() });
+                        break None;
+                    }
+                };
+                if !tunnel.send(TunnelFrame::Data { id, text, data }) {
+                    break gone();
+                }
+            },
+            frame = tunnel.recv() => {
+                let sent = match frame {
+                    Some(TunnelFrame::Data { text: true, data, .. }) => {
+                        session.text(String::from_utf8_lossy(&data).into_owned()).await
+                    }
+                    Some(TunnelFrame::Data { data, .. }) => session.binary(data).await,
+                    Some(TunnelFrame::Close { code, reason, .. }) => {
+                        break Some(CloseReason {
+                            code: code.map_or(CloseCode::Normal, CloseCode::from),
+                            description: Some(reason).filter(|r| !r.is_empty()),
+                        });
+                    }
+                    Some(_) => continue,
+                    None => break gone(),
+                };
+                if sent.is_err() {
+                    tunnel.send(TunnelFrame::Close { id, code: None, reason: String::new() });
+                    break None;
+                }
+            }
+        }
+    };
+
+    let _ = session.close(close).await;
+    info!(event = "tunnel_closed", tunnel = id, session = tunnel.session_id; "Closed websocket tunnel {}", id);
+}
+
+/// Where the request came from and over what, believing a trusted proxy's `X-Forwarded-Proto`
+/// and `X-Forwarded-Host` like its `X-Forwarded-For`
+fn request_source(req: &HttpRequest, ip: Option<IpAddr>, access: &Access) -> RequestSource {
+    let from_proxy = req
+        .peer_addr()
+        .is_some_and(|peer| access.is_trusted(peer.ip()));
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.split(',').next().unwrap_or_default().trim().to_string())
     };
+    let forwarded = |name: &str| header(name).filter(|_| from_proxy);
+    let connection = req.connection_info();
+    let scheme = forwarded("x-forwarded-proto").unwrap_or(match req.app_config().secure() {
+        true => "https".to_string(),
+        false => "http".to_string(),
+    });
+
+    RequestSource {
+        ip: ip.map(|ip| ip.to_string()),
+        peer: connection.peer_addr().map(str::to_string),
+        tls: Some(scheme.eq_ignore_ascii_case("https")),
+        scheme: Some(scheme),
+        host: forwarded("x-forwarded-host").or_else(|| header("host")),
+    }
+}
+
+/// Where a client reached the server with its websocket request, the mount the relay is served
+/// on, by the host and scheme a trusted proxy says it was sent to
+fn reached_at(req: &HttpRequest, access: &Access) -> Option<Url> {
+    let source = request_source(req, None, access);
+    let mount = req.path().trim_end_matches('/');
+    let mount = mount.strip_suffix("/__hookhub__").unwrap_or(mount);
+
+    Url::parse(&format!("{}://{}{}", source.scheme?, source.host?, mount)).ok()
+}
+
+/// The path and query of an upgrade request matched by the tunnel's catch-all resource, which
+/// leaves nothing for `relayed_path` to go on
+fn tunnelled_path(req: &HttpRequest) -> String {
+    let path = format!("/{}", req.match_info().get("tail").unwrap_or_default());
 
-    broadcaster.send(message);
+    match req.uri().query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path,
+    }
+}
+
+/// Whether `fullpath`, which may have a query, is `prefix` or below it
+fn is_under(fullpath: &str, prefix: &str) -> bool {
+    let path = fullpath.split('?').next().unwrap_or_default();
+    prefix == "/"
+        || path
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
 
-    HttpResponse::Ok()
+/// The request's path and query below wherever the relay is mounted, so a relay nested under
+/// `/hooks` passes `/hooks/github` on as `/github`
+fn relayed_path(req: &HttpRequest) -> String {
+    let uri = &req.head().uri;
+    let mounted = req.match_info().as_str().len() - req.match_info().unprocessed().len();
+
+    let path = match uri.path().get(mounted..) {
+        Some("") => "/",
+        Some(path) if path.starts_with('/') => path,
+        _ => uri.path(),
+    };
+
+    match uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    }
 }
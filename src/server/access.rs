@@ -0,0 +1,152 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+/// Decides which source addresses may hit the ingest endpoint
+pub struct Access {
+    allow: Vec<IpNet>,
+    trusted_proxies: Vec<IpNet>,
+}
+
+impl Access {
+    pub fn new(allow: Vec<IpNet>, trusted_proxies: Vec<IpNet>) -> Self {
+        Self {
+            allow,
+            trusted_proxies,
+        }
+    }
+
+    /// Works out the address a request came from. `X-Forwarded-For` is only believed when the
+    /// peer is a trusted proxy, and is walked from the right until an untrusted hop is found so
+    /// a client can't spoof its address by sending the header itself.
+    pub fn client_ip(&self, peer: IpAddr, forwarded_for: Option<&str>) -> IpAddr {
+        if !self.is_trusted(peer) {
+            return peer;
+        }
+
+        let Some(forwarded_for) = forwarded_for else {
+            return peer;
+        };
+
+        let mut ip = peer;
+        for hop in forwarded_for.rsplit(',') {
+            match hop.trim().parse::<IpAddr>() {
+                Ok(hop) => {
+                    ip = hop;
+                    if !self.is_trusted(hop) {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+
+        ip
+    }
+
+    /// Whether an address is allowed, everything is when no ranges are configured
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Whether an address is a trusted proxy, whose forwarding headers are believed
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nets(nets: &[&str]) -> Vec<IpNet> {
+        nets.iter().map(|net| net.parse().unwrap()).collect()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn behind_proxies() -> Access {
+        Access::new(vec![], nets(&["10.0.0.0/8", "fd00::/8"]))
+    }
+
+    #[test]
+    fn ignores_forwarded_for_from_untrusted_peers() {
+        let access = behind_proxies();
+
+        assert_eq!(
+            access.client_ip(ip("203.0.113.7"), Some("198.51.100.1")),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn uses_the_peer_without_forwarded_for() {
+        let access = behind_proxies();
+
+        assert_eq!(access.client_ip(ip("10.0.0.1"), None), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn walks_forwarded_for_to_the_first_untrusted_hop() {
+        let access = behind_proxies();
+
+        // a client prepending its own entry can't get past the hop the proxies saw
+        assert_eq!(
+            access.client_ip(ip("10.0.0.1"), Some("192.0.2.1, 198.51.100.1, 10.0.0.2")),
+            ip("198.51.100.1")
+        );
+        assert_eq!(
+            access.client_ip(ip("fd00::1"), Some("2001:db8::1")),
+            ip("2001:db8::1")
+        );
+    }
+
+    #[test]
+    fn uses_the_leftmost_hop_when_the_whole_chain_is_trusted() {
+        let access = behind_proxies();
+
+        assert_eq!(
+            access.client_ip(ip("10.0.0.1"), Some("10.0.0.3,10.0.0.2")),
+            ip("10.0.0.3")
+        );
+    }
+
+    #[test]
+    fn stops_at_an_unparsable_hop() {
+        let access = behind_proxies();
+
+        assert_eq!(
+            access.client_ip(ip("10.0.0.1"), Some("198.51.100.1, unknown, 10.0.0.2")),
+            ip("10.0.0.2")
+        );
+        assert_eq!(
+            access.client_ip(ip("10.0.0.1"), Some("garbage")),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn allows_everything_without_ranges() {
+        let access = Access::new(vec![], vec![]);
+
+        assert!(access.allows(ip("203.0.113.7")));
+        assert!(access.allows(ip("::1")));
+    }
+
+    #[test]
+    fn allows_only_addresses_in_the_ranges() {
+        let access = Access::new(
+            nets(&["192.0.2.0/24", "2001:db8::/32", "198.51.100.7/32"]),
+            vec![],
+        );
+
+        for allowed in ["192.0.2.0", "192.0.2.255", "2001:db8::1", "198.51.100.7"] {
+            assert!(access.allows(ip(allowed)), "{}", allowed);
+        }
+        for refused in ["192.0.3.0", "198.51.100.8", "2001:db9::1", "10.0.0.1"] {
+            assert!(!access.allows(ip(refused)), "{}", refused);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use async_nats::jetstream::{
+    self,
+    consumer::{pull, DeliverPolicy},
+    stream,
+};
+use futures_util::StreamExt as _;
+use log::{error, info, warn};
+use url::Url;
+
+use crate::{wire, RequestMessage, WireFormat};
+
+/// How long a stream created for the bus keeps requests, an existing one keeps its own limits
+const STREAM_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Shares received requests between server instances over NATS, so a client connected to any
+/// instance gets the requests received by all of them. Requests are sent as CBOR so instances
+/// of different versions can share a bus. On core NATS nothing is stored: what's published
+/// while an instance isn't subscribed never reaches it. With a JetStream `Stream` requests are
+/// stored, and an instance reading through a durable consumer catches up on what it missed.
+pub struct Bus {
+    client: async_nats::Client,
+    subject: String,
+    jetstream: Option<(jetstream::Context, stream::Stream, Option<String>)>,
+}
+
+/// The JetStream stream a bus stores requests in, and the durable consumer this instance reads
+/// them through. Without one it only gets what's published while it's subscribed.
+#[derive(Clone)]
+pub struct Stream {
+    pub name: String,
+    pub consumer: Option<String>,
+}
+
+impl Bus {
+    pub async fn connect(url: &Url, subject: &str, stream: Option<&Stream>) -> Result<Self> {
+        let client = async_nats::connect(url.as_str())
+            .await
+            .with_context(|| format!("couldn't connect to the bus at {}", url))?;
+
+        let jetstream = match stream {
+            Some(Stream { name, consumer }) => {
+                let context = jetstream::new(client.clone());
+                let stream = context
+                    .get_or_create_stream(stream::Config {
+                        name: name.clone(),
+                        subjects: vec![subject.to_string()],
+                        max_age: STREAM_MAX_AGE,
+                        ..Default::default()
+                    })
+                    .await
+                    .with_context(|| format!("couldn't open JetStream stream {}", name))?;
+                info!(
+                    "Connected to the bus at {}, on {} stored in stream {}",
+                    url, subject, name
+                );
+
+                Some((context, stream, consumer.clone()))
+            }
+            None => {
+                info!("Connected to the bus at {}, on {}", url, subject);
+
+                None
+            }
+        };
+
+        Ok(Self {
+            client,
+            subject: subject.to_string(),
+            jetstream,
+        })
+    }
+
+    /// Publishes a request, once it's stored when the bus is a stream
+    pub async fn publish(&self, req: &RequestMessage) -> Result<()> {
+        let data = wire::encode(req, WireFormat::Cbor, None)?;
+
+        match &self.jetstream {
+            Some((context, _, _)) => {
+                context
+                    .publish(self.subject.clone(), data.into())
+                    .await?
+                    .await?;
+            }
+            None => {
+                self.client
+                    .publish(self.subject.clone(), data.into())
+                    .await?
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Calls `relay` with every request published to the bus, by any instance including this one
+    pub async fn subscribe(&self, relay: impl Fn(RequestMessage) + Send + 'static) -> Result<()> {
+        let Some((_, stream, consumer)) = &self.jetstream else {
+            let mut subscriber = self.client.subscribe(self.subject.clone()).await?;
+
+            actix_web::rt::spawn(async move {
+                while let Some(message) = subscriber.next().await {
+                    decode(&message.payload).map(&relay);
+                }
+
+                error!(
+                    "Subscription to the bus ended, requests from other instances won't be relayed"
+                );
+            });
+
+            return Ok(());
+        };
+
+        let config = pull::Config {
+            durable_name: consumer.clone(),
+            // the first time, a durable consumer then resumes from where it was
+            deliver_policy: DeliverPolicy::New,
+            ..Default::default()
+        };
+        let consumer = match consumer {
+            Some(name) => stream.get_or_create_consumer(name, config).await,
+            None => stream.create_consumer(config).await,
+        }
+        .context("couldn't create a consumer of the bus stream")?;
+        let mut messages = consumer.messages().await?;
+
+        actix_web::rt::spawn(async move {
+            while let Some(message) = messages.next().await {
+                match message {
+                    Ok(message) => {
+                        decode(&message.payload).map(&relay);
+                        if let Err(e) = message.ack().await {
+                            warn!("Couldn't acknowledge a request from the bus: {}", e);
+                        }
+                    }
+                    Err(e) => warn!("Couldn't read from the bus stream: {}", e),
+                }
+            }
+
+            error!(
+                "Consumer of the bus stream ended, requests from other instances won't be relayed"
+            );
+        });
+
+        Ok(())
+    }
+}
+
+fn decode(payload: &[u8]) -> Option<RequestMessage> {
+    wire::decode(payload, WireFormat::Cbor, false)
+        .inspect_err(|e| warn!("Ignoring invalid request from the bus: {:#}", e))
+        .ok()
+}
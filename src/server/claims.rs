@@ -0,0 +1,80 @@
+use std::{fs, io, path::Path};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// The path and subdomain a client was given, kept so it's given the same ones when it
+/// reconnects and nobody else gets them meanwhile
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct Claim {
+    /// The token (or authenticator's name) the client authenticated with, e.g. `token alice`
+    pub credential: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subdomain: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    pub claimed_at: DateTime<Utc>,
+}
+
+/// Claims persisted as JSON in a file. Like the tokens file it's read on every lookup, so
+/// claims released while the server is running take effect immediately.
+pub struct ClaimStore<'a> {
+    path: &'a Path,
+}
+
+impl<'a> ClaimStore<'a> {
+    pub fn new(path: &'a Path) -> Self {
+        Self { path }
+    }
+
+    pub fn list(&self) -> Result<Vec<Claim>> {
+        match fs::read(self.path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| anyhow!("invalid claims in {}: {}", self.path.display(), e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(anyhow!("{}: {}", self.path.display(), e)),
+        }
+    }
+
+    /// Keeps the subdomain and path given to the client with `credential`, replacing what it
+    /// had
+    pub fn keep(
+        &self,
+        credential: &str,
+        subdomain: Option<String>,
+        path: Option<String>,
+    ) -> Result<()> {
+        let mut claims = self.list()?;
+        claims.retain(|claim| claim.credential != credential);
+        claims.push(Claim {
+            credential: credential.to_string(),
+            subdomain,
+            path,
+            claimed_at: Utc::now(),
+        });
+
+        self.save(&claims)
+    }
+
+    /// Removes the claim of the client with `name` as its credential, subdomain or path,
+    /// returning whether there was one
+    pub fn release(&self, name: &str) -> Result<bool> {
+        let mut claims = self.list()?;
+        let count = claims.len();
+
+        claims.retain(|claim| {
+            claim.credential != name
+                && claim.subdomain.as_deref() != Some(name)
+                && claim.path.as_deref() != Some(name)
+        });
+        self.save(&claims)?;
+
+        Ok(claims.len() < count)
+    }
+
+    fn save(&self, claims: &[Claim]) -> Result<()> {
+        fs::write(self.path, serde_json::to_vec_pretty(claims)?)
+            .map_err(|e| anyhow!("{}: {}", self.path.display(), e))
+    }
+}
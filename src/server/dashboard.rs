@@ -0,0 +1,43 @@
+use std::time::Duration;
+
+use actix_web::{
+    web::{self, Data},
+    HttpResponse,
+};
+use futures::stream;
+use serde_json::json;
+
+use super::Shared;
+
+/// How often the dashboard's event stream pushes a fresh view
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+pub(super) async fn handle_ui() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(include_str!("dashboard.html"))
+}
+
+/// Server-sent events, each a JSON view of the sessions, recent requests and queue depth
+pub(super) async fn handle_events(shared: Data<Shared>) -> HttpResponse {
+    let events = stream::unfold(
+        (shared, tokio::time::interval(UPDATE_INTERVAL)),
+        |(shared, mut interval)| async move {
+            interval.tick().await;
+
+            let view = json!({
+                "sessions": shared.sessions(),
+                "recent": shared.recent.list(),
+                "queue_depth": shared.broadcaster.queue_depth(),
+            });
+            let event = web::Bytes::from(format!("data: {}\n\n", view));
+
+            Some((Ok::<_, actix_web::Error>(event), (shared, interval)))
+        },
+    );
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events)
+}
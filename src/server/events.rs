@@ -0,0 +1,157 @@
+use std::{sync::atomic::Ordering, sync::Arc, time::Duration};
+
+use actix_web::{
+    dev::ConnectionInfo,
+    web::{self, Data},
+    HttpMessage, HttpRequest, HttpResponse,
+};
+use futures::stream;
+use log::{info, warn};
+use tokio::{sync::oneshot, time::Interval};
+
+use super::{
+    queue::Queue,
+    sessions::Disconnect,
+    throttle::{Throttle, ThrottlePolicy},
+    Credential, Shared,
+};
+use crate::{JsonRequest, RequestMessage};
+
+/// How often an idle stream sends a comment, so proxies don't time it out
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Relays requests as server-sent events instead of over a websocket, each a `request` event
+/// with the request as JSON and the delivery id as its id. It's a session like any other, with
+/// its own queue, but can't send delivery reports or take tunnels.
+pub(super) async fn handle_events(
+    req: HttpRequest,
+    connection_info: ConnectionInfo,
+    shared: Data<Shared>,
+) -> HttpResponse {
+    if shared.draining.load(Ordering::Relaxed) {
+        return HttpResponse::ServiceUnavailable().body("server shutting down");
+    }
+
+    // there is no peer address over a unix socket, unless a proxy forwards one
+    let remote_addr = connection_info
+        .realip_remote_addr()
+        .unwrap_or("unix socket")
+        .to_owned();
+    let Some(credential) = req.extensions().get::<Credential>().cloned() else {
+        return HttpResponse::Unauthorized().finish();
+    };
+
+    let (id, disconnect) = match shared.register(&remote_addr, &credential, None).await {
+        Ok(registered) => registered,
+        Err(reason) => {
+            warn!("[{remote_addr}] Refused event stream: {reason}");
+            return HttpResponse::Conflict().body(reason);
+        }
+    };
+
+    info!(
+        event = "connected", remote_addr = remote_addr.as_str(), credential = credential.name.as_str();
+        "[{remote_addr}] Event stream session started using {}", credential.name
+    );
+
+    let queue = shared.broadcaster.subscribe(id, &remote_addr);
+    shared.announce(id, None, true);
+    let session = EventSession {
+        queue,
+        throttle: Throttle::new(shared.session_quota),
+        keepalive: tokio::time::interval_at(
+            tokio::time::Instant::now() + KEEPALIVE_INTERVAL,
+            KEEPALIVE_INTERVAL,
+        ),
+        shared,
+        id,
+        remote_addr,
+        disconnect,
+    };
+
+    let events = stream::unfold(session, |mut session| async move {
+        let event = session.next().await?;
+        Some((Ok::<_, actix_web::Error>(event), session))
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events)
+}
+
+/// A session receiving requests as server-sent events, finished once the stream is dropped
+struct EventSession {
+    shared: Data<Shared>,
+    id: u64,
+    remote_addr: String,
+    queue: Arc<Queue>,
+    throttle: Throttle,
+    keepalive: Interval,
+    disconnect: oneshot::Receiver<Disconnect>,
+}
+
+impl EventSession {
+    /// The next event to send, `None` once the session should end
+    async fn next(&mut self) -> Option<web::Bytes> {
+        loop {
+            tokio::select! {
+                received = self.queue.recv() => {
+                    self.shared.lagged(self.id, &self.remote_addr, self.queue.take_dropped());
+
+                    // closed after overflowing, the client should reconnect
+                    let event = encode(&received?);
+                    let size = event.len();
+                    if let Err(mut at) = self.throttle.admit(size) {
+                        self.shared.sessions.throttled(self.id, self.throttle.policy());
+                        if self.throttle.policy() == ThrottlePolicy::Drop {
+                            continue;
+                        }
+                        loop {
+                            tokio::time::sleep_until(at).await;
+                            match self.throttle.admit(size) {
+                                Ok(()) => break,
+                                Err(next) => at = next,
+                            }
+                        }
+                    }
+
+                    self.shared.sessions.sent(self.id, size);
+                    return Some(event);
+                }
+                _ = self.keepalive.tick() => {
+                    return Some(web::Bytes::from_static(b": keepalive\n\n"));
+                }
+                Ok(why) = &mut self.disconnect => {
+                    match why {
+                        Disconnect::Admin => info!("[{}] Disconnected through the admin API", self.remote_addr),
+                        Disconnect::Replaced => info!("[{}] Replaced by another client", self.remote_addr),
+                        Disconnect::Shutdown => {}
+                    }
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for EventSession {
+    fn drop(&mut self) {
+        self.shared.broadcaster.unsubscribe(self.id);
+        self.shared.announce(self.id, None, false);
+        self.shared.sessions.finish(self.id);
+
+        info!(event = "disconnected", remote_addr = self.remote_addr.as_str(); "[{}] Event stream session finished", self.remote_addr);
+    }
+}
+
+/// A request as a `request` event, with its delivery id as the event's id if it has one
+fn encode(req: &RequestMessage) -> web::Bytes {
+    let data = serde_json::to_string(&JsonRequest::from(req)).unwrap();
+    let event = match req.delivery_id() {
+        Some(id) => format!("id: {}\nevent: request\ndata: {}\n\n", id, data),
+        None => format!("event: request\ndata: {}\n\n", data),
+    };
+
+    web::Bytes::from(event)
+}
@@ -0,0 +1,399 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use actix_web::http::header::HeaderMap;
+use anyhow::{anyhow, Result};
+use glob::Pattern;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Deserialize;
+
+use crate::RequestMessage;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+/// Whether to relay an incoming request, limited to those matching everything it gives. A rule
+/// giving nothing matches every request.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FilterRule {
+    pub action: Action,
+    /// What it's counted under in the stats, `rule <n>` if not given
+    pub name: Option<String>,
+    pub method: Option<String>,
+    pub path: Option<String>,
+    /// Headers the request must have, with values matching these globs
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    pub min_body_size: Option<usize>,
+    pub max_body_size: Option<usize>,
+    /// Fraction of the requests an allow rule matches to relay, instead of the --sample-rate
+    pub sample_rate: Option<f64>,
+    /// Only relay the requests an allow rule matches to clients with these labels, e.g.
+    /// `{"team": "payments"}`
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// What to do with an incoming request
+pub enum Verdict<'a> {
+    /// Answered with 404 and not relayed, because of the rule with this name
+    Deny(&'a str),
+    /// Relayed, or only a fraction of such requests are if there's a rate
+    Allow { sample_rate: Option<f64> },
+}
+
+struct Compiled {
+    name: String,
+    path: Option<Pattern>,
+    headers: Vec<(String, Pattern)>,
+    rule: FilterRule,
+}
+
+/// Rules checked in order against incoming requests before they're relayed, the first
+/// matching one deciding. Requests matching none are relayed, sampled at `sample_rate` if
+/// there is one.
+pub struct Filters {
+    rules: Vec<Compiled>,
+    sample_rate: Option<f64>,
+    random: SystemRandom,
+}
+
+impl Filters {
+    pub fn new(rules_file: Option<&Path>, sample_rate: Option<f64>) -> Result<Self> {
+        let rules: Vec<FilterRule> = match rules_file {
+            Some(path) => serde_json::from_slice(&fs::read(path)?)
+                .map_err(|e| anyhow!("invalid filter rules in {}: {}", path.display(), e))?,
+            None => vec![],
+        };
+
+        Self::compile(rules, sample_rate)
+    }
+
+    fn compile(rules: Vec<FilterRule>, sample_rate: Option<f64>) -> Result<Self> {
+        let rules = rules
+            .into_iter()
+            .enumerate()
+            .map(|(i, rule)| {
+                Ok(Compiled {
+                    name: rule
+                        .name
+                        .clone()
+                        .unwrap_or_else(|| format!("rule {}", i + 1)),
+                    path: rule.path.as_deref().map(Pattern::new).transpose()?,
+                    headers: rule
+                        .headers
+                        .iter()
+                        .map(|(name, value)| Ok((name.to_lowercase(), Pattern::new(value)?)))
+                        .collect::<Result<_>>()?,
+                    rule,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let rates = rules
+            .iter()
+            .map(|c| c.rule.sample_rate)
+            .chain([sample_rate]);
+        if let Some(rate) = rates.flatten().find(|rate| !(0.0..=1.0).contains(rate)) {
+            return Err(anyhow!("sample rate {} isn't between 0 and 1", rate));
+        }
+
+        Ok(Self {
+            rules,
+            sample_rate,
+            random: SystemRandom::new(),
+        })
+    }
+
+    /// What the first rule matching a request says to do with it. `path` shouldn't include the
+    /// query.
+    pub fn check(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &HeaderMap,
+        body_size: usize,
+    ) -> Verdict<'_> {
+        let has_header = |name: &str, pattern: &Pattern| {
+            headers
+                .get_all(name)
+                .any(|v| v.to_str().is_ok_and(|v| pattern.matches(v)))
+        };
+
+        match self
+            .rules
+            .iter()
+            .find(|c| c.matches(method, path, has_header, body_size))
+        {
+            Some(c) if c.rule.action == Action::Deny => Verdict::Deny(&c.name),
+            Some(c) => Verdict::Allow {
+                sample_rate: c.rule.sample_rate.or(self.sample_rate),
+            },
+            None => Verdict::Allow {
+                sample_rate: self.sample_rate,
+            },
+        }
+    }
+
+    /// The labels of the clients a request being relayed is for, from the first rule matching
+    /// it as it's relayed if that allows it and has any. Checked again on every instance
+    /// sharing a bus.
+    pub fn labels(&self, message: &RequestMessage) -> Option<&BTreeMap<String, String>> {
+        let path = message.fullpath.split('?').next().unwrap_or_default();
+        let has_header = |name: &str, pattern: &Pattern| {
+            message.headers.iter().any(|(n, v)| {
+                n.eq_ignore_ascii_case(name)
+                    && std::str::from_utf8(&v.0).is_ok_and(|v| pattern.matches(v))
+            })
+        };
+
+        self.rules
+            .iter()
+            .find(|c| c.matches(&message.method, path, has_header, message.body.len()))
+            .filter(|c| c.rule.action == Action::Allow && !c.rule.labels.is_empty())
+            .map(|c| &c.rule.labels)
+    }
+
+    /// Whether a request allowed with `sample_rate` is picked to be relayed
+    pub fn sampled(&self, sample_rate: Option<f64>) -> bool {
+        let Some(rate) = sample_rate else {
+            return true;
+        };
+
+        let mut bytes = [0; 8];
+        self.random.fill(&mut bytes).unwrap();
+        (u64::from_be_bytes(bytes) as f64 / u64::MAX as f64) < rate
+    }
+}
+
+impl Compiled {
+    fn matches(
+        &self,
+        method: &str,
+        path: &str,
+        has_header: impl Fn(&str, &Pattern) -> bool,
+        body_size: usize,
+    ) -> bool {
+        let rule = &self.rule;
+
+        rule.method
+            .as_ref()
+            .is_none_or(|m| m.eq_ignore_ascii_case(method))
+            && self.path.as_ref().is_none_or(|p| p.matches(path))
+            && self
+                .headers
+                .iter()
+                .all(|(name, pattern)| has_header(name, pattern))
+            && rule.min_body_size.is_none_or(|min| body_size >= min)
+            && rule.max_body_size.is_none_or(|max| body_size <= max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    use super::*;
+
+    type Headers<'a> = &'a [(&'a str, &'a str)];
+
+    fn filters(rules: &str, sample_rate: Option<f64>) -> Result<Filters> {
+        Filters::compile(serde_json::from_str(rules)?, sample_rate)
+    }
+
+    /// What `check` says, as `deny <rule>`, `allow` or `allow <sample rate>`
+    fn verdict(
+        filters: &Filters,
+        method: &str,
+        path: &str,
+        headers: Headers,
+        body_size: usize,
+    ) -> String {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+
+        match filters.check(method, path, &map, body_size) {
+            Verdict::Deny(name) => format!("deny {}", name),
+            Verdict::Allow { sample_rate: None } => "allow".to_string(),
+            Verdict::Allow {
+                sample_rate: Some(rate),
+            } => format!("allow {}", rate),
+        }
+    }
+
+    #[test]
+    fn parses_rules() {
+        let cases = [
+            (r#"[]"#, true),
+            (r#"[{"action": "allow"}]"#, true),
+            (
+                r#"[{"action": "deny", "name": "n", "method": "GET", "path": "/a/*",
+                     "headers": {"x-a": "b*"}, "min_body_size": 1, "max_body_size": 2,
+                     "sample_rate": 0.5, "labels": {"team": "t"}}]"#,
+                true,
+            ),
+            (r#"[{"action": "block"}]"#, false),
+            (r#"[{"path": "/a"}]"#, false),
+            (r#"[{"action": "allow", "paths": "/a"}]"#, false),
+            (r#"[{"action": "allow", "path": "/a/[b"}]"#, false),
+            (r#"[{"action": "allow", "headers": {"x-a": "[b"}}]"#, false),
+            (r#"[{"action": "allow", "sample_rate": 1.5}]"#, false),
+            (r#"[{"action": "allow", "sample_rate": -0.1}]"#, false),
+            (r#"{"action": "allow"}"#, false),
+        ];
+
+        for (rules, valid) in cases {
+            assert_eq!(filters(rules, None).is_ok(), valid, "{}", rules);
+        }
+        assert!(filters("[]", Some(2.0)).is_err());
+    }
+
+    #[test]
+    fn the_first_matching_rule_decides() {
+        let filters = filters(
+            r#"[
+                {"action": "allow", "name": "health", "method": "get", "path": "/health"},
+                {"action": "deny", "name": "no gets", "method": "GET"},
+                {"action": "allow", "path": "/hooks/*", "headers": {"x-github-event": "push"}},
+                {"action": "deny", "name": "hooks", "path": "/hooks/*"},
+                {"action": "deny", "name": "large", "min_body_size": 1000},
+                {"action": "deny", "max_body_size": 0},
+                {"action": "allow", "path": "/rare/*", "sample_rate": 0.25}
+            ]"#,
+            None,
+        )
+        .unwrap();
+
+        let cases: &[(&str, &str, Headers, usize, &str)] = &[
+            ("GET", "/health", &[], 0, "allow"),
+            ("GET", "/other", &[], 10, "deny no gets"),
+            (
+                "POST",
+                "/hooks/a",
+                &[("X-GitHub-Event", "push")],
+                10,
+                "allow",
+            ),
+            (
+                "POST",
+                "/hooks/a",
+                &[("x-github-event", "issues")],
+                10,
+                "deny hooks",
+            ),
+            (
+                "POST",
+                "/hooks/a",
+                &[("x-github-event", "issues"), ("x-github-event", "push")],
+                10,
+                "allow",
+            ),
+            ("POST", "/hooks/a", &[], 10, "deny hooks"),
+            ("POST", "/other", &[], 1000, "deny large"),
+            ("POST", "/other", &[], 999, "allow"),
+            ("POST", "/other", &[], 0, "deny rule 6"),
+            ("POST", "/rare/a", &[], 10, "allow 0.25"),
+        ];
+
+        for (method, path, headers, body_size, expected) in cases {
+            assert_eq!(
+                verdict(&filters, method, path, headers, *body_size),
+                *expected,
+                "{} {} {:?} {}",
+                method,
+                path,
+                headers,
+                body_size
+            );
+        }
+    }
+
+    #[test]
+    fn requests_matching_no_rule_are_allowed_at_the_default_rate() {
+        let cases = [
+            (r#"[]"#, None, "allow"),
+            (r#"[]"#, Some(0.1), "allow 0.1"),
+            (
+                r#"[{"action": "deny", "path": "/a"}]"#,
+                Some(0.1),
+                "allow 0.1",
+            ),
+            (
+                r#"[{"action": "allow", "path": "/b"}]"#,
+                Some(0.1),
+                "allow 0.1",
+            ),
+            (
+                r#"[{"action": "allow", "path": "/b", "sample_rate": 1}]"#,
+                Some(0.1),
+                "allow 0.1",
+            ),
+        ];
+
+        for (rules, sample_rate, expected) in cases {
+            let filters = filters(rules, sample_rate).unwrap();
+
+            assert_eq!(
+                verdict(&filters, "POST", "/b/c", &[], 0),
+                expected,
+                "{}",
+                rules
+            );
+        }
+
+        let filters = filters(
+            r#"[{"action": "allow", "path": "/b", "sample_rate": 1}]"#,
+            Some(0.1),
+        )
+        .unwrap();
+        assert_eq!(verdict(&filters, "POST", "/b", &[], 0), "allow 1");
+    }
+
+    #[test]
+    fn samples_at_the_rate() {
+        let filters = filters("[]", None).unwrap();
+
+        assert!(filters.sampled(None));
+        assert!(filters.sampled(Some(1.0)));
+        assert!(!filters.sampled(Some(0.0)));
+    }
+
+    #[test]
+    fn labels_come_from_the_first_matching_allow_rule() {
+        let filters = filters(
+            r#"[
+                {"action": "deny", "path": "/denied", "labels": {"team": "nobody"}},
+                {"action": "allow", "path": "/plain"},
+                {"action": "allow", "path": "/payments/*", "labels": {"team": "payments"}}
+            ]"#,
+            None,
+        )
+        .unwrap();
+        let labels = |fullpath: &str| {
+            filters
+                .labels(&RequestMessage {
+                    method: "POST".to_string(),
+                    fullpath: fullpath.to_string(),
+                    version: Default::default(),
+                    headers: vec![],
+                    body: vec![],
+                    source: None,
+                })
+                .and_then(|labels| labels.get("team").cloned())
+        };
+
+        assert_eq!(labels("/payments/a?x=1"), Some("payments".to_string()));
+        assert_eq!(labels("/plain"), None);
+        assert_eq!(labels("/denied"), None);
+        assert_eq!(labels("/other"), None);
+    }
+}
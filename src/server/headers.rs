@@ -0,0 +1,77 @@
+use actix_web::http::header::HeaderMap;
+
+use crate::HeaderBytes;
+
+/// Headers meaningful only for a single connection (RFC 7230 section 6.1), never relayed. Any
+/// others named in `Connection` are dropped along with them.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Headers starting with this are set by hookhub itself (e.g. the delivery id, or the client's
+/// signature verdict), never relayed from providers so they can't forge them
+const INTERNAL_PREFIX: &str = "x-hookhub-";
+
+/// Headers dropped from incoming requests when no deny list is given
+pub const DEFAULT_DENIED: &[&str] = &["origin"];
+
+/// Decides which headers of an incoming request are relayed to clients
+pub struct HeaderFilter {
+    deny: Vec<String>,
+    allow: Vec<String>,
+    preserve_host: bool,
+}
+
+impl HeaderFilter {
+    /// Drops the `deny` headers and, if `allow` isn't empty, any not in it. Host is only relayed
+    /// with `preserve_host`, for local apps routing on it, otherwise the client sets its own.
+    pub fn new(deny: Vec<String>, allow: Vec<String>, preserve_host: bool) -> Self {
+        let lowercase = |names: Vec<String>| names.iter().map(|n| n.to_lowercase()).collect();
+
+        Self {
+            deny: lowercase(deny),
+            allow: lowercase(allow),
+            preserve_host,
+        }
+    }
+
+    /// The headers to relay out of `headers`, in the order they came in
+    pub fn filter(&self, headers: &HeaderMap) -> Vec<(String, HeaderBytes)> {
+        let connection: Vec<String> = headers
+            .get_all("connection")
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|token| token.trim().to_lowercase())
+            .collect();
+
+        headers
+            .iter()
+            .filter(|(name, _)| self.relays(name.as_str(), &connection))
+            .map(|(k, v)| (k.as_str().to_owned(), HeaderBytes(v.as_bytes().to_vec())))
+            .collect()
+    }
+
+    /// Whether the header `name`, lowercase as actix gives it, is relayed
+    fn relays(&self, name: &str, connection: &[String]) -> bool {
+        if HOP_BY_HOP.contains(&name)
+            || connection.iter().any(|n| n == name)
+            || name.starts_with(INTERNAL_PREFIX)
+        {
+            return false;
+        }
+        if name == "host" {
+            return self.preserve_host;
+        }
+
+        !self.deny.iter().any(|n| n == name)
+            && (self.allow.is_empty() || self.allow.iter().any(|n| n == name))
+    }
+}
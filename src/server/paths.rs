@@ -0,0 +1,42 @@
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// Where client paths start, below any ingest prefix
+const CLIENT_PATHS: &str = "/t/";
+
+/// A new path for a client, `/t/<token>` below `prefix`
+pub fn mint(prefix: Option<&str>) -> String {
+    format!(
+        "{}{}{}",
+        prefix.unwrap_or_default().trim_end_matches('/'),
+        CLIENT_PATHS,
+        token()
+    )
+}
+
+/// A random token for naming something a client's given, short enough to type
+pub fn token() -> String {
+    let mut bytes = [0; 6];
+    SystemRandom::new().fill(&mut bytes).unwrap();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Splits `fullpath`, which may have a query, into the client path it's below and the path
+/// relayed to that client, with the `/t/<token>` taken out so `/t/8f3ab2/github` is relayed as
+/// `/github`
+pub fn split<'a>(fullpath: &'a str, prefix: Option<&str>) -> Option<(&'a str, String)> {
+    let base = prefix.unwrap_or_default().trim_end_matches('/');
+    let below = fullpath.strip_prefix(base)?.strip_prefix(CLIENT_PATHS)?;
+    let end = below.find(['/', '?']).unwrap_or(below.len());
+    if end == 0 {
+        return None;
+    }
+
+    let path = &fullpath[..base.len() + CLIENT_PATHS.len() + end];
+    let rest = &below[end..];
+    let relayed = match rest.starts_with('/') || !base.is_empty() {
+        true => format!("{}{}", base, rest),
+        false => format!("/{}", rest),
+    };
+
+    Some((path, relayed))
+}
@@ -0,0 +1,205 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use log::warn;
+use tokio::sync::Notify;
+
+use crate::RequestMessage;
+
+/// Requests queued for each session by default before it's treated as having fallen behind
+pub const DEFAULT_CAPACITY: usize = 50;
+
+/// What happens when a request is relayed to a session whose queue is full
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// The oldest queued request is dropped to make room
+    #[default]
+    DropOldest,
+    /// The new request is dropped
+    DropNewest,
+    /// The session is disconnected, so the client reconnects and starts again
+    Disconnect,
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(Overflow::DropOldest),
+            "drop-newest" => Ok(Overflow::DropNewest),
+            "disconnect" => Ok(Overflow::Disconnect),
+            _ => Err("expected drop-oldest, drop-newest or disconnect".to_string()),
+        }
+    }
+}
+
+/// Requests waiting to be sent to one session
+pub struct Queue {
+    session_id: u64,
+    remote_addr: String,
+    state: Mutex<QueueState>,
+    notify: Notify,
+}
+
+#[derive(Default)]
+struct QueueState {
+    messages: VecDeque<RequestMessage>,
+    /// Dropped since the session last took them with `take_dropped`
+    dropped: u64,
+    /// Whether the queue's been full since it was last below capacity, so overflowing is only
+    /// warned about once each time
+    overflowing: bool,
+    /// Closed because it overflowed with `Overflow::Disconnect`
+    closed: bool,
+}
+
+impl Queue {
+    /// Waits for the next request, `None` once the queue's overflowed and the session should
+    /// disconnect
+    pub async fn recv(&self) -> Option<RequestMessage> {
+        loop {
+            let notified = self.notify.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.closed {
+                    return None;
+                }
+                if let Some(message) = state.messages.pop_front() {
+                    return Some(message);
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// How many requests have been dropped since this was last called
+    pub fn take_dropped(&self) -> u64 {
+        std::mem::take(&mut self.state.lock().unwrap().dropped)
+    }
+
+    pub fn depth(&self) -> usize {
+        self.state.lock().unwrap().messages.len()
+    }
+
+    fn push(&self, message: RequestMessage, capacity: usize, overflow: Overflow) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return;
+        }
+
+        if state.messages.len() < capacity {
+            state.overflowing = false;
+            state.messages.push_back(message);
+            drop(state);
+            self.notify.notify_one();
+            return;
+        }
+
+        if !state.overflowing {
+            state.overflowing = true;
+            warn!(
+                event = "queue_full", session = self.session_id, remote_addr = self.remote_addr.as_str(), overflow:? = overflow;
+                "[{}] Session #{}'s queue is full with {} request(s), handling the overflow with {:?}",
+                self.remote_addr, self.session_id, capacity, overflow
+            );
+        }
+
+        match overflow {
+            Overflow::DropOldest => {
+                state.messages.pop_front();
+                state.messages.push_back(message);
+                state.dropped += 1;
+            }
+            Overflow::DropNewest => state.dropped += 1,
+            Overflow::Disconnect => {
+                state.dropped += state.messages.len() as u64 + 1;
+                state.messages.clear();
+                state.closed = true;
+            }
+        }
+        drop(state);
+        self.notify.notify_one();
+    }
+}
+
+/// Relays requests to every connected session through a bounded queue for each, so one
+/// falling behind only loses its own requests, and does so visibly
+pub struct Broadcaster {
+    capacity: usize,
+    overflow: Overflow,
+    queues: Mutex<HashMap<u64, Arc<Queue>>>,
+}
+
+impl Broadcaster {
+    pub fn new(capacity: usize, overflow: Overflow) -> Self {
+        Self {
+            capacity,
+            overflow,
+            queues: Mutex::default(),
+        }
+    }
+
+    /// Queues a request for every session, returning how many there were
+    pub fn send(&self, message: RequestMessage) -> usize {
+        let queues = self.queues.lock().unwrap();
+        for queue in queues.values() {
+            queue.push(message.clone(), self.capacity, self.overflow);
+        }
+
+        queues.len()
+    }
+
+    /// Queues a request for just one session, false if it's not subscribed
+    pub fn send_to(&self, session_id: u64, message: RequestMessage) -> bool {
+        match self.queues.lock().unwrap().get(&session_id) {
+            Some(queue) => {
+                queue.push(message, self.capacity, self.overflow);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Starts queueing requests for a session, until it's unsubscribed
+    pub fn subscribe(&self, session_id: u64, remote_addr: &str) -> Arc<Queue> {
+        let queue = Arc::new(Queue {
+            session_id,
+            remote_addr: remote_addr.to_string(),
+            state: Mutex::default(),
+            notify: Notify::new(),
+        });
+        self.queues
+            .lock()
+            .unwrap()
+            .insert(session_id, queue.clone());
+
+        queue
+    }
+
+    pub fn unsubscribe(&self, session_id: u64) {
+        self.queues.lock().unwrap().remove(&session_id);
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.queues.lock().unwrap().len()
+    }
+
+    /// The requests waiting in each session's queue
+    pub fn depths(&self) -> HashMap<u64, usize> {
+        self.queues
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, queue)| (*id, queue.depth()))
+            .collect()
+    }
+
+    /// The most requests waiting in any session's queue
+    pub fn queue_depth(&self) -> usize {
+        self.depths().into_values().max().unwrap_or(0)
+    }
+}
@@ -0,0 +1,342 @@
+use std::{
+    fs,
+    io::BufReader,
+    net::SocketAddr,
+    path::Path,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use actix_web::web::Data;
+use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
+use quinn::{
+    crypto::rustls::QuicServerConfig,
+    rustls::{self, crypto::ring},
+    Connection, ConnectionError, Endpoint, Incoming, RecvStream, SendStream, VarInt,
+};
+use tokio::sync::{mpsc, oneshot};
+
+use super::{
+    sessions::Disconnect,
+    subdomains::Claim,
+    throttle::{Throttle, ThrottlePolicy},
+    Shared, REPLACED, VERSION,
+};
+use crate::{
+    quic::{self, Login, UNAUTHORIZED},
+    wire, DeliveryReport, Feature, Hello, HelloAck, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+
+/// Close codes, matching the websocket's
+const CLOSE_AWAY: u32 = 1001;
+const CLOSE_POLICY: u32 = 1008;
+const CLOSE_AGAIN: u32 = 1013;
+
+/// Listens for clients on `addr` over UDP with the certificate chain in `cert` and its private
+/// key in `key`, both PEM
+pub(super) fn endpoint(addr: SocketAddr, cert: &Path, key: &Path) -> Result<Endpoint> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        fs::File::open(cert).with_context(|| format!("couldn't open {}", cert.display()))?,
+    ))
+    .collect::<Result<Vec<_>, _>>()
+    .with_context(|| format!("invalid certificate in {}", cert.display()))?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(
+        fs::File::open(key).with_context(|| format!("couldn't open {}", key.display()))?,
+    ))?
+    .ok_or_else(|| anyhow!("no private key in {}", key.display()))?;
+
+    let mut tls = rustls::ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    tls.alpn_protocols = vec![quic::ALPN.to_vec()];
+
+    let config = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?));
+    Endpoint::server(config, addr).with_context(|| format!("couldn't listen on {} for QUIC", addr))
+}
+
+/// Accepts clients until the endpoint's closed
+pub(super) async fn serve(shared: Data<Shared>, endpoint: Endpoint) {
+    if let Ok(addr) = endpoint.local_addr() {
+        info!("Accepting QUIC clients on {}", addr);
+    }
+
+    while let Some(incoming) = endpoint.accept().await {
+        actix_web::rt::spawn(accept(shared.clone(), incoming));
+    }
+}
+
+async fn accept(shared: Data<Shared>, incoming: Incoming) {
+    let remote_addr = incoming.remote_address().ip().to_string();
+
+    let connection = match incoming.await {
+        Ok(connection) => connection,
+        Err(err) => {
+            warn!("[{remote_addr}] QUIC handshake failed: {err}");
+            return;
+        }
+    };
+
+    // like a websocket's upgrade, so clients go to another instance while this one drains
+    if shared.draining.load(Ordering::Relaxed) {
+        connection.close(VarInt::from_u32(CLOSE_AWAY), b"server shutting down");
+        return;
+    }
+
+    let login = match login(&connection, &shared, &remote_addr).await {
+        Ok(login) => login,
+        Err(err) => {
+            warn!("[{remote_addr}] Handshake failed: {err}");
+            return;
+        }
+    };
+
+    info!(
+        event = "connected", remote_addr = remote_addr.as_str(), credential = login.credential.as_str();
+        "[{remote_addr}] Session started over QUIC using {}", login.credential
+    );
+    serve_session(shared, connection, login, remote_addr).await;
+}
+
+/// A client that's logged in and been sent its `HelloAck`
+struct LoggedIn {
+    /// Kept open for the session, the client's finishing its side ends it
+    _send: SendStream,
+    recv: RecvStream,
+    session_id: u64,
+    disconnect: oneshot::Receiver<Disconnect>,
+    credential: String,
+    hello: Hello,
+    ack: HelloAck,
+    /// Held until the session finishes
+    claim: Option<Claim>,
+}
+
+/// Reads the client's login from the control stream and answers it with a `HelloAck`
+async fn login(connection: &Connection, shared: &Shared, remote_addr: &str) -> Result<LoggedIn> {
+    let (mut send, mut recv) =
+        tokio::time::timeout(Duration::from_secs(10), connection.accept_bi())
+            .await
+            .map_err(|_| anyhow!("timed out waiting for login"))??;
+
+    let frame = quic::read_frame(&mut recv)
+        .await?
+        .ok_or_else(|| anyhow!("control stream closed before login"))?;
+    let login: Login =
+        rmp_serde::from_slice(&frame).map_err(|e| anyhow!("invalid login: {}", e))?;
+
+    let Some(credential) = shared.authenticate(&login.password) else {
+        connection.close(VarInt::from_u32(UNAUTHORIZED), b"authentication failed");
+        return Err(anyhow!("authentication failed"));
+    };
+
+    let hello = login.hello;
+    if hello.protocol < MIN_PROTOCOL_VERSION {
+        let reason = format!(
+            "Server is running version {} but you are running {}, upgrade the client",
+            VERSION, hello.version
+        );
+        connection.close(VarInt::from_u32(CLOSE_POLICY), reason.as_bytes());
+        return Err(anyhow!("client speaks protocol {}", hello.protocol));
+    }
+
+    let version = Some(hello.version.clone());
+    let (session_id, disconnect) = match shared.register(remote_addr, &credential, version).await {
+        Ok(registered) => registered,
+        Err(reason) => {
+            connection.close(VarInt::from_u32(CLOSE_POLICY), reason.as_bytes());
+            return Err(anyhow!(reason));
+        }
+    };
+    let (path, claim) = match shared.routes(&credential, hello.subdomain.as_deref()) {
+        Ok(routes) => routes,
+        Err(reason) => {
+            shared.sessions.finish(session_id);
+            connection.close(VarInt::from_u32(CLOSE_POLICY), reason.as_bytes());
+            return Err(anyhow!(reason));
+        }
+    };
+
+    // tunnels are only taken over websockets
+    let mut features = hello.shared_features();
+    features.retain(|f| {
+        !matches!(
+            f,
+            Feature::Tunnel | Feature::TcpForward | Feature::PeerEvents
+        )
+    });
+
+    let ack = HelloAck {
+        protocol: hello.protocol.min(PROTOCOL_VERSION),
+        version: VERSION.to_string(),
+        features,
+        max_body_size: Some(shared.max_body_size),
+        format: hello.wire_format(),
+        tcp_ports: vec![],
+        public_url: shared.public_url(
+            None,
+            claim.as_ref().map(|c| c.host.as_str()),
+            path.as_deref(),
+        ),
+        path,
+        host: claim.as_ref().map(|c| c.host.clone()),
+    };
+    if let Err(e) = quic::write_frame(&mut send, &rmp_serde::to_vec_named(&ack)?).await {
+        shared.sessions.finish(session_id);
+        return Err(e);
+    }
+
+    Ok(LoggedIn {
+        _send: send,
+        recv,
+        session_id,
+        disconnect,
+        credential: credential.name,
+        hello,
+        ack,
+        claim,
+    })
+}
+
+async fn serve_session(
+    shared: Data<Shared>,
+    connection: Connection,
+    login: LoggedIn,
+    remote_addr: String,
+) {
+    let LoggedIn {
+        mut recv,
+        session_id,
+        mut disconnect,
+        hello,
+        ack,
+        claim,
+        ..
+    } = login;
+    info!(
+        "[{remote_addr}] Client {} speaks protocol {} in {:?} with features {:?}",
+        hello.version, ack.protocol, ack.format, ack.features
+    );
+
+    let compress_above = ack
+        .features
+        .contains(&Feature::Compression)
+        .then_some(shared.compression_threshold);
+    let reports = ack.features.contains(&Feature::AckDelivery);
+
+    shared.routed(session_id, &remote_addr, &hello, &ack);
+    let queue = shared.broadcaster.subscribe(session_id, &remote_addr);
+    shared.announce(session_id, hello.name.clone(), true);
+    let mut throttle = Throttle::new(shared.session_quota);
+    // a frame over the session's quota, waiting until it may be sent
+    let mut held: Option<(Vec<u8>, tokio::time::Instant)> = None;
+
+    // reading a frame isn't cancel safe, so it's done alongside rather than in the select
+    let (frames, mut control) = mpsc::unbounded_channel();
+    actix_web::rt::spawn(async move {
+        while let Some(frame) = quic::read_frame(&mut recv).await.transpose() {
+            let failed = frame.is_err();
+            if frames.send(frame).is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    loop {
+        tokio::select! {
+            frame = control.recv() => {
+                match frame {
+                    Some(Ok(frame)) if reports => match rmp_serde::from_slice::<DeliveryReport>(&frame) {
+                        Ok(report) => shared.delivered(session_id, &remote_addr, report),
+                        Err(err) => warn!("[{remote_addr}] Invalid delivery report: {err}"),
+                    },
+                    Some(Ok(_)) => {}
+                    Some(Err(err)) => {
+                        warn!("[{remote_addr}] {err}");
+                        break;
+                    }
+                    None => break,
+                }
+            }
+            _ = tokio::time::sleep_until(held.as_ref().map_or_else(tokio::time::Instant::now, |(_, at)| *at)), if held.is_some() => {
+                let (frame, _) = held.take().unwrap();
+                let size = frame.len();
+                match throttle.admit(size) {
+                    Ok(()) => {
+                        if let Err(err) = send_frame(&connection, frame).await {
+                            warn!("[{remote_addr}] {err}");
+                            break;
+                        }
+                        shared.sessions.sent(session_id, size);
+                    }
+                    Err(at) => held = Some((frame, at)),
+                }
+            }
+            received = queue.recv(), if held.is_none() => {
+                shared.lagged(session_id, &remote_addr, queue.take_dropped());
+
+                let Some(msg) = received else {
+                    connection.close(VarInt::from_u32(CLOSE_AGAIN), b"fell behind");
+                    break;
+                };
+
+                let frame = wire::encode(&msg, ack.format, compress_above).unwrap();
+                let size = frame.len();
+                if let Err(at) = throttle.admit(size) {
+                    shared.sessions.throttled(session_id, throttle.policy());
+                    if throttle.policy() == ThrottlePolicy::Delay {
+                        held = Some((frame, at));
+                    }
+                    continue;
+                }
+
+                if let Err(err) = send_frame(&connection, frame).await {
+                    warn!("[{remote_addr}] {err}");
+                    break;
+                }
+                shared.sessions.sent(session_id, size);
+            }
+            err = connection.closed() => {
+                info!("[{remote_addr}] {err}");
+                break;
+            }
+            Ok(why) = &mut disconnect => {
+                match why {
+                    Disconnect::Admin => {
+                        info!("[{remote_addr}] Disconnected through the admin API");
+                        connection.close(VarInt::from_u32(CLOSE_POLICY), b"disconnected by an admin");
+                    }
+                    Disconnect::Shutdown => {
+                        connection.close(VarInt::from_u32(CLOSE_AWAY), b"server shutting down");
+                    }
+                    Disconnect::Replaced => {
+                        info!("[{remote_addr}] Replaced by another client");
+                        connection.close(VarInt::from_u32(CLOSE_POLICY), REPLACED.as_bytes());
+                    }
+                }
+                break;
+            }
+        }
+    }
+
+    shared.broadcaster.unsubscribe(session_id);
+    shared.announce(session_id, hello.name, false);
+    drop(claim);
+    shared.sessions.finish(session_id);
+
+    info!(event = "disconnected", remote_addr = remote_addr.as_str(); "[{remote_addr}] Session finished");
+}
+
+/// Sends a frame on a stream of its own, waiting while the client has as many streams open as
+/// it allows, so a client falling behind backs up into its queue
+async fn send_frame(connection: &Connection, frame: Vec<u8>) -> Result<(), ConnectionError> {
+    let mut stream = connection.open_uni().await?;
+    actix_web::rt::spawn(async move {
+        if stream.write_all(&frame).await.is_ok() {
+            let _ = stream.finish();
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,246 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Per-IP buckets are pruned once there are more than this many, dropping ones that have refilled
+const MAX_IDLE_BUCKETS: usize = 1024;
+
+#[derive(Clone, Copy)]
+pub(super) struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    pub(super) fn full(burst: f64, now: Instant) -> Self {
+        Self {
+            tokens: burst,
+            updated: now,
+        }
+    }
+
+    pub(super) fn refill(&mut self, rate: f64, burst: f64, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+    }
+
+    /// Whether a token can be taken, or how long until one can
+    fn ready(&self, rate: f64) -> Result<(), Duration> {
+        if self.tokens >= 1.0 {
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+        }
+    }
+
+    /// How long until `n` tokens can be taken, zero if they can now. More than `burst` can be
+    /// taken once the bucket is full, going into debt rather than never being available.
+    pub(super) fn wait_for(&self, n: f64, rate: f64, burst: f64) -> Duration {
+        let needed = n.min(burst);
+        Duration::from_secs_f64((needed - self.tokens).max(0.0) / rate)
+    }
+
+    /// Takes `n` tokens, which `wait_for` should have said are available
+    pub(super) fn take_n(&mut self, n: f64) {
+        self.tokens -= n;
+    }
+}
+
+/// A token bucket refilling at `rate` requests per second up to `burst`
+#[derive(Clone, Copy)]
+pub struct Limit {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+/// Token bucket rate limits on incoming requests, across all sources and per source address
+pub struct RateLimiter {
+    global: Option<(Limit, Mutex<Bucket>)>,
+    per_ip: Option<(Limit, Mutex<HashMap<IpAddr, Bucket>>)>,
+}
+
+impl RateLimiter {
+    pub fn new(global: Option<Limit>, per_ip: Option<Limit>) -> Self {
+        let now = Instant::now();
+
+        Self {
+            global: global.map(|limit| (limit, Mutex::new(Bucket::full(limit.burst, now)))),
+            per_ip: per_ip.map(|limit| (limit, Mutex::new(HashMap::new()))),
+        }
+    }
+
+    /// Counts a request against the limits, returning how long to wait before retrying if it's
+    /// over either of them
+    pub fn check(&self, ip: Option<IpAddr>) -> Result<(), Duration> {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&self, ip: Option<IpAddr>, now: Instant) -> Result<(), Duration> {
+        let mut per_ip = match (&self.per_ip, ip) {
+            (Some((limit, buckets)), Some(ip)) => Some((limit, buckets.lock().unwrap(), ip)),
+            _ => None,
+        };
+        let mut global = self
+            .global
+            .as_ref()
+            .map(|(limit, bucket)| (limit, bucket.lock().unwrap()));
+
+        let mut buckets = vec![];
+        if let Some((limit, by_ip, ip)) = &mut per_ip {
+            if by_ip.len() > MAX_IDLE_BUCKETS {
+                by_ip.retain(|_, bucket| {
+                    bucket.refill(limit.rate, limit.burst, now);
+                    bucket.tokens < limit.burst
+                });
+            }
+
+            let bucket = by_ip
+                .entry(*ip)
+                .or_insert_with(|| Bucket::full(limit.burst, now));
+            buckets.push((**limit, bucket));
+        }
+        if let Some((limit, bucket)) = &mut global {
+            buckets.push((**limit, &mut **bucket));
+        }
+
+        for (limit, bucket) in &mut buckets {
+            bucket.refill(limit.rate, limit.burst, now);
+        }
+        // both are checked before either is taken from, so a request one of them refuses
+        // doesn't use up the other
+        if let Some(wait) = buckets
+            .iter()
+            .filter_map(|(limit, bucket)| bucket.ready(limit.rate).err())
+            .max()
+        {
+            return Err(wait);
+        }
+        for (_, bucket) in &mut buckets {
+            bucket.take_n(1.0);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ONE_PER_SEC: Limit = Limit {
+        rate: 1.0,
+        burst: 2.0,
+    };
+
+    fn ip(n: u32) -> Option<IpAddr> {
+        Some(IpAddr::from(n.to_be_bytes()))
+    }
+
+    #[test]
+    fn allows_a_burst_then_says_when_to_retry() {
+        let limiter = RateLimiter::new(Some(ONE_PER_SEC), None);
+        let now = Instant::now();
+
+        assert_eq!(limiter.check_at(None, now), Ok(()));
+        assert_eq!(limiter.check_at(None, now), Ok(()));
+        assert_eq!(
+            limiter.check_at(None, now + Duration::from_millis(250)),
+            Err(Duration::from_millis(750))
+        );
+    }
+
+    #[test]
+    fn refills_at_the_rate_up_to_the_burst() {
+        let limiter = RateLimiter::new(Some(ONE_PER_SEC), None);
+        let now = Instant::now();
+        limiter.check_at(None, now).unwrap();
+        limiter.check_at(None, now).unwrap();
+
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.check_at(None, later), Ok(()));
+        assert!(limiter.check_at(None, later).is_err());
+
+        // no more than the burst after being idle a long time
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!(limiter.check_at(None, much_later), Ok(()));
+        assert_eq!(limiter.check_at(None, much_later), Ok(()));
+        assert!(limiter.check_at(None, much_later).is_err());
+    }
+
+    #[test]
+    fn limits_each_address_separately() {
+        let limiter = RateLimiter::new(None, Some(ONE_PER_SEC));
+        let now = Instant::now();
+
+        for _ in 0..2 {
+            assert_eq!(limiter.check_at(ip(1), now), Ok(()));
+        }
+        assert!(limiter.check_at(ip(1), now).is_err());
+        assert_eq!(limiter.check_at(ip(2), now), Ok(()));
+        // without an address only the global limit applies
+        assert_eq!(limiter.check_at(None, now), Ok(()));
+    }
+
+    #[test]
+    fn requests_refused_globally_dont_use_up_their_address() {
+        let global = Limit {
+            rate: 1.0,
+            burst: 1.0,
+        };
+        let limiter = RateLimiter::new(Some(global), Some(ONE_PER_SEC));
+        let now = Instant::now();
+
+        assert_eq!(limiter.check_at(ip(2), now), Ok(()));
+        for _ in 0..5 {
+            assert!(limiter.check_at(ip(1), now).is_err());
+        }
+
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.check_at(ip(1), later), Ok(()));
+        let buckets = limiter.per_ip.as_ref().unwrap().1.lock().unwrap();
+        assert_eq!(buckets[&ip(1).unwrap()].tokens, 1.0);
+    }
+
+    #[test]
+    fn retries_after_the_longer_of_the_two_waits() {
+        let global = Limit {
+            rate: 10.0,
+            burst: 1.0,
+        };
+        let limiter = RateLimiter::new(Some(global), Some(ONE_PER_SEC));
+        let now = Instant::now();
+        let later = now + Duration::from_millis(100);
+        limiter.check_at(ip(1), now).unwrap();
+        limiter.check_at(ip(1), later).unwrap();
+
+        // the global bucket is 100ms from a token, the address's 900ms
+        assert_eq!(
+            limiter.check_at(ip(1), later),
+            Err(Duration::from_millis(900))
+        );
+    }
+
+    #[test]
+    fn prunes_refilled_buckets_once_there_are_too_many() {
+        let limiter = RateLimiter::new(None, Some(ONE_PER_SEC));
+        let now = Instant::now();
+        for n in 0..=MAX_IDLE_BUCKETS as u32 {
+            limiter.check_at(ip(n), now).unwrap();
+        }
+        // still draining, so kept
+        limiter.check_at(ip(0), now).unwrap();
+
+        limiter
+            .check_at(ip(u32::MAX), now + Duration::from_millis(1500))
+            .unwrap();
+
+        let buckets = limiter.per_ip.as_ref().unwrap().1.lock().unwrap();
+        assert_eq!(buckets.len(), 2);
+        assert!(buckets.contains_key(&ip(0).unwrap()));
+        assert!(buckets.contains_key(&ip(u32::MAX).unwrap()));
+    }
+}
@@ -0,0 +1,100 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::DeliveryReport;
+
+/// How many requests are kept, older ones are forgotten
+const MAX_RECENT: usize = 100;
+
+/// The most recently received requests and what became of them
+#[derive(Default)]
+pub struct Recent {
+    next_id: AtomicU64,
+    requests: Mutex<VecDeque<RecentRequest>>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RecentRequest {
+    pub id: u64,
+    pub received_at: DateTime<Utc>,
+    pub method: String,
+    pub path: String,
+    pub size: usize,
+    pub outcome: Outcome,
+    /// What each session that reported said became of it
+    pub deliveries: Vec<Delivery>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Delivery {
+    pub session: u64,
+    #[serde(flatten)]
+    pub report: DeliveryReport,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// Queued for this many sessions
+    Relayed(usize),
+    NoClient,
+    Duplicate,
+    /// Left out by sampling
+    SampledOut,
+}
+
+impl Recent {
+    /// Ids carry on after `id`, so they don't repeat those of a previous run
+    pub fn starting_after(id: u64) -> Self {
+        Self {
+            next_id: AtomicU64::new(id),
+            requests: Mutex::default(),
+        }
+    }
+
+    /// Remembers a request, returning it with the id it's relayed with
+    pub fn record(&self, method: &str, path: &str, size: usize, outcome: Outcome) -> RecentRequest {
+        let request = RecentRequest {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            received_at: Utc::now(),
+            method: method.to_string(),
+            path: path.to_string(),
+            size,
+            outcome,
+            deliveries: vec![],
+        };
+
+        let mut requests = self.requests.lock().unwrap();
+        if requests.len() == MAX_RECENT {
+            requests.pop_back();
+        }
+        requests.push_front(request.clone());
+
+        request
+    }
+
+    /// Adds a session's report to the request it's about, unless it's been forgotten
+    pub fn reported(&self, delivery: Delivery) {
+        let mut requests = self.requests.lock().unwrap();
+
+        if let Some(request) = requests
+            .iter_mut()
+            .find(|r| r.id == delivery.report.request_id)
+        {
+            request.deliveries.push(delivery);
+        }
+    }
+
+    /// Newest first
+    pub fn list(&self) -> Vec<RecentRequest> {
+        self.requests.lock().unwrap().iter().cloned().collect()
+    }
+}
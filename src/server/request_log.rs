@@ -0,0 +1,139 @@
+use std::{collections::VecDeque, fs, io, path::PathBuf, sync::Mutex};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::recent::{Delivery, RecentRequest};
+use crate::RequestMessage;
+
+/// Received requests and their delivery reports kept on disk, one JSON file each, forgetting
+/// the oldest beyond a limit. Survives restarts so clients can see what they missed.
+pub struct RequestLog {
+    path: PathBuf,
+    max_entries: usize,
+    /// Ids of the entries on disk, oldest first
+    ids: Mutex<VecDeque<u64>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LoggedRequest {
+    #[serde(flatten)]
+    pub summary: RecentRequest,
+    pub request: RequestMessage,
+}
+
+impl RequestLog {
+    /// Opens the log in `path`, creating it if need be
+    pub fn open(path: PathBuf, max_entries: usize) -> Result<Self> {
+        fs::create_dir_all(&path)
+            .with_context(|| format!("couldn't create request log {}", path.display()))?;
+
+        let mut ids: Vec<u64> = fs::read_dir(&path)?
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name();
+                name.to_str()?.strip_suffix(".json")?.parse().ok()
+            })
+            .collect();
+        ids.sort();
+
+        Ok(Self {
+            path,
+            max_entries,
+            ids: Mutex::new(ids.into()),
+        })
+    }
+
+    /// The newest entry's id, so new ids can carry on from it
+    pub fn last_id(&self) -> u64 {
+        self.ids.lock().unwrap().back().copied().unwrap_or(0)
+    }
+
+    pub fn add(&self, summary: RecentRequest, request: RequestMessage) {
+        let mut ids = self.ids.lock().unwrap();
+        let id = summary.id;
+
+        if let Err(e) = self.write(&LoggedRequest { summary, request }) {
+            warn!("Could not add request {} to the request log: {:#}", id, e);
+            return;
+        }
+        ids.push_back(id);
+
+        while ids.len() > self.max_entries {
+            let oldest = ids.pop_front().unwrap();
+            if let Err(e) = fs::remove_file(self.file(oldest)) {
+                warn!(
+                    "Could not remove request {} from the request log: {}",
+                    oldest, e
+                );
+            }
+        }
+    }
+
+    pub fn reported(&self, delivery: Delivery) {
+        let _ids = self.ids.lock().unwrap();
+        let id = delivery.report.request_id;
+
+        let result = self.read(id).and_then(|entry| match entry {
+            Some(mut entry) => {
+                entry.summary.deliveries.push(delivery);
+                self.write(&entry)
+            }
+            None => Ok(()),
+        });
+
+        if let Err(e) = result {
+            warn!(
+                "Could not add delivery of {} to the request log: {:#}",
+                id, e
+            );
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Result<Option<LoggedRequest>> {
+        let _ids = self.ids.lock().unwrap();
+
+        self.read(id)
+    }
+
+    /// Summaries of entries received after `since`, newest first
+    pub fn list(&self, since: Option<DateTime<Utc>>, limit: usize) -> Result<Vec<RecentRequest>> {
+        let ids: Vec<u64> = self.ids.lock().unwrap().iter().rev().copied().collect();
+
+        let mut summaries = vec![];
+        for id in ids {
+            if summaries.len() == limit {
+                break;
+            }
+
+            let Some(entry) = self.get(id)? else {
+                continue;
+            };
+            if since.is_some_and(|since| entry.summary.received_at <= since) {
+                break;
+            }
+            summaries.push(entry.summary);
+        }
+
+        Ok(summaries)
+    }
+
+    fn file(&self, id: u64) -> PathBuf {
+        self.path.join(format!("{}.json", id))
+    }
+
+    fn read(&self, id: u64) -> Result<Option<LoggedRequest>> {
+        match fs::read(self.file(id)) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write(&self, entry: &LoggedRequest) -> Result<()> {
+        fs::write(self.file(entry.summary.id), serde_json::to_vec(entry)?)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use actix_web::{http::StatusCode, HttpResponse};
+use anyhow::{anyhow, Result};
+use glob::Pattern;
+use serde::Deserialize;
+
+use crate::RequestMessage;
+
+/// How to respond to an ingested request, optionally limited to a method and path glob
+#[derive(Deserialize, Clone)]
+pub struct ResponseRule {
+    pub method: Option<String>,
+    pub path: Option<String>,
+    #[serde(default = "default_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub body: String,
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+impl Default for ResponseRule {
+    fn default() -> Self {
+        Self {
+            method: None,
+            path: None,
+            status: default_status(),
+            headers: BTreeMap::new(),
+            body: String::new(),
+        }
+    }
+}
+
+pub struct Responses {
+    rules: Vec<(Option<Pattern>, ResponseRule)>,
+    default: ResponseRule,
+}
+
+impl Responses {
+    pub fn new(default: ResponseRule, rules_file: Option<&Path>) -> Result<Self> {
+        let rules: Vec<ResponseRule> = match rules_file {
+            Some(path) => serde_json::from_slice(&fs::read(path)?)
+                .map_err(|e| anyhow!("invalid response rules in {}: {}", path.display(), e))?,
+            None => vec![],
+        };
+
+        let rules = rules
+            .into_iter()
+            .map(|rule| {
+                let pattern = rule.path.as_deref().map(Pattern::new).transpose()?;
+                Ok((pattern, rule))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        for rule in rules.iter().map(|(_, rule)| rule).chain([&default]) {
+            StatusCode::from_u16(rule.status)?;
+        }
+
+        Ok(Self { rules, default })
+    }
+
+    /// Builds the response for a request from the first matching rule, or the default
+    pub fn respond(&self, req: &RequestMessage) -> HttpResponse {
+        let path = req.fullpath.split('?').next().unwrap_or_default();
+
+        let rule = self
+            .rules
+            .iter()
+            .find(|(pattern, rule)| {
+                rule.method
+                    .as_ref()
+                    .is_none_or(|m| m.eq_ignore_ascii_case(&req.method))
+                    && pattern.as_ref().is_none_or(|p| p.matches(path))
+            })
+            .map(|(_, rule)| rule)
+            .unwrap_or(&self.default);
+
+        let mut response = HttpResponse::build(StatusCode::from_u16(rule.status).unwrap());
+
+        for (name, value) in rule.headers.iter() {
+            response.insert_header((name.as_str(), render(value, req)));
+        }
+
+        response.body(render(&rule.body, req))
+    }
+}
+
+/// Returns the challenge of a Slack `url_verification` event, which must be echoed back for
+/// Slack to accept the URL
+pub fn slack_challenge(body: &[u8]) -> Option<String> {
+    let event: serde_json::Value = serde_json::from_slice(body).ok()?;
+
+    if event.get("type")?.as_str()? != "url_verification" {
+        return None;
+    }
+
+    Some(event.get("challenge")?.as_str()?.to_string())
+}
+
+/// Replaces `{{field}}` placeholders in a template with values from the request. Supported
+/// fields are `method`, `path`, `body`, `header.<name>` and `json.<dotted.path>` which looks
+/// inside a JSON body.
+pub fn render(template: &str, req: &RequestMessage) -> String {
+    let mut output = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+
+        let field = rest[start + 2..start + end].trim();
+        output.push_str(&lookup(field, req).unwrap_or_default());
+
+        rest = &rest[start + end + 2..];
+    }
+
+    output.push_str(rest);
+    output
+}
+
+fn lookup(field: &str, req: &RequestMessage) -> Option<String> {
+    match field {
+        "method" => Some(req.method.clone()),
+        "path" => Some(req.fullpath.clone()),
+        "body" => Some(String::from_utf8_lossy(&req.body).into_owned()),
+        _ => {
+            if let Some(name) = field.strip_prefix("header.") {
+                req.header(name).map(|v| v.to_str_lossy().into_owned())
+            } else if let Some(path) = field.strip_prefix("json.") {
+                let json: serde_json::Value = serde_json::from_slice(&req.body).ok()?;
+                let value = path.split('.').try_fold(&json, |value, key| match value {
+                    serde_json::Value::Array(items) => items.get(key.parse::<usize>().ok()?),
+                    _ => value.get(key),
+                })?;
+
+                match value {
+                    serde_json::Value::String(s) => Some(s.clone()),
+                    other => Some(other.to_string()),
+                }
+            } else {
+                None
+            }
+        }
+    }
+}
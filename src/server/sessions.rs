@@ -0,0 +1,288 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use super::throttle::ThrottlePolicy;
+use crate::DeliveryOutcome;
+
+/// The connected websocket sessions, with counters for each and a way to disconnect them
+#[derive(Default)]
+pub struct Sessions {
+    next_id: AtomicU64,
+    sessions: Mutex<HashMap<u64, Session>>,
+}
+
+struct Session {
+    info: SessionInfo,
+    disconnect: Option<oneshot::Sender<Disconnect>>,
+}
+
+/// Why a session is asked to disconnect
+#[derive(Clone, Copy, Debug)]
+pub enum Disconnect {
+    /// Through the admin API, the client shouldn't come back
+    Admin,
+    /// The server is shutting down, the client should reconnect to another instance
+    Shutdown,
+    /// Another client connected with the same credential, this one shouldn't come back
+    Replaced,
+}
+
+/// What happens when a client connects with a credential another connected client is using
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exclusive {
+    /// The new client is refused until the other disconnects
+    Reject,
+    /// The other client is disconnected to make way for the new one
+    Replace,
+}
+
+impl FromStr for Exclusive {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Exclusive::Reject),
+            "replace" => Ok(Exclusive::Replace),
+            _ => Err("expected reject or replace".to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub remote_addr: String,
+    pub credential: String,
+    /// The client's release, if its protocol says
+    pub version: Option<String>,
+    pub connected_at: DateTime<Utc>,
+    pub messages: u64,
+    pub bytes: u64,
+    /// Requests dropped from the session's queue because it fell behind
+    pub lag_drops: u64,
+    /// Requests held back because the session was over its limits
+    #[serde(default)]
+    pub throttle_delays: u64,
+    /// Requests not sent because the session was over its limits
+    #[serde(default)]
+    pub throttle_drops: u64,
+    /// Requests waiting in the session's queue to be sent
+    #[serde(default)]
+    pub queue_depth: usize,
+    /// What the client reported became of the requests, if it sends delivery reports
+    #[serde(default)]
+    pub outcomes: BTreeMap<DeliveryOutcome, u64>,
+    /// The path only this session is relayed requests to, with client paths
+    #[serde(default)]
+    pub path: Option<String>,
+    /// The host only this session is relayed requests to, with subdomain routing
+    #[serde(default)]
+    pub host: Option<String>,
+    /// What the client said it's called
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl Sessions {
+    /// Registers a new session, returning its id, a receiver that fires when it's asked to
+    /// disconnect and the sessions asked to make way for it. With `exclusive` the others using
+    /// `credential` are checked under the same lock, so two clients connecting at once can't
+    /// both get in, and a rejected session gets the id of the one already using it.
+    pub fn start(
+        &self,
+        remote_addr: &str,
+        credential: &str,
+        version: Option<String>,
+        exclusive: Option<Exclusive>,
+    ) -> Result<(u64, oneshot::Receiver<Disconnect>, Vec<u64>), u64> {
+        let mut sessions = self.sessions.lock().unwrap();
+
+        let mut replaced = vec![];
+        if let Some(exclusive) = exclusive {
+            let mut existing: Vec<&mut Session> = sessions
+                .values_mut()
+                .filter(|s| s.info.credential == credential)
+                .collect();
+            existing.sort_by_key(|s| s.info.id);
+
+            match (exclusive, existing.first()) {
+                (_, None) => {}
+                (Exclusive::Reject, Some(session)) => return Err(session.info.id),
+                (Exclusive::Replace, _) => {
+                    for session in existing {
+                        if let Some(disconnect) = session.disconnect.take() {
+                            let _ = disconnect.send(Disconnect::Replaced);
+                        }
+                        replaced.push(session.info.id);
+                    }
+                }
+            }
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let (disconnect, disconnected) = oneshot::channel();
+
+        sessions.insert(
+            id,
+            Session {
+                info: SessionInfo {
+                    id,
+                    remote_addr: remote_addr.to_string(),
+                    credential: credential.to_string(),
+                    version,
+                    connected_at: Utc::now(),
+                    messages: 0,
+                    bytes: 0,
+                    lag_drops: 0,
+                    throttle_delays: 0,
+                    throttle_drops: 0,
+                    queue_depth: 0,
+                    outcomes: BTreeMap::new(),
+                    path: None,
+                    host: None,
+                    name: None,
+                    labels: BTreeMap::new(),
+                },
+                disconnect: Some(disconnect),
+            },
+        );
+
+        Ok((id, disconnected, replaced))
+    }
+
+    pub fn sent(&self, id: u64, bytes: usize) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(&id) {
+            session.info.messages += 1;
+            session.info.bytes += bytes as u64;
+        }
+    }
+
+    pub fn lagged(&self, id: u64, missed: u64) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(&id) {
+            session.info.lag_drops += missed;
+        }
+    }
+
+    /// Counts a request that was over the session's limits, delayed or dropped by `policy`
+    pub fn throttled(&self, id: u64, policy: ThrottlePolicy) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(&id) {
+            match policy {
+                ThrottlePolicy::Delay => session.info.throttle_delays += 1,
+                ThrottlePolicy::Drop => session.info.throttle_drops += 1,
+            }
+        }
+    }
+
+    pub fn reported(&self, id: u64, outcome: DeliveryOutcome) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(&id) {
+            *session.info.outcomes.entry(outcome).or_default() += 1;
+        }
+    }
+
+    /// Gives a session the path and host it was told are its own
+    pub fn routed(&self, id: u64, path: Option<String>, host: Option<String>) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(&id) {
+            session.info.path = path;
+            session.info.host = host;
+        }
+    }
+
+    /// Gives a session the name and labels its client said it has
+    pub fn named(&self, id: u64, name: Option<String>, labels: BTreeMap<String, String>) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(&id) {
+            session.info.name = name;
+            session.info.labels = labels;
+        }
+    }
+
+    /// The sessions with all of `labels`, oldest first
+    pub fn labelled(&self, labels: &BTreeMap<String, String>) -> Vec<u64> {
+        let mut ids: Vec<u64> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|s| labels.iter().all(|(k, v)| s.info.labels.get(k) == Some(v)))
+            .map(|s| s.info.id)
+            .collect();
+        ids.sort();
+
+        ids
+    }
+
+    /// Whether the session hasn't finished yet
+    pub fn is_connected(&self, id: u64) -> bool {
+        self.sessions.lock().unwrap().contains_key(&id)
+    }
+
+    /// The session whose path is `path`
+    pub fn by_path(&self, path: &str) -> Option<u64> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .values()
+            .find(|s| s.info.path.as_deref() == Some(path))
+            .map(|s| s.info.id)
+    }
+
+    /// The session whose host is `host`
+    pub fn by_host(&self, host: &str) -> Option<u64> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .values()
+            .find(|s| s.info.host.as_deref() == Some(host))
+            .map(|s| s.info.id)
+    }
+
+    pub fn finish(&self, id: u64) {
+        self.sessions.lock().unwrap().remove(&id);
+    }
+
+    /// Asks a session to disconnect, false if there's no such session
+    pub fn disconnect(&self, id: u64, why: Disconnect) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+
+        match sessions.get_mut(&id).and_then(|s| s.disconnect.take()) {
+            Some(disconnect) => {
+                let _ = disconnect.send(why);
+                true
+            }
+            None => sessions.contains_key(&id),
+        }
+    }
+
+    pub fn disconnect_all(&self, why: Disconnect) {
+        for session in self.sessions.lock().unwrap().values_mut() {
+            if let Some(disconnect) = session.disconnect.take() {
+                let _ = disconnect.send(why);
+            }
+        }
+    }
+
+    /// The connected sessions, oldest first
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let mut sessions: Vec<SessionInfo> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .values()
+            .map(|s| s.info.clone())
+            .collect();
+        sessions.sort_by_key(|s| s.id);
+
+        sessions
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use log::info;
+use serde::Serialize;
+
+use super::sessions::SessionInfo;
+
+/// Paths beyond this many are counted together, so random paths can't grow the map forever
+const MAX_PATHS: usize = 1000;
+const OTHER_PATHS: &str = "(other)";
+
+/// Counters for the paths requests arrive on, sessions count for themselves in `Sessions`
+#[derive(Default)]
+pub struct Stats {
+    paths: Mutex<HashMap<String, PathCounters>>,
+    denied: Mutex<BTreeMap<String, u64>>,
+    sampled_out: AtomicU64,
+}
+
+#[derive(Default)]
+struct PathCounters {
+    count: u64,
+    bytes: u64,
+}
+
+#[derive(Serialize)]
+pub struct PathStats {
+    pub path: String,
+    pub count: u64,
+    pub avg_size: u64,
+}
+
+#[derive(Serialize)]
+pub struct Snapshot {
+    pub sessions: Vec<SessionInfo>,
+    pub paths: Vec<PathStats>,
+    /// Requests a filter rule kept from being relayed, by the rule's name
+    pub denied: BTreeMap<String, u64>,
+    /// Requests left out by sampling
+    pub sampled_out: u64,
+}
+
+impl Stats {
+    /// Counts a request received on `path`, which shouldn't include the query
+    pub fn received(&self, path: &str, size: usize) {
+        let mut paths = self.paths.lock().unwrap();
+
+        let key = if paths.contains_key(path) || paths.len() < MAX_PATHS {
+            path
+        } else {
+            OTHER_PATHS
+        };
+
+        let counters = paths.entry(key.to_string()).or_default();
+        counters.count += 1;
+        counters.bytes += size as u64;
+    }
+
+    /// Counts a request the filter rule `rule` denied
+    pub fn denied(&self, rule: &str) {
+        *self
+            .denied
+            .lock()
+            .unwrap()
+            .entry(rule.to_string())
+            .or_default() += 1;
+    }
+
+    /// Counts a request sampling left out
+    pub fn sampled_out(&self) {
+        self.sampled_out.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self, sessions: Vec<SessionInfo>) -> Snapshot {
+        let mut paths: Vec<PathStats> = self
+            .paths
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, counters)| PathStats {
+                path: path.clone(),
+                count: counters.count,
+                avg_size: counters.bytes / counters.count.max(1),
+            })
+            .collect();
+        paths.sort_by_key(|p| std::cmp::Reverse(p.count));
+
+        Snapshot {
+            sessions,
+            paths,
+            denied: self.denied.lock().unwrap().clone(),
+            sampled_out: self.sampled_out.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn log_summary(&self, sessions: Vec<SessionInfo>) {
+        let snapshot = self.snapshot(sessions);
+
+        let received: u64 = snapshot.paths.iter().map(|p| p.count).sum();
+        let sent: u64 = snapshot.sessions.iter().map(|s| s.messages).sum();
+        let lag_drops: u64 = snapshot.sessions.iter().map(|s| s.lag_drops).sum();
+        let throttle_drops: u64 = snapshot.sessions.iter().map(|s| s.throttle_drops).sum();
+        let denied: u64 = snapshot.denied.values().sum();
+
+        info!(
+            event = "stats", sessions = snapshot.sessions.len(), received = received, paths = snapshot.paths.len(), sent = sent, lag_drops = lag_drops, throttle_drops = throttle_drops, denied = denied, sampled_out = snapshot.sampled_out;
+            "{} session(s) connected, {} request(s) received on {} path(s), {} message(s) sent to current sessions, {} dropped, {} throttled, {} denied by filter rules, {} sampled out",
+            snapshot.sessions.len(), received, snapshot.paths.len(), sent, lag_drops, throttle_drops, denied, snapshot.sampled_out
+        );
+
+        if let Some(busiest) = snapshot.paths.first() {
+            info!(
+                "Busiest path {} with {} request(s) averaging {} bytes",
+                busiest.path, busiest.count, busiest.avg_size
+            );
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use super::paths;
+
+/// Subdomains of the base domain claimed by connected clients, each by one at a time
+pub struct Subdomains {
+    domain: String,
+    /// Never given to a client
+    reserved: HashSet<String>,
+    claimed: Arc<Mutex<HashSet<String>>>,
+}
+
+/// A subdomain held by a client, until it's dropped
+pub struct Claim {
+    pub name: String,
+    /// The subdomain below the base domain
+    pub host: String,
+    claimed: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Subdomains {
+    pub fn new(domain: String, reserved: &[String]) -> Self {
+        Self {
+            domain,
+            reserved: reserved
+                .iter()
+                .map(|name| name.to_ascii_lowercase())
+                .collect(),
+            claimed: Arc::default(),
+        }
+    }
+
+    /// Claims `requested`, or a random subdomain if none is, failing if another client holds it
+    pub fn claim(&self, requested: Option<&str>) -> Result<Claim, String> {
+        let mut claimed = self.claimed.lock().unwrap();
+
+        let name = match requested {
+            Some(name) => {
+                let name = name.to_ascii_lowercase();
+                if !is_label(&name) {
+                    return Err(format!("{} isn't a valid subdomain", name));
+                }
+                if self.reserved.contains(&name) {
+                    return Err(format!("subdomain {} is reserved", name));
+                }
+                if claimed.contains(&name) {
+                    return Err(format!("subdomain {} is already claimed", name));
+                }
+                name
+            }
+            None => loop {
+                let name = paths::token();
+                if !claimed.contains(&name) {
+                    break name;
+                }
+            },
+        };
+        claimed.insert(name.clone());
+
+        Ok(Claim {
+            host: format!("{}.{}", name, self.domain),
+            name,
+            claimed: self.claimed.clone(),
+        })
+    }
+
+    /// `host`, from a `Host` header, lowercased and without its port if it's one subdomain
+    /// below the base domain
+    pub fn below(&self, host: &str) -> Option<String> {
+        let host = match host.rsplit_once(':') {
+            Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+            _ => host,
+        };
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+
+        let name = host.strip_suffix(&self.domain)?.strip_suffix('.')?;
+        is_label(name).then_some(host)
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        self.claimed.lock().unwrap().remove(&self.name);
+    }
+}
+
+/// Whether `name` can be a single DNS label, so one subdomain below the base domain
+fn is_label(name: &str) -> bool {
+    (1..=63).contains(&name.len())
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+}
@@ -0,0 +1,100 @@
+//! Just enough of systemd's service protocol: readiness and watchdog notifications, and
+//! sockets passed in by socket activation.
+
+use std::{
+    env,
+    net::TcpListener,
+    os::{
+        fd::{FromRawFd, IntoRawFd, RawFd},
+        unix::net::{SocketAddr, UnixDatagram, UnixListener},
+    },
+    time::Duration,
+};
+
+use log::{info, warn};
+
+/// Socket activation passes sockets starting from this descriptor
+const LISTEN_FDS_START: RawFd = 3;
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Sends `state` (e.g. `READY=1`) to systemd, if it's managing this process
+pub fn notify(state: &str) {
+    let Ok(path) = env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    let result = (|| {
+        let addr = match path.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                <SocketAddr as std::os::linux::net::SocketAddrExt>::from_abstract_name(name)?
+            }
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => return Err(std::io::ErrorKind::Unsupported.into()),
+            None => SocketAddr::from_pathname(&path)?,
+        };
+        UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)
+    })();
+
+    if let Err(e) = result {
+        warn!("Could not notify systemd of {}: {}", state, e);
+    }
+}
+
+/// Pings systemd's watchdog at half the interval it asked for, if it asked
+pub fn start_watchdog() {
+    let Some(usec) = env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+    else {
+        return;
+    };
+    if env::var("WATCHDOG_PID").is_ok_and(|pid| pid != std::process::id().to_string()) {
+        return;
+    }
+
+    let period = Duration::from_micros(usec) / 2;
+    info!("Pinging the systemd watchdog every {:?}", period);
+
+    actix_web::rt::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            notify("WATCHDOG=1");
+        }
+    });
+}
+
+/// Takes the sockets systemd passed in by socket activation, empty if it didn't
+pub fn listeners() -> Vec<Listener> {
+    let Some(count) = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse::<RawFd>().ok())
+    else {
+        return vec![];
+    };
+    if env::var("LISTEN_PID").ok() != Some(std::process::id().to_string()) {
+        return vec![];
+    }
+
+    // so they aren't taken again, e.g. by a child process
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // systemd hands these over to us, nothing else owns them
+            let tcp = unsafe { TcpListener::from_raw_fd(fd) };
+            match tcp.local_addr() {
+                Ok(_) => Listener::Tcp(tcp),
+                // not an inet socket, so a unix one
+                Err(_) => Listener::Unix(unsafe { UnixListener::from_raw_fd(tcp.into_raw_fd()) }),
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,121 @@
+use std::{io, net::SocketAddr};
+
+use actix_web::web::Data;
+use log::{debug, info, warn};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use super::{Shared, TUNNEL_OPEN_TIMEOUT};
+use crate::tunnel::TunnelFrame;
+
+/// Largest chunk of a TCP stream sent in one frame
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Listens on each of `ports` on `host`
+pub(super) async fn bind(host: &str, ports: &[u16]) -> io::Result<Vec<TcpListener>> {
+    let mut listeners = Vec::with_capacity(ports.len());
+    for port in ports {
+        let listener = TcpListener::bind((host, *port)).await.map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("couldn't listen on TCP port {}: {}", port, e),
+            )
+        })?;
+        listeners.push(listener);
+    }
+
+    Ok(listeners)
+}
+
+/// Accepts connections on a forwarded port, tunnelling each through a client forwarding it
+pub(super) async fn serve(shared: Data<Shared>, listener: TcpListener) {
+    let port = listener.local_addr().map(|a| a.port()).unwrap_or_default();
+    info!("Forwarding TCP connections on port {}", port);
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                actix_web::rt::spawn(forward(shared.clone(), port, stream, peer));
+            }
+            Err(e) => warn!("Couldn't accept a connection on TCP port {}: {}", port, e),
+        }
+    }
+}
+
+async fn forward(shared: Data<Shared>, port: u16, mut stream: TcpStream, peer: SocketAddr) {
+    if !shared.policy().access.allows(peer.ip()) {
+        debug!("Refused TCP connection to port {} from {}", port, peer);
+        return;
+    }
+
+    let Some(mut tunnel) = shared.tunnels.connect(port) else {
+        warn!(
+            "No connected client forwards TCP port {}, closing the connection from {}",
+            port, peer
+        );
+        return;
+    };
+    let id = tunnel.id;
+
+    match tokio::time::timeout(TUNNEL_OPEN_TIMEOUT, tunnel.recv()).await {
+        Ok(Some(TunnelFrame::Opened { .. })) => {}
+        Ok(Some(TunnelFrame::Close { reason, .. })) => {
+            warn!(
+                "Session #{} couldn't open TCP port {} locally: {}",
+                tunnel.session_id, port, reason
+            );
+            return;
+        }
+        Ok(_) => return,
+        Err(_) => {
+            warn!(
+                "Session #{} took too long to open TCP port {} locally",
+                tunnel.session_id, port
+            );
+            tunnel.send(TunnelFrame::Close {
+                id,
+                code: None,
+                reason: "timed out".to_string(),
+            });
+            return;
+        }
+    }
+
+    info!(
+        event = "tcp_opened", tunnel = id, session = tunnel.session_id, port = port;
+        "Tunnelling TCP connection from {} to port {} through session #{}", peer, port, tunnel.session_id
+    );
+
+    let (mut reader, mut writer) = stream.split();
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        tokio::select! {
+            read = reader.read(&mut buf) => {
+                let data = match read {
+                    Ok(0) | Err(_) => {
+                        tunnel.send(TunnelFrame::Close { id, code: None, reason: String::new() });
+                        break;
+                    }
+                    Ok(n) => buf[..n].to_vec(),
+                };
+                if !tunnel.send(TunnelFrame::Data { id, text: false, data }) {
+                    break;
+                }
+            },
+            frame = tunnel.recv() => match frame {
+                Some(TunnelFrame::Data { data, .. }) => {
+                    if writer.write_all(&data).await.is_err() {
+                        tunnel.send(TunnelFrame::Close { id, code: None, reason: String::new() });
+                        break;
+                    }
+                }
+                Some(TunnelFrame::Close { .. }) | None => break,
+                Some(_) => {}
+            }
+        }
+    }
+
+    info!(event = "tcp_closed", tunnel = id, port = port; "Closed TCP connection from {} to port {}", peer, port);
+}
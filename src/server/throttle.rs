@@ -0,0 +1,86 @@
+use std::{str::FromStr, time::Duration};
+
+use tokio::time::Instant;
+
+use super::rate_limit::{Bucket, Limit};
+
+/// What a session over its quota does with the next request
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ThrottlePolicy {
+    /// Holds it until the quota allows it, leaving the rest queued behind it
+    #[default]
+    Delay,
+    /// Doesn't send it to the client
+    Drop,
+}
+
+impl FromStr for ThrottlePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "delay" => Ok(ThrottlePolicy::Delay),
+            "drop" => Ok(ThrottlePolicy::Drop),
+            _ => Err("expected delay or drop".to_string()),
+        }
+    }
+}
+
+/// How much each websocket session may be sent, so a client on a slow connection is held back
+/// rather than backing up the server
+#[derive(Clone, Copy, Default)]
+pub struct Quota {
+    pub messages: Option<Limit>,
+    pub bytes: Option<Limit>,
+    pub policy: ThrottlePolicy,
+}
+
+/// A session's buckets for its `Quota`
+pub struct Throttle {
+    quota: Quota,
+    messages: Bucket,
+    bytes: Bucket,
+}
+
+impl Throttle {
+    pub fn new(quota: Quota) -> Self {
+        let now = std::time::Instant::now();
+        let full = |limit: Option<Limit>| Bucket::full(limit.map_or(0.0, |l| l.burst), now);
+
+        Self {
+            quota,
+            messages: full(quota.messages),
+            bytes: full(quota.bytes),
+        }
+    }
+
+    pub fn policy(&self) -> ThrottlePolicy {
+        self.quota.policy
+    }
+
+    /// Counts a message of `size` bytes against the quota, or returns when it may be sent if
+    /// it's over, without counting it
+    pub fn admit(&mut self, size: usize) -> Result<(), Instant> {
+        let now = std::time::Instant::now();
+        let mut buckets = [
+            (self.quota.messages, &mut self.messages, 1.0),
+            (self.quota.bytes, &mut self.bytes, size as f64),
+        ];
+
+        let mut wait = Duration::ZERO;
+        for (limit, bucket, n) in buckets.iter_mut() {
+            if let Some(limit) = limit {
+                bucket.refill(limit.rate, limit.burst, now);
+                wait = wait.max(bucket.wait_for(*n, limit.rate, limit.burst));
+            }
+        }
+        if !wait.is_zero() {
+            return Err(Instant::now() + wait);
+        }
+
+        for (_, bucket, n) in buckets {
+            bucket.take_n(n);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+use std::{fs, io, path::Path, time::Duration};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use ring::{
+    digest,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+
+/// A client token, only a hash of the value is kept
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Token {
+    pub name: String,
+    hash: String,
+    pub created_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl Token {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|at| at <= Utc::now())
+    }
+}
+
+/// Tokens persisted as JSON in a file. It's read on every lookup so tokens added or revoked
+/// while the server is running take effect immediately.
+pub struct TokenStore<'a> {
+    path: &'a Path,
+}
+
+impl<'a> TokenStore<'a> {
+    pub fn new(path: &'a Path) -> Self {
+        Self { path }
+    }
+
+    pub fn list(&self) -> Result<Vec<Token>> {
+        match fs::read(self.path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| anyhow!("invalid tokens in {}: {}", self.path.display(), e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(anyhow!("{}: {}", self.path.display(), e)),
+        }
+    }
+
+    /// Finds the unexpired token with the given value
+    pub fn find(&self, value: &str) -> Result<Option<Token>> {
+        let hash = hash(value);
+
+        Ok(self
+            .list()?
+            .into_iter()
+            .find(|token| token.hash == hash && !token.is_expired()))
+    }
+
+    /// Adds a token, returning its value
+    pub fn add(&self, name: &str, expires_in: Option<Duration>) -> Result<String> {
+        let mut tokens = self.list()?;
+
+        if tokens.iter().any(|token| token.name == name) {
+            return Err(anyhow!("a token named {} already exists", name));
+        }
+
+        let mut bytes = [0u8; 24];
+        SystemRandom::new()
+            .fill(&mut bytes)
+            .map_err(|_| anyhow!("failed to generate a token"))?;
+        let value = format!("hh_{}", hex(&bytes));
+
+        let created_at = Utc::now();
+        let expires_at = expires_in
+            .map(chrono::Duration::from_std)
+            .transpose()?
+            .map(|expires_in| created_at + expires_in);
+
+        tokens.push(Token {
+            name: name.to_string(),
+            hash: hash(&value),
+            created_at,
+            expires_at,
+        });
+        self.save(&tokens)?;
+
+        Ok(value)
+    }
+
+    /// Removes a token, returning whether it existed
+    pub fn revoke(&self, name: &str) -> Result<bool> {
+        let mut tokens = self.list()?;
+        let count = tokens.len();
+
+        tokens.retain(|token| token.name != name);
+        self.save(&tokens)?;
+
+        Ok(tokens.len() < count)
+    }
+
+    fn save(&self, tokens: &[Token]) -> Result<()> {
+        fs::write(self.path, serde_json::to_vec_pretty(tokens)?)
+            .map_err(|e| anyhow!("{}: {}", self.path.display(), e))
+    }
+}
+
+fn hash(value: &str) -> String {
+    hex(digest::digest(&digest::SHA256, value.as_bytes()).as_ref())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,148 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::{tunnel::TunnelFrame, RequestMessage};
+
+/// The sessions websockets and TCP connections can be tunnelled through and the tunnels open
+/// through them
+#[derive(Default)]
+pub struct Tunnels {
+    next_id: AtomicU64,
+    sessions: Mutex<HashMap<u64, TunnelSession>>,
+    /// The session each tunnel goes through and where to pass the frames it sends for it
+    open: Mutex<HashMap<u64, (u64, UnboundedSender<TunnelFrame>)>>,
+}
+
+/// A session that negotiated tunnels
+struct TunnelSession {
+    /// Frames to send to it
+    frames: UnboundedSender<TunnelFrame>,
+    websockets: bool,
+    tcp_ports: Vec<u16>,
+}
+
+/// A tunnel opened through a session, closed when dropped
+pub struct Tunnel {
+    pub id: u64,
+    pub session_id: u64,
+    tunnels: Arc<Tunnels>,
+    to_client: UnboundedSender<TunnelFrame>,
+    from_client: UnboundedReceiver<TunnelFrame>,
+}
+
+impl Tunnels {
+    /// Starts taking websockets, if it offered them, and connections to `tcp_ports` through a
+    /// session, returning the frames to send it
+    pub fn add_session(
+        &self,
+        session_id: u64,
+        websockets: bool,
+        tcp_ports: Vec<u16>,
+    ) -> UnboundedReceiver<TunnelFrame> {
+        let (frames, rx) = unbounded_channel();
+        self.sessions.lock().unwrap().insert(
+            session_id,
+            TunnelSession {
+                frames,
+                websockets,
+                tcp_ports,
+            },
+        );
+        rx
+    }
+
+    /// Stops taking tunnels through a session, ending those open through it
+    pub fn remove_session(&self, session_id: u64) {
+        self.sessions.lock().unwrap().remove(&session_id);
+        self.open
+            .lock()
+            .unwrap()
+            .retain(|_, (session, _)| *session != session_id);
+    }
+
+    /// Asks the longest connected session that takes websockets, of just `among` if given, to
+    /// open one for `request`, `None` when there isn't one
+    pub fn open(
+        self: &Arc<Self>,
+        request: RequestMessage,
+        among: Option<&[u64]>,
+    ) -> Option<Tunnel> {
+        self.start(
+            |id, session| session.websockets && among.is_none_or(|ids| ids.contains(&id)),
+            |id| TunnelFrame::Open { id, request },
+        )
+    }
+
+    /// Asks the longest connected session forwarding `port` to open a connection for one made
+    /// to it, `None` when there isn't one
+    pub fn connect(self: &Arc<Self>, port: u16) -> Option<Tunnel> {
+        self.start(
+            |_, session| session.tcp_ports.contains(&port),
+            |id| TunnelFrame::Connect { id, port },
+        )
+    }
+
+    fn start(
+        self: &Arc<Self>,
+        takes: impl Fn(u64, &TunnelSession) -> bool,
+        frame: impl FnOnce(u64) -> TunnelFrame,
+    ) -> Option<Tunnel> {
+        let (session_id, to_client) = {
+            let sessions = self.sessions.lock().unwrap();
+            let (id, session) = sessions
+                .iter()
+                .filter(|(id, session)| takes(**id, session))
+                .min_by_key(|(id, _)| **id)?;
+            (*id, session.frames.clone())
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let (tx, from_client) = unbounded_channel();
+        self.open.lock().unwrap().insert(id, (session_id, tx));
+        let tunnel = Tunnel {
+            id,
+            session_id,
+            tunnels: self.clone(),
+            to_client,
+            from_client,
+        };
+
+        tunnel.send(frame(id)).then_some(tunnel)
+    }
+
+    /// Passes on a frame a session sent for one of its tunnels, ignoring it if that's closed or
+    /// not the session's
+    pub fn received(&self, session_id: u64, frame: TunnelFrame) {
+        let open = self.open.lock().unwrap();
+        if let Some((session, tx)) = open.get(&frame.id()) {
+            if *session == session_id {
+                let _ = tx.send(frame);
+            }
+        }
+    }
+}
+
+impl Tunnel {
+    /// Sends a frame to the client, false once its session is gone
+    pub fn send(&self, frame: TunnelFrame) -> bool {
+        self.to_client.send(frame).is_ok()
+    }
+
+    /// The next frame from the client, `None` once its session is gone
+    pub async fn recv(&mut self) -> Option<TunnelFrame> {
+        self.from_client.recv().await
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        self.tunnels.open.lock().unwrap().remove(&self.id);
+    }
+}
@@ -0,0 +1,425 @@
+use std::{fmt, str::FromStr};
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chrono::Utc;
+use ring::hmac;
+
+use crate::{find_header, HeaderBytes};
+
+const STRIPE_TOLERANCE_SECS: i64 = 300;
+
+/// Checks a webhook's HMAC-SHA256 signature the way a provider signs it.
+///
+/// Parsed from `github:<secret>`, `stripe:<secret>` or `hmac:<header>:<secret>`.
+#[derive(Clone)]
+pub enum Verifier {
+    /// `X-Hub-Signature-256: sha256=<hex>` over the body
+    GitHub { secret: String },
+    /// `Stripe-Signature: t=<timestamp>,v1=<hex>` over `<timestamp>.<body>`
+    Stripe { secret: String },
+    /// A hex (optionally `sha256=` prefixed) or base64 signature over the body in any header
+    Hmac { header: String, secret: String },
+}
+
+impl Verifier {
+    pub fn header(&self) -> &str {
+        match self {
+            Verifier::GitHub { .. } => "x-hub-signature-256",
+            Verifier::Stripe { .. } => "stripe-signature",
+            Verifier::Hmac { header, .. } => header,
+        }
+    }
+
+    /// Signs `body` the way the provider would, returning the value for `header()`
+    pub fn sign(&self, body: &[u8]) -> String {
+        match self {
+            Verifier::GitHub { secret } | Verifier::Hmac { secret, .. } => {
+                format!("sha256={}", encode_hex(&sign(secret, body)))
+            }
+            Verifier::Stripe { secret } => {
+                let timestamp = Utc::now().timestamp();
+                let mut payload = format!("{}.", timestamp).into_bytes();
+                payload.extend_from_slice(body);
+
+                format!("t={},v1={}", timestamp, encode_hex(&sign(secret, &payload)))
+            }
+        }
+    }
+
+    /// Verifies the signature of a request, returning why it's invalid if it isn't
+    pub fn verify(&self, headers: &[(String, HeaderBytes)], body: &[u8]) -> Result<(), String> {
+        self.verify_at(headers, body, Utc::now().timestamp())
+    }
+
+    /// Verifies a signature as `verify` does, `now` being the time Stripe timestamps are
+    /// checked against
+    fn verify_at(
+        &self,
+        headers: &[(String, HeaderBytes)],
+        body: &[u8],
+        now: i64,
+    ) -> Result<(), String> {
+        let value = find_header(headers, self.header())
+            .ok_or_else(|| format!("missing {} header", self.header()))?
+            .to_str()
+            .ok_or("malformed signature")?
+            .trim();
+
+        match self {
+            Verifier::GitHub { secret } => {
+                let signature = value
+                    .strip_prefix("sha256=")
+                    .and_then(decode_hex)
+                    .ok_or("malformed signature")?;
+
+                check(secret, body, &signature)
+            }
+            Verifier::Stripe { secret } => {
+                let mut timestamp = None;
+                let mut signatures = vec![];
+
+                for part in value.split(',') {
+                    match part.split_once('=') {
+                        Some(("t", t)) => timestamp = t.parse::<i64>().ok(),
+                        Some(("v1", s)) => signatures.extend(decode_hex(s)),
+                        _ => {}
+                    }
+                }
+
+                let timestamp = timestamp.ok_or("missing timestamp")?;
+                if (now - timestamp).abs() > STRIPE_TOLERANCE_SECS {
+                    return Err("timestamp outside of tolerance".to_string());
+                }
+
+                let mut payload = format!("{}.", timestamp).into_bytes();
+                payload.extend_from_slice(body);
+
+                signatures
+                    .iter()
+                    .find(|signature| check(secret, &payload, signature).is_ok())
+                    .map(|_| ())
+                    .ok_or_else(|| "signature mismatch".to_string())
+            }
+            Verifier::Hmac { secret, .. } => {
+                let value = value.strip_prefix("sha256=").unwrap_or(value);
+                let signature = decode_hex(value)
+                    .or_else(|| STANDARD.decode(value).ok())
+                    .ok_or("malformed signature")?;
+
+                check(secret, body, &signature)
+            }
+        }
+    }
+}
+
+impl FromStr for Verifier {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let secret = |s: &str| {
+            if s.is_empty() {
+                Err("secret can't be empty".to_string())
+            } else {
+                Ok(s.to_string())
+            }
+        };
+
+        match s.split_once(':') {
+            Some(("github", rest)) => Ok(Verifier::GitHub {
+                secret: secret(rest)?,
+            }),
+            Some(("stripe", rest)) => Ok(Verifier::Stripe {
+                secret: secret(rest)?,
+            }),
+            Some(("hmac", rest)) => match rest.split_once(':') {
+                Some((header, rest)) if !header.is_empty() => Ok(Verifier::Hmac {
+                    header: header.to_lowercase(),
+                    secret: secret(rest)?,
+                }),
+                _ => Err("expected hmac:<header>:<secret>".to_string()),
+            },
+            _ => Err(
+                "expected github:<secret>, stripe:<secret> or hmac:<header>:<secret>".to_string(),
+            ),
+        }
+    }
+}
+
+impl fmt::Debug for Verifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Verifier::GitHub { .. } => write!(f, "github"),
+            Verifier::Stripe { .. } => write!(f, "stripe"),
+            Verifier::Hmac { header, .. } => write!(f, "hmac({})", header),
+        }
+    }
+}
+
+/// Verifies a request against a set of verifiers, it's valid if any of them accepts it
+pub fn verify_any(
+    verifiers: &[Verifier],
+    headers: &[(String, HeaderBytes)],
+    body: &[u8],
+) -> Result<(), String> {
+    let mut reasons = vec![];
+
+    for verifier in verifiers {
+        match verifier.verify(headers, body) {
+            Ok(()) => return Ok(()),
+            Err(reason) => reasons.push(format!("{:?}: {}", verifier, reason)),
+        }
+    }
+
+    Err(reasons.join(", "))
+}
+
+fn check(secret: &str, payload: &[u8], signature: &[u8]) -> Result<(), String> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+
+    hmac::verify(&key, payload, signature).map_err(|_| "signature mismatch".to_string())
+}
+
+fn sign(secret: &str, payload: &[u8]) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+
+    hmac::sign(&key, payload).as_ref().to_vec()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// From GitHub's documentation on validating webhook deliveries
+    const GITHUB_SECRET: &str = "It's a Secret to Everybody";
+    const GITHUB_BODY: &[u8] = b"Hello, World!";
+    const GITHUB_SIGNATURE: &str =
+        "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+    const STRIPE_SECRET: &str = "whsec_test";
+    const STRIPE_BODY: &[u8] = br#"{"id":"evt_1"}"#;
+    const STRIPE_TIMESTAMP: i64 = 1_700_000_000;
+    const STRIPE_SIGNATURE: &str =
+        "c89214b5b5da833daed6f0b8c5bb6bd58cea9022bd80ccc78230f3942d632925";
+
+    /// RFC 4231 test case 2
+    const HMAC_SECRET: &str = "Jefe";
+    const HMAC_BODY: &[u8] = b"what do ya want for nothing?";
+    const HMAC_HEX: &str = "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+    const HMAC_BASE64: &str = "W9zBRr9gdU5qBCQmCJV1x1oAPwidJzmDnexYuWTsOEM=";
+
+    fn headers(name: &str, value: &str) -> Vec<(String, HeaderBytes)> {
+        vec![(name.to_string(), value.into())]
+    }
+
+    fn github() -> Verifier {
+        Verifier::GitHub {
+            secret: GITHUB_SECRET.to_string(),
+        }
+    }
+
+    fn stripe() -> Verifier {
+        Verifier::Stripe {
+            secret: STRIPE_SECRET.to_string(),
+        }
+    }
+
+    fn hmac() -> Verifier {
+        Verifier::Hmac {
+            header: "x-signature".to_string(),
+            secret: HMAC_SECRET.to_string(),
+        }
+    }
+
+    fn verify_stripe(value: &str, body: &[u8], now: i64) -> Result<(), String> {
+        stripe().verify_at(&headers("Stripe-Signature", value), body, now)
+    }
+
+    #[test]
+    fn verifies_github_signatures() {
+        let headers = headers("X-Hub-Signature-256", GITHUB_SIGNATURE);
+
+        assert_eq!(github().verify(&headers, GITHUB_BODY), Ok(()));
+        assert_eq!(
+            github().verify(&headers, b"Hello, World?"),
+            Err("signature mismatch".to_string())
+        );
+    }
+
+    #[test]
+    fn verifies_stripe_signatures() {
+        let value = format!("t={},v1={}", STRIPE_TIMESTAMP, STRIPE_SIGNATURE);
+
+        assert_eq!(
+            verify_stripe(&value, STRIPE_BODY, STRIPE_TIMESTAMP + 10),
+            Ok(())
+        );
+        assert_eq!(
+            verify_stripe(&value, br#"{"id":"evt_2"}"#, STRIPE_TIMESTAMP),
+            Err("signature mismatch".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_stale_stripe_timestamps() {
+        let value = format!("t={},v1={}", STRIPE_TIMESTAMP, STRIPE_SIGNATURE);
+
+        for now in [
+            STRIPE_TIMESTAMP + STRIPE_TOLERANCE_SECS + 1,
+            STRIPE_TIMESTAMP - STRIPE_TOLERANCE_SECS - 1,
+        ] {
+            assert_eq!(
+                verify_stripe(&value, STRIPE_BODY, now),
+                Err("timestamp outside of tolerance".to_string())
+            );
+        }
+        assert_eq!(
+            verify_stripe(
+                &value,
+                STRIPE_BODY,
+                STRIPE_TIMESTAMP + STRIPE_TOLERANCE_SECS
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn accepts_any_of_several_stripe_signatures() {
+        // as Stripe sends while a secret is being rolled
+        let other = "0".repeat(64);
+        let value = format!(
+            "t={},v1={},v0=abc,v1={}",
+            STRIPE_TIMESTAMP, other, STRIPE_SIGNATURE
+        );
+
+        assert_eq!(verify_stripe(&value, STRIPE_BODY, STRIPE_TIMESTAMP), Ok(()));
+
+        let value = format!("t={},v1={},v1={}", STRIPE_TIMESTAMP, other, other);
+        assert_eq!(
+            verify_stripe(&value, STRIPE_BODY, STRIPE_TIMESTAMP),
+            Err("signature mismatch".to_string())
+        );
+    }
+
+    #[test]
+    fn verifies_hex_and_base64_hmac_signatures() {
+        for value in [
+            HMAC_HEX.to_string(),
+            format!("sha256={}", HMAC_HEX),
+            HMAC_BASE64.to_string(),
+        ] {
+            assert_eq!(
+                hmac().verify(&headers("X-Signature", &value), HMAC_BODY),
+                Ok(()),
+                "{}",
+                value
+            );
+        }
+        assert_eq!(
+            hmac().verify(&headers("X-Signature", HMAC_HEX), b"what do ya want?"),
+            Err("signature mismatch".to_string())
+        );
+    }
+
+    #[test]
+    fn signs_what_it_verifies() {
+        for verifier in [github(), stripe(), hmac()] {
+            let headers = headers(verifier.header(), &verifier.sign(b"body"));
+
+            assert_eq!(verifier.verify(&headers, b"body"), Ok(()), "{:?}", verifier);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        let malformed = Err("malformed signature".to_string());
+
+        assert_eq!(
+            github().verify(&headers("X-Hub-Signature-256", "sha1=abcd"), GITHUB_BODY),
+            malformed
+        );
+        assert_eq!(
+            github().verify(&headers("X-Hub-Signature-256", "sha256=xyz"), GITHUB_BODY),
+            malformed
+        );
+        assert_eq!(
+            github().verify(&headers("X-Hub-Signature-256", "sha256=abc"), GITHUB_BODY),
+            malformed
+        );
+        assert_eq!(
+            hmac().verify(&headers("X-Signature", "not a signature!"), HMAC_BODY),
+            malformed
+        );
+        assert_eq!(
+            github().verify(&[], GITHUB_BODY),
+            Err("missing x-hub-signature-256 header".to_string())
+        );
+        assert_eq!(
+            verify_stripe(
+                &format!("v1={}", STRIPE_SIGNATURE),
+                STRIPE_BODY,
+                STRIPE_TIMESTAMP
+            ),
+            Err("missing timestamp".to_string())
+        );
+        assert_eq!(
+            verify_stripe(
+                &format!("t=soon,v1={}", STRIPE_SIGNATURE),
+                STRIPE_BODY,
+                STRIPE_TIMESTAMP
+            ),
+            Err("missing timestamp".to_string())
+        );
+        assert_eq!(
+            verify_stripe(
+                &format!("t={}", STRIPE_TIMESTAMP),
+                STRIPE_BODY,
+                STRIPE_TIMESTAMP
+            ),
+            Err("signature mismatch".to_string())
+        );
+    }
+
+    #[test]
+    fn any_verifier_accepting_is_enough() {
+        let headers = headers("X-Hub-Signature-256", GITHUB_SIGNATURE);
+
+        assert_eq!(
+            verify_any(&[stripe(), github()], &headers, GITHUB_BODY),
+            Ok(())
+        );
+        assert_eq!(
+            verify_any(&[stripe(), hmac()], &headers, GITHUB_BODY),
+            Err("stripe: missing stripe-signature header, hmac(x-signature): missing x-signature header".to_string())
+        );
+    }
+
+    #[test]
+    fn parses_verifiers() {
+        assert!(matches!(
+            "github:s".parse(),
+            Ok(Verifier::GitHub { secret }) if secret == "s"
+        ));
+        assert!(matches!(
+            "hmac:X-Sig:s:with:colons".parse(),
+            Ok(Verifier::Hmac { header, secret }) if header == "x-sig" && secret == "s:with:colons"
+        ));
+        for invalid in ["github:", "hmac::s", "hmac:x", "sha1:s", "stripe"] {
+            assert!(invalid.parse::<Verifier>().is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! Websockets tunnelled through a client's connection to the local origin: a provider's upgrade
+//! request is relayed to one client, which opens a websocket to its local origin and relays the
+//! messages both ways. Raw TCP connections to a server's forwarded port are tunnelled the same
+//! way to a local port. Negotiated as `Feature::Tunnel` or `Feature::TcpForward`, after which
+//! every binary frame on the connection starts with a byte saying what it carries.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::RequestMessage;
+
+/// Frame carrying a request from the server, or a delivery report from the client
+pub const MESSAGE_FRAME: u8 = 0;
+/// Frame carrying a `TunnelFrame` either way
+pub const TUNNEL_FRAME: u8 = 1;
+
+/// A message about one tunnelled websocket, identified by `id`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TunnelFrame {
+    /// Server to client: a provider asked to upgrade `request` to a websocket
+    Open { id: u64, request: RequestMessage },
+    /// Server to client: a connection was made to the forwarded TCP `port`
+    Connect { id: u64, port: u16 },
+    /// Client to server: the local origin accepted, with the subprotocol it chose if any
+    Opened { id: u64, protocol: Option<String> },
+    /// Either way: a message on the websocket, or bytes on the TCP connection
+    Data { id: u64, text: bool, data: Vec<u8> },
+    /// Either way: the websocket or TCP connection closed, or couldn't be opened
+    Close {
+        id: u64,
+        code: Option<u16>,
+        reason: String,
+    },
+}
+
+impl TunnelFrame {
+    pub fn id(&self) -> u64 {
+        match self {
+            TunnelFrame::Open { id, .. }
+            | TunnelFrame::Connect { id, .. }
+            | TunnelFrame::Opened { id, .. }
+            | TunnelFrame::Data { id, .. }
+            | TunnelFrame::Close { id, .. } => *id,
+        }
+    }
+
+    /// The frame as sent on a connection that negotiated tunnels
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut frame = vec![TUNNEL_FRAME];
+        frame.extend(rmp_serde::to_vec_named(self)?);
+        Ok(frame)
+    }
+}
+
+/// What a binary frame on a connection that negotiated tunnels carries
+pub enum Frame<'a> {
+    /// A request, or a delivery report, encoded as it would be without tunnels
+    Message(&'a [u8]),
+    Tunnel(TunnelFrame),
+}
+
+/// Splits a frame on a connection that negotiated tunnels into what it carries
+pub fn decode(frame: &[u8]) -> Result<Frame<'_>> {
+    match frame.split_first() {
+        Some((&MESSAGE_FRAME, data)) => Ok(Frame::Message(data)),
+        Some((&TUNNEL_FRAME, data)) => Ok(Frame::Tunnel(rmp_serde::from_slice(data)?)),
+        Some((kind, _)) => Err(anyhow!("unknown frame type {}", kind)),
+        None => Err(anyhow!("empty frame")),
+    }
+}
+
+/// `data` as a message frame on a connection that negotiated tunnels
+pub fn message_frame(data: Vec<u8>) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(data.len() + 1);
+    frame.push(MESSAGE_FRAME);
+    frame.extend(data);
+    frame
+}
@@ -0,0 +1,294 @@
+use anyhow::{anyhow, Result};
+use ciborium::Value;
+
+use crate::{HeaderBytes, RequestMessage, Version, WireFormat};
+
+const RAW: u8 = 0;
+const ZSTD: u8 = 1;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Version of the CBOR schema, only bumped for changes older peers can't ignore. New optional
+/// fields get a new tag instead.
+const CBOR_SCHEMA: u64 = 1;
+
+// CBOR field tags
+const SCHEMA: u64 = 0;
+const METHOD: u64 = 1;
+const FULLPATH: u64 = 2;
+const VERSION: u64 = 3;
+const HEADERS: u64 = 4;
+const BODY: u64 = 5;
+const SOURCE: u64 = 6;
+
+/// Encodes a request for the websocket. When compression was negotiated, `compress_above` is the
+/// size over which it's compressed with zstd and each frame starts with a byte saying whether
+/// it was.
+pub fn encode(
+    req: &RequestMessage,
+    format: WireFormat,
+    compress_above: Option<usize>,
+) -> Result<Vec<u8>> {
+    let data = match format {
+        WireFormat::Cbor => to_cbor(req)?,
+        WireFormat::Msgpack | WireFormat::Unknown if req.source.is_some() => {
+            let mut req = req.clone();
+            req.source = None;
+            rmp_serde::to_vec(&req)?
+        }
+        WireFormat::Msgpack | WireFormat::Unknown => rmp_serde::to_vec(req)?,
+    };
+
+    let Some(threshold) = compress_above else {
+        return Ok(data);
+    };
+
+    if data.len() <= threshold {
+        let mut frame = vec![RAW];
+        frame.extend_from_slice(&data);
+        return Ok(frame);
+    }
+
+    let mut frame = vec![ZSTD];
+    frame.extend_from_slice(&zstd::encode_all(data.as_slice(), ZSTD_LEVEL)?);
+    Ok(frame)
+}
+
+/// Decodes a request from the websocket, `compression` is whether it was negotiated
+pub fn decode(frame: &[u8], format: WireFormat, compression: bool) -> Result<RequestMessage> {
+    let data = if compression {
+        match frame.split_first() {
+            Some((&RAW, data)) => data.to_vec(),
+            Some((&ZSTD, data)) => zstd::decode_all(data)?,
+            Some((flag, _)) => return Err(anyhow!("unknown frame encoding {}", flag)),
+            None => return Err(anyhow!("empty frame")),
+        }
+    } else {
+        frame.to_vec()
+    };
+
+    match format {
+        WireFormat::Cbor => from_cbor(&data),
+        WireFormat::Msgpack | WireFormat::Unknown => Ok(rmp_serde::from_slice(&data)?),
+    }
+}
+
+/// A CBOR map of field tag to value. Headers are an array of `[name, bytes]` pairs.
+fn to_cbor(req: &RequestMessage) -> Result<Vec<u8>> {
+    let headers = req
+        .headers
+        .iter()
+        .map(|(name, value)| {
+            Value::Array(vec![
+                Value::Text(name.clone()),
+                Value::Bytes(value.as_bytes().to_vec()),
+            ])
+        })
+        .collect();
+
+    let mut fields = vec![
+        (SCHEMA, Value::Integer(CBOR_SCHEMA.into())),
+        (METHOD, Value::Text(req.method.clone())),
+        (FULLPATH, Value::Text(req.fullpath.clone())),
+        (VERSION, Value::Integer(u32::from(req.version).into())),
+        (HEADERS, Value::Array(headers)),
+        (BODY, Value::Bytes(req.body.clone())),
+    ];
+    if let Some(source) = &req.source {
+        fields.push((SOURCE, Value::serialized(source)?));
+    }
+
+    let value = Value::Map(
+        fields
+            .into_iter()
+            .map(|(tag, value)| (Value::Integer(tag.into()), value))
+            .collect(),
+    );
+
+    let mut data = vec![];
+    ciborium::into_writer(&value, &mut data)?;
+    Ok(data)
+}
+
+/// Reads a request written by `to_cbor`, ignoring tags it doesn't know so newer peers can add
+/// fields
+fn from_cbor(data: &[u8]) -> Result<RequestMessage> {
+    let Value::Map(fields) = ciborium::from_reader(data)? else {
+        return Err(anyhow!("expected a CBOR map"));
+    };
+
+    let mut req = RequestMessage {
+        method: String::new(),
+        fullpath: String::new(),
+        version: Version::default(),
+        headers: vec![],
+        body: vec![],
+        source: None,
+    };
+
+    for (tag, value) in fields {
+        let Some(tag) = tag.as_integer().and_then(|t| u64::try_from(t).ok()) else {
+            continue;
+        };
+
+        match (tag, value) {
+            (SCHEMA, Value::Integer(schema)) => {
+                let schema = u64::try_from(schema).unwrap_or(u64::MAX);
+                if schema > CBOR_SCHEMA {
+                    return Err(anyhow!("unsupported CBOR schema {}", schema));
+                }
+            }
+            (METHOD, Value::Text(method)) => req.method = method,
+            (FULLPATH, Value::Text(fullpath)) => req.fullpath = fullpath,
+            (VERSION, Value::Integer(version)) => {
+                req.version = u32::try_from(version)
+                    .map(Version::from)
+                    .unwrap_or_default()
+            }
+            (HEADERS, Value::Array(headers)) => {
+                for header in headers {
+                    if let Value::Array(pair) = header {
+                        if let [Value::Text(name), Value::Bytes(value)] = pair.as_slice() {
+                            req.headers.push((name.clone(), HeaderBytes(value.clone())));
+                        }
+                    }
+                }
+            }
+            (BODY, Value::Bytes(body)) => req.body = body,
+            // a map keyed by name, so fields can be added to it without a new tag
+            (SOURCE, source @ Value::Map(_)) => req.source = source.deserialized().ok(),
+            _ => {}
+        }
+    }
+
+    if req.method.is_empty() {
+        return Err(anyhow!("request is missing a method"));
+    }
+
+    Ok(req)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RequestSource;
+
+    fn request(body: Vec<u8>) -> RequestMessage {
+        RequestMessage {
+            method: "POST".to_string(),
+            fullpath: "/github?delivery=1".to_string(),
+            version: Version::Http2,
+            headers: vec![
+                ("Content-Type".to_string(), "application/json".into()),
+                ("X-Repeated".to_string(), "a".into()),
+                ("X-Repeated".to_string(), "b".into()),
+                ("X-Binary".to_string(), HeaderBytes(vec![0xff, 0xfe, b'x'])),
+            ],
+            body,
+            source: Some(RequestSource {
+                ip: Some("192.30.252.1".to_string()),
+                tls: Some(true),
+                host: Some("hooks.example.com".to_string()),
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn cbor(fields: Vec<(u64, Value)>) -> Vec<u8> {
+        let value = Value::Map(
+            fields
+                .into_iter()
+                .map(|(tag, value)| (Value::Integer(tag.into()), value))
+                .collect(),
+        );
+        let mut data = vec![];
+        ciborium::into_writer(&value, &mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn cbor_round_trips() {
+        let req = request(b"{}".to_vec());
+        let frame = encode(&req, WireFormat::Cbor, None).unwrap();
+
+        assert_eq!(decode(&frame, WireFormat::Cbor, false).unwrap(), req);
+    }
+
+    #[test]
+    fn msgpack_round_trips_without_source() {
+        let req = request(b"{}".to_vec());
+        let frame = encode(&req, WireFormat::Msgpack, None).unwrap();
+        let decoded = decode(&frame, WireFormat::Msgpack, false).unwrap();
+
+        assert_eq!(decoded.source, None);
+        assert_eq!(
+            decoded,
+            RequestMessage {
+                source: None,
+                ..req
+            }
+        );
+    }
+
+    #[test]
+    fn small_frames_are_flagged_raw() {
+        let req = request(b"{}".to_vec());
+        let frame = encode(&req, WireFormat::Cbor, Some(1024)).unwrap();
+
+        assert_eq!(frame[0], RAW);
+        assert_eq!(frame[1..], to_cbor(&req).unwrap());
+        assert_eq!(decode(&frame, WireFormat::Cbor, true).unwrap(), req);
+    }
+
+    #[test]
+    fn large_frames_are_compressed() {
+        let req = request(vec![b'a'; 4096]);
+        let uncompressed = to_cbor(&req).unwrap();
+
+        for format in [WireFormat::Cbor, WireFormat::Msgpack] {
+            let frame = encode(&req, format, Some(1024)).unwrap();
+
+            assert_eq!(frame[0], ZSTD);
+            assert!(frame.len() < uncompressed.len());
+            assert_eq!(decode(&frame, format, true).unwrap().body, req.body);
+        }
+    }
+
+    #[test]
+    fn unknown_frame_encodings_are_rejected() {
+        assert!(decode(&[7, 1, 2], WireFormat::Cbor, true).is_err());
+        assert!(decode(&[], WireFormat::Cbor, true).is_err());
+    }
+
+    #[test]
+    fn unknown_cbor_tags_are_ignored() {
+        let data = cbor(vec![
+            (SCHEMA, Value::Integer(CBOR_SCHEMA.into())),
+            (METHOD, Value::Text("GET".to_string())),
+            (FULLPATH, Value::Text("/".to_string())),
+            (99, Value::Text("from a newer peer".to_string())),
+        ]);
+        let req = decode(&data, WireFormat::Cbor, false).unwrap();
+
+        assert_eq!(req.method, "GET");
+        assert_eq!(req.fullpath, "/");
+        assert_eq!(req.version, Version::Http11);
+    }
+
+    #[test]
+    fn newer_cbor_schemas_are_rejected() {
+        let data = cbor(vec![
+            (SCHEMA, Value::Integer((CBOR_SCHEMA + 1).into())),
+            (METHOD, Value::Text("GET".to_string())),
+        ]);
+
+        assert!(decode(&data, WireFormat::Cbor, false).is_err());
+    }
+
+    #[test]
+    fn cbor_without_a_method_is_rejected() {
+        let data = cbor(vec![(FULLPATH, Value::Text("/".to_string()))]);
+
+        assert!(decode(&data, WireFormat::Cbor, false).is_err());
+    }
+}